@@ -7,11 +7,12 @@ use postbus::{Handler, SmtpService, SmtpState};
 pub async fn main() {
     env_logger::init();
 
-    let service = SmtpService::create(
-        "0.0.0.0:2525".parse().unwrap(),
-        "Postbus Demo".into(),
-        Arc::new(PrintingHandler {}),
-    );
+    let service = SmtpService::builder()
+        .address("0.0.0.0:2525".parse().unwrap())
+        .server_name("Postbus Demo")
+        .handler(Arc::new(PrintingHandler {}))
+        .build()
+        .unwrap();
 
     service.listen().await
 }
@@ -29,7 +30,11 @@ impl Handler for PrintingHandler {
     async fn save(&self, state: &SmtpState) -> bool {
         println!("Saving mail state:\r\n{:#?}", state);
 
-        let parsed = mailparse::parse_mail(state.data.as_bytes()).unwrap();
+        for recipient in state.recipient_mailboxes() {
+            println!("Delivering to {}.", recipient);
+        }
+
+        let parsed = mailparse::parse_mail(&state.data).unwrap();
         println!("Body:\r\n{:#?}", parsed);
 
         true
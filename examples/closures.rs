@@ -0,0 +1,33 @@
+use std::sync::Arc;
+
+use postbus::{handlers::FnHandler, SmtpService};
+
+#[tokio::main]
+pub async fn main() {
+    env_logger::init();
+
+    let handler = FnHandler::new(
+        |recipient| async move {
+            println!("Checking recipient {}.", recipient);
+
+            recipient.domain == "nexium.app".into()
+        },
+        |state| async move {
+            for recipient in state.recipient_mailboxes() {
+                println!("Delivering to {}.", recipient);
+            }
+            println!("Body:\r\n{}", String::from_utf8_lossy(&state.data));
+
+            true
+        },
+    );
+
+    let service = SmtpService::builder()
+        .address("0.0.0.0:2525".parse().unwrap())
+        .server_name("Postbus Demo")
+        .handler(Arc::new(handler))
+        .build()
+        .unwrap();
+
+    service.listen().await
+}
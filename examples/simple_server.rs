@@ -0,0 +1,27 @@
+use postbus::parser;
+
+/// Feeds raw input to the parser, the way a server would after reading from a socket.
+/// The second read completes the command which was cut off in the first one.
+pub fn main() {
+    let reads = [
+        "EHLO nexium.app\r\nMAIL FROM:<info@nexium.app>\r\nNOT A COMMAND\r\nRCPT TO:<adm",
+        "in@nexium.app>\r\nDATA\r\n",
+    ];
+
+    let mut remaining = String::new();
+
+    for read in reads.iter() {
+        let input = format!("{}{}", remaining, read);
+        let (commands, rem) = parser::parse(&input);
+
+        for (line, command) in commands {
+            match command {
+                Some(command) => print!("Parsed: {}", command),
+                None => println!("Syntax error: {:?}", line),
+            }
+        }
+
+        println!("Remaining: {:?}", rem);
+        remaining = rem.to_string();
+    }
+}
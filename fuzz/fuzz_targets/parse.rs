@@ -0,0 +1,20 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use postbus::parser;
+
+fuzz_target!(|input: &str| {
+    let (commands, remaining) = parser::parse(input);
+
+    // Only an unterminated line is left over, at the end of the input.
+    assert!(input.ends_with(remaining));
+    assert!(!remaining.contains('\n'));
+    for (line, _) in &commands {
+        assert!(!line.contains('\n'));
+    }
+
+    // The borrowing parser agrees with the owning one.
+    let (borrowed, borrowed_remaining) = parser::parse_borrowed(input);
+    assert_eq!(remaining, borrowed_remaining);
+    assert_eq!(commands.len(), borrowed.len());
+});
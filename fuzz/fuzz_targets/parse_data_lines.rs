@@ -0,0 +1,16 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use postbus::parser;
+
+fuzz_target!(|input: &[u8]| {
+    let (ended, data, remaining) = parser::parse_data_lines(input);
+
+    assert!(input.ends_with(&remaining));
+    // Without the end of the data, only an unterminated line is left over.
+    assert!(ended || !remaining.contains(&b'\n'));
+    // Dot-unstuffing only removes bytes, and lines are joined with CRLF.
+    let breaks = data.windows(2).filter(|w| *w == b"\r\n").count();
+    assert!(data.len() <= input.len() + breaks);
+    assert_eq!(breaks, data.iter().filter(|&&b| b == b'\n').count());
+});
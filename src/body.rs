@@ -0,0 +1,73 @@
+use std::{
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+use tokio::{
+    io::{AsyncRead, ReadBuf},
+    sync::mpsc,
+};
+
+/// Part of the body sent to a `BodyStream`, or `None` for the end of the body.
+pub(crate) type BodyChunk = Option<Vec<u8>>;
+
+/// Body of a message while it is received, passed to `Handler::save_stream`.
+/// Reading fails with `UnexpectedEof` when the message is aborted before its end.
+#[derive(Debug)]
+pub struct BodyStream {
+    receiver: mpsc::Receiver<BodyChunk>,
+    chunk: Vec<u8>,
+    pos: usize,
+    ended: bool,
+}
+
+/// Create a body and the sender feeding it, buffering up to `capacity` chunks.
+/// Dropping the sender without sending the end aborts the body.
+pub(crate) fn body_channel(capacity: usize) -> (mpsc::Sender<BodyChunk>, BodyStream) {
+    let (sender, receiver) = mpsc::channel(capacity);
+
+    let body = BodyStream {
+        receiver,
+        chunk: Vec::new(),
+        pos: 0,
+        ended: false,
+    };
+
+    (sender, body)
+}
+
+impl AsyncRead for BodyStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            if self.pos < self.chunk.len() {
+                let n = buf.remaining().min(self.chunk.len() - self.pos);
+                buf.put_slice(&self.chunk[self.pos..self.pos + n]);
+                self.pos += n;
+
+                return Poll::Ready(Ok(()));
+            }
+
+            if self.ended {
+                return Poll::Ready(Ok(()));
+            }
+
+            match ready!(self.receiver.poll_recv(cx)) {
+                Some(Some(chunk)) => {
+                    self.chunk = chunk;
+                    self.pos = 0;
+                }
+                Some(None) => self.ended = true,
+                None => {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "message aborted",
+                    )))
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,218 @@
+use std::fmt::Display;
+
+#[cfg(test)]
+mod tests;
+
+// All command types.
+#[non_exhaustive]
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    HELO(Domain),
+    EHLO(Domain),
+    RCPT(Mailbox, Vec<Parameter>),
+    FROM(Mailbox, Vec<Parameter>),
+    DATA,
+    /// Transfer a chunk of the given number of octets, with whether it is the last one, per RFC 3030.
+    BDAT(usize, bool),
+    RSET,
+    NOOP,
+    /// Expand a mailing list, given by name or address.
+    EXPN(String),
+    QUIT,
+    /// A known SMTP verb this server does not implement, e.g. `ETRN` or `VRFY`.
+    Unimplemented(String),
+}
+
+impl Display for Command {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Command::EHLO(ident) => writeln!(f, "EHLO {:?}", ident),
+            Command::HELO(ident) => writeln!(f, "HELO {:?}", ident),
+            Command::FROM(
+                Mailbox {
+                    local,
+                    domain: Domain(domain),
+                },
+                params,
+            ) => {
+                write!(f, "MAIL FROM: {}@{}", local, domain)?;
+                write_parameters(f, params)
+            }
+            Command::RCPT(mailbox, params) => {
+                write!(f, "RCPT TO: {}", mailbox)?;
+                write_parameters(f, params)
+            }
+            Command::DATA => writeln!(f, "DATA"),
+            Command::BDAT(size, true) => writeln!(f, "BDAT {} LAST", size),
+            Command::BDAT(size, false) => writeln!(f, "BDAT {}", size),
+            Command::RSET => writeln!(f, "RSET"),
+            Command::NOOP => writeln!(f, "NOOP"),
+            Command::EXPN(list) => writeln!(f, "EXPN {}", list),
+            Command::Unimplemented(verb) => writeln!(f, "{}", verb),
+            Command::QUIT => writeln!(f, "QUIT"),
+        }
+    }
+}
+
+/// Write ESMTP parameters, terminating the line.
+fn write_parameters(f: &mut std::fmt::Formatter<'_>, params: &[Parameter]) -> std::fmt::Result {
+    for (keyword, value) in params {
+        match value {
+            Some(value) => write!(f, " {}={}", keyword, value)?,
+            None => write!(f, " {}", keyword)?,
+        }
+    }
+
+    writeln!(f)
+}
+
+/// ESMTP parameter given to MAIL or RCPT, as a keyword with an optional value.
+pub type Parameter = (String, Option<String>);
+
+/// Find a parameter by its keyword, which is case-insensitive.
+pub fn find_parameter<'a>(params: &'a [Parameter], keyword: &str) -> Option<&'a Parameter> {
+    params.iter().find(|(k, _)| k.eq_ignore_ascii_case(keyword))
+}
+
+/// Body type declared with the BODY parameter of MAIL.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum BodyType {
+    /// `BODY=7BIT`, plain 7-bit ASCII, the default without a parameter.
+    SevenBit,
+    /// `BODY=8BITMIME`, from RFC 6152.
+    EightBitMime,
+    /// `BODY=BINARYMIME`, from RFC 3030.
+    BinaryMime,
+}
+
+impl BodyType {
+    /// Parse the value of a BODY parameter, which is case-insensitive.
+    pub fn parse(value: &str) -> Option<BodyType> {
+        match value.to_ascii_uppercase().as_str() {
+            "7BIT" => Some(BodyType::SevenBit),
+            "8BITMIME" => Some(BodyType::EightBitMime),
+            "BINARYMIME" => Some(BodyType::BinaryMime),
+            _ => None,
+        }
+    }
+}
+
+/// Content returned in a delivery status notification, from the RET parameter of MAIL per RFC 3461.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum DsnReturn {
+    /// `RET=FULL`, the whole message.
+    Full,
+    /// `RET=HDRS`, only the headers of the message.
+    Headers,
+}
+
+impl DsnReturn {
+    /// Parse the value of a RET parameter, which is case-insensitive.
+    pub fn parse(value: &str) -> Option<DsnReturn> {
+        match value.to_ascii_uppercase().as_str() {
+            "FULL" => Some(DsnReturn::Full),
+            "HDRS" => Some(DsnReturn::Headers),
+            _ => None,
+        }
+    }
+}
+
+/// Decode an xtext value from RFC 3461, as used by the AUTH parameter of MAIL.
+/// Characters outside of `!` to `~`, a plain `+` or `=`, and lowercase hex digits are invalid and return `None`.
+pub fn decode_xtext(value: &str) -> Option<String> {
+    let mut decoded = Vec::with_capacity(value.len());
+    let mut bytes = value.bytes();
+
+    while let Some(byte) = bytes.next() {
+        match byte {
+            b'+' => {
+                let hex = [bytes.next()?, bytes.next()?];
+                if !hex.iter().all(|b| matches!(b, b'0'..=b'9' | b'A'..=b'F')) {
+                    return None;
+                }
+
+                decoded.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+            }
+            b'=' => return None,
+            b'!'..=b'~' => decoded.push(byte),
+            _ => return None,
+        }
+    }
+
+    String::from_utf8(decoded).ok()
+}
+
+/// Domain as sent by the client.
+/// Domains are case-insensitive, so they compare equal regardless of case.
+/// Parse it with `str::parse` to validate the syntax, `From<&str>` accepts anything.
+#[derive(Debug, Clone)]
+pub struct Domain(pub String);
+
+/// Error returned when parsing a `Domain` or `Mailbox` from a string with invalid syntax.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct AddressError;
+
+impl Display for AddressError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid address syntax")
+    }
+}
+
+impl std::error::Error for AddressError {}
+
+/// Mailbox of a sender or recipient.
+/// The local part is case-sensitive, only the domain is compared case-insensitively.
+/// The domain is empty only for the `<postmaster>` recipient, see `Mailbox::is_postmaster`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Mailbox {
+    pub local: String,
+    pub domain: Domain,
+}
+
+impl Display for Mailbox {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.domain.0.is_empty() {
+            true => write!(f, "{}", self.local),
+            false => write!(f, "{}@{}", self.local, self.domain.0),
+        }
+    }
+}
+
+impl Mailbox {
+    /// Check if the mailbox only contains ASCII characters.
+    /// Mailboxes with other characters require the SMTPUTF8 extension.
+    pub fn is_ascii(&self) -> bool {
+        self.local.is_ascii() && self.domain.0.is_ascii()
+    }
+
+    /// Check if this is the postmaster, which every server must accept mail for per RFC 5321 section 4.5.1.
+    /// This is true for `<postmaster>` without a domain, which means the postmaster of this server,
+    /// and for a postmaster at any domain, its local part is case-insensitive unlike others.
+    /// Handlers should accept it in `Handler::check_recipient`, the domain tells which of the two it is.
+    pub fn is_postmaster(&self) -> bool {
+        self.local.eq_ignore_ascii_case("postmaster")
+    }
+}
+
+impl Domain {
+    /// The domain in lowercase, for use as a key or in comparisons outside of `PartialEq`.
+    pub fn normalized(&self) -> String {
+        self.0.to_lowercase()
+    }
+}
+
+impl PartialEq for Domain {
+    /// Domains are equal when their normalized forms are, so equal domains always share a key.
+    fn eq(&self, other: &Self) -> bool {
+        self.normalized() == other.normalized()
+    }
+}
+
+impl Eq for Domain {}
+
+impl From<&str> for Domain {
+    /// Take the domain as is, without validating it.
+    fn from(input: &str) -> Self {
+        Domain(input.to_string())
+    }
+}
@@ -0,0 +1,124 @@
+use super::*;
+
+fn mailbox(local: &str, domain: &str) -> Mailbox {
+    Mailbox {
+        local: local.to_string(),
+        domain: domain.into(),
+    }
+}
+
+#[test]
+fn domain_case_insensitive() {
+    assert_eq!(Domain::from("EXAMPLE.COM"), Domain::from("example.com"));
+    assert_eq!(Domain::from("Nexium.App"), Domain::from("nexium.app"));
+    assert_ne!(Domain::from("nexium.app"), Domain::from("nexium.dev"));
+}
+
+#[test]
+fn domain_case_insensitive_non_ascii() {
+    assert_eq!(Domain::from("BÜCHER.DE"), Domain::from("bücher.de"));
+}
+
+#[test]
+fn domain_normalized() {
+    assert_eq!(Domain::from("EXAMPLE.COM").normalized(), "example.com");
+    assert_eq!(Domain::from("BÜCHER.de").normalized(), "bücher.de");
+}
+
+#[test]
+fn domain_equal_when_normalized_equal() {
+    // A capital sigma ending the domain lowercases to a final sigma.
+    let (upper, lower) = (Domain::from("nexium.ΟΔΟΣ"), Domain::from("nexium.οδοσ"));
+
+    assert_ne!(upper.normalized(), lower.normalized());
+    assert_ne!(upper, lower);
+    assert_eq!(Domain::from("nexium.ΟΔΟΣ"), Domain::from("nexium.οδος"));
+}
+
+#[test]
+fn domain_keeps_original_case() {
+    assert_eq!(Domain::from("Nexium.App").0, "Nexium.App");
+}
+
+#[test]
+fn mailbox_local_case_sensitive() {
+    assert_eq!(mailbox("info", "NEXIUM.APP"), mailbox("info", "nexium.app"));
+    assert_ne!(mailbox("Info", "nexium.app"), mailbox("info", "nexium.app"));
+}
+
+#[test]
+fn mailbox_postmaster() {
+    assert!(mailbox("postmaster", "").is_postmaster());
+    assert!(mailbox("Postmaster", "example.com").is_postmaster());
+    assert!(!mailbox("postmasters", "example.com").is_postmaster());
+}
+
+#[test]
+fn mailbox_display() {
+    assert_eq!("info@nexium.app", mailbox("info", "nexium.app").to_string());
+    assert_eq!("postmaster", mailbox("postmaster", "").to_string());
+}
+
+#[test]
+fn domain_from_str() {
+    assert_eq!(Ok(Domain::from("nexium.app")), "nexium.app".parse());
+    assert_eq!(Ok(Domain::from("παράδειγμα.ελ")), "παράδειγμα.ελ".parse());
+    assert_eq!(Err(AddressError), "not a domain!".parse::<Domain>());
+    assert_eq!(Err(AddressError), "".parse::<Domain>());
+}
+
+#[test]
+fn mailbox_from_str() {
+    assert_eq!(Ok(mailbox("a", "b.com")), "a@b.com".parse());
+    assert_eq!(
+        Ok(mailbox("first last", "nexium.app")),
+        "\"first last\"@nexium.app".parse()
+    );
+    assert_eq!(Err(AddressError), "a@b.com trailing".parse::<Mailbox>());
+    assert_eq!(Err(AddressError), "<a@b.com>".parse::<Mailbox>());
+    assert_eq!(Err(AddressError), "postmaster".parse::<Mailbox>());
+}
+
+#[test]
+fn find_parameter_case_insensitive() {
+    let params = vec![("SIZE".to_string(), Some("100".to_string()))];
+
+    assert_eq!(find_parameter(&params, "size"), params.first());
+    assert_eq!(find_parameter(&params, "BODY"), None);
+}
+
+#[test]
+fn xtext_decoded() {
+    assert_eq!(
+        Some("user@example.com".to_string()),
+        decode_xtext("user@example.com")
+    );
+    assert_eq!(
+        Some("user+tag=1@example.com".to_string()),
+        decode_xtext("user+2Btag+3D1@example.com")
+    );
+    assert_eq!(Some("<>".to_string()), decode_xtext("<>"));
+}
+
+#[test]
+fn xtext_invalid() {
+    assert_eq!(None, decode_xtext("user+2b@example.com"));
+    assert_eq!(None, decode_xtext("user+2"));
+    assert_eq!(None, decode_xtext("user=@example.com"));
+    assert_eq!(None, decode_xtext("üser@example.com"));
+}
+
+#[test]
+fn dsn_return_parse() {
+    assert_eq!(Some(DsnReturn::Full), DsnReturn::parse("FULL"));
+    assert_eq!(Some(DsnReturn::Headers), DsnReturn::parse("hdrs"));
+    assert_eq!(None, DsnReturn::parse("HEADERS"));
+}
+
+#[test]
+fn body_type_parse() {
+    assert_eq!(Some(BodyType::SevenBit), BodyType::parse("7BIT"));
+    assert_eq!(Some(BodyType::EightBitMime), BodyType::parse("8bitmime"));
+    assert_eq!(Some(BodyType::BinaryMime), BodyType::parse("BinaryMIME"));
+    assert_eq!(None, BodyType::parse("8BIT"));
+}
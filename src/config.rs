@@ -0,0 +1,158 @@
+use ipnet::IpNet;
+use std::{net::IpAddr, sync::Arc, time::Duration};
+
+use crate::{response::ReplyTexts, Resolver};
+
+/// How much of the data sent by clients is masked in log output, e.g. for privacy regulations.
+/// Authentication data is never logged, whatever the level.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RedactionLevel {
+    /// Log everything, e.g. `MAIL FROM:<info@example.com>`.
+    None,
+    /// Mask local parts and other identities, e.g. `MAIL FROM:<***@example.com>`.
+    Addresses,
+    /// Mask every value sent by the client, including domains, e.g. `MAIL FROM:<***@***>`.
+    All,
+}
+
+/// Which line endings are accepted for commands.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LineEnding {
+    /// Require CRLF as RFC 5321 does, commands ending in a bare LF are rejected with 500.
+    Strict,
+    /// Accept both CRLF and a bare LF, for broken clients.
+    Lenient,
+}
+
+/// Settings shared by the service and all of its sessions.
+#[derive(Debug, Clone)]
+pub(crate) struct Config {
+    /// Name of the server, used in the greeting.
+    pub server_name: String,
+    /// Hostname of the server in the greeting and HELO replies, with `server_name` as banner after the greeting.
+    pub ehlo_hostname: Option<String>,
+    /// Maximum number of consecutive unparsable commands before the connection is closed.
+    pub max_bad_commands: usize,
+    /// Maximum size of a message in bytes, unlimited when `None`.
+    pub max_size: Option<usize>,
+    /// Number of lines a message may have, longer messages are rejected with 552 like oversized ones.
+    pub max_data_lines: Option<usize>,
+    /// Number of recipients accepted per message, further recipients are rejected with 452.
+    pub max_recipients_soft: usize,
+    /// Number of RCPT commands per message after which the connection is closed with 421.
+    pub max_recipients_hard: Option<usize>,
+    /// Number of messages a client may send before MAIL is answered with 421 and the connection closed.
+    pub max_transactions_per_connection: Option<usize>,
+    /// Time to wait for input from the client before closing the connection with 421.
+    pub command_timeout: Option<Duration>,
+    /// Time the handler may take to save a message, after which the client receives a 451.
+    pub save_timeout: Option<Duration>,
+    /// Time the handler may take to check a recipient or allow data, after which the client receives a 451.
+    pub handler_timeout: Option<Duration>,
+    /// Number of commands processed before yielding to other sessions.
+    pub max_commands_per_batch: usize,
+    /// Number of commands a session may issue per second, further commands are delayed.
+    pub max_commands_per_second: Option<u32>,
+    /// Length of a command line in octets including the CRLF, longer lines are answered with 500 without parsing them.
+    pub max_command_length: usize,
+    /// Length of the local part of senders and recipients in octets, longer addresses are answered with 501.
+    pub max_local_part_len: Option<usize>,
+    /// Length of the domain of senders and recipients in octets, longer addresses are answered with 501.
+    pub max_domain_len: Option<usize>,
+    /// Whether EXPN is answered, it discloses the members of mailing lists.
+    pub allow_expn: bool,
+    /// Whether clients connect through a load balancer sending a PROXY protocol header.
+    pub trust_proxy: bool,
+    /// Pause before the greeting, clients sending anything during it are closed with 554.
+    pub greet_delay: Option<Duration>,
+    /// Maximum time a connection may stay open, however active it is, after which it is closed with 421.
+    pub max_session_duration: Option<Duration>,
+    /// Replacement texts for fixed replies.
+    pub reply_texts: ReplyTexts,
+    /// Whether messages are passed to `Handler::save_stream` while their body is received.
+    pub stream_data: bool,
+    /// Whether the message is also kept exactly as received, in `SmtpState::raw_data`.
+    pub preserve_raw_data: bool,
+    /// Whether accepted recipients are echoed in the RCPT reply.
+    pub echo_recipients: bool,
+    /// Number of clients waiting for a command after which the longest waiting one is evicted for a new one.
+    pub max_idle_sessions: Option<usize>,
+    /// Number of sessions running at once, further clients are refused with 421.
+    pub max_concurrent_sessions: Option<usize>,
+    /// Networks clients may connect from, any network when empty.
+    pub allow_cidrs: Vec<IpNet>,
+    /// Networks clients are refused from with 554, even when they are allowed.
+    pub block_cidrs: Vec<IpNet>,
+    /// Whether Nagle's algorithm is disabled on accepted connections.
+    pub tcp_nodelay: bool,
+    /// Idle time after which TCP keepalive probes are sent on accepted connections, none when `None`.
+    pub tcp_keepalive: Option<Duration>,
+    /// Resolver looking up the reverse DNS of clients for `Handler::connection_allowed`.
+    pub resolver: Option<Arc<dyn Resolver>>,
+    /// What is masked in log output.
+    pub log_redaction: RedactionLevel,
+    /// Which line endings are accepted for commands.
+    pub line_ending: LineEnding,
+    /// Whether a client sending anything after DATA before receiving the 354 is closed with 503.
+    pub strict_pipelining: bool,
+    /// Additional EHLO lines advertised after the built-in extensions.
+    pub extra_capabilities: Vec<String>,
+}
+
+impl Config {
+    /// Name identifying the server in replies, the hostname when set and otherwise the server name.
+    pub fn hostname(&self) -> &str {
+        self.ehlo_hostname.as_deref().unwrap_or(&self.server_name)
+    }
+
+    /// Whether a client may connect from the address, per the allowed and blocked networks.
+    /// IPv4 clients of an IPv6 listener are matched by their IPv4 address.
+    pub fn address_allowed(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+
+        let allowed =
+            self.allow_cidrs.is_empty() || self.allow_cidrs.iter().any(|net| net.contains(&ip));
+        allowed && !self.block_cidrs.iter().any(|net| net.contains(&ip))
+    }
+
+    /// Create the default configuration for the given server name.
+    pub fn new(server_name: String) -> Self {
+        Config {
+            server_name,
+            ehlo_hostname: None,
+            max_bad_commands: 10,
+            max_size: None,
+            max_data_lines: None,
+            max_recipients_soft: 100,
+            max_recipients_hard: None,
+            max_transactions_per_connection: None,
+            command_timeout: Some(Duration::from_secs(300)),
+            save_timeout: Some(Duration::from_secs(300)),
+            handler_timeout: Some(Duration::from_secs(60)),
+            max_commands_per_batch: 100,
+            max_commands_per_second: None,
+            max_command_length: 512,
+            max_local_part_len: None,
+            max_domain_len: None,
+            allow_expn: false,
+            trust_proxy: false,
+            greet_delay: None,
+            max_session_duration: None,
+            reply_texts: ReplyTexts::new(),
+            stream_data: false,
+            preserve_raw_data: false,
+            echo_recipients: false,
+            max_idle_sessions: None,
+            max_concurrent_sessions: None,
+            allow_cidrs: Vec::new(),
+            block_cidrs: Vec::new(),
+            tcp_nodelay: true,
+            tcp_keepalive: None,
+            resolver: None,
+            log_redaction: RedactionLevel::None,
+            line_ending: LineEnding::Lenient,
+            strict_pipelining: false,
+            extra_capabilities: Vec::new(),
+        }
+    }
+}
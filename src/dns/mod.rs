@@ -0,0 +1,37 @@
+use async_trait::async_trait;
+use std::{fmt, net::IpAddr};
+
+#[cfg(test)]
+mod tests;
+
+/// Maximum number of PTR names checked, as a client can publish any number of them.
+const MAX_PTR_NAMES: usize = 10;
+
+/// DNS lookups for checking clients, install one with `SmtpServiceBuilder::resolver`.
+/// Implement it with a resolver library of choice, failed lookups return no records.
+#[async_trait]
+pub trait Resolver: Send + Sync {
+    /// Names of the PTR records of an address.
+    async fn reverse(&self, ip: IpAddr) -> Vec<String>;
+    /// Addresses of the A and AAAA records of a name.
+    async fn forward(&self, name: &str) -> Vec<IpAddr>;
+}
+
+impl fmt::Debug for dyn Resolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Resolver")
+    }
+}
+
+/// Look up the forward-confirmed reverse DNS name of an address, also called FCrDNS.
+/// This is the first PTR name of the address which resolves back to it, without the trailing dot.
+/// Returns `None` when there is no such name.
+pub async fn forward_confirmed(resolver: &dyn Resolver, ip: IpAddr) -> Option<String> {
+    for name in resolver.reverse(ip).await.into_iter().take(MAX_PTR_NAMES) {
+        if resolver.forward(&name).await.contains(&ip) {
+            return Some(name.trim_end_matches('.').to_string());
+        }
+    }
+
+    None
+}
@@ -0,0 +1,62 @@
+use std::collections::HashMap;
+
+use super::*;
+
+/// Resolver answering from fixed records.
+#[derive(Default)]
+struct MockResolver {
+    ptr: HashMap<IpAddr, Vec<String>>,
+    addresses: HashMap<String, Vec<IpAddr>>,
+}
+
+#[async_trait]
+impl Resolver for MockResolver {
+    async fn reverse(&self, ip: IpAddr) -> Vec<String> {
+        self.ptr.get(&ip).cloned().unwrap_or_default()
+    }
+
+    async fn forward(&self, name: &str) -> Vec<IpAddr> {
+        self.addresses.get(name).cloned().unwrap_or_default()
+    }
+}
+
+fn ip(address: &str) -> IpAddr {
+    address.parse().unwrap()
+}
+
+fn resolver() -> MockResolver {
+    let mut resolver = MockResolver::default();
+    resolver.ptr.insert(
+        ip("192.0.2.1"),
+        vec!["forged.example.com.".into(), "mx.example.com.".into()],
+    );
+    resolver
+        .addresses
+        .insert("mx.example.com.".into(), vec![ip("192.0.2.1")]);
+    resolver
+        .addresses
+        .insert("forged.example.com.".into(), vec![ip("198.51.100.1")]);
+    resolver
+        .ptr
+        .insert(ip("192.0.2.2"), vec!["forged.example.com.".into()]);
+
+    resolver
+}
+
+#[tokio::test]
+async fn confirmed_name() {
+    assert_eq!(
+        Some("mx.example.com".to_string()),
+        forward_confirmed(&resolver(), ip("192.0.2.1")).await
+    );
+}
+
+#[tokio::test]
+async fn unconfirmed_name() {
+    assert_eq!(None, forward_confirmed(&resolver(), ip("192.0.2.2")).await);
+}
+
+#[tokio::test]
+async fn missing_ptr() {
+    assert_eq!(None, forward_confirmed(&resolver(), ip("192.0.2.3")).await);
+}
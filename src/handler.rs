@@ -1,13 +1,191 @@
-use crate::{command, session::SmtpState};
+use crate::{command, machine::SmtpState, BodyStream, Response};
 use async_trait::async_trait;
+use tokio::io::AsyncReadExt;
+
+/// Decision of the handler whether a transaction may transfer its data.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum DataDecision {
+    /// Accept the data, replying with 354.
+    Accept,
+    /// Permanently reject the transaction with 550.
+    Reject,
+    /// Temporarily reject the transaction with 451.
+    TryLater,
+}
+
+/// Decision of the handler whether to accept a recipient.
+#[derive(Debug, PartialEq, Clone)]
+pub enum RecipientDecision {
+    /// Accept the recipient, replying with 250.
+    Accept,
+    /// Permanently reject the recipient with 550, e.g. because it does not exist.
+    RejectPermanent,
+    /// Temporarily reject the recipient with 450, e.g. because its mailbox store is unavailable.
+    RejectTemporary,
+    /// The recipient is not local, but the message may be relayed to it.
+    /// This is only accepted for authenticated clients, see `SmtpState::authenticated`,
+    /// others are rejected with 550.
+    Relay,
+    /// Reject the recipient with a custom response.
+    Custom(Response),
+}
+
+/// Result of saving a message, selecting the reply to the client.
+#[derive(Debug, PartialEq, Clone)]
+pub enum SaveOutcome {
+    /// The message was accepted without saying how, replying with `250 Ok`.
+    /// This is what `Handler::save` returning true maps to.
+    Accepted,
+    /// The message reached its final destination, e.g. a local mailbox, replying with `250 2.6.0 Delivered`.
+    Delivered,
+    /// The message was queued for later delivery, e.g. by a relay, replying with `250 2.0.0 Ok: queued as <id>`.
+    /// Ids containing line breaks are left out of the reply.
+    Queued(String),
+    /// The message was rejected with the given response.
+    Rejected(Response),
+}
+
+impl From<bool> for SaveOutcome {
+    /// Adapt the result of `Handler::save`, rejecting with 554 when false.
+    fn from(saved: bool) -> Self {
+        match saved {
+            true => SaveOutcome::Accepted,
+            false => SaveOutcome::Rejected(Response::TransactionFailed),
+        }
+    }
+}
+
+impl SaveOutcome {
+    /// Whether the message was accepted, in any way.
+    pub fn is_accepted(&self) -> bool {
+        !matches!(self, SaveOutcome::Rejected(_))
+    }
+}
+
+/// Load of the system, reported by `Handler::load_hint` to adapt how strictly new clients are treated.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum LoadLevel {
+    /// Serve clients with the configured settings.
+    Normal,
+    /// Serve clients with a command timeout of at most a minute, and a greeting delay of at least five seconds.
+    High,
+    /// Refuse new clients with 421.
+    Critical,
+}
+
+/// Why a transaction ended without its message being saved, see `Handler::transaction_aborted`.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum AbortReason {
+    /// The client closed the connection.
+    Eof,
+    /// The client sent QUIT.
+    Quit,
+    /// The client did not send anything in time, or the session time limit was reached.
+    Timeout,
+    /// Reading from or writing to the connection failed.
+    Error,
+    /// The server closed the connection, e.g. after too many bad commands or to make room for other clients.
+    Closed,
+}
 
 /// Handler for SMTP events.
+///
+/// The service holds the handler as an `Arc<dyn Handler>` shared by every session, so it must be
+/// `Send + Sync + 'static` and stay object safe: implementations use `#[async_trait]`, and methods
+/// added to the trait take no generic parameters. Only `recipient_local` and `save` are required,
+/// see `handlers::FnHandler` to provide those as closures instead of implementing the trait.
 #[async_trait]
 pub trait Handler: Send + Sync {
+    /// Report the current load, e.g. from the queue depth or CPU usage, consulted for every client which connects.
+    /// Clients are treated more strictly under load, see `LoadLevel`. Reports `LoadLevel::Normal` by default.
+    async fn load_hint(&self) -> LoadLevel {
+        LoadLevel::Normal
+    }
+    /// Decide whether to serve a client which just connected, closing the connection with 554 otherwise.
+    /// Meant for checks on the client address, e.g. requiring `SmtpState::reverse_dns` when a resolver is set.
+    /// Accepts by default.
+    async fn connection_allowed(&self, _state: &SmtpState) -> bool {
+        true
+    }
+    /// Decide whether to accept the identity given with HELO or EHLO, rejecting it with 550.
+    /// Spammers often forge it, e.g. claiming to be this server or giving an address other than their own.
+    /// The identity is already stored in the state, to compare it with e.g. `SmtpState::peer`.
+    /// It is not validated by the server, see `SmtpState::helo_identity`. Accepts by default.
+    async fn helo_allowed(&self, _state: &SmtpState, _identity: &str) -> bool {
+        true
+    }
     /// Validate the recipient to be local.
-    /// Return false to reject the recipient.
+    /// Return false to reject the recipient permanently, so the sender bounces the message.
+    /// When the recipient can not be looked up, e.g. because the user directory is unreachable,
+    /// implement `check_recipient` instead to return `RecipientDecision::RejectTemporary`, which the sender retries.
     async fn recipient_local(&self, _recipient: &command::Mailbox) -> bool;
+    /// Decide whether to accept the recipient, and how to reject it.
+    /// Defaults to `recipient_local`, rejecting permanently.
+    async fn check_recipient(&self, recipient: &command::Mailbox) -> RecipientDecision {
+        match self.recipient_local(recipient).await {
+            true => RecipientDecision::Accept,
+            false => RecipientDecision::RejectPermanent,
+        }
+    }
+    /// Decide whether to accept the recipient for the transaction in the state, e.g. based on the client and sender.
+    /// The recipient is not yet in `SmtpState::recipients`. Defaults to `check_recipient`.
+    async fn recipient_allowed(
+        &self,
+        _state: &SmtpState,
+        recipient: &command::Mailbox,
+    ) -> RecipientDecision {
+        self.check_recipient(recipient).await
+    }
+    /// Rewrite an accepted recipient into its canonical address, e.g. stripping a `+tag` or folding the case.
+    /// Called after `recipient_allowed`, so after `check_recipient`, and only for recipients it accepted.
+    /// The returned address is stored in `SmtpState::recipients`, return `None` to reject the recipient with 550.
+    /// Keeps the recipient unchanged by default.
+    async fn rewrite_recipient(&self, recipient: command::Mailbox) -> Option<command::Mailbox> {
+        Some(recipient)
+    }
+    /// Decide if the transaction may start transferring its data.
+    /// This is called on DATA, before the body is sent. Accepts by default.
+    async fn data_allowed(&self, _state: &SmtpState) -> DataDecision {
+        DataDecision::Accept
+    }
+    /// Expand a mailing list into its members, for the EXPN command.
+    /// Only called when EXPN is enabled on the service. Returns `None` for unknown lists by default.
+    async fn expand(&self, _list: &str) -> Option<Vec<command::Mailbox>> {
+        None
+    }
+    /// Handle a command line the server could not parse, e.g. a proprietary `X` command.
+    /// The line is passed without its line break. The state may be changed for stateful extensions.
+    /// Return the reply to send, or `None` to reply with the usual 500, which counts as a bad command.
+    /// Returns `None` by default.
+    async fn unknown_command(&self, _line: &str, _state: &mut SmtpState) -> Option<Response> {
+        None
+    }
     /// Save an email to the system.
     /// Return true to accept the email.
     async fn save(&self, _state: &SmtpState) -> bool;
+    /// Save an email to the system, telling the client whether it was delivered or queued.
+    /// Content scans can reject with e.g. `Response::VirusDetected`, `PolicyRejected` or `ScanFailed`.
+    /// Defaults to `save`, see `SaveOutcome::from`.
+    async fn save_message(&self, state: &SmtpState) -> SaveOutcome {
+        self.save(state).await.into()
+    }
+    /// Called when the session ends during a transaction, after MAIL was accepted but before its message was saved.
+    /// The state holds the transaction as it was, including any data received so far, e.g. to clean up or log it.
+    /// Messages aborted for their size or rejected by the handler end the transaction without ending the session,
+    /// so they are not reported here. Does nothing by default.
+    async fn transaction_aborted(&self, _state: &SmtpState, _reason: AbortReason) {}
+    /// Save an email while its body is received, instead of `save_message` once it is complete.
+    /// Only called when `SmtpServiceBuilder::stream_data` is enabled, right after DATA is accepted,
+    /// so `SmtpState::data` is empty. The body is dot-unstuffed and ends before the final line break.
+    /// Reading fails when the message is aborted, e.g. for its size or a disconnect, the result is then ignored.
+    /// The client is read only as fast as the body is, so a handler should keep reading until the end.
+    /// Defaults to reading the whole body and passing it to `save_message`.
+    async fn save_stream(&self, state: &SmtpState, mut body: BodyStream) -> SaveOutcome {
+        let mut state = state.clone();
+        if body.read_to_end(&mut state.data).await.is_err() {
+            return SaveOutcome::Rejected(Response::TransactionFailed);
+        }
+
+        self.save_message(&state).await
+    }
 }
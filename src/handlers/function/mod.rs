@@ -0,0 +1,58 @@
+use async_trait::async_trait;
+use std::future::Future;
+
+use crate::{command::Mailbox, Handler, SmtpState};
+
+#[cfg(test)]
+mod tests;
+
+/// Handler made of two closures, to run a server without declaring a type.
+/// The closures receive their arguments by value, so they can be moved into an `async move` block.
+/// Every other method of `Handler` keeps its default.
+///
+/// ```
+/// use postbus::handlers::FnHandler;
+///
+/// let handler = FnHandler::new(
+///     |recipient| async move { recipient.domain == "nexium.app".into() },
+///     |state| async move { !state.data.is_empty() },
+/// );
+/// ```
+pub struct FnHandler<R, S> {
+    recipient_local: R,
+    save: S,
+}
+
+impl<R, RF, S, SF> FnHandler<R, S>
+where
+    R: Fn(Mailbox) -> RF + Send + Sync,
+    RF: Future<Output = bool> + Send,
+    S: Fn(SmtpState) -> SF + Send + Sync,
+    SF: Future<Output = bool> + Send,
+{
+    /// Create a handler checking recipients with `recipient_local` and saving messages with `save`.
+    /// These are called like `Handler::recipient_local` and `Handler::save`, with a clone of their argument.
+    pub fn new(recipient_local: R, save: S) -> Self {
+        FnHandler {
+            recipient_local,
+            save,
+        }
+    }
+}
+
+#[async_trait]
+impl<R, RF, S, SF> Handler for FnHandler<R, S>
+where
+    R: Fn(Mailbox) -> RF + Send + Sync,
+    RF: Future<Output = bool> + Send,
+    S: Fn(SmtpState) -> SF + Send + Sync,
+    SF: Future<Output = bool> + Send,
+{
+    async fn recipient_local(&self, recipient: &Mailbox) -> bool {
+        (self.recipient_local)(recipient.clone()).await
+    }
+
+    async fn save(&self, state: &SmtpState) -> bool {
+        (self.save)(state.clone()).await
+    }
+}
@@ -0,0 +1,51 @@
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+use super::*;
+use crate::{testing::TestHarness, Response};
+
+#[tokio::test]
+async fn closures_drive_a_session() {
+    let saved = Arc::new(AtomicUsize::new(0));
+    let counter = saved.clone();
+
+    let handler = FnHandler::new(
+        |recipient| async move { recipient.domain == "nexium.app".into() },
+        move |state| {
+            let counter = counter.clone();
+            async move {
+                counter.fetch_add(state.recipients.len(), Ordering::SeqCst);
+                state.data.starts_with(b"Subject: Hello")
+            }
+        },
+    );
+    let mut harness = TestHarness::new(Arc::new(handler)).await;
+
+    harness.send("EHLO example.com").await;
+    harness.send("MAIL FROM:<info@example.com>").await;
+    assert_eq!(
+        Response::RecipientNotLocal,
+        harness.send("RCPT TO:<info@example.com>").await
+    );
+    assert_eq!(
+        Response::Ok,
+        harness.send("RCPT TO:<admin@nexium.app>").await
+    );
+    assert_eq!(Response::StartData, harness.send("DATA").await);
+    assert_eq!(
+        Response::Ok,
+        harness.send_data("Subject: Hello\r\n\r\nHi!\r\n").await
+    );
+
+    harness.send("MAIL FROM:<info@example.com>").await;
+    harness.send("RCPT TO:<admin@nexium.app>").await;
+    harness.send("DATA").await;
+    assert_eq!(
+        Response::TransactionFailed,
+        harness.send_data("Subject: Bye\r\n\r\nHi!\r\n").await
+    );
+
+    assert_eq!(2, saved.load(Ordering::SeqCst));
+}
@@ -0,0 +1,236 @@
+use async_trait::async_trait;
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+
+use crate::{
+    command::Mailbox, AbortReason, BodyStream, DataDecision, Handler, LoadLevel, RecipientDecision,
+    Response, SaveOutcome, SmtpState,
+};
+
+#[cfg(test)]
+mod tests;
+
+/// Client address, sender and recipient of a delivery attempt, the key greylisting is based on.
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+pub struct Triplet {
+    pub ip: IpAddr,
+    /// Address of the sender, with the domain in lowercase.
+    pub sender: String,
+    /// Address of the recipient, with the domain in lowercase.
+    pub recipient: String,
+}
+
+impl Triplet {
+    /// Create the triplet for a delivery attempt.
+    pub fn new(ip: IpAddr, sender: &Mailbox, recipient: &Mailbox) -> Self {
+        let address =
+            |mailbox: &Mailbox| format!("{}@{}", mailbox.local, mailbox.domain.normalized());
+
+        Triplet {
+            ip,
+            sender: address(sender),
+            recipient: address(recipient),
+        }
+    }
+}
+
+/// Storage remembering when triplets were first seen, e.g. in memory or in Redis to share it between servers.
+#[async_trait]
+pub trait GreylistStore: Send + Sync {
+    /// Return when the triplet was first seen, recording the current time if it is new.
+    async fn first_seen(&self, triplet: &Triplet) -> SystemTime;
+}
+
+/// Time after which a triplet which was not seen again is forgotten, by default.
+const DEFAULT_RETRY_WINDOW: Duration = Duration::from_secs(2 * 24 * 60 * 60);
+
+/// Interval between removing the expired triplets of a `MemoryGreylistStore`.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Store keeping triplets in memory, which are lost on restart.
+/// Triplets not seen again within the retry window are removed, a client sending after that is greylisted anew.
+#[derive(Debug)]
+pub struct MemoryGreylistStore {
+    seen: Mutex<Seen>,
+    retry_window: Duration,
+}
+
+#[derive(Debug)]
+struct Seen {
+    /// When each triplet was first and last seen.
+    triplets: HashMap<Triplet, (SystemTime, SystemTime)>,
+    pruned: SystemTime,
+}
+
+impl MemoryGreylistStore {
+    /// Create an empty store, forgetting triplets which were not seen for two days.
+    pub fn new() -> Self {
+        MemoryGreylistStore::with_retry_window(DEFAULT_RETRY_WINDOW)
+    }
+
+    /// Create an empty store, forgetting triplets which were not seen within the given time.
+    /// It should be well above the greylisting delay, so retrying clients are still remembered.
+    pub fn with_retry_window(retry_window: Duration) -> Self {
+        MemoryGreylistStore {
+            seen: Mutex::new(Seen {
+                triplets: HashMap::new(),
+                pruned: SystemTime::now(),
+            }),
+            retry_window,
+        }
+    }
+
+    fn seen(&self) -> std::sync::MutexGuard<'_, Seen> {
+        self.seen.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Default for MemoryGreylistStore {
+    fn default() -> Self {
+        MemoryGreylistStore::new()
+    }
+}
+
+#[async_trait]
+impl GreylistStore for MemoryGreylistStore {
+    async fn first_seen(&self, triplet: &Triplet) -> SystemTime {
+        let now = SystemTime::now();
+        let mut seen = self.seen();
+        let expired =
+            |last: SystemTime| now.duration_since(last).unwrap_or_default() > self.retry_window;
+
+        if now.duration_since(seen.pruned).unwrap_or_default() >= PRUNE_INTERVAL {
+            seen.triplets.retain(|_, (_, last)| !expired(*last));
+            seen.pruned = now;
+        }
+
+        let (first, last) = seen.triplets.entry(triplet.clone()).or_insert((now, now));
+        if expired(*last) {
+            *first = now;
+        }
+        *last = now;
+
+        *first
+    }
+}
+
+/// Handler greylisting recipients, passing everything else to the wrapped handler.
+/// A recipient accepted by the wrapped handler is temporarily rejected with 450 until `delay` passed
+/// since its triplet was first seen. Legitimate servers retry later and are then accepted,
+/// while many spambots never retry. Transactions without a known client address are not greylisted.
+pub struct GreylistHandler<H, S = MemoryGreylistStore> {
+    inner: H,
+    store: S,
+    delay: Duration,
+}
+
+impl<H: Handler> GreylistHandler<H> {
+    /// Greylist the recipients for a handler, remembering triplets in memory.
+    pub fn new(inner: H, delay: Duration) -> Self {
+        GreylistHandler::with_store(inner, MemoryGreylistStore::new(), delay)
+    }
+}
+
+impl<H: Handler, S: GreylistStore> GreylistHandler<H, S> {
+    /// Greylist the recipients for a handler, remembering triplets in the given store.
+    pub fn with_store(inner: H, store: S, delay: Duration) -> Self {
+        GreylistHandler {
+            inner,
+            store,
+            delay,
+        }
+    }
+
+    /// Check the triplet of a recipient in the transaction, returning true when it is still greylisted.
+    async fn greylisted(&self, state: &SmtpState, recipient: &Mailbox) -> bool {
+        let (ip, sender) = match (state.peer, &state.from) {
+            (Some(peer), Some(sender)) => (peer.ip(), sender),
+            _ => return false,
+        };
+
+        let first_seen = self
+            .store
+            .first_seen(&Triplet::new(ip, sender, recipient))
+            .await;
+
+        SystemTime::now()
+            .duration_since(first_seen)
+            .unwrap_or_default()
+            < self.delay
+    }
+}
+
+#[async_trait]
+impl<H: Handler, S: GreylistStore> Handler for GreylistHandler<H, S> {
+    async fn load_hint(&self) -> LoadLevel {
+        self.inner.load_hint().await
+    }
+
+    async fn connection_allowed(&self, state: &SmtpState) -> bool {
+        self.inner.connection_allowed(state).await
+    }
+
+    async fn helo_allowed(&self, state: &SmtpState, identity: &str) -> bool {
+        self.inner.helo_allowed(state, identity).await
+    }
+
+    async fn recipient_local(&self, recipient: &Mailbox) -> bool {
+        self.inner.recipient_local(recipient).await
+    }
+
+    async fn check_recipient(&self, recipient: &Mailbox) -> RecipientDecision {
+        self.inner.check_recipient(recipient).await
+    }
+
+    async fn recipient_allowed(&self, state: &SmtpState, recipient: &Mailbox) -> RecipientDecision {
+        let decision = self.inner.recipient_allowed(state, recipient).await;
+
+        // Only recipients which would be accepted are recorded, so unknown ones don't fill the store.
+        let accepted = matches!(
+            decision,
+            RecipientDecision::Accept | RecipientDecision::Relay
+        );
+        if accepted && self.greylisted(state, recipient).await {
+            debug!(session = state.session_id; "Greylisted the recipient.");
+            return RecipientDecision::RejectTemporary;
+        }
+
+        decision
+    }
+
+    async fn rewrite_recipient(&self, recipient: Mailbox) -> Option<Mailbox> {
+        self.inner.rewrite_recipient(recipient).await
+    }
+
+    async fn data_allowed(&self, state: &SmtpState) -> DataDecision {
+        self.inner.data_allowed(state).await
+    }
+
+    async fn expand(&self, list: &str) -> Option<Vec<Mailbox>> {
+        self.inner.expand(list).await
+    }
+
+    async fn transaction_aborted(&self, state: &SmtpState, reason: AbortReason) {
+        self.inner.transaction_aborted(state, reason).await
+    }
+
+    async fn unknown_command(&self, line: &str, state: &mut SmtpState) -> Option<Response> {
+        self.inner.unknown_command(line, state).await
+    }
+
+    async fn save(&self, state: &SmtpState) -> bool {
+        self.inner.save(state).await
+    }
+
+    async fn save_message(&self, state: &SmtpState) -> SaveOutcome {
+        self.inner.save_message(state).await
+    }
+
+    async fn save_stream(&self, state: &SmtpState, body: BodyStream) -> SaveOutcome {
+        self.inner.save_stream(state, body).await
+    }
+}
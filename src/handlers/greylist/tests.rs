@@ -0,0 +1,191 @@
+use super::*;
+
+/// Handler accepting every recipient except `nobody`, and every message.
+struct AcceptingHandler {}
+
+#[async_trait]
+impl Handler for AcceptingHandler {
+    async fn recipient_local(&self, recipient: &Mailbox) -> bool {
+        recipient.local != "nobody"
+    }
+
+    async fn save(&self, _state: &SmtpState) -> bool {
+        true
+    }
+}
+
+const DELAY: Duration = Duration::from_millis(100);
+
+fn mailbox(local: &str, domain: &str) -> Mailbox {
+    Mailbox {
+        local: local.to_string(),
+        domain: domain.into(),
+    }
+}
+
+/// Transaction from the given client and sender.
+fn transaction(peer: &str, from: Mailbox) -> SmtpState {
+    SmtpState {
+        peer: Some(peer.parse().unwrap()),
+        from: Some(from),
+        ..SmtpState::default()
+    }
+}
+
+fn first_contact() -> SmtpState {
+    transaction("192.0.2.1:25", mailbox("info", "example.com"))
+}
+
+fn admin() -> Mailbox {
+    mailbox("admin", "nexium.app")
+}
+
+#[tokio::test]
+async fn first_contact_rejected() {
+    let handler = GreylistHandler::new(AcceptingHandler {}, DELAY);
+
+    assert_eq!(
+        RecipientDecision::RejectTemporary,
+        handler.recipient_allowed(&first_contact(), &admin()).await
+    );
+    assert_eq!(
+        RecipientDecision::RejectTemporary,
+        handler.recipient_allowed(&first_contact(), &admin()).await
+    );
+}
+
+#[tokio::test]
+async fn retry_after_delay_accepted() {
+    let handler = GreylistHandler::new(AcceptingHandler {}, DELAY);
+
+    assert_eq!(
+        RecipientDecision::RejectTemporary,
+        handler.recipient_allowed(&first_contact(), &admin()).await
+    );
+
+    tokio::time::sleep(DELAY).await;
+
+    assert_eq!(
+        RecipientDecision::Accept,
+        handler.recipient_allowed(&first_contact(), &admin()).await
+    );
+}
+
+#[tokio::test]
+async fn new_triplets_rejected_after_delay() {
+    let handler = GreylistHandler::new(AcceptingHandler {}, DELAY);
+    handler.recipient_allowed(&first_contact(), &admin()).await;
+
+    tokio::time::sleep(DELAY).await;
+
+    let other_client = transaction("192.0.2.2:25", mailbox("info", "example.com"));
+
+    assert_eq!(
+        RecipientDecision::Accept,
+        handler.recipient_allowed(&first_contact(), &admin()).await
+    );
+    assert_eq!(
+        RecipientDecision::RejectTemporary,
+        handler
+            .recipient_allowed(&first_contact(), &mailbox("sales", "nexium.app"))
+            .await
+    );
+    assert_eq!(
+        RecipientDecision::RejectTemporary,
+        handler.recipient_allowed(&other_client, &admin()).await
+    );
+}
+
+#[tokio::test]
+async fn sender_domain_case_ignored() {
+    let handler = GreylistHandler::new(AcceptingHandler {}, DELAY);
+    handler.recipient_allowed(&first_contact(), &admin()).await;
+
+    tokio::time::sleep(DELAY).await;
+
+    let retry = transaction("192.0.2.1:2525", mailbox("info", "EXAMPLE.com"));
+    assert_eq!(
+        RecipientDecision::Accept,
+        handler
+            .recipient_allowed(&retry, &mailbox("admin", "Nexium.App"))
+            .await
+    );
+}
+
+#[tokio::test]
+async fn unknown_client_not_greylisted() {
+    let handler = GreylistHandler::new(AcceptingHandler {}, DELAY);
+    let state = SmtpState {
+        peer: None,
+        ..first_contact()
+    };
+
+    assert_eq!(
+        RecipientDecision::Accept,
+        handler.recipient_allowed(&state, &admin()).await
+    );
+}
+
+#[tokio::test]
+async fn rejected_recipient_not_recorded() {
+    let handler = GreylistHandler::new(AcceptingHandler {}, DELAY);
+
+    assert_eq!(
+        RecipientDecision::RejectPermanent,
+        handler
+            .recipient_allowed(&first_contact(), &mailbox("nobody", "nexium.app"))
+            .await
+    );
+    assert!(handler.store.seen().triplets.is_empty());
+}
+
+#[tokio::test]
+async fn data_not_greylisted() {
+    let handler = GreylistHandler::new(AcceptingHandler {}, DELAY);
+
+    assert_eq!(
+        DataDecision::Accept,
+        handler.data_allowed(&first_contact()).await
+    );
+}
+
+#[tokio::test]
+async fn memory_store_forgets_expired_triplets() {
+    let store = MemoryGreylistStore::with_retry_window(DELAY);
+    let triplet = |local| {
+        Triplet::new(
+            "192.0.2.1".parse().unwrap(),
+            &mailbox("info", "example.com"),
+            &mailbox(local, "nexium.app"),
+        )
+    };
+
+    let first = store.first_seen(&triplet("admin")).await;
+    store.first_seen(&triplet("sales")).await;
+    tokio::time::sleep(DELAY * 2).await;
+
+    // An expired triplet is seen anew, and the others are removed on the next pruning.
+    store.seen().pruned = SystemTime::UNIX_EPOCH;
+    assert!(store.first_seen(&triplet("admin")).await > first);
+    assert_eq!(1, store.seen().triplets.len());
+}
+
+/// Store which has seen every triplet long ago.
+struct KnownStore {}
+
+#[async_trait]
+impl GreylistStore for KnownStore {
+    async fn first_seen(&self, _triplet: &Triplet) -> SystemTime {
+        SystemTime::UNIX_EPOCH
+    }
+}
+
+#[tokio::test]
+async fn custom_store_used() {
+    let handler = GreylistHandler::with_store(AcceptingHandler {}, KnownStore {}, DELAY);
+
+    assert_eq!(
+        RecipientDecision::Accept,
+        handler.recipient_allowed(&first_contact(), &admin()).await
+    );
+}
@@ -0,0 +1,8 @@
+//! Ready-made handlers, wrapping another `Handler` to add a common policy,
+//! or adapting closures for servers which do not need a type of their own.
+
+mod function;
+mod greylist;
+
+pub use function::FnHandler;
+pub use greylist::{GreylistHandler, GreylistStore, MemoryGreylistStore, Triplet};
@@ -1,15 +1,32 @@
 #[macro_use]
 extern crate log;
 
+mod body;
 pub mod command;
+mod config;
+mod dns;
 mod handler;
+pub mod handlers;
+pub mod machine;
+mod metrics;
 pub mod parser;
+mod proxy;
+mod redact;
 mod response;
 mod service;
 mod session;
+#[cfg(any(test, feature = "test-util"))]
+pub mod testing;
+mod trace;
 
-pub use handler::Handler;
+pub use body::BodyStream;
+pub use config::{LineEnding, RedactionLevel};
+pub use dns::{forward_confirmed, Resolver};
+pub use handler::{AbortReason, DataDecision, Handler, LoadLevel, RecipientDecision, SaveOutcome};
+pub use ipnet::IpNet;
+pub use machine::{Phase, Recipient, SmtpState};
+pub use metrics::{Metrics, MetricsCounters, Rejection};
 pub use response::Response;
-pub use service::SmtpService;
+pub use service::{BuildError, SmtpService, SmtpServiceBuilder};
 pub use session::SmtpSession;
-pub use session::SmtpState;
+pub use trace::{Direction, Tracer};
@@ -0,0 +1,917 @@
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use crate::{
+    command::{
+        decode_xtext, find_parameter, BodyType, Command, Domain, DsnReturn, Mailbox, Parameter,
+    },
+    config::Config,
+    redact, DataDecision, RecipientDecision, Response, SaveOutcome,
+};
+
+#[cfg(test)]
+mod tests;
+
+/// Counter used to hand out a unique id to every session.
+static NEXT_SESSION_ID: AtomicU64 = AtomicU64::new(1);
+/// Counter used to hand out a unique id to every transaction.
+static NEXT_TRANSACTION_ID: AtomicU64 = AtomicU64::new(1);
+/// Longest ENVID parameter of MAIL in its encoded form, per RFC 3461 section 4.4.
+const MAX_ENVID_LENGTH: usize = 100;
+
+/// Phase of the session, deciding which commands are in sequence.
+/// Commands sent out of sequence are answered with 503 and leave the phase as it was.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Phase {
+    /// The client has not been greeted with an accepted HELO or EHLO yet.
+    #[default]
+    Connected,
+    /// The client was greeted, and may start a transaction with MAIL.
+    Greeted,
+    /// MAIL was accepted, the transaction needs a recipient.
+    MailStarted,
+    /// At least one recipient was accepted, more may follow before DATA.
+    RcptStarted,
+    /// DATA was accepted, the message is being received.
+    Data,
+}
+
+/// Struct holding the current state of an transaction.
+#[derive(Debug, Default, Clone)]
+pub struct SmtpState {
+    /// Correlation id of the session, included in every log line of the server.
+    pub session_id: u64,
+    /// Id of the current transaction, unique within the process and new for every accepted MAIL.
+    /// It is zero outside of a transaction. Handlers keeping state per message should key it on this id,
+    /// `data_allowed`, `save_message` and `save_stream` of one transaction all see the same id.
+    pub transaction_id: u64,
+    /// Number of messages saved during the session.
+    pub transactions: usize,
+    /// Address of the client, taken from the PROXY protocol header when the service trusts it.
+    pub peer: Option<SocketAddr>,
+    /// Forward-confirmed reverse DNS name of the client, when the service has a resolver and the client has one.
+    pub reverse_dns: Option<String>,
+    /// Whether the client authenticated, which allows it to relay to non-local recipients.
+    /// The server has no AUTH command of its own, a handler implementing one sets this from `unknown_command`.
+    pub authenticated: bool,
+    /// Phase of the session, advanced by the commands which were accepted.
+    pub phase: Phase,
+    /// Identity given with HELO or EHLO, see `helo_identity`.
+    pub domain: Option<Domain>,
+    pub from: Option<Mailbox>,
+    /// ESMTP parameters given with MAIL FROM.
+    pub mail_params: Vec<Parameter>,
+    /// Whether the transaction is internationalized, using the SMTPUTF8 extension.
+    pub smtputf8: bool,
+    /// Identity which originally submitted the message, from the AUTH parameter of MAIL, decoded.
+    /// Per RFC 4954 this is `None` for `AUTH=<>`, and for any identity given by a client which did not authenticate.
+    pub mail_auth: Option<String>,
+    /// Envelope identifier from the ENVID parameter of MAIL, decoded, for delivery status notifications per RFC 3461.
+    pub envelope_id: Option<String>,
+    /// Accepted recipients with their ESMTP parameters, in the order of the RCPT commands.
+    pub recipients: Vec<Recipient>,
+    /// The message, dot-unstuffed and with every line ending in CRLF, but without the final line break.
+    /// These are octets, as 8-bit bodies are not necessarily UTF-8, e.g. Latin-1 text sent with `BODY=8BITMIME`.
+    /// A message sent with BDAT is the concatenation of its chunks exactly as received, e.g. a `BODY=BINARYMIME` one.
+    pub data: Vec<u8>,
+    /// Bytes of the message as received on the wire, including line breaks and dot-stuffing,
+    /// but not the terminating `.` line.
+    pub data_bytes: usize,
+    /// The message exactly as received, with its original line endings and dot-stuffing, but without the terminating `.` line.
+    /// Only kept when `SmtpServiceBuilder::preserve_raw_data` is enabled, e.g. for DKIM verification, otherwise `None`.
+    pub raw_data: Option<Vec<u8>>,
+    /// Size of the message in its canonical form, every line ending with CRLF and without dot-stuffing,
+    /// even when the client sent bare LFs. This is the size the SIZE parameter and limit refer to, per RFC 1870.
+    pub message_size: usize,
+}
+
+impl SmtpState {
+    /// The size of the message declared with the SIZE parameter of MAIL.
+    pub fn size_hint(&self) -> Option<usize> {
+        find_parameter(&self.mail_params, "SIZE")?
+            .1
+            .as_ref()?
+            .parse()
+            .ok()
+    }
+
+    /// The body type declared with the BODY parameter of MAIL.
+    pub fn body_type(&self) -> Option<BodyType> {
+        BodyType::parse(find_parameter(&self.mail_params, "BODY")?.1.as_ref()?)
+    }
+
+    /// What delivery status notifications should return of the message, from the RET parameter of MAIL.
+    pub fn dsn_return(&self) -> Option<DsnReturn> {
+        DsnReturn::parse(find_parameter(&self.mail_params, "RET")?.1.as_ref()?)
+    }
+
+    /// Identity the client gave with HELO or EHLO, exactly as sent.
+    /// This is usually a domain, but may be an address literal like `[192.0.2.1]` or any other word.
+    pub fn helo_identity(&self) -> Option<&str> {
+        self.domain.as_ref().map(|domain| domain.0.as_str())
+    }
+
+    /// All ESMTP parameters given with MAIL, as sent by the client.
+    pub fn raw_mail_params(&self) -> &[Parameter] {
+        &self.mail_params
+    }
+
+    /// Accepted recipients with their ESMTP parameters.
+    pub fn recipients(&self) -> &[Recipient] {
+        &self.recipients
+    }
+
+    /// Addresses of the accepted recipients, for handlers not interested in their parameters.
+    pub fn recipient_mailboxes(&self) -> impl Iterator<Item = &Mailbox> {
+        self.recipients.iter().map(|recipient| &recipient.mailbox)
+    }
+}
+
+/// Recipient accepted with RCPT.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Recipient {
+    pub mailbox: Mailbox,
+    /// ESMTP parameters given with RCPT, e.g. `NOTIFY` from DSN.
+    pub params: Vec<Parameter>,
+}
+
+/// Action the driver of the state machine should perform.
+#[derive(Debug, PartialEq)]
+pub enum Action {
+    /// Send the response to the client.
+    Reply(Response),
+    /// Send the response to the client, and close the connection afterwards.
+    Close(Response),
+    /// Ask the handler if the recipient is local.
+    /// The answer should be passed to `SmtpMachine::recipient_checked`.
+    CheckRecipient(Mailbox, Vec<Parameter>),
+    /// Ask the handler if the identity given with HELO or EHLO is allowed.
+    /// The answer should be passed to `SmtpMachine::helo_checked`.
+    CheckHelo,
+    /// Ask the handler if the transaction may transfer its data.
+    /// The answer should be passed to `SmtpMachine::data_checked`.
+    CheckData,
+    /// Read the chunk of the given number of octets following BDAT, passing it to `SmtpMachine::chunk`.
+    /// The command is answered once its chunk has been received.
+    ReadChunk(usize),
+    /// Ask the handler if the transaction may transfer its data, once its first BDAT chunk has been received.
+    /// The answer should be passed to `SmtpMachine::chunk_checked`.
+    CheckChunk,
+    /// Ask the handler for the members of a mailing list.
+    /// The answer should be passed to `SmtpMachine::expanded`.
+    Expand(String),
+    /// The message is complete, ask the handler to save the current state.
+    /// The answer should be passed to `SmtpMachine::saved`.
+    Save,
+}
+
+/// The SMTP protocol as a state machine, without any I/O.
+/// It is fed parsed commands and data, and tells the driver what to do next.
+pub struct SmtpMachine {
+    config: Arc<Config>,
+    peer: SocketAddr,
+    error_count: usize,
+    recipient_attempts: usize,
+    discarding_data: bool,
+    /// Whether the rest of a discarded line is skipped, up to its line break.
+    discarding_line: bool,
+    /// Number of lines of the message data received so far.
+    data_lines: usize,
+    /// The BDAT chunk being received, or waiting for `Action::CheckChunk`.
+    chunk: Option<Chunk>,
+    /// Whether the transaction is sent with BDAT, after its first chunk was accepted.
+    chunking: bool,
+    extended: bool,
+    state: SmtpState,
+}
+
+/// Chunk following a BDAT command, per RFC 3030.
+struct Chunk {
+    /// Octets of the chunk which were not received yet.
+    remaining: usize,
+    last: bool,
+    /// Reply refusing the command, its chunk is still read from the input but discarded.
+    refusal: Option<Response>,
+    data: Vec<u8>,
+}
+
+impl SmtpMachine {
+    /// Create a new state machine for a client connected from `peer`.
+    pub fn new(server_name: String, peer: SocketAddr) -> Self {
+        SmtpMachine::with_config(Arc::new(Config::new(server_name)), peer)
+    }
+
+    /// Create a new state machine using the settings of a service.
+    pub(crate) fn with_config(config: Arc<Config>, peer: SocketAddr) -> Self {
+        let id = NEXT_SESSION_ID.fetch_add(1, Ordering::Relaxed);
+
+        SmtpMachine {
+            config,
+            peer,
+            error_count: 0,
+            recipient_attempts: 0,
+            discarding_data: false,
+            discarding_line: false,
+            data_lines: 0,
+            chunk: None,
+            chunking: false,
+            extended: false,
+            state: SmtpState {
+                session_id: id,
+                peer: Some(peer),
+                ..SmtpState::default()
+            },
+        }
+    }
+
+    /// The current state of the transaction.
+    pub fn state(&self) -> &SmtpState {
+        &self.state
+    }
+
+    /// The current state of the transaction, for handlers extending the protocol.
+    pub(crate) fn state_mut(&mut self) -> &mut SmtpState {
+        &mut self.state
+    }
+
+    /// Replace the address of the client, when it was connected through a proxy.
+    pub(crate) fn set_peer(&mut self, peer: SocketAddr) {
+        self.peer = peer;
+        self.state.peer = Some(peer);
+    }
+
+    /// Store the forward-confirmed reverse DNS name of the client.
+    pub(crate) fn set_reverse_dns(&mut self, name: Option<String>) {
+        self.state.reverse_dns = name;
+    }
+
+    /// Check if the machine is receiving message data instead of commands.
+    pub fn receiving_data(&self) -> bool {
+        self.state.phase == Phase::Data
+    }
+
+    /// Check if the machine is receiving the chunk of a BDAT command instead of commands.
+    pub fn receiving_chunk(&self) -> bool {
+        matches!(&self.chunk, Some(chunk) if chunk.remaining > 0)
+    }
+
+    /// The response which should be sent when the client connects.
+    pub fn greeting(&self) -> Response {
+        match &self.config.ehlo_hostname {
+            Some(hostname) => {
+                Response::BannerGreeting(hostname.clone(), self.config.server_name.clone())
+            }
+            None => Response::Greeting(self.config.server_name.clone()),
+        }
+    }
+
+    /// Process a command line which could not be parsed.
+    pub fn invalid_command(&mut self) -> Action {
+        self.bad_command(Response::SyntaxError)
+    }
+
+    /// Process a command line which was too long to be parsed, counting like an invalid command.
+    pub fn line_too_long(&mut self) -> Action {
+        self.bad_command(Response::LineTooLong)
+    }
+
+    /// Count a bad command, closing the connection when there were too many in a row.
+    fn bad_command(&mut self, response: Response) -> Action {
+        self.error_count += 1;
+
+        if self.error_count > self.config.max_bad_commands {
+            debug!(session = self.state.session_id, peer:% = self.peer; "Too many bad commands, closing connection.");
+            return Action::Close(Response::TooManyErrors);
+        }
+
+        Action::Reply(response)
+    }
+
+    /// Process the reply of the handler to a command line which could not be parsed.
+    /// A line the handler answered is a valid command, otherwise it is a bad one, see `invalid_command`.
+    pub fn unknown_checked(&mut self, response: Option<Response>) -> Action {
+        match response {
+            Some(response) => {
+                self.error_count = 0;
+                Action::Reply(response)
+            }
+            None => self.invalid_command(),
+        }
+    }
+
+    /// Process a parsed command.
+    /// While receiving data, lines like RSET are message text to be passed to `data` instead.
+    /// A driver abandoning a message may still pass RSET, which resets the whole transaction, including the data.
+    pub fn command(&mut self, command: Command) -> Action {
+        self.error_count = 0;
+
+        match command {
+            Command::HELO(domain) => self.process_helo(domain, false),
+            Command::EHLO(domain) => self.process_helo(domain, true),
+            Command::FROM(_, _) if self.transactions_exhausted() => {
+                debug!(session = self.state.session_id, peer:% = self.peer; "Reached the transaction limit of the connection.");
+                Action::Close(Response::TooManyTransactions)
+            }
+            Command::FROM(sender, params) => Action::Reply(self.process_from(sender, params)),
+            Command::RCPT(recipient, params) => self.process_rcpt(recipient, params),
+            Command::DATA => self.process_data(),
+            Command::BDAT(size, last) => self.process_bdat(size, last),
+            Command::RSET => Action::Reply(self.process_reset()),
+            Command::NOOP => Action::Reply(Response::Ok),
+            Command::EXPN(list) => self.process_expn(list),
+            Command::QUIT => Action::Close(Response::Goodbye),
+            Command::Unimplemented(_) => Action::Reply(Response::NotImplemented),
+        }
+    }
+
+    /// Process message data, should only be called while receiving data.
+    /// Returns the action to take when the end of the data was reached, and the input following it.
+    /// Without an action the returned input is an unterminated line, to be passed again with the next input.
+    /// Once the message exceeds the maximum size or the size declared with MAIL, or has too many lines,
+    /// the rest of the data is discarded until its end. An unterminated line counts towards the size too,
+    /// so a client never sending a line break can't grow the returned input beyond the limit.
+    /// Data is not decoded, any octets are kept as received.
+    pub fn data(&mut self, input: &[u8]) -> (Option<Action>, Vec<u8>) {
+        // The start of this line was discarded already, and was too long to end the data.
+        let input = match self.discarding_line {
+            true => match input.iter().position(|&b| b == b'\n') {
+                Some(end) => {
+                    self.discarding_line = false;
+                    &input[end + 1..]
+                }
+                None => return (None, Vec::new()),
+            },
+            false => input,
+        };
+
+        let (has_ended, res, mut rem) = crate::parser::parse_data_lines(input);
+
+        let consumed = input.len() - rem.len();
+        let terminator = match has_ended {
+            true if input[..consumed].ends_with(b".\r\n") => 3,
+            true => 2,
+            false => 0,
+        };
+        let body = consumed - terminator;
+
+        // Lines from earlier input are separated from these by a line break.
+        let separator: &[u8] = match self.state.data_bytes > 0 && body > 0 {
+            true => b"\r\n",
+            false => b"",
+        };
+        self.state.data_bytes += body;
+
+        // The joined lines lack the CRLF of the last one.
+        if body > 0 {
+            self.state.message_size += res.len() + 2;
+        }
+
+        self.data_lines += input[..body].iter().filter(|&&b| b == b'\n').count();
+
+        let pending = match has_ended {
+            true => 0,
+            false => rem.len(),
+        };
+        let too_big =
+            matches!(self.size_limit(), Some(limit) if self.state.message_size + pending > limit);
+        let too_long = matches!(self.config.max_data_lines, Some(max) if self.data_lines > max);
+
+        if (too_big || too_long) && !self.discarding_data {
+            debug!(session = self.state.session_id, peer:% = self.peer, too_big, too_long; "Message exceeded the maximum size, discarding the rest.");
+            self.discarding_data = true;
+            self.state.data = Vec::new();
+            if let Some(raw) = &mut self.state.raw_data {
+                *raw = Vec::new();
+            }
+        }
+
+        if !self.discarding_data {
+            self.state.data.extend_from_slice(separator);
+            self.state.data.extend_from_slice(&res);
+            if let Some(raw) = &mut self.state.raw_data {
+                raw.extend_from_slice(&input[..body]);
+            }
+        }
+
+        if !has_ended {
+            // Only a line with a lone dot ends the data, the rest of any other line is skipped.
+            if self.discarding_data && !matches!(&rem[..], b"" | b"." | b".\r") {
+                self.discarding_line = true;
+                rem = Vec::new();
+            }
+
+            return (None, rem);
+        }
+
+        if self.discarding_data {
+            self.reset_transaction();
+            return (Some(Action::Reply(Response::MessageTooBig)), rem);
+        }
+
+        (Some(Action::Save), rem)
+    }
+
+    /// Process the chunk of a BDAT command, should only be called while receiving a chunk.
+    /// Returns the action to take when the end of the chunk was reached, and the input following it.
+    /// Without an action all input was part of the chunk, and the rest of it follows with the next input.
+    /// The chunk is taken as is, it is neither split into lines nor unstuffed.
+    pub fn chunk(&mut self, input: &[u8]) -> (Option<Action>, Vec<u8>) {
+        let chunk = match self.chunk.as_mut() {
+            Some(chunk) if chunk.remaining > 0 => chunk,
+            _ => return (None, input.to_vec()),
+        };
+
+        let (received, rem) = input.split_at(chunk.remaining.min(input.len()));
+        chunk.remaining -= received.len();
+        if chunk.refusal.is_none() {
+            chunk.data.extend_from_slice(received);
+        }
+
+        match chunk.remaining {
+            0 => (Some(self.chunk_received()), rem.to_vec()),
+            _ => (None, rem.to_vec()),
+        }
+    }
+
+    /// Maximum size of the current message, the smaller of the server limit and the size declared with MAIL.
+    fn size_limit(&self) -> Option<usize> {
+        match (self.config.max_size, self.state.size_hint()) {
+            (Some(max), Some(declared)) => Some(max.min(declared)),
+            (max, declared) => max.or(declared),
+        }
+    }
+
+    /// Take the message data received so far out of the state, e.g. to pass it on while the rest is received.
+    /// The taken data still counts towards the size limit, and the data of the next call continues where it ended.
+    pub fn take_data(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.state.data)
+    }
+
+    /// Finish the identity check requested by `Action::CheckHelo`.
+    /// A rejected identity is forgotten, so the client has to send HELO or EHLO again before MAIL.
+    pub fn helo_checked(&mut self, allowed: bool) -> Response {
+        if !allowed {
+            debug!(session = self.state.session_id, peer:% = self.peer; "Handler rejected the HELO identity.");
+            self.state.domain = None;
+            self.state.phase = Phase::Connected;
+            return Response::InvalidHelo;
+        }
+
+        self.helo_reply()
+    }
+
+    /// Finish the recipient check requested by `Action::CheckRecipient`.
+    pub fn recipient_checked(
+        &mut self,
+        recipient: Mailbox,
+        params: Vec<Parameter>,
+        decision: RecipientDecision,
+    ) -> Response {
+        let rejection = match decision {
+            RecipientDecision::Accept => None,
+            RecipientDecision::RejectPermanent => Some(Response::RecipientNotLocal),
+            RecipientDecision::RejectTemporary => Some(Response::RecipientUnavailable),
+            RecipientDecision::Relay if self.state.authenticated => None,
+            RecipientDecision::Relay => Some(Response::RelayDenied),
+            RecipientDecision::Custom(response) => Some(response),
+        };
+
+        if let Some(response) = rejection {
+            debug!(session = self.state.session_id, peer:% = self.peer; "Handler rejected the recipient.");
+            return response;
+        }
+
+        debug!(session = self.state.session_id, peer:% = self.peer; "Recipient accepted.");
+        let reply = match self.config.echo_recipients {
+            true => Response::RecipientOk(recipient.clone()),
+            false => Response::Ok,
+        };
+        self.state.recipients.push(Recipient {
+            mailbox: recipient,
+            params,
+        });
+        self.state.phase = Phase::RcptStarted;
+        reply
+    }
+
+    /// Finish the data check requested by `Action::CheckData`.
+    pub fn data_checked(&mut self, decision: DataDecision) -> Response {
+        match decision {
+            DataDecision::Accept => {
+                self.state.phase = Phase::Data;
+                if self.config.preserve_raw_data {
+                    self.state.raw_data = Some(Vec::new());
+                }
+                Response::StartData
+            }
+            DataDecision::Reject => {
+                debug!(session = self.state.session_id, peer:% = self.peer; "Handler rejected the transaction data.");
+                Response::TransactionRejected
+            }
+            DataDecision::TryLater => {
+                debug!(session = self.state.session_id, peer:% = self.peer; "Handler deferred the transaction data.");
+                Response::TryLater
+            }
+        }
+    }
+
+    /// Finish the data check requested by `Action::CheckChunk`.
+    /// An accepted chunk is added to the message, which is saved when it was the last one.
+    pub fn chunk_checked(&mut self, decision: DataDecision) -> Action {
+        let chunk = match self.chunk.take() {
+            Some(chunk) if chunk.remaining == 0 => chunk,
+            _ => return Action::Reply(Response::OutOfSequence),
+        };
+
+        match decision {
+            DataDecision::Accept => {
+                self.chunking = true;
+                if self.config.preserve_raw_data {
+                    self.state.raw_data = Some(Vec::new());
+                }
+                self.append_chunk(chunk)
+            }
+            DataDecision::Reject => {
+                debug!(session = self.state.session_id, peer:% = self.peer; "Handler rejected the transaction data.");
+                Action::Reply(Response::TransactionRejected)
+            }
+            DataDecision::TryLater => {
+                debug!(session = self.state.session_id, peer:% = self.peer; "Handler deferred the transaction data.");
+                Action::Reply(Response::TryLater)
+            }
+        }
+    }
+
+    /// Finish the expansion requested by `Action::Expand`.
+    pub fn expanded(&mut self, members: Option<Vec<Mailbox>>) -> Response {
+        match members {
+            Some(members) if !members.is_empty() => Response::Expanded(members),
+            _ => {
+                debug!(session = self.state.session_id, peer:% = self.peer; "Handler could not expand the list.");
+                Response::NotImplemented
+            }
+        }
+    }
+
+    /// Finish the message requested to be saved by `Action::Save`.
+    /// The transaction ends either way, so the next message starts with a new MAIL command.
+    /// A queue id is included in the reply, unless it contains line breaks.
+    pub fn saved(&mut self, outcome: SaveOutcome) -> Response {
+        self.reset_transaction();
+
+        if outcome.is_accepted() {
+            self.state.transactions += 1;
+        }
+
+        match outcome {
+            SaveOutcome::Delivered => Response::Delivered,
+            SaveOutcome::Queued(id) if !id.contains(['\r', '\n']) => Response::Queued(id),
+            SaveOutcome::Queued(_) | SaveOutcome::Accepted => Response::Ok,
+            SaveOutcome::Rejected(response) => response,
+        }
+    }
+
+    /// Check if the client sent as many messages as a connection may.
+    fn transactions_exhausted(&self) -> bool {
+        matches!(self.config.max_transactions_per_connection, Some(max) if self.state.transactions >= max)
+    }
+
+    /// Store the identity from HELO or EHLO, so the handler can judge it.
+    /// This ends any transaction like RSET, per RFC 5321 section 4.1.4.
+    fn process_helo(&mut self, domain: Domain, extended: bool) -> Action {
+        debug!(session = self.state.session_id, peer:% = self.peer, domain:% = redact::text(self.config.log_redaction, &domain.0), extended; "Processing HELO.");
+
+        self.reset_transaction();
+        self.state.domain = Some(domain);
+        self.state.phase = Phase::Greeted;
+        self.extended = extended;
+
+        Action::CheckHelo
+    }
+
+    fn helo_reply(&self) -> Response {
+        if !self.extended {
+            return Response::Helo(self.config.hostname().to_string());
+        }
+
+        let size = self.config.max_size.unwrap_or(0);
+
+        let mut capabilities = vec![
+            format!("SIZE {}", size),
+            "8BITMIME".to_string(),
+            "BINARYMIME".to_string(),
+            "CHUNKING".to_string(),
+            "SMTPUTF8".to_string(),
+        ];
+        capabilities.extend(self.config.extra_capabilities.iter().cloned());
+
+        Response::Ehlo(self.config.hostname().to_string(), capabilities)
+    }
+
+    fn process_from(&mut self, sender: Mailbox, params: Vec<Parameter>) -> Response {
+        debug!(session = self.state.session_id, peer:% = self.peer, sender:% = redact::mailbox(self.config.log_redaction, &sender); "Processing FROM.");
+
+        match self.state.phase {
+            Phase::Connected => {
+                debug!(session = self.state.session_id, peer:% = self.peer; "MAIL command was out of sequence.");
+                return Response::OutOfSequence;
+            }
+            Phase::Greeted => {}
+            // A transaction is ended by RSET or the end of its data, not by starting another one.
+            Phase::MailStarted | Phase::RcptStarted | Phase::Data => {
+                debug!(session = self.state.session_id, peer:% = self.peer; "MAIL command during a transaction.");
+                return Response::NestedMail;
+            }
+        }
+
+        let smtputf8 = find_parameter(&params, "SMTPUTF8").is_some();
+
+        if let Some((_, size)) = find_parameter(&params, "SIZE") {
+            let size = match size.as_ref().map(|s| s.parse::<usize>()) {
+                Some(Ok(size)) => size,
+                _ => {
+                    debug!(session = self.state.session_id, peer:% = self.peer; "Invalid SIZE parameter.");
+                    return Response::InvalidParameters;
+                }
+            };
+
+            if matches!(self.config.max_size, Some(max) if size > max) {
+                debug!(session = self.state.session_id, peer:% = self.peer; "Declared size exceeds the maximum.");
+                return Response::SizeExceeded;
+            }
+        }
+
+        if let Some((_, body)) = find_parameter(&params, "BODY") {
+            if body.as_deref().and_then(BodyType::parse).is_none() {
+                debug!(session = self.state.session_id, peer:% = self.peer; "Invalid BODY parameter.");
+                return Response::InvalidParameters;
+            }
+        }
+
+        if let Some((_, ret)) = find_parameter(&params, "RET") {
+            if ret.as_deref().and_then(DsnReturn::parse).is_none() {
+                debug!(session = self.state.session_id, peer:% = self.peer; "Invalid RET parameter.");
+                return Response::InvalidParameters;
+            }
+        }
+
+        let envelope_id = match find_parameter(&params, "ENVID") {
+            Some((_, value)) => match value
+                .as_deref()
+                .filter(|value| value.len() <= MAX_ENVID_LENGTH)
+                .and_then(decode_xtext)
+            {
+                Some(id) => Some(id),
+                None => {
+                    debug!(session = self.state.session_id, peer:% = self.peer; "Invalid ENVID parameter.");
+                    return Response::InvalidParameters;
+                }
+            },
+            None => None,
+        };
+
+        let mail_auth = match find_parameter(&params, "AUTH") {
+            Some((_, value)) => match value.as_deref().and_then(decode_xtext) {
+                Some(identity) => Some(identity),
+                None => {
+                    debug!(session = self.state.session_id, peer:% = self.peer; "Invalid AUTH parameter.");
+                    return Response::InvalidParameters;
+                }
+            },
+            None => None,
+        };
+
+        if !smtputf8 && !sender.is_ascii() {
+            debug!(session = self.state.session_id, peer:% = self.peer; "Non-ASCII sender without SMTPUTF8.");
+            return Response::NonAsciiAddress;
+        }
+
+        if self.address_too_long(&sender) {
+            debug!(session = self.state.session_id, peer:% = self.peer; "Sender exceeds the address length limits.");
+            return Response::AddressTooLong;
+        }
+
+        self.state.transaction_id = NEXT_TRANSACTION_ID.fetch_add(1, Ordering::Relaxed);
+        debug!(session = self.state.session_id, peer:% = self.peer, transaction = self.state.transaction_id; "Sender accepted.");
+        self.state.from = Some(sender);
+        self.state.phase = Phase::MailStarted;
+        self.state.mail_params = params;
+        self.state.smtputf8 = smtputf8;
+        // An unauthenticated client can't vouch for the submitter, so its identity is ignored.
+        self.state.mail_auth =
+            mail_auth.filter(|identity| identity != "<>" && self.state.authenticated);
+        self.state.envelope_id = envelope_id;
+        Response::Ok
+    }
+
+    fn process_rcpt(&mut self, recipient: Mailbox, params: Vec<Parameter>) -> Action {
+        debug!(session = self.state.session_id, peer:% = self.peer, recipient:% = redact::mailbox(self.config.log_redaction, &recipient); "Processing RCPT.");
+
+        if self.state.phase == Phase::Connected {
+            debug!(session = self.state.session_id, peer:% = self.peer; "RCPT command was send out of sequence.");
+            return Action::Reply(Response::OutOfSequence);
+        }
+
+        if !matches!(self.state.phase, Phase::MailStarted | Phase::RcptStarted) {
+            debug!(session = self.state.session_id, peer:% = self.peer, phase:? = self.state.phase; "RCPT command was send outside of a transaction.");
+            return Action::Reply(Response::OutOfSequence);
+        }
+
+        if self.chunking {
+            debug!(session = self.state.session_id, peer:% = self.peer; "RCPT command after the message data started.");
+            return Action::Reply(Response::OutOfSequence);
+        }
+
+        if !self.state.smtputf8 && !recipient.is_ascii() {
+            debug!(session = self.state.session_id, peer:% = self.peer; "Non-ASCII recipient without SMTPUTF8.");
+            return Action::Reply(Response::NonAsciiAddress);
+        }
+
+        self.recipient_attempts += 1;
+
+        if matches!(self.config.max_recipients_hard, Some(max) if self.recipient_attempts > max) {
+            debug!(session = self.state.session_id, peer:% = self.peer; "Exceeded the hard recipient limit.");
+            return Action::Close(Response::TooManyRecipientsClosing);
+        }
+
+        // Counted as an attempt, so the hard recipient limit also closes clients sending many of these.
+        if self.address_too_long(&recipient) {
+            debug!(session = self.state.session_id, peer:% = self.peer; "Recipient exceeds the address length limits.");
+            return Action::Reply(Response::AddressTooLong);
+        }
+
+        if self.state.recipients.len() >= self.config.max_recipients_soft {
+            debug!(session = self.state.session_id, peer:% = self.peer; "Exceeded the soft recipient limit.");
+            return Action::Reply(Response::TooManyRecipients);
+        }
+
+        Action::CheckRecipient(recipient, params)
+    }
+
+    /// Check whether the local part or domain of the address is longer than configured.
+    fn address_too_long(&self, mailbox: &Mailbox) -> bool {
+        matches!(self.config.max_local_part_len, Some(max) if mailbox.local.len() > max)
+            || matches!(self.config.max_domain_len, Some(max) if mailbox.domain.0.len() > max)
+    }
+
+    fn process_data(&mut self) -> Action {
+        if self.state.phase == Phase::Connected {
+            debug!(session = self.state.session_id, peer:% = self.peer; "Received DATA without EHLO.");
+            return Action::Reply(Response::OutOfSequence);
+        }
+
+        // Every recipient is answered on its own, so only a transaction without any accepted one is refused.
+        if self.state.phase == Phase::MailStarted && self.recipient_attempts > 0 {
+            debug!(session = self.state.session_id, peer:% = self.peer; "Received DATA after every recipient was rejected.");
+            return Action::Reply(Response::InvalidRecipient);
+        }
+
+        if self.state.phase != Phase::RcptStarted {
+            debug!(session = self.state.session_id, peer:% = self.peer, phase:? = self.state.phase; "Received DATA without a recipient.");
+            return Action::Reply(Response::OutOfSequence);
+        }
+
+        // RFC 3030 does not allow a message to mix DATA and BDAT.
+        if self.chunking {
+            debug!(session = self.state.session_id, peer:% = self.peer; "Received DATA after BDAT.");
+            return Action::Reply(Response::OutOfSequence);
+        }
+
+        // Binary content can't be dot-stuffed, RFC 3030 only allows it to be sent with BDAT.
+        if self.state.body_type() == Some(BodyType::BinaryMime) {
+            debug!(session = self.state.session_id, peer:% = self.peer; "Received DATA for a BINARYMIME message.");
+            return Action::Reply(Response::BdatRequired);
+        }
+
+        Action::CheckData
+    }
+
+    /// Start receiving the chunk of a BDAT command.
+    /// The chunk always follows the command, so it is read and discarded when the command is refused.
+    fn process_bdat(&mut self, size: usize, last: bool) -> Action {
+        debug!(session = self.state.session_id, peer:% = self.peer, size, last; "Processing BDAT.");
+
+        self.chunk = Some(Chunk {
+            remaining: size,
+            last,
+            refusal: self.bdat_refusal(size),
+            data: Vec::new(),
+        });
+
+        match size {
+            0 => self.chunk_received(),
+            _ => Action::ReadChunk(size),
+        }
+    }
+
+    /// The reply refusing a BDAT command with a chunk of the given size, if it is refused.
+    fn bdat_refusal(&self, size: usize) -> Option<Response> {
+        if self.state.phase == Phase::Connected {
+            debug!(session = self.state.session_id, peer:% = self.peer; "Received BDAT without EHLO.");
+            return Some(Response::OutOfSequence);
+        }
+
+        if self.state.phase == Phase::MailStarted && self.recipient_attempts > 0 {
+            debug!(session = self.state.session_id, peer:% = self.peer; "Received BDAT after every recipient was rejected.");
+            return Some(Response::InvalidRecipient);
+        }
+
+        if self.state.phase != Phase::RcptStarted {
+            debug!(session = self.state.session_id, peer:% = self.peer, phase:? = self.state.phase; "Received BDAT without a recipient.");
+            return Some(Response::OutOfSequence);
+        }
+
+        // The size is known up front, so a chunk which does not fit is never buffered.
+        let total = self.state.message_size.saturating_add(size);
+        if matches!(self.size_limit(), Some(limit) if total > limit) {
+            debug!(session = self.state.session_id, peer:% = self.peer, total; "Chunk exceeds the maximum message size.");
+            return Some(Response::MessageTooBig);
+        }
+
+        None
+    }
+
+    /// Answer a BDAT command once its whole chunk was received.
+    /// The first chunk of a transaction is held until the handler allowed the data.
+    fn chunk_received(&mut self) -> Action {
+        let chunk = match self.chunk.take() {
+            Some(chunk) => chunk,
+            None => return Action::Reply(Response::OutOfSequence),
+        };
+
+        match chunk.refusal {
+            // Nothing of the message is saved, so a client continuing it has to start over.
+            Some(Response::MessageTooBig) => {
+                self.reset_transaction();
+                Action::Reply(Response::MessageTooBig)
+            }
+            Some(refusal) => Action::Reply(refusal),
+            None if !self.chunking => {
+                self.chunk = Some(chunk);
+                Action::CheckChunk
+            }
+            None => self.append_chunk(chunk),
+        }
+    }
+
+    /// Add an accepted chunk to the message, saving it after the last chunk.
+    fn append_chunk(&mut self, chunk: Chunk) -> Action {
+        let size = chunk.data.len();
+        self.state.data_bytes += size;
+        self.state.message_size += size;
+        if let Some(raw) = &mut self.state.raw_data {
+            raw.extend_from_slice(&chunk.data);
+        }
+        self.state.data.extend(chunk.data);
+
+        match chunk.last {
+            true => Action::Save,
+            false => Action::Reply(Response::ChunkReceived(size)),
+        }
+    }
+
+    fn process_expn(&mut self, list: String) -> Action {
+        debug!(session = self.state.session_id, peer:% = self.peer, list:% = redact::text(self.config.log_redaction, &list); "Processing EXPN.");
+
+        if !self.config.allow_expn {
+            debug!(session = self.state.session_id, peer:% = self.peer; "EXPN is disabled.");
+            return Action::Reply(Response::NotImplemented);
+        }
+
+        Action::Expand(list)
+    }
+
+    fn process_reset(&mut self) -> Response {
+        self.reset_transaction();
+
+        Response::Ok
+    }
+
+    /// Clear the sender, recipients and data of the current transaction.
+    /// A greeted client stays greeted.
+    fn reset_transaction(&mut self) {
+        self.state.phase = self.state.phase.min(Phase::Greeted);
+        self.discarding_data = false;
+        self.discarding_line = false;
+        self.state.transaction_id = 0;
+        self.state.from = None;
+        self.state.mail_params = Vec::new();
+        self.state.smtputf8 = false;
+        self.state.mail_auth = None;
+        self.state.envelope_id = None;
+        self.state.recipients = Vec::new();
+        self.recipient_attempts = 0;
+        self.state.data = Vec::new();
+        self.state.data_bytes = 0;
+        self.state.message_size = 0;
+        self.state.raw_data = None;
+        self.data_lines = 0;
+        self.chunk = None;
+        self.chunking = false;
+    }
+}
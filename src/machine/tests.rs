@@ -0,0 +1,1913 @@
+use super::*;
+
+fn machine() -> SmtpMachine {
+    SmtpMachine::new("test".into(), "127.0.0.1:25".parse().unwrap())
+}
+
+fn machine_with(config: Config) -> SmtpMachine {
+    SmtpMachine::with_config(Arc::new(config), "127.0.0.1:25".parse().unwrap())
+}
+
+fn size_limited(max: usize) -> SmtpMachine {
+    let mut config = Config::new("test".into());
+    config.max_size = Some(max);
+
+    machine_with(config)
+}
+
+fn mailbox(local: &str, domain: &str) -> Mailbox {
+    Mailbox {
+        local: local.to_string(),
+        domain: domain.into(),
+    }
+}
+
+#[test]
+fn machine_helo() {
+    let mut machine = machine();
+
+    assert_eq!(
+        Action::CheckHelo,
+        machine.command(Command::HELO("[192.0.2.1]".into()))
+    );
+    assert_eq!(Some("[192.0.2.1]"), machine.state().helo_identity());
+    assert_eq!(Response::Helo("test".into()), machine.helo_checked(true));
+}
+
+#[test]
+fn machine_helo_rejected() {
+    let mut machine = machine();
+
+    machine.command(Command::EHLO("localhost".into()));
+    assert_eq!(Response::InvalidHelo, machine.helo_checked(false));
+    assert_eq!(None, machine.state().helo_identity());
+    assert_eq!(
+        Action::Reply(Response::OutOfSequence),
+        machine.command(Command::FROM(mailbox("info", "nexium.app"), vec![]))
+    );
+}
+
+#[test]
+fn machine_greeting() {
+    let machine = machine();
+
+    assert_eq!(Response::Greeting("test".into()), machine.greeting());
+}
+
+#[test]
+fn machine_full_transaction() {
+    let mut machine = machine();
+
+    assert_eq!(
+        Action::CheckHelo,
+        machine.command(Command::EHLO("nexium.app".into()))
+    );
+    assert_eq!(
+        Response::Ehlo(
+            "test".into(),
+            vec![
+                "SIZE 0".into(),
+                "8BITMIME".into(),
+                "BINARYMIME".into(),
+                "CHUNKING".into(),
+                "SMTPUTF8".into()
+            ]
+        ),
+        machine.helo_checked(true)
+    );
+    assert_eq!(
+        Action::Reply(Response::Ok),
+        machine.command(Command::FROM(mailbox("info", "nexium.app"), vec![]))
+    );
+
+    let recipient = mailbox("admin", "nexium.app");
+    assert_eq!(
+        Action::CheckRecipient(recipient.clone(), vec![]),
+        machine.command(Command::RCPT(recipient.clone(), vec![]))
+    );
+    assert_eq!(
+        Response::Ok,
+        machine.recipient_checked(recipient, vec![], RecipientDecision::Accept)
+    );
+
+    assert_eq!(Action::CheckData, machine.command(Command::DATA));
+    assert_eq!(
+        Response::StartData,
+        machine.data_checked(DataDecision::Accept)
+    );
+    assert!(machine.receiving_data());
+
+    assert_eq!((None, Vec::new()), machine.data(b"Hello\r\n"));
+    assert_eq!(
+        (Some(Action::Save), Vec::new()),
+        machine.data(b"World\r\n.\r\n")
+    );
+    assert_eq!(Response::Ok, machine.saved(SaveOutcome::Accepted));
+    assert!(!machine.receiving_data());
+    assert!(machine.state().from.is_none());
+    assert!(machine.state().recipients.is_empty());
+
+    assert_eq!(
+        Action::Close(Response::Goodbye),
+        machine.command(Command::QUIT)
+    );
+}
+
+#[test]
+fn machine_mail_without_ehlo() {
+    let mut machine = machine();
+
+    assert_eq!(
+        Action::Reply(Response::OutOfSequence),
+        machine.command(Command::FROM(mailbox("info", "nexium.app"), vec![]))
+    );
+    assert!(machine.state().from.is_none());
+}
+
+#[test]
+fn machine_rcpt_without_mail() {
+    let mut machine = machine();
+
+    machine.command(Command::EHLO("nexium.app".into()));
+
+    assert_eq!(
+        Action::Reply(Response::OutOfSequence),
+        machine.command(Command::RCPT(mailbox("info", "nexium.app"), vec![]))
+    );
+    assert!(machine.state().recipients().is_empty());
+}
+
+#[test]
+fn machine_data_without_recipients() {
+    let mut machine = machine();
+
+    machine.command(Command::HELO("nexium.app".into()));
+    machine.command(Command::FROM(mailbox("info", "nexium.app"), vec![]));
+
+    assert_eq!(
+        Action::Reply(Response::OutOfSequence),
+        machine.command(Command::DATA)
+    );
+    assert!(!machine.receiving_data());
+}
+
+#[test]
+fn machine_recipient_not_local() {
+    let mut machine = machine();
+
+    machine.command(Command::EHLO("nexium.app".into()));
+    machine.command(Command::FROM(mailbox("info", "nexium.app"), vec![]));
+
+    let recipient = mailbox("someone", "example.com");
+    assert_eq!(
+        Response::RecipientNotLocal,
+        machine.recipient_checked(recipient, vec![], RecipientDecision::RejectPermanent)
+    );
+    assert!(machine.state().recipients.is_empty());
+}
+
+#[test]
+fn machine_save_rejected() {
+    let mut machine = machine();
+
+    machine.command(Command::EHLO("nexium.app".into()));
+    machine.command(Command::FROM(mailbox("info", "nexium.app"), vec![]));
+    machine.recipient_checked(
+        mailbox("admin", "nexium.app"),
+        vec![],
+        RecipientDecision::Accept,
+    );
+    machine.command(Command::DATA);
+    machine.data_checked(DataDecision::Accept);
+    machine.data(b"Hello\r\n.\r\n");
+
+    assert_eq!(
+        Response::TransactionFailed,
+        machine.saved(SaveOutcome::Rejected(Response::TransactionFailed))
+    );
+    assert!(machine.state().data.is_empty());
+    assert!(machine.state().from.is_none());
+    assert_eq!(
+        Action::Reply(Response::OutOfSequence),
+        machine.command(Command::DATA)
+    );
+}
+
+#[test]
+fn machine_data_rejected() {
+    let mut machine = machine();
+
+    machine.command(Command::EHLO("nexium.app".into()));
+    machine.command(Command::FROM(mailbox("info", "nexium.app"), vec![]));
+    machine.recipient_checked(
+        mailbox("admin", "nexium.app"),
+        vec![],
+        RecipientDecision::Accept,
+    );
+
+    assert_eq!(Action::CheckData, machine.command(Command::DATA));
+    assert_eq!(
+        Response::TransactionRejected,
+        machine.data_checked(DataDecision::Reject)
+    );
+    assert!(!machine.receiving_data());
+}
+
+#[test]
+fn machine_data_try_later() {
+    let mut machine = machine();
+
+    machine.command(Command::EHLO("nexium.app".into()));
+    machine.command(Command::FROM(mailbox("info", "nexium.app"), vec![]));
+    machine.recipient_checked(
+        mailbox("admin", "nexium.app"),
+        vec![],
+        RecipientDecision::Accept,
+    );
+
+    assert_eq!(Action::CheckData, machine.command(Command::DATA));
+    assert_eq!(
+        Response::TryLater,
+        machine.data_checked(DataDecision::TryLater)
+    );
+    assert!(!machine.receiving_data());
+}
+
+#[test]
+fn machine_reset() {
+    let mut machine = machine();
+
+    machine.command(Command::EHLO("nexium.app".into()));
+    machine.command(Command::FROM(mailbox("info", "nexium.app"), vec![]));
+    machine.recipient_checked(
+        mailbox("admin", "nexium.app"),
+        vec![],
+        RecipientDecision::Accept,
+    );
+
+    assert_eq!(Action::Reply(Response::Ok), machine.command(Command::RSET));
+    assert!(machine.state().from.is_none());
+    assert!(machine.state().recipients.is_empty());
+    assert!(machine.state().domain.is_some());
+}
+
+#[test]
+fn machine_invalid_commands() {
+    let mut machine = machine();
+
+    for _ in 0..10 {
+        assert_eq!(
+            Action::Reply(Response::SyntaxError),
+            machine.invalid_command()
+        );
+    }
+
+    assert_eq!(
+        Action::Close(Response::TooManyErrors),
+        machine.invalid_command()
+    );
+}
+
+#[test]
+fn machine_lines_too_long() {
+    let mut machine = machine();
+
+    for _ in 0..10 {
+        assert_eq!(
+            Action::Reply(Response::LineTooLong),
+            machine.line_too_long()
+        );
+    }
+
+    assert_eq!(
+        Action::Close(Response::TooManyErrors),
+        machine.invalid_command()
+    );
+}
+
+#[test]
+fn machine_addresses_too_long() {
+    let mut config = Config::new("test".into());
+    config.max_local_part_len = Some(8);
+    config.max_domain_len = Some(12);
+    let mut machine = machine_with(config);
+    machine.command(Command::EHLO("nexium.app".into()));
+
+    assert_eq!(
+        Action::Reply(Response::AddressTooLong),
+        machine.command(Command::FROM(mailbox("newsletter", "nexium.app"), vec![]))
+    );
+    assert_eq!(
+        Action::Reply(Response::AddressTooLong),
+        machine.command(Command::FROM(mailbox("info", "mail.nexium.app"), vec![]))
+    );
+    assert_eq!(
+        Action::Reply(Response::Ok),
+        machine.command(Command::FROM(mailbox("info", "nexium.app"), vec![]))
+    );
+
+    assert_eq!(
+        Action::Reply(Response::AddressTooLong),
+        machine.command(Command::RCPT(mailbox("postmaster", "nexium.app"), vec![]))
+    );
+    assert_eq!(
+        Action::CheckRecipient(mailbox("admin", "nexium.app"), vec![]),
+        machine.command(Command::RCPT(mailbox("admin", "nexium.app"), vec![]))
+    );
+}
+
+#[test]
+fn machine_unknown_commands() {
+    let mut machine = machine();
+
+    for _ in 0..10 {
+        machine.invalid_command();
+    }
+
+    // A line answered by the handler is a valid command, resetting the count.
+    assert_eq!(
+        Action::Reply(Response::Ok),
+        machine.unknown_checked(Some(Response::Ok))
+    );
+    assert_eq!(
+        Action::Reply(Response::SyntaxError),
+        machine.unknown_checked(None)
+    );
+}
+
+#[test]
+fn machine_unique_session_ids() {
+    let first = machine();
+    let second = machine();
+
+    assert_ne!(first.state().session_id, second.state().session_id);
+}
+
+#[test]
+fn machine_transaction_ids() {
+    let mut machine = machine();
+    machine.command(Command::EHLO("nexium.app".into()));
+    assert_eq!(0, machine.state().transaction_id);
+
+    machine.command(Command::FROM(mailbox("info", "nexium.app"), vec![]));
+    let first = machine.state().transaction_id;
+    assert_ne!(0, first);
+
+    machine.command(Command::RSET);
+    assert_eq!(0, machine.state().transaction_id);
+
+    machine.command(Command::FROM(mailbox("info", "nexium.app"), vec![]));
+    assert_ne!(first, machine.state().transaction_id);
+    assert_ne!(0, machine.state().transaction_id);
+}
+
+#[test]
+fn machine_custom_replies() {
+    let mut machine = machine();
+    let quota = Response::custom(552, "Quota exceeded").unwrap();
+    let unavailable = Response::custom(450, "Mailbox temporarily unavailable").unwrap();
+
+    machine.command(Command::EHLO("nexium.app".into()));
+    machine.command(Command::FROM(mailbox("info", "nexium.app"), vec![]));
+
+    assert_eq!(
+        unavailable,
+        machine.recipient_checked(
+            mailbox("busy", "nexium.app"),
+            vec![],
+            RecipientDecision::Custom(unavailable.clone())
+        )
+    );
+    assert!(machine.state().recipients.is_empty());
+
+    machine.recipient_checked(
+        mailbox("admin", "nexium.app"),
+        vec![],
+        RecipientDecision::Accept,
+    );
+    machine.command(Command::DATA);
+    machine.data_checked(DataDecision::Accept);
+    machine.data(b"Hello\r\n.\r\n");
+
+    assert_eq!(quota, machine.saved(SaveOutcome::Rejected(quota.clone())));
+}
+
+#[test]
+fn machine_smtputf8_transaction() {
+    let mut machine = machine();
+    let recipient = mailbox("δοκιμή", "παράδειγμα.ελ");
+
+    machine.command(Command::EHLO("nexium.app".into()));
+    assert_eq!(
+        Action::Reply(Response::Ok),
+        machine.command(Command::FROM(
+            mailbox("info", "nexium.app"),
+            vec![("SMTPUTF8".into(), None)]
+        ))
+    );
+    assert!(machine.state().smtputf8);
+
+    assert_eq!(
+        Action::CheckRecipient(recipient.clone(), vec![]),
+        machine.command(Command::RCPT(recipient, vec![]))
+    );
+
+    machine.command(Command::RSET);
+    assert!(!machine.state().smtputf8);
+}
+
+#[test]
+fn machine_utf8_recipient_without_smtputf8() {
+    let mut machine = machine();
+
+    machine.command(Command::EHLO("nexium.app".into()));
+    machine.command(Command::FROM(mailbox("info", "nexium.app"), vec![]));
+
+    assert_eq!(
+        Action::Reply(Response::NonAsciiAddress),
+        machine.command(Command::RCPT(mailbox("δοκιμή", "παράδειγμα.ελ"), vec![]))
+    );
+}
+
+#[test]
+fn machine_stores_parameters() {
+    let mut machine = machine();
+    let mail_params = vec![
+        ("SIZE".to_string(), Some("100".to_string())),
+        ("FOO".to_string(), Some("bar".to_string())),
+        ("BAR".to_string(), None),
+    ];
+    let rcpt_params = vec![("NOTIFY".to_string(), Some("NEVER".to_string()))];
+
+    machine.command(Command::EHLO("nexium.app".into()));
+    machine.command(Command::FROM(mailbox("a", "b"), mail_params.clone()));
+    machine.recipient_checked(
+        mailbox("c", "d"),
+        rcpt_params.clone(),
+        RecipientDecision::Accept,
+    );
+
+    assert_eq!(mail_params, machine.state().mail_params);
+    assert_eq!(
+        vec![Recipient {
+            mailbox: mailbox("c", "d"),
+            params: rcpt_params,
+        }],
+        machine.state().recipients()
+    );
+
+    machine.command(Command::RSET);
+    assert!(machine.state().mail_params.is_empty());
+    assert!(machine.state().recipients().is_empty());
+}
+
+#[test]
+fn machine_ehlo_size_limit() {
+    let mut machine = size_limited(1000);
+    machine.command(Command::EHLO("nexium.app".into()));
+    let response = machine.helo_checked(true);
+
+    assert_eq!(
+        "250-test ESMTP\r\n250-SIZE 1000\r\n250-8BITMIME\r\n250-BINARYMIME\r\n250-CHUNKING\r\n250 SMTPUTF8\r\n",
+        response.to_response()
+    );
+}
+
+#[test]
+fn machine_ehlo_size_unlimited() {
+    let mut machine = machine();
+    machine.command(Command::EHLO("nexium.app".into()));
+    let response = machine.helo_checked(true);
+
+    assert_eq!(
+        "250-test ESMTP\r\n250-SIZE 0\r\n250-8BITMIME\r\n250-BINARYMIME\r\n250-CHUNKING\r\n250 SMTPUTF8\r\n",
+        response.to_response()
+    );
+}
+
+#[test]
+fn machine_ehlo_hostname() {
+    let mut config = Config::new("Postbus".into());
+    config.ehlo_hostname = Some("mx.nexium.app".into());
+    let mut machine = machine_with(config);
+
+    assert_eq!(
+        Response::BannerGreeting("mx.nexium.app".into(), "Postbus".into()),
+        machine.greeting()
+    );
+
+    machine.command(Command::HELO("nexium.app".into()));
+    assert_eq!(
+        Response::Helo("mx.nexium.app".into()),
+        machine.helo_checked(true)
+    );
+}
+
+#[test]
+fn machine_rcpt_echoes_recipient() {
+    let mut config = Config::new("test".into());
+    config.echo_recipients = true;
+    let mut machine = machine_with(config);
+    machine.command(Command::EHLO("nexium.app".into()));
+    machine.helo_checked(true);
+    machine.command(Command::FROM(mailbox("info", "nexium.app"), vec![]));
+
+    let recipient = mailbox("admin", "nexium.app");
+    let response = machine.recipient_checked(recipient.clone(), vec![], RecipientDecision::Accept);
+
+    assert_eq!(Response::RecipientOk(recipient), response);
+    assert_eq!(
+        "250 2.1.5 <admin@nexium.app>... Recipient ok\r\n",
+        response.to_response()
+    );
+}
+
+#[test]
+fn machine_rcpt_terse_by_default() {
+    let mut machine = machine_with(Config::new("test".into()));
+    machine.command(Command::EHLO("nexium.app".into()));
+    machine.helo_checked(true);
+    machine.command(Command::FROM(mailbox("info", "nexium.app"), vec![]));
+
+    let response = machine.recipient_checked(
+        mailbox("admin", "nexium.app"),
+        vec![],
+        RecipientDecision::Accept,
+    );
+
+    assert_eq!(Response::Ok, response);
+    assert!(!response.to_response().contains("admin@nexium.app"));
+}
+
+#[test]
+fn machine_ehlo_extra_capabilities() {
+    let mut config = Config::new("test".into());
+    config.extra_capabilities = vec!["X-MY-EXT".into(), "XFOO 1 2".into()];
+
+    let mut machine = machine_with(config);
+    machine.command(Command::EHLO("nexium.app".into()));
+    let response = machine.helo_checked(true);
+
+    assert_eq!(
+        "250-test ESMTP\r\n250-SIZE 0\r\n250-8BITMIME\r\n250-BINARYMIME\r\n250-CHUNKING\r\n250-SMTPUTF8\r\n250-X-MY-EXT\r\n250 XFOO 1 2\r\n",
+        response.to_response()
+    );
+}
+
+#[test]
+fn machine_declared_size_exceeded() {
+    let mut machine = size_limited(1000);
+
+    machine.command(Command::EHLO("nexium.app".into()));
+
+    assert_eq!(
+        Action::Reply(Response::SizeExceeded),
+        machine.command(Command::FROM(
+            mailbox("info", "nexium.app"),
+            vec![("SIZE".into(), Some("1001".into()))]
+        ))
+    );
+    assert!(machine.state().from.is_none());
+
+    assert_eq!(
+        Action::Reply(Response::Ok),
+        machine.command(Command::FROM(
+            mailbox("info", "nexium.app"),
+            vec![("SIZE".into(), Some("1000".into()))]
+        ))
+    );
+}
+
+#[test]
+fn machine_declared_size_invalid() {
+    let mut machine = machine();
+
+    machine.command(Command::EHLO("nexium.app".into()));
+
+    assert_eq!(
+        Action::Reply(Response::InvalidParameters),
+        machine.command(Command::FROM(
+            mailbox("info", "nexium.app"),
+            vec![("SIZE".into(), Some("large".into()))]
+        ))
+    );
+    assert_eq!(
+        Action::Reply(Response::InvalidParameters),
+        machine.command(Command::FROM(
+            mailbox("info", "nexium.app"),
+            vec![("SIZE".into(), None)]
+        ))
+    );
+}
+
+/// Send MAIL with the given AUTH parameter, returning the reply.
+fn mail_with_auth(machine: &mut SmtpMachine, value: &str) -> Action {
+    machine.command(Command::EHLO("nexium.app".into()));
+    machine.command(Command::FROM(
+        mailbox("a", "b"),
+        vec![("AUTH".into(), Some(value.into()))],
+    ))
+}
+
+#[test]
+fn machine_mail_auth() {
+    let mut machine = machine();
+    machine.state.authenticated = true;
+
+    assert_eq!(
+        Action::Reply(Response::Ok),
+        mail_with_auth(&mut machine, "user@example.com")
+    );
+    assert_eq!(
+        Some("user@example.com"),
+        machine.state().mail_auth.as_deref()
+    );
+
+    machine.command(Command::RSET);
+    assert_eq!(None, machine.state().mail_auth);
+}
+
+/// Start a transaction with the given DSN parameters.
+fn mail_with_dsn(machine: &mut SmtpMachine, ret: &str, envid: &str) -> Action {
+    machine.command(Command::EHLO("nexium.app".into()));
+    machine.command(Command::FROM(
+        mailbox("a", "b"),
+        vec![
+            ("RET".into(), Some(ret.into())),
+            ("ENVID".into(), Some(envid.into())),
+        ],
+    ))
+}
+
+#[test]
+fn machine_mail_dsn() {
+    let mut machine = machine();
+
+    assert_eq!(
+        Action::Reply(Response::Ok),
+        mail_with_dsn(&mut machine, "HDRS", "QQ314159")
+    );
+    assert_eq!(Some(DsnReturn::Headers), machine.state().dsn_return());
+    assert_eq!(Some("QQ314159"), machine.state().envelope_id.as_deref());
+
+    machine.command(Command::RSET);
+    assert_eq!(None, machine.state().dsn_return());
+    assert_eq!(None, machine.state().envelope_id);
+}
+
+#[test]
+fn machine_mail_dsn_xtext() {
+    let mut machine = machine();
+
+    assert_eq!(
+        Action::Reply(Response::Ok),
+        mail_with_dsn(&mut machine, "full", "QQ+2B314159+3D")
+    );
+    assert_eq!(Some(DsnReturn::Full), machine.state().dsn_return());
+    assert_eq!(Some("QQ+314159="), machine.state().envelope_id.as_deref());
+}
+
+#[test]
+fn machine_mail_dsn_invalid() {
+    let long = "A".repeat(101);
+
+    for (ret, envid) in [
+        ("NEVER", "QQ314159"),
+        ("HDRS", "QQ+2b"),
+        ("HDRS", "QQ=314159"),
+        ("HDRS", long.as_str()),
+    ] {
+        let mut machine = machine();
+
+        assert_eq!(
+            Action::Reply(Response::InvalidParameters),
+            mail_with_dsn(&mut machine, ret, envid),
+            "RET={} ENVID={}",
+            ret,
+            envid
+        );
+        assert!(machine.state().from.is_none());
+    }
+}
+
+#[test]
+fn machine_mail_auth_xtext() {
+    let mut machine = machine();
+    machine.state.authenticated = true;
+
+    assert_eq!(
+        Action::Reply(Response::Ok),
+        mail_with_auth(&mut machine, "user+2Btag+3D1@example.com")
+    );
+    assert_eq!(
+        Some("user+tag=1@example.com"),
+        machine.state().mail_auth.as_deref()
+    );
+}
+
+#[test]
+fn machine_mail_auth_empty() {
+    let mut machine = machine();
+    machine.state.authenticated = true;
+
+    assert_eq!(
+        Action::Reply(Response::Ok),
+        mail_with_auth(&mut machine, "<>")
+    );
+    assert_eq!(None, machine.state().mail_auth);
+}
+
+#[test]
+fn machine_mail_auth_unauthenticated() {
+    let mut machine = machine();
+
+    assert_eq!(
+        Action::Reply(Response::Ok),
+        mail_with_auth(&mut machine, "user@example.com")
+    );
+    assert_eq!(None, machine.state().mail_auth);
+}
+
+#[test]
+fn machine_mail_auth_invalid() {
+    let mut machine = machine();
+    machine.state.authenticated = true;
+
+    assert_eq!(
+        Action::Reply(Response::InvalidParameters),
+        mail_with_auth(&mut machine, "user+zz@example.com")
+    );
+    assert!(machine.state().from.is_none());
+}
+
+/// Machine with a recipient for a transaction with the given body type.
+fn with_body_type(body: &str) -> SmtpMachine {
+    let mut machine = machine();
+
+    machine.command(Command::EHLO("nexium.app".into()));
+    assert_eq!(
+        Action::Reply(Response::Ok),
+        machine.command(Command::FROM(
+            mailbox("info", "nexium.app"),
+            vec![("BODY".into(), Some(body.into()))]
+        ))
+    );
+    machine.recipient_checked(
+        mailbox("admin", "nexium.app"),
+        vec![],
+        RecipientDecision::Accept,
+    );
+
+    machine
+}
+
+#[test]
+fn machine_body_seven_bit() {
+    let mut machine = with_body_type("7BIT");
+
+    assert_eq!(Some(BodyType::SevenBit), machine.state().body_type());
+    assert_eq!(Action::CheckData, machine.command(Command::DATA));
+}
+
+#[test]
+fn machine_body_eight_bit_mime() {
+    let mut machine = with_body_type("8bitmime");
+
+    assert_eq!(Some(BodyType::EightBitMime), machine.state().body_type());
+    assert_eq!(Action::CheckData, machine.command(Command::DATA));
+    machine.data_checked(DataDecision::Accept);
+
+    assert_eq!(
+        Some(Action::Save),
+        machine.data("Grüße aus Köln\r\n.\r\n".as_bytes()).0
+    );
+    assert_eq!("Grüße aus Köln".as_bytes(), &machine.state().data[..]);
+}
+
+#[test]
+fn machine_body_binary_mime_refuses_data() {
+    let mut machine = with_body_type("BINARYMIME");
+
+    assert_eq!(Some(BodyType::BinaryMime), machine.state().body_type());
+    assert_eq!(
+        Action::Reply(Response::BdatRequired),
+        machine.command(Command::DATA)
+    );
+    assert!(!machine.receiving_data());
+    assert_eq!(
+        "503 5.5.1 BINARYMIME requires BDAT\r\n",
+        Response::BdatRequired.to_response()
+    );
+}
+
+#[test]
+fn machine_bdat_chunks() {
+    let mut machine = with_body_type("BINARYMIME");
+
+    assert_eq!(
+        Action::ReadChunk(5),
+        machine.command(Command::BDAT(5, false))
+    );
+    assert!(machine.receiving_chunk());
+    assert_eq!((None, vec![]), machine.chunk(b"\0."));
+    assert_eq!(
+        (Some(Action::CheckChunk), b"RSET\r\n".to_vec()),
+        machine.chunk(b"\r\n.RSET\r\n")
+    );
+    assert!(!machine.receiving_chunk());
+    assert_eq!(
+        Action::Reply(Response::ChunkReceived(5)),
+        machine.chunk_checked(DataDecision::Accept)
+    );
+
+    // Recipients and DATA can't follow once the message started.
+    assert_eq!(
+        Action::Reply(Response::OutOfSequence),
+        machine.command(Command::DATA)
+    );
+    assert_eq!(
+        Action::Reply(Response::OutOfSequence),
+        machine.command(Command::RCPT(mailbox("other", "nexium.app"), vec![]))
+    );
+
+    assert_eq!(Action::Save, machine.command(Command::BDAT(0, true)));
+    assert_eq!(b"\0.\r\n.", &machine.state().data[..]);
+    assert_eq!(5, machine.state().message_size);
+    assert_eq!(Response::Ok, machine.saved(SaveOutcome::Accepted));
+    assert!(machine.state().data.is_empty());
+}
+
+#[test]
+fn machine_bdat_refused_chunk_discarded() {
+    let mut machine = machine();
+    machine.command(Command::EHLO("nexium.app".into()));
+
+    assert_eq!(
+        Action::ReadChunk(4),
+        machine.command(Command::BDAT(4, true))
+    );
+    assert_eq!(
+        (
+            Some(Action::Reply(Response::OutOfSequence)),
+            b"NOOP".to_vec()
+        ),
+        machine.chunk(b"QUITNOOP")
+    );
+    assert!(machine.state().data.is_empty());
+}
+
+#[test]
+fn machine_bdat_rejected_by_handler() {
+    let mut machine = with_body_type("7BIT");
+
+    assert_eq!(Action::CheckChunk, machine.command(Command::BDAT(0, true)));
+    assert_eq!(
+        Action::Reply(Response::TryLater),
+        machine.chunk_checked(DataDecision::TryLater)
+    );
+
+    // The next chunk asks the handler again.
+    assert_eq!(
+        Action::ReadChunk(2),
+        machine.command(Command::BDAT(2, true))
+    );
+    assert_eq!(Some(Action::CheckChunk), machine.chunk(b"Hi").0);
+    assert_eq!(Action::Save, machine.chunk_checked(DataDecision::Accept));
+    assert_eq!(b"Hi", &machine.state().data[..]);
+}
+
+#[test]
+fn machine_bdat_too_big() {
+    let mut machine = size_limited(8);
+    machine.command(Command::EHLO("nexium.app".into()));
+    machine.command(Command::FROM(mailbox("info", "nexium.app"), vec![]));
+    machine.recipient_checked(
+        mailbox("admin", "nexium.app"),
+        vec![],
+        RecipientDecision::Accept,
+    );
+
+    assert_eq!(Action::CheckChunk, machine.command(Command::BDAT(0, false)));
+    machine.chunk_checked(DataDecision::Accept);
+    assert_eq!(
+        Action::ReadChunk(9),
+        machine.command(Command::BDAT(9, true))
+    );
+    assert_eq!(
+        Some(Action::Reply(Response::MessageTooBig)),
+        machine.chunk(b"123456789").0
+    );
+    assert!(machine.state().data.is_empty());
+    assert_eq!(Phase::Greeted, machine.state().phase);
+}
+
+#[test]
+fn machine_body_invalid() {
+    let mut machine = machine();
+    machine.command(Command::EHLO("nexium.app".into()));
+
+    assert_eq!(
+        Action::Reply(Response::InvalidParameters),
+        machine.command(Command::FROM(
+            mailbox("info", "nexium.app"),
+            vec![("BODY".into(), Some("8BIT".into()))]
+        ))
+    );
+}
+
+#[test]
+fn machine_parameters_per_recipient() {
+    let mut machine = machine();
+    let notify = vec![("NOTIFY".to_string(), Some("NEVER".to_string()))];
+    let orcpt = vec![("ORCPT".to_string(), Some("rfc822;d@e".to_string()))];
+
+    machine.command(Command::EHLO("nexium.app".into()));
+    machine.command(Command::FROM(mailbox("a", "b"), vec![]));
+    machine.recipient_checked(mailbox("c", "d"), notify.clone(), RecipientDecision::Accept);
+    machine.recipient_checked(
+        mailbox("x", "y"),
+        vec![],
+        RecipientDecision::RejectPermanent,
+    );
+    machine.recipient_checked(mailbox("d", "e"), orcpt.clone(), RecipientDecision::Accept);
+
+    let recipients = machine.state().recipients();
+    assert_eq!(2, recipients.len());
+    assert_eq!(
+        (&mailbox("c", "d"), &notify),
+        (&recipients[0].mailbox, &recipients[0].params)
+    );
+    assert_eq!(
+        (&mailbox("d", "e"), &orcpt),
+        (&recipients[1].mailbox, &recipients[1].params)
+    );
+    assert_eq!(
+        vec![&mailbox("c", "d"), &mailbox("d", "e")],
+        machine.state().recipient_mailboxes().collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn machine_nested_mail() {
+    let mut machine = machine();
+
+    machine.command(Command::EHLO("nexium.app".into()));
+    machine.command(Command::FROM(mailbox("info", "nexium.app"), vec![]));
+
+    assert_eq!(
+        Action::Reply(Response::NestedMail),
+        machine.command(Command::FROM(mailbox("other", "nexium.app"), vec![]))
+    );
+    assert_eq!(Some(mailbox("info", "nexium.app")), machine.state().from);
+
+    machine.command(Command::RSET);
+    assert_eq!(
+        Action::Reply(Response::Ok),
+        machine.command(Command::FROM(mailbox("other", "nexium.app"), vec![]))
+    );
+}
+
+/// Machine which accepted DATA for a transaction.
+fn receiving_data() -> SmtpMachine {
+    let mut machine = machine();
+
+    machine.command(Command::EHLO("nexium.app".into()));
+    machine.command(Command::FROM(mailbox("info", "nexium.app"), vec![]));
+    machine.recipient_checked(
+        mailbox("admin", "nexium.app"),
+        vec![],
+        RecipientDecision::Accept,
+    );
+    machine.command(Command::DATA);
+    machine.data_checked(DataDecision::Accept);
+
+    machine
+}
+
+#[test]
+fn machine_data_split_terminator() {
+    let input = b"Hello\r\n\r\nWorld\r\n.\r\nQUIT\r\n";
+    let end = input.len() - b"QUIT\r\n".len();
+
+    for split in 0..end {
+        let mut machine = receiving_data();
+
+        let (action, rem) = machine.data(&input[..split]);
+        assert_eq!(None, action, "split at {}", split);
+
+        let (action, rem) = machine.data(&[&rem[..], &input[split..]].concat());
+        assert_eq!(Some(Action::Save), action, "split at {}", split);
+        assert_eq!(b"QUIT\r\n", &rem[..]);
+        assert_eq!(
+            b"Hello\r\n\r\nWorld",
+            &machine.state().data[..],
+            "split at {}",
+            split
+        );
+        assert_eq!(16, machine.state().data_bytes, "split at {}", split);
+    }
+}
+
+#[test]
+fn machine_data_lines_across_reads() {
+    let mut machine = receiving_data();
+
+    for line in ["Subject: Hi\r\n", "\r\n", "Body\r\n", ".\r\n"].iter() {
+        machine.data(line.as_bytes());
+    }
+
+    assert_eq!(b"Subject: Hi\r\n\r\nBody", &machine.state().data[..]);
+}
+
+#[test]
+fn machine_data_taken_in_chunks() {
+    let mut machine = receiving_data();
+    let mut taken = Vec::new();
+
+    for line in ["Subject: Hi\r\n", "\r\n", "..Body\r\nEnd\r\n", ".\r\n"].iter() {
+        machine.data(line.as_bytes());
+        taken.extend(machine.take_data());
+    }
+
+    assert_eq!(b"Subject: Hi\r\n\r\n.Body\r\nEnd", &taken[..]);
+}
+
+#[test]
+fn machine_taken_data_counts_towards_size() {
+    let mut machine = size_limited(10);
+    machine.command(Command::EHLO("nexium.app".into()));
+    machine.command(Command::FROM(mailbox("info", "nexium.app"), vec![]));
+    machine.recipient_checked(
+        mailbox("admin", "nexium.app"),
+        vec![],
+        RecipientDecision::Accept,
+    );
+    machine.command(Command::DATA);
+    machine.data_checked(DataDecision::Accept);
+
+    machine.data(b"Short\r\n");
+    assert_eq!(b"Short", &machine.take_data()[..]);
+    machine.data(b"Longer\r\n");
+    assert!(machine.take_data().is_empty());
+
+    let (action, _) = machine.data(b".\r\n");
+    assert_eq!(Some(Action::Reply(Response::MessageTooBig)), action);
+}
+
+#[test]
+fn machine_data_bytes() {
+    let mut machine = machine();
+
+    machine.command(Command::EHLO("nexium.app".into()));
+    machine.command(Command::FROM(mailbox("info", "nexium.app"), vec![]));
+    machine.recipient_checked(
+        mailbox("admin", "nexium.app"),
+        vec![],
+        RecipientDecision::Accept,
+    );
+    machine.command(Command::DATA);
+    machine.data_checked(DataDecision::Accept);
+
+    let body = b"Subject: Hi\r\n\r\n..dotted\r\n";
+    assert_eq!((None, Vec::new()), machine.data(body));
+    let (action, _) = machine.data(b"Last\r\n.\r\nQUIT\r\n");
+
+    assert_eq!(Some(Action::Save), action);
+    assert_eq!(body.len() + "Last\r\n".len(), machine.state().data_bytes);
+
+    machine.saved(SaveOutcome::Accepted);
+    assert_eq!(0, machine.state().data_bytes);
+}
+
+#[test]
+fn machine_message_size_canonical() {
+    let mut machine = receiving_data();
+
+    let (action, _) = machine.data(b"Subject: Hi\n\n..dotted\r\nLast\n.\n");
+
+    assert_eq!(Some(Action::Save), action);
+    assert_eq!(
+        "Subject: Hi\r\n\r\n.dotted\r\nLast\r\n".len(),
+        machine.state().message_size
+    );
+    assert_eq!(
+        "Subject: Hi\n\n..dotted\r\nLast\n".len(),
+        machine.state().data_bytes
+    );
+
+    machine.saved(SaveOutcome::Accepted);
+    assert_eq!(0, machine.state().message_size);
+}
+
+#[test]
+fn machine_dot_stuffing_not_counted_towards_size() {
+    let mut machine = size_limited(8);
+    machine.command(Command::EHLO("nexium.app".into()));
+    machine.command(Command::FROM(mailbox("info", "nexium.app"), vec![]));
+    machine.recipient_checked(
+        mailbox("admin", "nexium.app"),
+        vec![],
+        RecipientDecision::Accept,
+    );
+    machine.command(Command::DATA);
+    machine.data_checked(DataDecision::Accept);
+
+    let (action, _) = machine.data(b"..a\r\n..b\r\n.\r\n");
+    assert_eq!(Some(Action::Save), action);
+    assert_eq!(10, machine.state().data_bytes);
+    assert_eq!(8, machine.state().message_size);
+}
+
+#[test]
+fn machine_declared_size_enforced() {
+    let mut machine = machine();
+    machine.command(Command::EHLO("nexium.app".into()));
+    machine.command(Command::FROM(
+        mailbox("info", "nexium.app"),
+        vec![("SIZE".into(), Some("7".into()))],
+    ));
+    machine.recipient_checked(
+        mailbox("admin", "nexium.app"),
+        vec![],
+        RecipientDecision::Accept,
+    );
+    machine.command(Command::DATA);
+    machine.data_checked(DataDecision::Accept);
+
+    // Five characters and a CRLF fit the declaration, even though the LF-only line is 6 bytes on the wire.
+    assert_eq!((None, Vec::new()), machine.data(b"Hello\n"));
+    let (action, _) = machine.data(b"!\n.\n");
+    assert_eq!(Some(Action::Reply(Response::MessageTooBig)), action);
+}
+
+fn line_limited(max: usize) -> SmtpMachine {
+    let mut config = Config::new("test".into());
+    config.max_data_lines = Some(max);
+
+    let mut machine = machine_with(config);
+    machine.command(Command::EHLO("nexium.app".into()));
+    machine.command(Command::FROM(mailbox("info", "nexium.app"), vec![]));
+    machine.recipient_checked(
+        mailbox("admin", "nexium.app"),
+        vec![],
+        RecipientDecision::Accept,
+    );
+    machine.command(Command::DATA);
+    machine.data_checked(DataDecision::Accept);
+    machine
+}
+
+#[test]
+fn machine_data_lines_exceeded() {
+    let mut machine = line_limited(100);
+
+    for _ in 0..10 {
+        assert_eq!(
+            (None, Vec::new()),
+            machine.data("a\r\n".repeat(20).as_bytes())
+        );
+    }
+    assert!(machine.state().data.is_empty());
+
+    let (action, _) = machine.data(b".\r\n");
+    assert_eq!(Some(Action::Reply(Response::MessageTooBig)), action);
+    assert!(!machine.receiving_data());
+}
+
+#[test]
+fn machine_data_lines_at_limit() {
+    let mut machine = line_limited(100);
+
+    for _ in 0..5 {
+        machine.data("a\n".repeat(20).as_bytes());
+    }
+
+    let (action, _) = machine.data(b".\r\n");
+    assert_eq!(Some(Action::Save), action);
+    assert_eq!(100 * 3 - 2, machine.state().data.len());
+}
+
+/// Start a transaction with a single recipient, ready to receive its data.
+fn start_data(machine: &mut SmtpMachine) {
+    machine.command(Command::FROM(mailbox("info", "nexium.app"), vec![]));
+    machine.recipient_checked(
+        mailbox("admin", "nexium.app"),
+        vec![],
+        RecipientDecision::Accept,
+    );
+    machine.command(Command::DATA);
+    machine.data_checked(DataDecision::Accept);
+}
+
+/// Machine which reached the given phase through the regular commands.
+fn machine_in(phase: Phase) -> SmtpMachine {
+    let mut machine = machine();
+
+    if phase >= Phase::Greeted {
+        machine.command(Command::EHLO("nexium.app".into()));
+        machine.helo_checked(true);
+    }
+    if phase >= Phase::MailStarted {
+        machine.command(Command::FROM(mailbox("info", "nexium.app"), vec![]));
+    }
+    if phase >= Phase::RcptStarted {
+        let recipient = mailbox("admin", "nexium.app");
+        machine.recipient_checked(recipient, vec![], RecipientDecision::Accept);
+    }
+    if phase >= Phase::Data {
+        machine.command(Command::DATA);
+        machine.data_checked(DataDecision::Accept);
+    }
+
+    assert_eq!(phase, machine.state().phase);
+    machine
+}
+
+#[test]
+fn machine_commands_in_every_phase() {
+    use Phase::*;
+
+    let sender = || Command::FROM(mailbox("other", "nexium.app"), vec![]);
+    let recipient = || Command::RCPT(mailbox("other", "nexium.app"), vec![]);
+    let check_recipient = || Action::CheckRecipient(mailbox("other", "nexium.app"), vec![]);
+    let ok = || Action::Reply(Response::Ok);
+    let out_of_sequence = || Action::Reply(Response::OutOfSequence);
+    let nested = || Action::Reply(Response::NestedMail);
+
+    // Command, the phase it is sent in, the expected action and the phase afterwards.
+    let cases: Vec<(Command, Phase, Action, Phase)> = vec![
+        (sender(), Connected, out_of_sequence(), Connected),
+        (sender(), Greeted, ok(), MailStarted),
+        (sender(), MailStarted, nested(), MailStarted),
+        (sender(), RcptStarted, nested(), RcptStarted),
+        (sender(), Data, nested(), Data),
+        (recipient(), Connected, out_of_sequence(), Connected),
+        (recipient(), Greeted, out_of_sequence(), Greeted),
+        (recipient(), MailStarted, check_recipient(), MailStarted),
+        (recipient(), RcptStarted, check_recipient(), RcptStarted),
+        (recipient(), Data, out_of_sequence(), Data),
+        (Command::DATA, Connected, out_of_sequence(), Connected),
+        (Command::DATA, Greeted, out_of_sequence(), Greeted),
+        (Command::DATA, MailStarted, out_of_sequence(), MailStarted),
+        (Command::DATA, RcptStarted, Action::CheckData, RcptStarted),
+        (Command::DATA, Data, out_of_sequence(), Data),
+        (Command::RSET, Connected, ok(), Connected),
+        (Command::RSET, Greeted, ok(), Greeted),
+        (Command::RSET, MailStarted, ok(), Greeted),
+        (Command::RSET, RcptStarted, ok(), Greeted),
+        (Command::RSET, Data, ok(), Greeted),
+    ];
+
+    for (command, phase, action, next) in cases {
+        let mut machine = machine_in(phase);
+        let description = format!("{:?} in {:?}", command, phase);
+
+        assert_eq!(action, machine.command(command), "{}", description);
+        assert_eq!(next, machine.state().phase, "{}", description);
+    }
+
+    // These are allowed in every phase, HELO and EHLO start over from the greeting.
+    for phase in [Connected, Greeted, MailStarted, RcptStarted, Data] {
+        let helo = Command::HELO("nexium.app".into());
+        let ehlo = Command::EHLO("nexium.app".into());
+        let unimplemented = Command::Unimplemented("VRFY".into());
+
+        for (command, action, next) in [
+            (helo, Action::CheckHelo, Greeted),
+            (ehlo, Action::CheckHelo, Greeted),
+            (Command::NOOP, ok(), phase),
+            (Command::QUIT, Action::Close(Response::Goodbye), phase),
+            (
+                Command::EXPN("staff".into()),
+                Action::Reply(Response::NotImplemented),
+                phase,
+            ),
+            (
+                unimplemented,
+                Action::Reply(Response::NotImplemented),
+                phase,
+            ),
+        ] {
+            let mut machine = machine_in(phase);
+            let description = format!("{:?} in {:?}", command, phase);
+
+            assert_eq!(action, machine.command(command), "{}", description);
+            assert_eq!(next, machine.state().phase, "{}", description);
+        }
+    }
+}
+
+#[test]
+fn machine_data_after_every_recipient_rejected() {
+    let mut machine = machine_in(Phase::MailStarted);
+
+    for local in ["a", "b"] {
+        let recipient = mailbox(local, "nexium.app");
+        machine.command(Command::RCPT(recipient.clone(), vec![]));
+        assert_eq!(
+            Response::RecipientNotLocal,
+            machine.recipient_checked(recipient, vec![], RecipientDecision::RejectPermanent)
+        );
+    }
+
+    assert_eq!(
+        Action::Reply(Response::InvalidRecipient),
+        machine.command(Command::DATA)
+    );
+    assert_eq!(
+        "554 No valid recipient\r\n",
+        Response::InvalidRecipient.to_response()
+    );
+
+    // Data proceeds for the recipients which were accepted.
+    let recipient = mailbox("c", "nexium.app");
+    machine.command(Command::RCPT(recipient.clone(), vec![]));
+    machine.recipient_checked(recipient, vec![], RecipientDecision::Accept);
+    assert_eq!(Action::CheckData, machine.command(Command::DATA));
+}
+
+#[test]
+fn machine_helo_resets_transaction() {
+    let mut machine = machine_in(Phase::RcptStarted);
+
+    assert_eq!(
+        Action::CheckHelo,
+        machine.command(Command::EHLO("other.nexium.app".into()))
+    );
+    assert_eq!(Phase::Greeted, machine.state().phase);
+    assert!(machine.state().from.is_none());
+    assert!(machine.state().recipients.is_empty());
+    assert_eq!(Some("other.nexium.app"), machine.state().helo_identity());
+}
+
+#[test]
+fn machine_rejected_helo_needs_new_greeting() {
+    let mut machine = machine_in(Phase::Greeted);
+
+    machine.command(Command::HELO("forged.example".into()));
+    assert_eq!(Response::InvalidHelo, machine.helo_checked(false));
+    assert_eq!(Phase::Connected, machine.state().phase);
+    assert_eq!(
+        Action::Reply(Response::OutOfSequence),
+        machine.command(Command::FROM(mailbox("info", "nexium.app"), vec![]))
+    );
+}
+
+#[test]
+fn machine_rset_during_data_is_text() {
+    let mut machine = machine();
+    machine.command(Command::EHLO("nexium.app".into()));
+    start_data(&mut machine);
+
+    let (action, rem) = machine.data(b"Hello\r\nRSET\r\n.\r\n");
+
+    assert_eq!(Some(Action::Save), action);
+    assert_eq!(b"", &rem[..]);
+    assert_eq!(b"Hello\r\nRSET", &machine.state().data[..]);
+}
+
+#[test]
+fn machine_rset_after_abandoned_data() {
+    let mut config = Config::new("test".into());
+    config.max_size = Some(10);
+    config.preserve_raw_data = true;
+    let mut machine = machine_with(config);
+    machine.command(Command::EHLO("nexium.app".into()));
+    start_data(&mut machine);
+
+    // The message is too big, and abandoned before its end.
+    assert_eq!(
+        (None, Vec::new()),
+        machine.data(b"Far too long for the limit\r\n")
+    );
+    assert_eq!(Action::Reply(Response::Ok), machine.command(Command::RSET));
+
+    let state = machine.state();
+    assert!(!machine.receiving_data());
+    assert!(state.from.is_none());
+    assert!(state.recipients.is_empty());
+    assert_eq!(b"", &state.data[..]);
+    assert_eq!(0, state.message_size);
+    assert_eq!(None, state.raw_data);
+
+    // Nothing of the abandoned message carries over to the next one.
+    start_data(&mut machine);
+    assert!(machine.receiving_data());
+    assert_eq!(
+        (Some(Action::Save), Vec::new()),
+        machine.data(b"Hi\r\n.\r\n")
+    );
+    assert_eq!(b"Hi", &machine.state().data[..]);
+    assert_eq!(Some(b"Hi\r\n".to_vec()), machine.state().raw_data);
+}
+
+#[test]
+fn machine_rset_after_message_too_big() {
+    let mut machine = size_limited(10);
+    machine.command(Command::EHLO("nexium.app".into()));
+    start_data(&mut machine);
+
+    assert_eq!(
+        (
+            Some(Action::Reply(Response::MessageTooBig)),
+            "RSET\r\n".into()
+        ),
+        machine.data(b"Far too long for the limit\r\n.\r\nRSET\r\n")
+    );
+    assert_eq!(Action::Reply(Response::Ok), machine.command(Command::RSET));
+
+    start_data(&mut machine);
+    assert_eq!(
+        (Some(Action::Save), Vec::new()),
+        machine.data(b"Hi\r\n.\r\n")
+    );
+    assert_eq!(b"Hi", &machine.state().data[..]);
+}
+
+#[test]
+fn machine_raw_data_preserved() {
+    let mut config = Config::new("test".into());
+    config.preserve_raw_data = true;
+
+    let mut machine = machine_with(config);
+    machine.command(Command::EHLO("nexium.app".into()));
+    machine.command(Command::FROM(mailbox("info", "nexium.app"), vec![]));
+    machine.recipient_checked(
+        mailbox("admin", "nexium.app"),
+        vec![],
+        RecipientDecision::Accept,
+    );
+    machine.command(Command::DATA);
+    machine.data_checked(DataDecision::Accept);
+
+    let (_, rem) = machine.data(b"Subject: Hi\n\r\n..dotted\r\nLa");
+    let (action, _) = machine.data(&[&rem[..], b"st\n.\r\n"].concat());
+
+    assert_eq!(Some(Action::Save), action);
+    assert_eq!(
+        b"Subject: Hi\r\n\r\n.dotted\r\nLast",
+        &machine.state().data[..]
+    );
+    assert_eq!(
+        Some(b"Subject: Hi\n\r\n..dotted\r\nLast\n".to_vec()),
+        machine.state().raw_data
+    );
+
+    machine.saved(SaveOutcome::Accepted);
+    assert_eq!(None, machine.state().raw_data);
+}
+
+#[test]
+fn machine_raw_data_not_preserved_by_default() {
+    let mut machine = receiving_data();
+    machine.data(b"Hello\r\n.\r\n");
+
+    assert_eq!(None, machine.state().raw_data);
+}
+
+#[test]
+fn machine_data_size_exceeded() {
+    let mut machine = size_limited(10);
+
+    machine.command(Command::EHLO("nexium.app".into()));
+    machine.command(Command::FROM(mailbox("info", "nexium.app"), vec![]));
+    machine.recipient_checked(
+        mailbox("admin", "nexium.app"),
+        vec![],
+        RecipientDecision::Accept,
+    );
+    machine.command(Command::DATA);
+    machine.data_checked(DataDecision::Accept);
+
+    let (action, _) = machine.data(b"This body is too large\r\n.\r\n");
+    assert_eq!(Some(Action::Reply(Response::MessageTooBig)), action);
+    assert!(!machine.receiving_data());
+    assert!(machine.state().data.is_empty());
+}
+
+#[test]
+fn machine_data_size_exceeded_mid_transfer() {
+    let mut machine = size_limited(10);
+
+    machine.command(Command::EHLO("nexium.app".into()));
+    machine.command(Command::FROM(mailbox("info", "nexium.app"), vec![]));
+    machine.recipient_checked(
+        mailbox("admin", "nexium.app"),
+        vec![],
+        RecipientDecision::Accept,
+    );
+    machine.command(Command::DATA);
+    machine.data_checked(DataDecision::Accept);
+
+    assert_eq!((None, Vec::new()), machine.data(b"Short\r\n"));
+    assert_eq!(b"Short", &machine.state().data[..]);
+
+    assert_eq!(
+        (None, Vec::new()),
+        machine.data(b"This line is too large\r\n")
+    );
+    assert!(machine.state().data.is_empty());
+    assert_eq!((None, Vec::new()), machine.data(b"More\r\n"));
+    assert!(machine.state().data.is_empty());
+    assert!(machine.receiving_data());
+
+    let (action, rem) = machine.data(b".\r\nMAIL FROM:<info@nexium.app>\r\n");
+    assert_eq!(Some(Action::Reply(Response::MessageTooBig)), action);
+    assert_eq!(b"MAIL FROM:<info@nexium.app>\r\n", &rem[..]);
+    assert!(!machine.receiving_data());
+    assert!(machine.state().from.is_none());
+    assert!(machine.state().recipients.is_empty());
+}
+
+#[test]
+fn machine_data_unterminated_line_exceeded() {
+    let mut machine = size_limited(16);
+
+    machine.command(Command::EHLO("nexium.app".into()));
+    machine.command(Command::FROM(mailbox("info", "nexium.app"), vec![]));
+    machine.recipient_checked(
+        mailbox("admin", "nexium.app"),
+        vec![],
+        RecipientDecision::Accept,
+    );
+    machine.command(Command::DATA);
+    machine.data_checked(DataDecision::Accept);
+
+    assert_eq!((None, b"Short".to_vec()), machine.data(b"Short"));
+
+    // A line without a break is not kept beyond the limit.
+    let mut rem = b"Short".to_vec();
+    for _ in 0..1000 {
+        rem.extend_from_slice(&[b'a'; 1024]);
+        let (action, next) = machine.data(&rem);
+        assert_eq!(None, action);
+        assert!(next.is_empty());
+        rem = next;
+    }
+    assert!(machine.state().data.is_empty());
+
+    // The skipped line ends with a dot, which does not end the data.
+    assert_eq!((None, Vec::new()), machine.data(b"a.\r\n"));
+    assert!(machine.receiving_data());
+
+    let (action, rem) = machine.data(b".\r\nQUIT\r\n");
+    assert_eq!(Some(Action::Reply(Response::MessageTooBig)), action);
+    assert_eq!(b"QUIT\r\n", &rem[..]);
+}
+
+#[test]
+fn machine_data_discarded_line_split_before_dot() {
+    let mut machine = size_limited(4);
+
+    machine.command(Command::EHLO("nexium.app".into()));
+    machine.command(Command::FROM(mailbox("info", "nexium.app"), vec![]));
+    machine.recipient_checked(
+        mailbox("admin", "nexium.app"),
+        vec![],
+        RecipientDecision::Accept,
+    );
+    machine.command(Command::DATA);
+    machine.data_checked(DataDecision::Accept);
+
+    assert_eq!((None, Vec::new()), machine.data(b"Too long\r\n"));
+    assert_eq!((None, b".\r".to_vec()), machine.data(b".\r"));
+
+    let (action, rem) = machine.data(b".\r\n");
+    assert_eq!(Some(Action::Reply(Response::MessageTooBig)), action);
+    assert!(rem.is_empty());
+}
+
+#[test]
+fn machine_recipients_soft_limit() {
+    let mut config = Config::new("test".into());
+    config.max_recipients_soft = 2;
+    let mut machine = machine_with(config);
+
+    machine.command(Command::EHLO("nexium.app".into()));
+    machine.command(Command::FROM(mailbox("info", "nexium.app"), vec![]));
+
+    for local in ["a", "b"].iter() {
+        let recipient = mailbox(local, "nexium.app");
+        machine.command(Command::RCPT(recipient.clone(), vec![]));
+        machine.recipient_checked(recipient, vec![], RecipientDecision::Accept);
+    }
+
+    assert_eq!(
+        Action::Reply(Response::TooManyRecipients),
+        machine.command(Command::RCPT(mailbox("c", "nexium.app"), vec![]))
+    );
+    assert_eq!(2, machine.state().recipients.len());
+
+    assert_eq!(Action::CheckData, machine.command(Command::DATA));
+    assert_eq!(
+        Response::StartData,
+        machine.data_checked(DataDecision::Accept)
+    );
+}
+
+#[test]
+fn machine_recipients_hard_limit() {
+    let mut config = Config::new("test".into());
+    config.max_recipients_soft = 1;
+    config.max_recipients_hard = Some(3);
+    let mut machine = machine_with(config);
+
+    machine.command(Command::EHLO("nexium.app".into()));
+    machine.command(Command::FROM(mailbox("info", "nexium.app"), vec![]));
+
+    let recipient = mailbox("a", "nexium.app");
+    machine.command(Command::RCPT(recipient.clone(), vec![]));
+    machine.recipient_checked(recipient, vec![], RecipientDecision::Accept);
+
+    for _ in 0..2 {
+        assert_eq!(
+            Action::Reply(Response::TooManyRecipients),
+            machine.command(Command::RCPT(mailbox("b", "nexium.app"), vec![]))
+        );
+    }
+
+    assert_eq!(
+        Action::Close(Response::TooManyRecipientsClosing),
+        machine.command(Command::RCPT(mailbox("b", "nexium.app"), vec![]))
+    );
+}
+
+#[test]
+fn machine_transactions_per_connection() {
+    let mut config = Config::new("test".into());
+    config.max_transactions_per_connection = Some(2);
+    let mut machine = machine_with(config);
+    machine.command(Command::EHLO("nexium.app".into()));
+
+    for _ in 0..2 {
+        assert_eq!(
+            Action::Reply(Response::Ok),
+            machine.command(Command::FROM(mailbox("info", "nexium.app"), vec![]))
+        );
+        machine.recipient_checked(
+            mailbox("admin", "nexium.app"),
+            vec![],
+            RecipientDecision::Accept,
+        );
+        machine.command(Command::DATA);
+        machine.data_checked(DataDecision::Accept);
+        assert_eq!(Some(Action::Save), machine.data(b"Hello\r\n.\r\n").0);
+        assert_eq!(Response::Ok, machine.saved(SaveOutcome::Accepted));
+    }
+
+    assert_eq!(2, machine.state().transactions);
+    assert_eq!(
+        Action::Close(Response::TooManyTransactions),
+        machine.command(Command::FROM(mailbox("info", "nexium.app"), vec![]))
+    );
+}
+
+#[test]
+fn machine_rejected_transactions_not_counted() {
+    let mut config = Config::new("test".into());
+    config.max_transactions_per_connection = Some(1);
+    let mut machine = machine_with(config);
+    machine.command(Command::EHLO("nexium.app".into()));
+
+    machine.command(Command::FROM(mailbox("info", "nexium.app"), vec![]));
+    machine.recipient_checked(
+        mailbox("admin", "nexium.app"),
+        vec![],
+        RecipientDecision::Accept,
+    );
+    machine.command(Command::DATA);
+    machine.data_checked(DataDecision::Accept);
+    machine.data(b"Hello\r\n.\r\n");
+    machine.saved(SaveOutcome::Rejected(Response::TransactionFailed));
+
+    assert_eq!(0, machine.state().transactions);
+    assert_eq!(
+        Action::Reply(Response::Ok),
+        machine.command(Command::FROM(mailbox("info", "nexium.app"), vec![]))
+    );
+}
+
+#[test]
+fn machine_recipients_hard_limit_reset() {
+    let mut config = Config::new("test".into());
+    config.max_recipients_hard = Some(1);
+    let mut machine = machine_with(config);
+
+    machine.command(Command::EHLO("nexium.app".into()));
+    machine.command(Command::FROM(mailbox("info", "nexium.app"), vec![]));
+    machine.command(Command::RCPT(mailbox("a", "nexium.app"), vec![]));
+    machine.command(Command::RSET);
+    machine.command(Command::FROM(mailbox("info", "nexium.app"), vec![]));
+
+    assert_eq!(
+        Action::CheckRecipient(mailbox("a", "nexium.app"), vec![]),
+        machine.command(Command::RCPT(mailbox("a", "nexium.app"), vec![]))
+    );
+}
+
+#[test]
+fn machine_saved_with_queue_id() {
+    let mut machine = machine();
+
+    assert_eq!(
+        Response::Queued("ABC123".into()),
+        machine.saved(SaveOutcome::Queued("ABC123".into()))
+    );
+    assert_eq!(
+        Response::Ok,
+        machine.saved(SaveOutcome::Queued("ABC\r\n250 Ok".into()))
+    );
+}
+
+#[test]
+fn machine_saved_outcomes() {
+    let mut machine = machine();
+
+    assert_eq!(Response::Ok, machine.saved(SaveOutcome::Accepted));
+    assert_eq!(Response::Delivered, machine.saved(SaveOutcome::Delivered));
+    assert_eq!(
+        Response::Queued("ABC123".into()),
+        machine.saved(SaveOutcome::Queued("ABC123".into()))
+    );
+    assert_eq!(
+        Response::MessageTooBig,
+        machine.saved(SaveOutcome::Rejected(Response::MessageTooBig))
+    );
+    assert_eq!(3, machine.state().transactions);
+}
+
+#[test]
+fn save_outcome_from_bool() {
+    assert_eq!(SaveOutcome::Accepted, SaveOutcome::from(true));
+    assert_eq!(
+        SaveOutcome::Rejected(Response::TransactionFailed),
+        SaveOutcome::from(false)
+    );
+}
+
+#[test]
+fn machine_recipient_decisions() {
+    let mut machine = machine();
+
+    machine.command(Command::EHLO("nexium.app".into()));
+    machine.command(Command::FROM(mailbox("info", "nexium.app"), vec![]));
+
+    let decisions = vec![
+        (
+            RecipientDecision::RejectPermanent,
+            Response::RecipientNotLocal,
+        ),
+        (
+            RecipientDecision::RejectTemporary,
+            Response::RecipientUnavailable,
+        ),
+        (RecipientDecision::Relay, Response::RelayDenied),
+        (
+            RecipientDecision::Custom(Response::TryLater),
+            Response::TryLater,
+        ),
+    ];
+
+    for (decision, response) in decisions {
+        assert_eq!(
+            response,
+            machine.recipient_checked(mailbox("admin", "example.com"), vec![], decision)
+        );
+    }
+    assert!(machine.state().recipients.is_empty());
+
+    assert_eq!(
+        Response::Ok,
+        machine.recipient_checked(
+            mailbox("admin", "nexium.app"),
+            vec![],
+            RecipientDecision::Accept
+        )
+    );
+    assert_eq!(1, machine.state().recipients.len());
+}
+
+#[test]
+fn machine_relay_authenticated() {
+    let mut machine = machine();
+    machine.state.authenticated = true;
+
+    machine.command(Command::EHLO("nexium.app".into()));
+    machine.command(Command::FROM(mailbox("info", "nexium.app"), vec![]));
+
+    assert_eq!(
+        Response::Ok,
+        machine.recipient_checked(
+            mailbox("someone", "example.com"),
+            vec![],
+            RecipientDecision::Relay
+        )
+    );
+    assert_eq!(
+        vec![&mailbox("someone", "example.com")],
+        machine.state().recipient_mailboxes().collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn machine_relay_unauthenticated() {
+    let mut machine = machine();
+
+    machine.command(Command::EHLO("nexium.app".into()));
+    machine.command(Command::FROM(mailbox("info", "nexium.app"), vec![]));
+
+    assert_eq!(
+        Response::RelayDenied,
+        machine.recipient_checked(
+            mailbox("someone", "example.com"),
+            vec![],
+            RecipientDecision::Relay
+        )
+    );
+    assert!(machine.state().recipients.is_empty());
+}
+
+#[test]
+fn machine_expn_disabled() {
+    let mut machine = machine();
+
+    assert_eq!(
+        Action::Reply(Response::NotImplemented),
+        machine.command(Command::EXPN("staff".into()))
+    );
+}
+
+#[test]
+fn machine_expn() {
+    let mut config = Config::new("test".into());
+    config.allow_expn = true;
+    let mut machine = machine_with(config);
+
+    assert_eq!(
+        Action::Expand("staff".into()),
+        machine.command(Command::EXPN("staff".into()))
+    );
+
+    let members = vec![mailbox("alice", "nexium.app"), mailbox("bob", "nexium.app")];
+    assert_eq!(
+        Response::Expanded(members.clone()),
+        machine.expanded(Some(members))
+    );
+    assert_eq!(Response::NotImplemented, machine.expanded(None));
+    assert_eq!(Response::NotImplemented, machine.expanded(Some(vec![])));
+}
+
+fn param(keyword: &str, value: Option<&str>) -> Parameter {
+    (keyword.to_string(), value.map(|v| v.to_string()))
+}
+
+#[test]
+fn machine_mail_params_accessors() {
+    let mut machine = machine();
+    let params = vec![param("BODY", Some("8BITMIME")), param("SIZE", Some("1024"))];
+
+    machine.command(Command::EHLO("nexium.app".into()));
+    assert_eq!(
+        Action::Reply(Response::Ok),
+        machine.command(Command::FROM(mailbox("info", "nexium.app"), params.clone()))
+    );
+
+    assert_eq!(Some(BodyType::EightBitMime), machine.state().body_type());
+    assert_eq!(Some(1024), machine.state().size_hint());
+    assert_eq!(params.as_slice(), machine.state().raw_mail_params());
+}
+
+#[test]
+fn machine_mail_params_absent() {
+    let mut machine = machine();
+
+    machine.command(Command::EHLO("nexium.app".into()));
+    machine.command(Command::FROM(mailbox("info", "nexium.app"), vec![]));
+
+    assert_eq!(None, machine.state().body_type());
+    assert_eq!(None, machine.state().size_hint());
+    assert!(machine.state().raw_mail_params().is_empty());
+}
+
+#[test]
+fn machine_mail_invalid_body() {
+    let mut machine = machine();
+
+    machine.command(Command::EHLO("nexium.app".into()));
+
+    assert_eq!(
+        Action::Reply(Response::InvalidParameters),
+        machine.command(Command::FROM(
+            mailbox("info", "nexium.app"),
+            vec![param("BODY", Some("9BIT"))]
+        ))
+    );
+    assert_eq!(
+        Action::Reply(Response::InvalidParameters),
+        machine.command(Command::FROM(
+            mailbox("info", "nexium.app"),
+            vec![param("BODY", None)]
+        ))
+    );
+    assert!(machine.state().from.is_none());
+}
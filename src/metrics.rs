@@ -0,0 +1,118 @@
+use std::{
+    net::SocketAddr,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// Recorder for server events, install one with `SmtpServiceBuilder::metrics`.
+/// All events are ignored by default, so only the interesting ones need to be implemented.
+/// These are called on the connection's task, so they should return quickly.
+pub trait Metrics: Send + Sync {
+    /// A client connected to the service.
+    fn connection_accepted(&self, _peer: SocketAddr) {}
+    /// A client connection was closed, for any reason.
+    fn connection_closed(&self) {}
+    /// A message of `size` bytes was saved by the handler.
+    fn message_accepted(&self, _size: usize) {}
+    /// A message was rejected after its data was received, by the handler or for its size.
+    fn message_rejected(&self) {}
+    /// Bytes were read from a client.
+    fn bytes_received(&self, _count: usize) {}
+    /// A client, command or message was refused, see `Rejection` for the reasons.
+    fn rejected(&self, _reason: Rejection) {}
+}
+
+/// Reason a client, command or message was refused, reported to `Metrics::rejected`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Rejection {
+    /// A command which could not be parsed, is not implemented or has invalid parameters.
+    Syntax,
+    /// A command sent out of order, e.g. RCPT before MAIL.
+    OutOfSequence,
+    /// The sender given with MAIL was refused.
+    Sender,
+    /// The recipient given with RCPT was refused, by the handler or for its address.
+    Recipient,
+    /// The declared or actual size of a message exceeds the limit.
+    Size,
+    /// The message was rejected after its data was received.
+    Content,
+    /// The client exceeded a limit on connections, transactions or recipients, or the server is overloaded.
+    RateLimited,
+}
+
+/// Metrics recorder keeping a total count of every event.
+/// Share it between the service and an exporter, which reads the totals with the getters.
+#[derive(Debug, Default)]
+pub struct MetricsCounters {
+    connections_accepted: AtomicU64,
+    connections_closed: AtomicU64,
+    messages_accepted: AtomicU64,
+    messages_rejected: AtomicU64,
+    bytes_received: AtomicU64,
+    /// Indexed by `Rejection`, in the order of its variants.
+    rejections: [AtomicU64; 7],
+}
+
+impl MetricsCounters {
+    /// Create counters starting at zero.
+    pub fn new() -> Self {
+        MetricsCounters::default()
+    }
+
+    /// Number of accepted client connections.
+    pub fn connections_accepted(&self) -> u64 {
+        self.connections_accepted.load(Ordering::Relaxed)
+    }
+
+    /// Number of closed client connections.
+    pub fn connections_closed(&self) -> u64 {
+        self.connections_closed.load(Ordering::Relaxed)
+    }
+
+    /// Number of messages saved by the handler.
+    pub fn messages_accepted(&self) -> u64 {
+        self.messages_accepted.load(Ordering::Relaxed)
+    }
+
+    /// Number of messages rejected after their data was received.
+    pub fn messages_rejected(&self) -> u64 {
+        self.messages_rejected.load(Ordering::Relaxed)
+    }
+
+    /// Number of bytes read from clients.
+    pub fn bytes_received(&self) -> u64 {
+        self.bytes_received.load(Ordering::Relaxed)
+    }
+
+    /// Number of refusals for the given reason.
+    pub fn rejections(&self, reason: Rejection) -> u64 {
+        self.rejections[reason as usize].load(Ordering::Relaxed)
+    }
+}
+
+impl Metrics for MetricsCounters {
+    fn connection_accepted(&self, _peer: SocketAddr) {
+        self.connections_accepted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn connection_closed(&self) {
+        self.connections_closed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn message_accepted(&self, _size: usize) {
+        self.messages_accepted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn message_rejected(&self) {
+        self.messages_rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn bytes_received(&self, count: usize) {
+        self.bytes_received
+            .fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    fn rejected(&self, reason: Rejection) {
+        self.rejections[reason as usize].fetch_add(1, Ordering::Relaxed);
+    }
+}
@@ -1,35 +1,100 @@
 use nom::branch::alt;
 use nom::bytes::complete::{is_a, tag, tag_no_case};
-use nom::character::complete::{alphanumeric1, satisfy};
-use nom::combinator::{eof, opt, recognize};
+use nom::character::complete::{alphanumeric1, digit1, satisfy};
+use nom::combinator::{eof, map, map_res, opt, recognize, rest, verify};
 use nom::multi::{many0, many1};
 use nom::sequence::{delimited, pair, preceded, terminated, tuple};
 use nom::IResult;
 
-use crate::command::{Command, Domain, Mailbox};
+use std::str::FromStr;
 
+use crate::command::{AddressError, Command, Domain, Mailbox};
+
+pub mod result;
 #[cfg(test)]
 mod tests;
 
+use result::{DomainParam, MailboxParam, ParameterParam, ParseCommand};
+
 type NomResult<'a, T> = IResult<&'a str, T>;
 
 /// Parse an SMTP command.
 /// It automatically splits the commands into lines, so raw strings can be put in.
+///
+/// RFC 5321 requires lines to end with CRLF, but many clients send a bare LF, so both are accepted.
+/// A bare CR does not end a line: it stays part of the line, which then fails to parse,
+/// and an input ending with CR is kept as remaining until its LF arrives.
 pub fn parse(input: &str) -> (Vec<(&str, Option<Command>)>, &str) {
+    parse_lines(input, parse_command)
+}
+
+/// Parse an SMTP command without copying its arguments.
+/// Works like `parse`, but the returned commands borrow from the input.
+/// Where `parse` allocates a string for every domain, local part and parameter,
+/// this only allocates the returned vectors. Convert a command into a `Command` to retain it.
+pub fn parse_borrowed(input: &str) -> (Vec<(&str, Option<ParseCommand<'_>>)>, &str) {
+    parse_lines(input, parse_command_borrowed)
+}
+
+impl FromStr for Domain {
+    type Err = AddressError;
+
+    /// Parse a domain like `nexium.app`, which may contain U-labels.
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match alt((
+            terminated(parse_domain, eof),
+            terminated(parse_domain_utf8, eof),
+        ))(input)
+        {
+            Ok((_, domain)) => Ok(domain.into()),
+            Err(_) => Err(AddressError),
+        }
+    }
+}
+
+impl FromStr for Mailbox {
+    type Err = AddressError;
+
+    /// Parse a mailbox like `info@nexium.app`, without angle brackets, which may contain UTF-8.
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match alt((
+            terminated(parse_mailbox, eof),
+            terminated(parse_mailbox_utf8, eof),
+        ))(input)
+        {
+            Ok((_, mailbox)) => Ok(mailbox.into()),
+            Err(_) => Err(AddressError),
+        }
+    }
+}
+
+/// Split the input into lines, and parse each complete line with the given parser.
+/// Only lines terminated by LF are complete, the unterminated rest is returned as remaining.
+fn parse_lines<'a, T>(
+    input: &'a str,
+    parser: impl Fn(&'a str) -> NomResult<'a, T>,
+) -> (Vec<(&'a str, Option<T>)>, &'a str) {
     let mut result = Vec::new();
     let mut remaining = "";
 
-    let mut lines = input.lines().peekable();
+    for raw in input.split_inclusive('\n') {
+        let line = match raw.strip_suffix('\n') {
+            Some(line) => line.strip_suffix('\r').unwrap_or(line),
+            None => {
+                remaining = raw;
+                break;
+            }
+        };
 
-    while let Some(line) = lines.next() {
-        if lines.peek().is_none() && !input.ends_with('\n') {
-            remaining = line;
-            break;
+        // Some clients send stray blank lines, they are answered like any other invalid command.
+        if line.is_empty() {
+            result.push((line, None));
+            continue;
         }
 
-        match parse_command(line) {
+        match parser(line) {
             Ok((rem, cmd)) => {
-                if rem != "" {
+                if !rem.is_empty() {
                     result.push((line, None));
                     continue;
                 }
@@ -45,162 +110,362 @@ pub fn parse(input: &str) -> (Vec<(&str, Option<Command>)>, &str) {
 
 /// Parse a data line.
 /// This is not done with Nom.
+/// Like commands, lines may end with CRLF or a bare LF, the data always uses CRLF.
+/// Data is handled as octets, so 8-bit bodies which are not UTF-8 are passed on unchanged.
 /// The returning tuple contains:
 /// - Boolean indicating if an end of data state was reached.
-/// - String with the data, this is only complete if the boolean is true.
-/// - Remaining string with input after the data end. This is only non-empty if the boolean is true.
-pub fn parse_data_lines(input: &str) -> (bool, String, String) {
-    let mut has_ended = false;
+/// - Octets of the complete lines, this is only the complete data if the boolean is true.
+/// - Remaining input after the data end, or the unterminated last line when the end was not reached.
+///   The caller should prepend it to the next input, so a line or terminator split between reads is recognized.
+pub fn parse_data_lines(input: &[u8]) -> (bool, Vec<u8>, Vec<u8>) {
     let mut result = Vec::new();
-    let mut remaining = Vec::new();
+    let mut consumed = 0;
 
-    let mut lines = input.lines().peekable();
+    for raw in input.split_inclusive(|&b| b == b'\n') {
+        let line = match raw.strip_suffix(b"\n") {
+            Some(line) => line.strip_suffix(b"\r").unwrap_or(line),
+            None => break,
+        };
 
-    while let Some(line) = lines.next() {
-        if has_ended {
-            remaining.push(line);
-        }
+        consumed += raw.len();
 
-        if line == "." {
-            has_ended = true;
+        if line == b"." {
+            return (true, result.join(&b"\r\n"[..]), input[consumed..].to_vec());
         }
 
-        if line.starts_with(".") {
-            result.push(&line[1..]);
-        } else {
-            result.push(line);
+        match line.strip_prefix(b".") {
+            Some(stripped) => result.push(stripped),
+            None => result.push(line),
         }
     }
 
-    (has_ended, result.join("\r\n"), remaining.join("\r\n"))
+    (false, result.join(&b"\r\n"[..]), input[consumed..].to_vec())
+}
+
+fn parse_command(input: &str) -> NomResult<'_, Command> {
+    map(parse_command_borrowed, Command::from)(input)
 }
 
-fn parse_command(input: &str) -> NomResult<Command> {
+fn parse_command_borrowed(input: &str) -> NomResult<'_, ParseCommand<'_>> {
     alt((
-        parse_ehlo, parse_helo, parse_mail, parse_rcpt, parse_data, parse_rset, parse_quit,
+        parse_ehlo,
+        parse_helo,
+        parse_mail,
+        parse_rcpt,
+        parse_data,
+        parse_bdat,
+        parse_rset,
+        parse_noop,
+        parse_expn,
+        parse_quit,
+        parse_unimplemented,
     ))(input)
 }
 
-fn parse_ehlo(input: &str) -> NomResult<Command> {
-    let (rem, domain) = delimited(tag_no_case("EHLO "), parse_domain, eof)(input)?;
+fn parse_ehlo(input: &str) -> NomResult<'_, ParseCommand<'_>> {
+    let (rem, domain) = delimited(tag_no_case("EHLO "), parse_helo_identity, eof)(input)?;
 
-    Ok((rem, Command::EHLO(domain)))
+    Ok((rem, ParseCommand::EHLO(domain)))
 }
 
-fn parse_helo(input: &str) -> NomResult<Command> {
-    let (rem, domain) = delimited(tag_no_case("HELO "), parse_domain, eof)(input)?;
+fn parse_helo(input: &str) -> NomResult<'_, ParseCommand<'_>> {
+    let (rem, domain) = delimited(tag_no_case("HELO "), parse_helo_identity, eof)(input)?;
 
-    Ok((rem, Command::HELO(domain)))
+    Ok((rem, ParseCommand::HELO(domain)))
 }
 
-fn parse_mail(input: &str) -> NomResult<Command> {
-    let (rem, res) = tuple((tag_no_case("MAIL FROM:"), opt(tag(" ")), parse_path, eof))(input)?;
-    let (_, _, mailbox, _) = res;
+/// Parse the identity given with HELO or EHLO.
+/// RFC 5321 asks for a domain or an address literal like `[192.0.2.1]`, but clients also send
+/// bare hostnames and other text, so any word without whitespace or control characters is accepted.
+/// Judging the identity is left to `Handler::helo_allowed`.
+fn parse_helo_identity(input: &str) -> NomResult<'_, DomainParam<'_>> {
+    let (rem, identity) =
+        recognize(many1(satisfy(|c| !c.is_whitespace() && !c.is_control())))(input)?;
 
-    Ok((rem, Command::FROM(mailbox)))
+    Ok((rem, DomainParam(identity)))
 }
 
-fn parse_rcpt(input: &str) -> NomResult<Command> {
-    let (rem, res) = tuple((tag_no_case("RCPT TO:"), opt(tag(" ")), parse_path, eof))(input)?;
-    let (_, _, mailbox, _) = res;
+fn parse_mail(input: &str) -> NomResult<'_, ParseCommand<'_>> {
+    let (rem, res) = tuple((
+        tag_no_case("MAIL FROM:"),
+        opt(tag(" ")),
+        alt((
+            pair(parse_path, parse_esmtp_params),
+            verify(pair(parse_path_utf8, parse_esmtp_params), |(_, params)| {
+                params
+                    .iter()
+                    .any(|(k, _)| k.eq_ignore_ascii_case("SMTPUTF8"))
+            }),
+        )),
+        eof,
+    ))(input)?;
+    let (_, _, (mailbox, params), _) = res;
+
+    Ok((rem, ParseCommand::FROM(mailbox, params)))
+}
+
+fn parse_rcpt(input: &str) -> NomResult<'_, ParseCommand<'_>> {
+    let (rem, res) = tuple((
+        tag_no_case("RCPT TO:"),
+        opt(tag(" ")),
+        alt((parse_path, parse_path_utf8, parse_postmaster)),
+        parse_esmtp_params,
+        eof,
+    ))(input)?;
+    let (_, _, mailbox, params, _) = res;
+
+    Ok((rem, ParseCommand::RCPT(mailbox, params)))
+}
+
+/// Parse the ESMTP parameters following a MAIL or RCPT path.
+fn parse_esmtp_params(input: &str) -> NomResult<'_, Vec<ParameterParam<'_>>> {
+    many0(preceded(tag(" "), parse_esmtp_param))(input)
+}
+
+fn parse_esmtp_param(input: &str) -> NomResult<'_, ParameterParam<'_>> {
+    pair(
+        parse_esmtp_keyword,
+        opt(preceded(tag("="), parse_esmtp_value)),
+    )(input)
+}
+
+fn parse_esmtp_keyword(input: &str) -> NomResult<'_, &str> {
+    recognize(pair(
+        satisfy(|c| c.is_ascii_alphanumeric()),
+        many0(satisfy(|c| c.is_ascii_alphanumeric() || c == '-')),
+    ))(input)
+}
 
-    Ok((rem, Command::RCPT(mailbox)))
+fn parse_esmtp_value(input: &str) -> NomResult<'_, &str> {
+    recognize(many1(alt((
+        recognize(satisfy(|c| {
+            let val = c as u32;
+
+            (33..=60).contains(&val) || (62..=126).contains(&val)
+        })),
+        parse_utf8_non_ascii,
+    ))))(input)
 }
 
-fn parse_data(input: &str) -> NomResult<Command> {
+fn parse_data(input: &str) -> NomResult<'_, ParseCommand<'_>> {
     let (rem, _) = terminated(tag_no_case("DATA"), eof)(input)?;
 
-    Ok((rem, Command::DATA))
+    Ok((rem, ParseCommand::DATA))
+}
+
+/// Parse BDAT with the size of its chunk, and LAST when it ends the message.
+fn parse_bdat(input: &str) -> NomResult<'_, ParseCommand<'_>> {
+    let (rem, (size, last)) = delimited(
+        tag_no_case("BDAT "),
+        pair(
+            map_res(digit1, str::parse::<usize>),
+            opt(tag_no_case(" LAST")),
+        ),
+        eof,
+    )(input)?;
+
+    Ok((rem, ParseCommand::BDAT(size, last.is_some())))
 }
 
-fn parse_rset(input: &str) -> NomResult<Command> {
+fn parse_rset(input: &str) -> NomResult<'_, ParseCommand<'_>> {
     let (rem, _) = terminated(tag_no_case("RSET"), eof)(input)?;
 
-    Ok((rem, Command::RSET))
+    Ok((rem, ParseCommand::RSET))
 }
 
-fn parse_quit(input: &str) -> NomResult<Command> {
+/// Parse NOOP, ignoring its optional argument.
+fn parse_noop(input: &str) -> NomResult<'_, ParseCommand<'_>> {
+    let (rem, _) = terminated(
+        tag_no_case("NOOP"),
+        pair(opt(preceded(tag(" "), rest)), eof),
+    )(input)?;
+
+    Ok((rem, ParseCommand::NOOP))
+}
+
+fn parse_expn(input: &str) -> NomResult<'_, ParseCommand<'_>> {
+    let (rem, list) = preceded(
+        tag_no_case("EXPN "),
+        verify(rest, |s: &str| {
+            !s.is_empty() && !s.contains(char::is_control)
+        }),
+    )(input)?;
+
+    Ok((rem, ParseCommand::EXPN(list)))
+}
+
+/// Parse a verb from RFC 5321 or a common extension which this server does not implement.
+/// These are answered with 502 instead of the 500 for unrecognized input, so clients can tell the difference.
+/// TURN and ATRN from RFC 2645 reverse the direction of the connection, TURN without authenticating the client,
+/// so they are intentionally never supported.
+fn parse_unimplemented(input: &str) -> NomResult<'_, ParseCommand<'_>> {
+    let (rem, verb) = terminated(
+        alt((
+            tag_no_case("VRFY"),
+            tag_no_case("HELP"),
+            tag_no_case("SEND"),
+            tag_no_case("SOML"),
+            tag_no_case("SAML"),
+            tag_no_case("TURN"),
+            tag_no_case("ETRN"),
+            tag_no_case("ATRN"),
+            tag_no_case("AUTH"),
+            tag_no_case("STARTTLS"),
+        )),
+        alt((eof, preceded(tag(" "), rest))),
+    )(input)?;
+
+    Ok((rem, ParseCommand::Unimplemented(verb)))
+}
+
+fn parse_quit(input: &str) -> NomResult<'_, ParseCommand<'_>> {
     let (rem, _) = terminated(tag_no_case("QUIT"), eof)(input)?;
 
-    Ok((rem, Command::QUIT))
+    Ok((rem, ParseCommand::QUIT))
 }
 
-fn parse_path(input: &str) -> NomResult<Mailbox> {
+fn parse_path(input: &str) -> NomResult<'_, MailboxParam<'_>> {
     delimited(tag("<"), parse_mailbox, tag(">"))(input)
 }
 
-fn parse_mailbox(input: &str) -> NomResult<Mailbox> {
-    let (rem, res) = tuple((parse_localpart, tag("@"), parse_domain))(input)?;
-    let (user, _, domain) = res;
+/// Path allowing UTF-8 mailboxes, as defined in RFC 6531.
+fn parse_path_utf8(input: &str) -> NomResult<'_, MailboxParam<'_>> {
+    delimited(tag("<"), parse_mailbox_utf8, tag(">"))(input)
+}
+
+/// The domainless `<postmaster>` recipient from RFC 5321 section 4.1.1.3, case-insensitive.
+/// It is returned as a mailbox with an empty domain.
+fn parse_postmaster(input: &str) -> NomResult<'_, MailboxParam<'_>> {
+    let (rem, local) = delimited(tag("<"), tag_no_case("postmaster"), tag(">"))(input)?;
 
     Ok((
         rem,
-        Mailbox {
-            domain,
-            local: user.to_string(),
+        MailboxParam {
+            local,
+            domain: DomainParam(""),
         },
     ))
 }
 
-fn parse_domain(input: &str) -> NomResult<Domain> {
+fn parse_mailbox(input: &str) -> NomResult<'_, MailboxParam<'_>> {
+    let (rem, res) = tuple((parse_localpart, tag("@"), parse_domain))(input)?;
+    let (local, _, domain) = res;
+
+    Ok((rem, MailboxParam { domain, local }))
+}
+
+fn parse_mailbox_utf8(input: &str) -> NomResult<'_, MailboxParam<'_>> {
+    let (rem, res) = tuple((parse_localpart_utf8, tag("@"), parse_domain_utf8))(input)?;
+    let (local, _, domain) = res;
+
+    Ok((rem, MailboxParam { domain, local }))
+}
+
+fn parse_domain(input: &str) -> NomResult<'_, DomainParam<'_>> {
     let (rem, res) = recognize(pair(
         parse_subdomain,
         many0(pair(tag("."), parse_subdomain)),
     ))(input)?;
 
-    Ok((rem, res.into()))
+    Ok((rem, DomainParam(res)))
 }
 
-fn parse_subdomain(input: &str) -> NomResult<&str> {
+fn parse_subdomain(input: &str) -> NomResult<'_, &str> {
     recognize(pair(alphanumeric1, many0(pair(tag("-"), alphanumeric1))))(input)
 }
 
-fn parse_localpart(input: &str) -> NomResult<&str> {
+/// Domain which may contain U-labels.
+fn parse_domain_utf8(input: &str) -> NomResult<'_, DomainParam<'_>> {
+    let (rem, res) = recognize(pair(
+        parse_subdomain_utf8,
+        many0(pair(tag("."), parse_subdomain_utf8)),
+    ))(input)?;
+
+    Ok((rem, DomainParam(res)))
+}
+
+fn parse_subdomain_utf8(input: &str) -> NomResult<'_, &str> {
+    let label = |i| recognize(many1(alt((alphanumeric1, parse_utf8_non_ascii))))(i);
+
+    recognize(pair(label, many0(pair(tag("-"), label))))(input)
+}
+
+fn parse_localpart(input: &str) -> NomResult<'_, &str> {
     alt((parse_dot_string, parse_quoted_string))(input)
 }
 
-fn parse_dot_string(input: &str) -> NomResult<&str> {
+fn parse_dot_string(input: &str) -> NomResult<'_, &str> {
     recognize(pair(parse_atom, many0(pair(opt(tag(".")), parse_atom))))(input)
 }
 
-fn parse_quoted_string(input: &str) -> NomResult<&str> {
+fn parse_localpart_utf8(input: &str) -> NomResult<'_, &str> {
+    alt((parse_dot_string_utf8, parse_quoted_string_utf8))(input)
+}
+
+fn parse_dot_string_utf8(input: &str) -> NomResult<'_, &str> {
+    recognize(pair(
+        parse_atom_utf8,
+        many0(pair(opt(tag(".")), parse_atom_utf8)),
+    ))(input)
+}
+
+fn parse_quoted_string(input: &str) -> NomResult<'_, &str> {
     delimited(tag("\""), recognize(many1(parse_qcontent_smtp)), tag("\""))(input)
 }
 
-fn parse_qcontent_smtp(input: &str) -> NomResult<&str> {
+fn parse_quoted_string_utf8(input: &str) -> NomResult<'_, &str> {
+    delimited(
+        tag("\""),
+        recognize(many1(alt((parse_qcontent_smtp, parse_utf8_non_ascii)))),
+        tag("\""),
+    )(input)
+}
+
+fn parse_qcontent_smtp(input: &str) -> NomResult<'_, &str> {
     alt((parse_qtext_smtp, parse_quotedpair_smtp))(input)
 }
 
-fn parse_qtext_smtp(input: &str) -> NomResult<&str> {
+fn parse_qtext_smtp(input: &str) -> NomResult<'_, &str> {
     recognize(satisfy(|c| {
-        let val = c as u8;
+        let val = c as u32;
 
-        (val >= 32 && val <= 33) || (val >= 35 && val <= 91) || (val >= 93 && val <= 126)
+        (32..=33).contains(&val) || (35..=91).contains(&val) || (93..=126).contains(&val)
     }))(input)
 }
 
-fn parse_quotedpair_smtp(input: &str) -> NomResult<&str> {
+fn parse_quotedpair_smtp(input: &str) -> NomResult<'_, &str> {
     preceded(
         tag("\\"),
         recognize(satisfy(|c| {
-            let val = c as u8;
+            let val = c as u32;
 
-            val >= 32 && val <= 126
+            (32..=126).contains(&val)
         })),
     )(input)
 }
 
-fn parse_atom(input: &str) -> NomResult<&str> {
+fn parse_atom(input: &str) -> NomResult<'_, &str> {
     recognize(many1(parse_atext))(input)
 }
 
-fn parse_atext(input: &str) -> NomResult<&str> {
+fn parse_atom_utf8(input: &str) -> NomResult<'_, &str> {
+    recognize(many1(alt((parse_atext, parse_utf8_non_ascii))))(input)
+}
+
+fn parse_atext(input: &str) -> NomResult<'_, &str> {
     alt((
         recognize(satisfy(|c| {
-            let val = c as u8;
+            let val = c as u32;
 
-            (val >= 48 && val <= 57) || (val >= 65 && val <= 90) || (val >= 97 && val <= 122)
+            (48..=57).contains(&val) || (65..=90).contains(&val) || (97..=122).contains(&val)
         })),
         is_a("!#$%&'*+-/=?^_`{|}~"),
     ))(input)
 }
+
+/// Any non-ASCII character, allowed in mailboxes by RFC 6531.
+fn parse_utf8_non_ascii(input: &str) -> NomResult<'_, &str> {
+    recognize(satisfy(|c| {
+        !c.is_ascii() && !c.is_control() && !c.is_whitespace()
+    }))(input)
+}
@@ -0,0 +1,77 @@
+use crate::command::{Command, Domain, Mailbox, Parameter};
+
+/// Command borrowing its arguments from the parsed input.
+/// Returned by `parse_borrowed`, convert it into a `Command` to retain it.
+#[non_exhaustive]
+#[derive(Debug, PartialEq, Clone)]
+pub enum ParseCommand<'a> {
+    HELO(DomainParam<'a>),
+    EHLO(DomainParam<'a>),
+    RCPT(MailboxParam<'a>, Vec<ParameterParam<'a>>),
+    FROM(MailboxParam<'a>, Vec<ParameterParam<'a>>),
+    DATA,
+    BDAT(usize, bool),
+    RSET,
+    NOOP,
+    EXPN(&'a str),
+    QUIT,
+    Unimplemented(&'a str),
+}
+
+/// Domain borrowed from the parsed input.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct DomainParam<'a>(pub &'a str);
+
+/// Mailbox borrowed from the parsed input.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct MailboxParam<'a> {
+    pub local: &'a str,
+    pub domain: DomainParam<'a>,
+}
+
+/// ESMTP parameter borrowed from the parsed input.
+pub type ParameterParam<'a> = (&'a str, Option<&'a str>);
+
+impl From<ParseCommand<'_>> for Command {
+    fn from(command: ParseCommand<'_>) -> Self {
+        match command {
+            ParseCommand::HELO(domain) => Command::HELO(domain.into()),
+            ParseCommand::EHLO(domain) => Command::EHLO(domain.into()),
+            ParseCommand::RCPT(mailbox, params) => {
+                Command::RCPT(mailbox.into(), owned_parameters(params))
+            }
+            ParseCommand::FROM(mailbox, params) => {
+                Command::FROM(mailbox.into(), owned_parameters(params))
+            }
+            ParseCommand::DATA => Command::DATA,
+            ParseCommand::BDAT(size, last) => Command::BDAT(size, last),
+            ParseCommand::RSET => Command::RSET,
+            ParseCommand::NOOP => Command::NOOP,
+            ParseCommand::EXPN(list) => Command::EXPN(list.to_string()),
+            ParseCommand::QUIT => Command::QUIT,
+            ParseCommand::Unimplemented(verb) => Command::Unimplemented(verb.to_ascii_uppercase()),
+        }
+    }
+}
+
+impl From<DomainParam<'_>> for Domain {
+    fn from(domain: DomainParam<'_>) -> Self {
+        Domain(domain.0.to_string())
+    }
+}
+
+impl From<MailboxParam<'_>> for Mailbox {
+    fn from(mailbox: MailboxParam<'_>) -> Self {
+        Mailbox {
+            local: mailbox.local.to_string(),
+            domain: mailbox.domain.into(),
+        }
+    }
+}
+
+fn owned_parameters(params: Vec<ParameterParam<'_>>) -> Vec<Parameter> {
+    params
+        .into_iter()
+        .map(|(keyword, value)| (keyword.to_string(), value.map(|v| v.to_string())))
+        .collect()
+}
@@ -1,6 +1,7 @@
 use nom::error::ParseError;
 
 use super::*;
+use crate::command::{Domain, Mailbox};
 
 #[test]
 fn parse_single() {
@@ -9,7 +10,7 @@ fn parse_single() {
     assert_eq!(1, cmds.len());
     assert_eq!("", rem);
 
-    let first = cmds.get(0).unwrap();
+    let first = cmds.first().unwrap();
     let parsed = first.1.as_ref().unwrap();
     assert_eq!(Command::EHLO(Domain("nexium.app".to_string())), *parsed);
 }
@@ -21,7 +22,7 @@ fn parse_multiple() {
     assert_eq!(2, cmds.len());
     assert_eq!("", rem);
 
-    let first = cmds.get(0).unwrap();
+    let first = cmds.first().unwrap();
     let parsed = first.1.as_ref().unwrap();
 
     assert_eq!(Command::EHLO(Domain("nexium.app".to_string())), *parsed);
@@ -29,10 +30,13 @@ fn parse_multiple() {
     let second = cmds.get(1).unwrap();
     let parsed = second.1.as_ref().unwrap();
     assert_eq!(
-        Command::FROM(Mailbox {
-            local: "info".to_string(),
-            domain: "nexium.app".into()
-        }),
+        Command::FROM(
+            Mailbox {
+                local: "info".to_string(),
+                domain: "nexium.app".into()
+            },
+            vec![]
+        ),
         *parsed
     );
 }
@@ -44,11 +48,62 @@ fn parse_unfinished() {
     assert_eq!(1, cmds.len());
     assert_eq!("MAIL FR", rem);
 
-    let first = cmds.get(0).unwrap();
+    let first = cmds.first().unwrap();
     let parsed = first.1.as_ref().unwrap();
     assert_eq!(Command::EHLO(Domain("nexium.app".to_string())), *parsed);
 }
 
+#[test]
+fn parse_bare_lf() {
+    let (cmds, rem) = parse("EHLO nexium.app\nRSET\nQUIT\n");
+
+    assert_eq!(3, cmds.len());
+    assert_eq!("", rem);
+    assert_eq!(Some(Command::EHLO("nexium.app".into())), cmds[0].1);
+    assert_eq!(Some(Command::RSET), cmds[1].1);
+    assert_eq!(Some(Command::QUIT), cmds[2].1);
+}
+
+#[test]
+fn parse_mixed_line_endings() {
+    let (cmds, rem) = parse("RSET\r\nRSET\nQUIT\r\n");
+
+    assert_eq!(3, cmds.len());
+    assert_eq!("", rem);
+    assert!(cmds.iter().all(|(_, cmd)| cmd.is_some()));
+}
+
+#[test]
+fn parse_trailing_cr_unfinished() {
+    let (cmds, rem) = parse("RSET\r\nQUIT\r");
+
+    assert_eq!(1, cmds.len());
+    assert_eq!("QUIT\r", rem);
+
+    let input = format!("{}\n", rem);
+    let (cmds, rem) = parse(&input);
+    assert_eq!(Some(Command::QUIT), cmds[0].1);
+    assert_eq!("", rem);
+}
+
+#[test]
+fn parse_bare_cr_not_a_line_ending() {
+    let (cmds, rem) = parse("RSET\rQUIT\r\n");
+
+    assert_eq!(1, cmds.len());
+    assert_eq!("RSET\rQUIT", cmds[0].0);
+    assert_eq!(None, cmds[0].1);
+    assert_eq!("", rem);
+}
+
+#[test]
+fn parse_borrowed_trailing_cr_unfinished() {
+    let (cmds, rem) = parse_borrowed("QUIT\r");
+
+    assert!(cmds.is_empty());
+    assert_eq!("QUIT\r", rem);
+}
+
 #[test]
 fn parse_empty() {
     let (cmds, rem) = parse("");
@@ -57,6 +112,31 @@ fn parse_empty() {
     assert_eq!("", rem);
 }
 
+#[test]
+fn parse_blank_line() {
+    for input in ["\r\n", "\n"] {
+        let (cmds, rem) = parse(input);
+
+        assert_eq!(vec![("", None)], cmds);
+        assert_eq!("", rem);
+    }
+}
+
+#[test]
+fn parse_blank_line_between_commands() {
+    let (cmds, rem) = parse("NOOP\r\n\r\nQUIT\r\nDA");
+
+    assert_eq!(
+        vec![
+            ("NOOP", Some(Command::NOOP)),
+            ("", None),
+            ("QUIT", Some(Command::QUIT))
+        ],
+        cmds
+    );
+    assert_eq!("DA", rem);
+}
+
 #[test]
 fn parse_invalid_valid() {
     let (cmds, rem) = parse("THIS IS AN ERROR\r\nMAIL FROM:<info@nexium.app>\r\nRC");
@@ -64,17 +144,20 @@ fn parse_invalid_valid() {
     assert_eq!(2, cmds.len());
     assert_eq!("RC", rem);
 
-    let first = cmds.get(0).unwrap();
+    let first = cmds.first().unwrap();
     assert_eq!("THIS IS AN ERROR", first.0);
     assert!(first.1.as_ref().is_none());
 
     let second = cmds.get(1).unwrap();
     let parsed = second.1.as_ref().unwrap();
     assert_eq!(
-        Command::FROM(Mailbox {
-            local: "info".to_string(),
-            domain: "nexium.app".into()
-        }),
+        Command::FROM(
+            Mailbox {
+                local: "info".to_string(),
+                domain: "nexium.app".into()
+            },
+            vec![]
+        ),
         *parsed
     );
 }
@@ -126,6 +209,27 @@ fn parse_command_ehlo_empty_with_space() {
     );
 }
 
+#[test]
+fn parse_command_ehlo_identities() {
+    for identity in [
+        "[10.0.0.1]",
+        "[IPv6:2001:db8::1]",
+        "localhost",
+        "not_a-domain!",
+    ]
+    .iter()
+    {
+        let line = format!("EHLO {}", identity);
+        let (rem, cmd) = parse_command(&line).unwrap();
+
+        assert_eq!(Command::EHLO(Domain(identity.to_string())), cmd);
+        assert_eq!("", rem);
+    }
+
+    assert!(parse_command("EHLO two words").is_err());
+    assert!(parse_command("HELO tab\there").is_err());
+}
+
 #[test]
 fn parse_command_helo_simple() {
     let (rem, cmd) = parse_command("HELO nexium.app").unwrap();
@@ -152,10 +256,13 @@ fn parse_command_from_simple() {
     let (rem, cmd) = parse_command("MAIL FROM:<hello@nexium.app>").unwrap();
 
     assert_eq!(
-        Command::FROM(Mailbox {
-            local: "hello".to_string(),
-            domain: "nexium.app".into()
-        }),
+        Command::FROM(
+            Mailbox {
+                local: "hello".to_string(),
+                domain: "nexium.app".into()
+            },
+            vec![]
+        ),
         cmd
     );
     assert_eq!("", rem);
@@ -166,10 +273,13 @@ fn parse_command_from_space() {
     let (rem, cmd) = parse_command("MAIL FROM: <hello@nexium.app>").unwrap();
 
     assert_eq!(
-        Command::FROM(Mailbox {
-            local: "hello".to_string(),
-            domain: "nexium.app".into()
-        }),
+        Command::FROM(
+            Mailbox {
+                local: "hello".to_string(),
+                domain: "nexium.app".into()
+            },
+            vec![]
+        ),
         cmd
     );
     assert_eq!("", rem);
@@ -193,15 +303,59 @@ fn parse_command_rcpt_simple() {
     let (rem, cmd) = parse_command("RCPT TO:<sendme@nexium.app>").unwrap();
 
     assert_eq!(
-        Command::RCPT(Mailbox {
-            local: "sendme".to_string(),
-            domain: "nexium.app".into()
-        }),
+        Command::RCPT(
+            Mailbox {
+                local: "sendme".to_string(),
+                domain: "nexium.app".into()
+            },
+            vec![]
+        ),
         cmd
     );
     assert_eq!("", rem);
 }
 
+#[test]
+fn parse_command_rcpt_postmaster() {
+    for input in ["RCPT TO:<postmaster>", "RCPT TO:<PostMaster>"] {
+        let (rem, cmd) = parse_command(input).unwrap();
+
+        assert_eq!(
+            Command::RCPT(
+                Mailbox {
+                    local: input[9..19].to_string(),
+                    domain: "".into()
+                },
+                vec![]
+            ),
+            cmd
+        );
+        assert_eq!("", rem);
+    }
+}
+
+#[test]
+fn parse_command_rcpt_postmaster_domain() {
+    let (_, cmd) = parse_command("RCPT TO:<Postmaster@example.com>").unwrap();
+
+    assert_eq!(
+        Command::RCPT(
+            Mailbox {
+                local: "Postmaster".to_string(),
+                domain: "example.com".into()
+            },
+            vec![]
+        ),
+        cmd
+    );
+}
+
+#[test]
+fn parse_command_domainless_rejected() {
+    assert!(parse_command("RCPT TO:<admin>").is_err());
+    assert!(parse_command("MAIL FROM:<postmaster>").is_err());
+}
+
 #[test]
 fn parse_command_data_simple() {
     let (rem, cmd) = parse_command("DATA").unwrap();
@@ -218,6 +372,82 @@ fn parse_command_reset_simple() {
     assert_eq!("", rem);
 }
 
+#[test]
+fn parse_command_noop_simple() {
+    let (rem, cmd) = parse_command("NOOP").unwrap();
+
+    assert_eq!(Command::NOOP, cmd);
+    assert_eq!("", rem);
+}
+
+#[test]
+fn parse_command_noop_argument() {
+    let (rem, cmd) = parse_command("noop are you there?").unwrap();
+
+    assert_eq!(Command::NOOP, cmd);
+    assert_eq!("", rem);
+    assert!(parse_command("NOOPS").is_err());
+}
+
+#[test]
+fn parse_command_expn() {
+    let (rem, cmd) = parse_command("EXPN staff@nexium.app").unwrap();
+
+    assert_eq!(Command::EXPN("staff@nexium.app".into()), cmd);
+    assert_eq!("", rem);
+    assert!(parse_command("EXPN").is_err());
+    assert!(parse_command("EXPN ").is_err());
+}
+
+#[test]
+fn parse_command_bdat() {
+    let (rem, cmd) = parse_command("BDAT 1000").unwrap();
+    assert_eq!(Command::BDAT(1000, false), cmd);
+    assert_eq!("", rem);
+
+    let (_, cmd) = parse_command("bdat 0 last").unwrap();
+    assert_eq!(Command::BDAT(0, true), cmd);
+
+    assert!(parse_command("BDAT").is_err());
+    assert!(parse_command("BDAT -1").is_err());
+    assert!(parse_command("BDAT 10 FIRST").is_err());
+    assert!(parse_command("BDAT 99999999999999999999999").is_err());
+}
+
+#[test]
+fn parse_command_unimplemented() {
+    let (rem, cmd) = parse_command("ETRN example.com").unwrap();
+    assert_eq!(Command::Unimplemented("ETRN".into()), cmd);
+    assert_eq!("", rem);
+
+    let (_, cmd) = parse_command("vrfy").unwrap();
+    assert_eq!(Command::Unimplemented("VRFY".into()), cmd);
+
+    let (_, cmd) = parse_command("TURN").unwrap();
+    assert_eq!(Command::Unimplemented("TURN".into()), cmd);
+
+    let (_, cmd) = parse_command("atrn example.com").unwrap();
+    assert_eq!(Command::Unimplemented("ATRN".into()), cmd);
+
+    assert!(parse_command("GIBBERISH").is_err());
+    assert!(parse_command("ETRNX").is_err());
+}
+
+#[test]
+fn parse_command_control_characters() {
+    assert!(parse_command("EHLO nexium.app\r\n250 injected").is_err());
+    assert!(parse_command("HELO nex\rium.app").is_err());
+    assert!(parse_command("EHLO nex\0ium.app").is_err());
+    assert!(parse_command("MAIL FROM:<info@nexium.app\r\n250 injected>").is_err());
+    assert!(parse_command("RCPT TO:<in\0fo@nexium.app>").is_err());
+    assert!(parse_command("RCPT TO:<\"in\rfo\"@nexium.app>").is_err());
+    assert!(parse_command("EXPN staff\r250 injected").is_err());
+    assert!(parse_command("EXPN st\0aff").is_err());
+
+    let (commands, _) = parse("EHLO nexium\r.app\r\n");
+    assert_eq!(vec![("EHLO nexium\r.app", None)], commands);
+}
+
 #[test]
 fn parse_command_quit_simple() {
     let (rem, cmd) = parse_command("QUIT").unwrap();
@@ -231,9 +461,9 @@ fn parse_mailbox_simple() {
     let (rem, res) = parse_mailbox("postbus@nexium.app ").unwrap();
 
     assert_eq!(
-        Mailbox {
-            local: "postbus".to_string(),
-            domain: "nexium.app".into()
+        MailboxParam {
+            local: "postbus",
+            domain: DomainParam("nexium.app")
         },
         res
     );
@@ -245,9 +475,9 @@ fn parse_mailbox_quoted() {
     let (rem, res) = parse_mailbox("\"john\"@nexium.app").unwrap();
 
     assert_eq!(
-        Mailbox {
-            local: "john".to_string(),
-            domain: "nexium.app".into()
+        MailboxParam {
+            local: "john",
+            domain: DomainParam("nexium.app")
         },
         res
     );
@@ -259,9 +489,9 @@ fn parse_mailbox_numbered() {
     let (rem, res) = parse_mailbox("1234567890@nexium.app").unwrap();
 
     assert_eq!(
-        Mailbox {
-            local: "1234567890".to_string(),
-            domain: "nexium.app".into()
+        MailboxParam {
+            local: "1234567890",
+            domain: DomainParam("nexium.app")
         },
         res
     );
@@ -298,7 +528,7 @@ fn parse_mailbox_nouser() {
 fn parse_domain_normal() {
     let (rem, res) = parse_domain("nexium.app").unwrap();
 
-    assert_eq!(Domain("nexium.app".to_string()), res);
+    assert_eq!(DomainParam("nexium.app"), res);
     assert_eq!("", rem);
 }
 
@@ -306,10 +536,25 @@ fn parse_domain_normal() {
 fn parse_domain_nested() {
     let (rem, res) = parse_domain("very.deep.nesting.nexium.app").unwrap();
 
-    assert_eq!(Domain("very.deep.nesting.nexium.app".to_string()), res);
+    assert_eq!(DomainParam("very.deep.nesting.nexium.app"), res);
     assert_eq!("", rem);
 }
 
+#[test]
+fn parse_domain_deeply_nested() {
+    let input = "a.".repeat(100_000) + "app";
+    let (rem, res) = parse_domain(&input).unwrap();
+
+    assert_eq!(DomainParam(input.as_str()), res);
+    assert_eq!("", rem);
+
+    let input = "a.".repeat(100_000);
+    let (rem, res) = parse_domain(&input).unwrap();
+
+    assert_eq!(DomainParam(&input[..input.len() - 1]), res);
+    assert_eq!(".", rem);
+}
+
 #[test]
 fn parse_domain_firstdot() {
     let err = parse_domain(".nexium.app").unwrap_err();
@@ -327,7 +572,7 @@ fn parse_domain_firstdot() {
 fn parse_domain_lastdot() {
     let (rem, res) = parse_domain("nexium.app.").unwrap();
 
-    assert_eq!(Domain("nexium.app".to_string()), res);
+    assert_eq!(DomainParam("nexium.app"), res);
     assert_eq!(".", rem);
 }
 
@@ -458,6 +703,19 @@ fn parse_quoted_last_dot() {
     assert_eq!("", rem);
 }
 
+#[test]
+fn parse_quoted_escape_sequences() {
+    let input = format!("\"{}\"", "\\\"".repeat(100_000));
+    let (rem, res) = parse_quoted_string(&input).unwrap();
+
+    assert_eq!(&input[1..input.len() - 1], res);
+    assert_eq!("", rem);
+
+    // Unterminated, so every escape has to be tried before giving up.
+    let input = format!("\"{}", "\\".repeat(100_000));
+    assert!(parse_quoted_string(&input).is_err());
+}
+
 #[test]
 fn parse_localpart_normal() {
     let (rem, res) = parse_localpart("this.matches but this does not").unwrap();
@@ -481,3 +739,308 @@ fn parse_quotepair_escape() {
     assert_eq!(" ", res);
     assert_eq!("", rem);
 }
+
+#[test]
+fn parse_command_from_smtputf8() {
+    let (rem, cmd) = parse_command("MAIL FROM:<δοκιμή@παράδειγμα.ελ> SMTPUTF8").unwrap();
+
+    assert_eq!(
+        Command::FROM(
+            Mailbox {
+                local: "δοκιμή".to_string(),
+                domain: "παράδειγμα.ελ".into()
+            },
+            vec![("SMTPUTF8".to_string(), None)]
+        ),
+        cmd
+    );
+    assert_eq!("", rem);
+}
+
+#[test]
+fn parse_command_from_ascii_smtputf8() {
+    let (rem, cmd) = parse_command("MAIL FROM:<hello@nexium.app> smtputf8").unwrap();
+
+    assert_eq!(
+        Command::FROM(
+            Mailbox {
+                local: "hello".to_string(),
+                domain: "nexium.app".into()
+            },
+            vec![("smtputf8".to_string(), None)]
+        ),
+        cmd
+    );
+    assert_eq!("", rem);
+}
+
+#[test]
+fn parse_command_from_utf8_without_smtputf8() {
+    assert!(parse_command("MAIL FROM:<δοκιμή@παράδειγμα.ελ>").is_err());
+}
+
+#[test]
+fn parse_command_rcpt_utf8() {
+    let (rem, cmd) = parse_command("RCPT TO:<\"θέμα\"@παράδειγμα.ελ>").unwrap();
+
+    assert_eq!(
+        Command::RCPT(
+            Mailbox {
+                local: "θέμα".to_string(),
+                domain: "παράδειγμα.ελ".into()
+            },
+            vec![]
+        ),
+        cmd
+    );
+    assert_eq!("", rem);
+}
+
+#[test]
+fn parse_path_non_ascii() {
+    assert!(parse_path("<šarlota@nexium.app>").is_err());
+    assert!(parse_path("<info@nexium.àpp>").is_err());
+}
+
+#[test]
+fn parse_command_from_params() {
+    let (rem, cmd) = parse_command("MAIL FROM:<a@b> SIZE=100 FOO=bar BAR").unwrap();
+
+    assert_eq!(
+        Command::FROM(
+            Mailbox {
+                local: "a".to_string(),
+                domain: "b".into()
+            },
+            vec![
+                ("SIZE".to_string(), Some("100".to_string())),
+                ("FOO".to_string(), Some("bar".to_string())),
+                ("BAR".to_string(), None),
+            ]
+        ),
+        cmd
+    );
+    assert_eq!("", rem);
+}
+
+#[test]
+fn parse_command_from_dsn() {
+    for (input, envid) in [
+        ("MAIL FROM:<a@b> RET=HDRS ENVID=QQ314159", "QQ314159"),
+        (
+            "MAIL FROM:<a@b> RET=HDRS ENVID=QQ+2B314159+3D",
+            "QQ+2B314159+3D",
+        ),
+    ] {
+        let (rem, cmd) = parse_command(input).unwrap();
+
+        assert_eq!(
+            Command::FROM(
+                Mailbox {
+                    local: "a".to_string(),
+                    domain: "b".into()
+                },
+                vec![
+                    ("RET".to_string(), Some("HDRS".to_string())),
+                    ("ENVID".to_string(), Some(envid.to_string())),
+                ]
+            ),
+            cmd
+        );
+        assert_eq!("", rem);
+    }
+}
+
+#[test]
+fn parse_command_from_auth() {
+    for (input, value) in [
+        ("MAIL FROM:<a@b> AUTH=user@example.com", "user@example.com"),
+        (
+            "MAIL FROM:<a@b> AUTH=user+2Btag@example.com",
+            "user+2Btag@example.com",
+        ),
+        ("MAIL FROM:<a@b> AUTH=<>", "<>"),
+    ] {
+        let (rem, cmd) = parse_command(input).unwrap();
+
+        assert_eq!(
+            Command::FROM(
+                Mailbox {
+                    local: "a".to_string(),
+                    domain: "b".into()
+                },
+                vec![("AUTH".to_string(), Some(value.to_string()))]
+            ),
+            cmd
+        );
+        assert_eq!("", rem);
+    }
+}
+
+#[test]
+fn parse_command_rcpt_params() {
+    let (rem, cmd) = parse_command("RCPT TO:<a@b> NOTIFY=SUCCESS,FAILURE X-CUSTOM=a+b").unwrap();
+
+    assert_eq!(
+        Command::RCPT(
+            Mailbox {
+                local: "a".to_string(),
+                domain: "b".into()
+            },
+            vec![
+                ("NOTIFY".to_string(), Some("SUCCESS,FAILURE".to_string())),
+                ("X-CUSTOM".to_string(), Some("a+b".to_string())),
+            ]
+        ),
+        cmd
+    );
+    assert_eq!("", rem);
+}
+
+#[test]
+fn parse_command_from_invalid_params() {
+    assert!(parse_command("MAIL FROM:<a@b> -FOO").is_err());
+    assert!(parse_command("MAIL FROM:<a@b> FOO=").is_err());
+    assert!(parse_command("MAIL FROM:<a@b> FOO=a=b").is_err());
+    assert!(parse_command("MAIL FROM:<a@b> F_OO").is_err());
+    assert!(parse_command("MAIL FROM:<a@b>  FOO").is_err());
+}
+
+#[test]
+fn parse_esmtp_param_keyword_only() {
+    let (rem, res) = parse_esmtp_param("8BITMIME").unwrap();
+
+    assert_eq!(("8BITMIME", None), res);
+    assert_eq!("", rem);
+}
+
+#[test]
+fn parse_borrowed_commands() {
+    let input = "EHLO nexium.app\r\nRCPT TO:<admin@nexium.app> NOTIFY=NEVER\r\nNOPE\r\nDA";
+    let (cmds, rem) = parse_borrowed(input);
+
+    assert_eq!(3, cmds.len());
+    assert_eq!("DA", rem);
+
+    assert_eq!(
+        Some(ParseCommand::EHLO(DomainParam("nexium.app"))),
+        cmds[0].1
+    );
+    assert_eq!(
+        Some(ParseCommand::RCPT(
+            MailboxParam {
+                local: "admin",
+                domain: DomainParam("nexium.app")
+            },
+            vec![("NOTIFY", Some("NEVER"))]
+        )),
+        cmds[1].1
+    );
+    assert_eq!(("NOPE", None), cmds[2]);
+}
+
+#[test]
+fn parse_borrowed_into_owned() {
+    let (cmds, _) = parse_borrowed("MAIL FROM:<info@nexium.app> SIZE=10\r\n");
+    let owned: Command = cmds[0].1.clone().unwrap().into();
+
+    assert_eq!(
+        Command::FROM(
+            Mailbox {
+                local: "info".to_string(),
+                domain: "nexium.app".into()
+            },
+            vec![("SIZE".to_string(), Some("10".to_string()))]
+        ),
+        owned
+    );
+}
+
+#[test]
+fn parse_data_lines_unfinished() {
+    let (ended, data, rem) = parse_data_lines(b"Hello\r\nWorld\r\n");
+
+    assert!(!ended);
+    assert_eq!(b"Hello\r\nWorld", &data[..]);
+    assert_eq!(b"", &rem[..]);
+}
+
+#[test]
+fn parse_data_lines_partial_line() {
+    let (ended, data, rem) = parse_data_lines(b"Hello\r\nWor");
+
+    assert!(!ended);
+    assert_eq!(b"Hello", &data[..]);
+    assert_eq!(b"Wor", &rem[..]);
+
+    let (ended, data, rem) = parse_data_lines(b"Hello\r\n.\r");
+
+    assert!(!ended);
+    assert_eq!(b"Hello", &data[..]);
+    assert_eq!(b".\r", &rem[..]);
+}
+
+#[test]
+fn parse_data_lines_bare_lf() {
+    let (ended, data, rem) = parse_data_lines(b"Hello\nWorld\n.\nQUIT\n");
+
+    assert!(ended);
+    assert_eq!(b"Hello\r\nWorld", &data[..]);
+    assert_eq!(b"QUIT\n", &rem[..]);
+}
+
+#[test]
+fn parse_data_lines_dot_stuffing() {
+    let (ended, data, rem) = parse_data_lines(b"..Hello\r\n.\r\n");
+
+    assert!(ended);
+    assert_eq!(b".Hello", &data[..]);
+    assert_eq!(b"", &rem[..]);
+}
+
+#[test]
+fn parse_data_lines_eight_bit() {
+    let (ended, data, rem) = parse_data_lines(b"Gr\xfc\xdfe\r\n.\xff\r\n.\r\n");
+
+    assert!(ended);
+    assert_eq!(b"Gr\xfc\xdfe\r\n\xff", &data[..]);
+    assert_eq!(b"", &rem[..]);
+}
+
+#[test]
+fn parse_data_lines_command_after_end() {
+    let (ended, data, rem) = parse_data_lines(b"Hello\r\n.\r\nRSET\r\nMAIL FR");
+
+    assert!(ended);
+    assert_eq!(b"Hello", &data[..]);
+    assert_eq!(b"RSET\r\nMAIL FR", &rem[..]);
+
+    let (cmds, rem) = parse(std::str::from_utf8(&rem).unwrap());
+    assert_eq!(Some(Command::RSET), cmds[0].1);
+    assert_eq!("MAIL FR", rem);
+}
+
+#[test]
+fn domain_from_str_mixed_utf8() {
+    assert_eq!(Ok(Domain::from("münchen.de")), "münchen.de".parse());
+    assert_eq!(Ok(Domain::from("ü.example")), "ü.example".parse());
+    assert_eq!(Err(AddressError), "münchen.de!".parse::<Domain>());
+}
+
+#[test]
+fn mailbox_from_str_mixed_utf8() {
+    assert_eq!(
+        Ok(Mailbox {
+            local: "a".to_string(),
+            domain: "münchen.de".into()
+        }),
+        "a@münchen.de".parse()
+    );
+    assert_eq!(
+        Ok(Mailbox {
+            local: "ü".to_string(),
+            domain: "example.com".into()
+        }),
+        "ü@example.com".parse()
+    );
+}
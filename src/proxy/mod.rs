@@ -0,0 +1,129 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+#[cfg(test)]
+mod tests;
+
+/// Signature starting every version 2 header.
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Maximum length of a version 1 header, including the line ending.
+const V1_MAX_LENGTH: usize = 107;
+
+/// Result of parsing the PROXY protocol header sent by a load balancer before the SMTP session.
+#[derive(Debug, PartialEq)]
+pub(crate) enum ProxyHeader {
+    /// More input is needed to parse the header.
+    Incomplete,
+    /// The input does not start with a valid header.
+    Invalid,
+    /// The header was parsed, it is `length` bytes long.
+    /// The source is `None` when the balancer does not know the client, e.g. for its own health checks.
+    Complete {
+        source: Option<SocketAddr>,
+        length: usize,
+    },
+}
+
+/// Parse a version 1 or version 2 PROXY protocol header at the start of the input.
+pub(crate) fn parse_proxy_header(input: &[u8]) -> ProxyHeader {
+    if input.starts_with(b"PROXY ") {
+        return parse_v1(input);
+    }
+
+    if input.starts_with(V2_SIGNATURE) {
+        return parse_v2(input);
+    }
+
+    if b"PROXY ".starts_with(input) || V2_SIGNATURE.starts_with(input) {
+        return ProxyHeader::Incomplete;
+    }
+
+    ProxyHeader::Invalid
+}
+
+/// Parse the text header, e.g. `PROXY TCP4 192.0.2.1 192.0.2.2 56324 25\r\n`.
+fn parse_v1(input: &[u8]) -> ProxyHeader {
+    let end = match input.windows(2).position(|w| w == b"\r\n") {
+        Some(end) => end,
+        None if input.len() < V1_MAX_LENGTH => return ProxyHeader::Incomplete,
+        None => return ProxyHeader::Invalid,
+    };
+
+    if end + 2 > V1_MAX_LENGTH {
+        return ProxyHeader::Invalid;
+    }
+
+    let line = match std::str::from_utf8(&input[..end]) {
+        Ok(line) => line,
+        Err(_) => return ProxyHeader::Invalid,
+    };
+    let parts: Vec<&str> = line.split(' ').collect();
+    let length = end + 2;
+
+    match parts.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => ProxyHeader::Complete {
+            source: None,
+            length,
+        },
+        ["PROXY", protocol, source, _, port, _] => {
+            let ip = match (*protocol, source.parse::<IpAddr>()) {
+                ("TCP4", Ok(ip @ IpAddr::V4(_))) | ("TCP6", Ok(ip @ IpAddr::V6(_))) => ip,
+                _ => return ProxyHeader::Invalid,
+            };
+
+            match port.parse::<u16>() {
+                Ok(port) => ProxyHeader::Complete {
+                    source: Some(SocketAddr::new(ip, port)),
+                    length,
+                },
+                Err(_) => ProxyHeader::Invalid,
+            }
+        }
+        _ => ProxyHeader::Invalid,
+    }
+}
+
+/// Parse the binary header, starting with `V2_SIGNATURE`.
+fn parse_v2(input: &[u8]) -> ProxyHeader {
+    if input.len() < 16 {
+        return ProxyHeader::Incomplete;
+    }
+
+    let (version_command, family) = (input[12], input[13]);
+    let address_length = u16::from_be_bytes([input[14], input[15]]) as usize;
+    let length = 16 + address_length;
+
+    if version_command >> 4 != 2 {
+        return ProxyHeader::Invalid;
+    }
+
+    if input.len() < length {
+        return ProxyHeader::Incomplete;
+    }
+
+    let addresses = &input[16..length];
+
+    let source = match (version_command & 0x0F, family >> 4) {
+        // LOCAL command, the connection was made by the balancer itself.
+        (0, _) => None,
+        (1, 1) if addresses.len() >= 12 => {
+            let mut ip = [0; 4];
+            ip.copy_from_slice(&addresses[..4]);
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+
+            Some(SocketAddr::new(Ipv4Addr::from(ip).into(), port))
+        }
+        (1, 2) if addresses.len() >= 36 => {
+            let mut ip = [0; 16];
+            ip.copy_from_slice(&addresses[..16]);
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+
+            Some(SocketAddr::new(Ipv6Addr::from(ip).into(), port))
+        }
+        // Unspecified or unix socket addresses.
+        (1, 0) | (1, 3) => None,
+        _ => return ProxyHeader::Invalid,
+    };
+
+    ProxyHeader::Complete { source, length }
+}
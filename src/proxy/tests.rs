@@ -0,0 +1,141 @@
+use super::*;
+
+fn v2_header(command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+    let mut header = V2_SIGNATURE.to_vec();
+    header.push(0x20 | command);
+    header.push(family);
+    header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+    header.extend_from_slice(addresses);
+    header
+}
+
+#[test]
+fn v1_tcp4() {
+    let input = b"PROXY TCP4 192.0.2.1 192.0.2.2 56324 25\r\nEHLO nexium.app\r\n";
+
+    assert_eq!(
+        parse_proxy_header(input),
+        ProxyHeader::Complete {
+            source: Some("192.0.2.1:56324".parse().unwrap()),
+            length: 41,
+        }
+    );
+}
+
+#[test]
+fn v1_tcp6() {
+    let input = b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 25\r\n";
+
+    assert_eq!(
+        parse_proxy_header(input),
+        ProxyHeader::Complete {
+            source: Some("[2001:db8::1]:56324".parse().unwrap()),
+            length: input.len(),
+        }
+    );
+}
+
+#[test]
+fn v1_unknown() {
+    let input = b"PROXY UNKNOWN\r\n";
+
+    assert_eq!(
+        parse_proxy_header(input),
+        ProxyHeader::Complete {
+            source: None,
+            length: input.len(),
+        }
+    );
+}
+
+#[test]
+fn v1_incomplete() {
+    assert_eq!(parse_proxy_header(b"PRO"), ProxyHeader::Incomplete);
+    assert_eq!(
+        parse_proxy_header(b"PROXY TCP4 192.0.2.1"),
+        ProxyHeader::Incomplete
+    );
+}
+
+#[test]
+fn v1_invalid() {
+    assert_eq!(
+        parse_proxy_header(b"PROXY TCP4 2001:db8::1 192.0.2.2 56324 25\r\n"),
+        ProxyHeader::Invalid
+    );
+    assert_eq!(
+        parse_proxy_header(b"PROXY TCP4 192.0.2.1 192.0.2.2 port 25\r\n"),
+        ProxyHeader::Invalid
+    );
+    assert_eq!(
+        parse_proxy_header(&[b'P', b'R', b'O', b'X', b'Y', b' '].repeat(20)),
+        ProxyHeader::Invalid
+    );
+}
+
+#[test]
+fn no_header() {
+    assert_eq!(
+        parse_proxy_header(b"EHLO nexium.app\r\n"),
+        ProxyHeader::Invalid
+    );
+}
+
+#[test]
+fn v2_tcp4() {
+    let mut input = v2_header(1, 0x11, &[192, 0, 2, 1, 192, 0, 2, 2, 0xDC, 0x04, 0, 25]);
+    input.extend_from_slice(b"EHLO nexium.app\r\n");
+
+    assert_eq!(
+        parse_proxy_header(&input),
+        ProxyHeader::Complete {
+            source: Some("192.0.2.1:56324".parse().unwrap()),
+            length: 28,
+        }
+    );
+}
+
+#[test]
+fn v2_tcp6() {
+    let mut addresses = Ipv6Addr::LOCALHOST.octets().to_vec();
+    addresses.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+    addresses.extend_from_slice(&[0xDC, 0x04, 0, 25]);
+    let input = v2_header(1, 0x21, &addresses);
+
+    assert_eq!(
+        parse_proxy_header(&input),
+        ProxyHeader::Complete {
+            source: Some("[::1]:56324".parse().unwrap()),
+            length: 52,
+        }
+    );
+}
+
+#[test]
+fn v2_local() {
+    let input = v2_header(0, 0x00, &[]);
+
+    assert_eq!(
+        parse_proxy_header(&input),
+        ProxyHeader::Complete {
+            source: None,
+            length: 16,
+        }
+    );
+}
+
+#[test]
+fn v2_incomplete() {
+    let input = v2_header(1, 0x11, &[192, 0, 2, 1, 192, 0, 2, 2, 0xDC, 0x04, 0, 25]);
+
+    assert_eq!(parse_proxy_header(&input[..8]), ProxyHeader::Incomplete);
+    assert_eq!(parse_proxy_header(&input[..20]), ProxyHeader::Incomplete);
+}
+
+#[test]
+fn v2_invalid_version() {
+    let mut input = v2_header(1, 0x11, &[192, 0, 2, 1, 192, 0, 2, 2, 0xDC, 0x04, 0, 25]);
+    input[12] = 0x11;
+
+    assert_eq!(parse_proxy_header(&input), ProxyHeader::Invalid);
+}
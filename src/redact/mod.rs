@@ -0,0 +1,99 @@
+use crate::{
+    command::{Command, Mailbox, Parameter},
+    config::RedactionLevel,
+    Response,
+};
+
+#[cfg(test)]
+mod tests;
+
+/// Placeholder for a masked value.
+const MASK: &str = "***";
+
+/// Format a command for the log, masking what the level hides.
+/// Verbs like AUTH are logged without their arguments at every level, so credentials never reach the log.
+pub(crate) fn command(level: RedactionLevel, command: &Command) -> String {
+    match command {
+        Command::HELO(domain) => format!("HELO {}", all(level, &domain.0)),
+        Command::EHLO(domain) => format!("EHLO {}", all(level, &domain.0)),
+        Command::FROM(sender, params) => format!(
+            "MAIL FROM:<{}>{}",
+            mailbox(level, sender),
+            parameters(level, params)
+        ),
+        Command::RCPT(recipient, params) => format!(
+            "RCPT TO:<{}>{}",
+            mailbox(level, recipient),
+            parameters(level, params)
+        ),
+        Command::EXPN(list) => format!("EXPN {}", addresses(level, list)),
+        Command::DATA => "DATA".into(),
+        Command::BDAT(size, true) => format!("BDAT {} LAST", size),
+        Command::BDAT(size, false) => format!("BDAT {}", size),
+        Command::RSET => "RSET".into(),
+        Command::NOOP => "NOOP".into(),
+        Command::QUIT => "QUIT".into(),
+        Command::Unimplemented(verb) => verb.clone(),
+    }
+}
+
+/// Format a mailbox for the log, masking the local part, and at `All` the domain as well.
+pub(crate) fn mailbox(level: RedactionLevel, mailbox: &Mailbox) -> String {
+    // The domainless postmaster identifies nobody.
+    if mailbox.domain.0.is_empty() {
+        return mailbox.local.clone();
+    }
+
+    format!(
+        "{}@{}",
+        addresses(level, &mailbox.local),
+        all(level, &mailbox.domain.0)
+    )
+}
+
+/// Format a response for the log, masking the echoed recipient and the list members of an EXPN reply.
+pub(crate) fn response(level: RedactionLevel, response: &Response) -> String {
+    match response {
+        Response::RecipientOk(recipient) => format!("RecipientOk({})", mailbox(level, recipient)),
+        Response::Expanded(members) => {
+            let members: Vec<String> = members.iter().map(|m| mailbox(level, m)).collect();
+            format!("Expanded([{}])", members.join(", "))
+        }
+        _ => format!("{:?}", response),
+    }
+}
+
+/// Format any other text from the client, which is masked at `All`.
+pub(crate) fn text(level: RedactionLevel, text: &str) -> String {
+    all(level, text).to_string()
+}
+
+/// Format ESMTP parameters, masking the AUTH identity, and at `All` every value.
+fn parameters(level: RedactionLevel, params: &[Parameter]) -> String {
+    params
+        .iter()
+        .map(|(keyword, value)| match value {
+            Some(value) if keyword.eq_ignore_ascii_case("AUTH") => {
+                format!(" {}={}", keyword, addresses(level, value))
+            }
+            Some(value) => format!(" {}={}", keyword, all(level, value)),
+            None => format!(" {}", keyword),
+        })
+        .collect()
+}
+
+/// Value masked when addresses are.
+fn addresses(level: RedactionLevel, value: &str) -> &str {
+    match level {
+        RedactionLevel::None => value,
+        RedactionLevel::Addresses | RedactionLevel::All => MASK,
+    }
+}
+
+/// Value masked only when everything is.
+fn all(level: RedactionLevel, value: &str) -> &str {
+    match level {
+        RedactionLevel::All => MASK,
+        RedactionLevel::None | RedactionLevel::Addresses => value,
+    }
+}
@@ -0,0 +1,128 @@
+use super::*;
+
+fn mail() -> Command {
+    Command::FROM(
+        Mailbox {
+            local: "info".into(),
+            domain: "example.com".into(),
+        },
+        vec![
+            ("SIZE".into(), Some("100".into())),
+            ("AUTH".into(), Some("user@example.com".into())),
+            ("SMTPUTF8".into(), None),
+        ],
+    )
+}
+
+#[test]
+fn commands_not_redacted() {
+    assert_eq!(
+        "MAIL FROM:<info@example.com> SIZE=100 AUTH=user@example.com SMTPUTF8",
+        command(RedactionLevel::None, &mail())
+    );
+    assert_eq!(
+        "EHLO mx.example.com",
+        command(
+            RedactionLevel::None,
+            &Command::EHLO("mx.example.com".into())
+        )
+    );
+}
+
+#[test]
+fn addresses_redacted() {
+    assert_eq!(
+        "MAIL FROM:<***@example.com> SIZE=100 AUTH=*** SMTPUTF8",
+        command(RedactionLevel::Addresses, &mail())
+    );
+    assert_eq!(
+        "EXPN ***",
+        command(RedactionLevel::Addresses, &Command::EXPN("staff".into()))
+    );
+    assert_eq!(
+        "EHLO mx.example.com",
+        command(
+            RedactionLevel::Addresses,
+            &Command::EHLO("mx.example.com".into())
+        )
+    );
+    assert_eq!(
+        "RCPT TO:<postmaster>",
+        command(
+            RedactionLevel::All,
+            &Command::RCPT(
+                Mailbox {
+                    local: "postmaster".into(),
+                    domain: "".into()
+                },
+                vec![]
+            )
+        )
+    );
+}
+
+#[test]
+fn everything_redacted() {
+    assert_eq!(
+        "MAIL FROM:<***@***> SIZE=*** AUTH=*** SMTPUTF8",
+        command(RedactionLevel::All, &mail())
+    );
+    assert_eq!(
+        "EHLO ***",
+        command(RedactionLevel::All, &Command::EHLO("mx.example.com".into()))
+    );
+    assert_eq!("***", text(RedactionLevel::All, "mx.example.com"));
+}
+
+#[test]
+fn credentials_never_logged() {
+    for level in [
+        RedactionLevel::None,
+        RedactionLevel::Addresses,
+        RedactionLevel::All,
+    ] {
+        assert_eq!(
+            "AUTH",
+            command(level, &Command::Unimplemented("AUTH".into()))
+        );
+    }
+}
+
+#[test]
+fn expanded_members_redacted() {
+    let expanded = Response::Expanded(vec![Mailbox {
+        local: "admin".into(),
+        domain: "nexium.app".into(),
+    }]);
+
+    assert_eq!(
+        "Expanded([admin@nexium.app])",
+        response(RedactionLevel::None, &expanded)
+    );
+    assert_eq!(
+        "Expanded([***@nexium.app])",
+        response(RedactionLevel::Addresses, &expanded)
+    );
+    assert_eq!("Ok", response(RedactionLevel::All, &Response::Ok));
+}
+
+#[test]
+fn echoed_recipient_redacted() {
+    let accepted = Response::RecipientOk(Mailbox {
+        local: "admin".into(),
+        domain: "nexium.app".into(),
+    });
+
+    assert_eq!(
+        "RecipientOk(admin@nexium.app)",
+        response(RedactionLevel::None, &accepted)
+    );
+    assert_eq!(
+        "RecipientOk(***@nexium.app)",
+        response(RedactionLevel::Addresses, &accepted)
+    );
+    assert_eq!(
+        "RecipientOk(***@***)",
+        response(RedactionLevel::All, &accepted)
+    );
+}
@@ -0,0 +1,113 @@
+/// Maximum length of a reply line in octets, including the code and the CRLF, per RFC 5321 section 4.5.3.1.5.
+pub(crate) const MAX_LINE_LENGTH: usize = 512;
+
+/// Builder for the text of a reply, producing one or more lines with the same code.
+/// Every line but the last continues the reply with `-` after the code, e.g. `250-SIZE 0`.
+/// Text too long for a single line is folded over several lines.
+#[derive(Debug, Clone)]
+pub(crate) struct ReplyBuilder {
+    code: u16,
+    enhanced: Option<&'static str>,
+    lines: Vec<String>,
+}
+
+impl ReplyBuilder {
+    /// Start a reply with the given code.
+    pub fn new(code: u16) -> Self {
+        ReplyBuilder {
+            code,
+            enhanced: None,
+            lines: Vec::new(),
+        }
+    }
+
+    /// The code of the reply.
+    pub fn code(&self) -> u16 {
+        self.code
+    }
+
+    /// Add an enhanced status code from RFC 3463 to every line, e.g. `5.7.1`.
+    pub fn enhanced(mut self, status: &'static str) -> Self {
+        self.enhanced = Some(status);
+        self
+    }
+
+    /// Add a line of text.
+    pub fn line(mut self, text: impl Into<String>) -> Self {
+        self.lines.push(text.into());
+        self
+    }
+
+    /// Replace the text with a single line.
+    pub fn text(mut self, text: impl Into<String>) -> Self {
+        self.lines = vec![text.into()];
+        self
+    }
+
+    /// Add several lines of text.
+    pub fn lines(mut self, lines: impl IntoIterator<Item = String>) -> Self {
+        self.lines.extend(lines);
+        self
+    }
+
+    /// Build the reply, terminating every line with CRLF.
+    /// A reply without any text consists of only the code.
+    /// Control characters in the text are replaced with spaces, so a text containing CR or LF,
+    /// e.g. from the client or handler, can never end a line early and inject another reply.
+    pub fn build(&self) -> String {
+        let prefix = match self.enhanced {
+            Some(status) => format!("{} ", status),
+            None => String::new(),
+        };
+        // Code, separator, the enhanced status and the CRLF.
+        let limit = MAX_LINE_LENGTH - 4 - prefix.len() - 2;
+
+        let lines: Vec<String> = self
+            .lines
+            .iter()
+            .map(|line| line.replace(char::is_control, " "))
+            .collect();
+        let folded: Vec<&str> = lines.iter().flat_map(|line| fold(line, limit)).collect();
+
+        if folded.is_empty() {
+            return format!("{}\r\n", self.code);
+        }
+
+        let mut reply = String::new();
+
+        for (i, text) in folded.iter().enumerate() {
+            let separator = match i == folded.len() - 1 {
+                true => ' ',
+                false => '-',
+            };
+
+            reply.push_str(&format!("{}{}{}{}\r\n", self.code, separator, prefix, text));
+        }
+
+        reply
+    }
+}
+
+/// Split text into pieces of at most `limit` bytes, preferably at spaces.
+fn fold(text: &str, limit: usize) -> Vec<&str> {
+    let mut pieces = Vec::new();
+    let mut rest = text;
+
+    while rest.len() > limit {
+        let mut end = limit;
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+
+        let (piece, tail) = match rest[..end].rfind(' ') {
+            Some(space) if space > 0 => (&rest[..space], &rest[space + 1..]),
+            _ => rest.split_at(end),
+        };
+
+        pieces.push(piece);
+        rest = tail;
+    }
+
+    pieces.push(rest);
+    pieces
+}
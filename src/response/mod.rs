@@ -0,0 +1,267 @@
+use std::{collections::HashMap, mem::Discriminant};
+
+use crate::command::Mailbox;
+
+mod builder;
+pub(crate) use builder::ReplyBuilder;
+
+#[cfg(test)]
+mod tests;
+
+/// Replacement texts for fixed replies, keyed by `std::mem::discriminant` of the response.
+pub(crate) type ReplyTexts = HashMap<Discriminant<Response>, String>;
+
+/// All responses possible from the server.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Response {
+    Goodbye,
+    Ok,
+    StartData,
+    TryLater,
+    RecipientUnavailable,
+    TooManyRecipientsClosing,
+    /// The client sent as many messages as a connection may, see `SmtpServiceBuilder::max_transactions_per_connection`.
+    TooManyTransactions,
+    Timeout,
+    SessionExpired,
+    TooManyRecipients,
+    SyntaxError,
+    /// An address was longer than `SmtpServiceBuilder::max_local_part_len` or `max_domain_len`.
+    AddressTooLong,
+    /// A command line was longer than `SmtpServiceBuilder::max_command_length`.
+    LineTooLong,
+    InvalidParameters,
+    OutOfSequence,
+    NestedMail,
+    /// DATA was sent for a `BODY=BINARYMIME` message, which requires BDAT.
+    BdatRequired,
+    /// The client sent input after DATA without waiting for its reply, see `SmtpServiceBuilder::strict_pipelining`.
+    PipelinedData,
+    NotImplemented,
+    RecipientNotLocal,
+    InvalidHelo,
+    RelayDenied,
+    TransactionRejected,
+    SizeExceeded,
+    MessageTooBig,
+    NonAsciiAddress,
+    InvalidRecipient,
+    TransactionFailed,
+    /// Message delivered to its final destination, see `SaveOutcome::Delivered`.
+    Delivered,
+    /// Message rejected by a content scan for containing a virus.
+    VirusDetected,
+    /// Message rejected for violating the policy of the server, e.g. a spam score too high.
+    PolicyRejected,
+    /// Message content could not be scanned, the client should try again later.
+    ScanFailed,
+    TooManyErrors,
+    EarlyTalker,
+    /// The handler refused the client when it connected, see `Handler::connection_allowed`.
+    ConnectionRefused,
+    /// The client connected from a network which is not allowed, see `SmtpServiceBuilder::allow_cidrs`.
+    AddressRefused,
+    /// The connection is closed to make room for other clients, as too many are idle.
+    Evicted,
+    /// The session is closed on request of the application, see `SmtpService::disconnect`.
+    Disconnected,
+    /// The client is refused, as too many sessions are running, see `SmtpServiceBuilder::max_concurrent_sessions`.
+    TooManyConnections,
+    Greeting(String),
+    /// Greeting with the hostname of the server and a banner after it, see `SmtpServiceBuilder::ehlo_hostname`.
+    BannerGreeting(String, String),
+    /// Reply in place of the greeting with the server name, refusing the client for now, e.g. while the service is paused.
+    ServiceUnavailable(String),
+    Helo(String),
+    /// Message accepted, with the queue id given by the handler.
+    Queued(String),
+    /// A BDAT chunk other than the last was received, with its size in octets.
+    ChunkReceived(usize),
+    /// Recipient accepted, echoing its address, see `SmtpServiceBuilder::echo_recipients`.
+    RecipientOk(Mailbox),
+    /// EHLO reply with the server name and the supported extensions.
+    Ehlo(String, Vec<String>),
+    /// EXPN reply with the members of a mailing list, there should be at least one.
+    Expanded(Vec<Mailbox>),
+    /// Reply with an arbitrary code and message, construct it with `Response::custom`.
+    Custom {
+        code: u16,
+        message: String,
+    },
+}
+
+impl Response {
+    /// Create a reply with a custom code and message.
+    /// Returns `None` when the code is not a valid SMTP reply code, or the message contains line breaks.
+    pub fn custom(code: u16, message: impl Into<String>) -> Option<Response> {
+        let message = message.into();
+        let (first, second) = (code / 100, code / 10 % 10);
+
+        if !(2..=5).contains(&first) || second > 5 || message.contains(['\r', '\n']) {
+            return None;
+        }
+
+        Some(Response::Custom { code, message })
+    }
+
+    /// Format the reply sent to the client.
+    /// Every line ends with the only CRLF in it, whatever text the response contains.
+    pub fn to_response(&self) -> String {
+        self.reply().build()
+    }
+
+    /// Format the reply like `to_response`, replacing the text of fixed replies found in `texts`.
+    /// The code and enhanced status code are kept.
+    pub(crate) fn to_response_with(&self, texts: &ReplyTexts) -> String {
+        match texts.get(&std::mem::discriminant(self)) {
+            Some(text) if self.has_fixed_text() => self.reply().text(text.as_str()).build(),
+            _ => self.to_response(),
+        }
+    }
+
+    /// Whether the reply text is always the same, instead of depending on the server or transaction.
+    pub(crate) fn has_fixed_text(&self) -> bool {
+        !matches!(
+            self,
+            Response::Greeting(_)
+                | Response::BannerGreeting(_, _)
+                | Response::ServiceUnavailable(_)
+                | Response::Helo(_)
+                | Response::Queued(_)
+                | Response::ChunkReceived(_)
+                | Response::RecipientOk(_)
+                | Response::Ehlo(_, _)
+                | Response::Expanded(_)
+                | Response::Custom { .. }
+        )
+    }
+
+    /// The reply code, e.g. 250.
+    pub fn code(&self) -> u16 {
+        self.reply().code()
+    }
+
+    /// Whether the command succeeded, or the server waits for more input, with a 2xx or 3xx code.
+    pub fn is_positive(&self) -> bool {
+        self.code() < 400
+    }
+
+    /// Whether the command failed for now and may be retried, with a 4xx code.
+    pub fn is_transient(&self) -> bool {
+        (400..500).contains(&self.code())
+    }
+
+    /// Whether the command failed and should not be retried as is, with a 5xx code.
+    pub fn is_permanent(&self) -> bool {
+        self.code() >= 500
+    }
+
+    /// Reply for this response, formatted with `to_response`.
+    fn reply(&self) -> ReplyBuilder {
+        match self {
+            Response::Goodbye => ReplyBuilder::new(221).line("Goodbye!"),
+            Response::Ok => ReplyBuilder::new(250).line("Ok"),
+            Response::Delivered => ReplyBuilder::new(250).enhanced("2.6.0").line("Delivered"),
+            Response::StartData => ReplyBuilder::new(354).line("Go ahead"),
+            Response::TooManyRecipientsClosing => {
+                ReplyBuilder::new(421).line("Too many recipients, closing connection")
+            }
+            Response::TooManyTransactions => {
+                ReplyBuilder::new(421).line("Too many messages, closing connection")
+            }
+            Response::SessionExpired => ReplyBuilder::new(421).line("Session time limit exceeded"),
+            Response::Evicted => {
+                ReplyBuilder::new(421).line("Too many idle connections, closing connection")
+            }
+            Response::Disconnected => ReplyBuilder::new(421).line("Closing connection"),
+            Response::TooManyConnections => {
+                ReplyBuilder::new(421).line("Too many connections, try again later")
+            }
+            Response::Timeout => ReplyBuilder::new(421).line("Timeout, closing connection"),
+            Response::RecipientUnavailable => ReplyBuilder::new(450).line("Mailbox unavailable"),
+            Response::TryLater => ReplyBuilder::new(451).line("Try again later"),
+            Response::TooManyRecipients => ReplyBuilder::new(452).line("Too many recipients"),
+            Response::SyntaxError => ReplyBuilder::new(500).line("Syntax error"),
+            Response::LineTooLong => ReplyBuilder::new(500).line("Line too long"),
+            Response::AddressTooLong => ReplyBuilder::new(501).line("Address too long"),
+            Response::InvalidParameters => {
+                ReplyBuilder::new(501).line("Syntax error in parameters")
+            }
+            Response::OutOfSequence => ReplyBuilder::new(503).line("Command out of sequence"),
+            Response::NestedMail => ReplyBuilder::new(503)
+                .enhanced("5.5.1")
+                .line("Sender already specified"),
+            Response::NotImplemented => ReplyBuilder::new(502).line("Command not implemented"),
+            Response::RecipientNotLocal => ReplyBuilder::new(550).line("User not local"),
+            Response::InvalidHelo => ReplyBuilder::new(550).line("Invalid HELO"),
+            Response::RelayDenied => ReplyBuilder::new(550)
+                .enhanced("5.7.1")
+                .line("Relaying denied"),
+            Response::TransactionRejected => ReplyBuilder::new(550).line("Transaction rejected"),
+            Response::SizeExceeded => {
+                ReplyBuilder::new(552).line("Message size exceeds fixed maximum message size")
+            }
+            Response::MessageTooBig => ReplyBuilder::new(552)
+                .enhanced("5.3.4")
+                .line("Message too big"),
+            Response::NonAsciiAddress => {
+                ReplyBuilder::new(553).line("Non-ASCII addresses require SMTPUTF8")
+            }
+            Response::InvalidRecipient => ReplyBuilder::new(554).line("No valid recipient"),
+            Response::TransactionFailed => ReplyBuilder::new(554).line("Transaction failed"),
+            Response::VirusDetected => ReplyBuilder::new(552)
+                .enhanced("5.7.0")
+                .line("Message rejected, virus detected"),
+            Response::PolicyRejected => ReplyBuilder::new(550)
+                .enhanced("5.7.1")
+                .line("Message rejected by policy"),
+            Response::ScanFailed => ReplyBuilder::new(451)
+                .enhanced("4.7.0")
+                .line("Message could not be scanned, try again later"),
+            Response::TooManyErrors => {
+                ReplyBuilder::new(554).line("Too many errors, closing connection")
+            }
+            Response::BdatRequired => ReplyBuilder::new(503)
+                .enhanced("5.5.1")
+                .line("BINARYMIME requires BDAT"),
+            Response::PipelinedData => ReplyBuilder::new(503)
+                .enhanced("5.5.0")
+                .line("Data sent before the DATA reply, closing connection"),
+            Response::ConnectionRefused => ReplyBuilder::new(554)
+                .enhanced("5.7.1")
+                .line("Connection refused"),
+            Response::AddressRefused => ReplyBuilder::new(554)
+                .enhanced("5.7.1")
+                .line("Connection refused from this address"),
+            Response::EarlyTalker => {
+                ReplyBuilder::new(554).line("Sent data before the greeting, closing connection")
+            }
+
+            Response::Greeting(name) => ReplyBuilder::new(220).line(format!("{} ESMTP", name)),
+            Response::BannerGreeting(hostname, banner) => {
+                ReplyBuilder::new(220).line(format!("{} ESMTP {}", hostname, banner))
+            }
+            Response::ServiceUnavailable(name) => ReplyBuilder::new(421).line(format!(
+                "{} Service not available, closing transmission channel",
+                name
+            )),
+            Response::Helo(name) => ReplyBuilder::new(250).line(format!("{} ESMTP", name)),
+            Response::Queued(id) => ReplyBuilder::new(250)
+                .enhanced("2.0.0")
+                .line(format!("Ok: queued as {}", id)),
+            Response::ChunkReceived(size) => ReplyBuilder::new(250)
+                .enhanced("2.0.0")
+                .line(format!("{} octets received", size)),
+            Response::RecipientOk(recipient) => ReplyBuilder::new(250)
+                .enhanced("2.1.5")
+                .line(format!("<{}>... Recipient ok", recipient)),
+            Response::Ehlo(name, capabilities) => ReplyBuilder::new(250)
+                .line(format!("{} ESMTP", name))
+                .lines(capabilities.iter().cloned()),
+            Response::Expanded(members) => {
+                ReplyBuilder::new(250).lines(members.iter().map(|member| format!("<{}>", member)))
+            }
+            Response::Custom { code, message } => ReplyBuilder::new(*code).line(message.clone()),
+        }
+    }
+}
@@ -0,0 +1,203 @@
+use super::*;
+
+#[test]
+fn custom_valid() {
+    let response = Response::custom(452, "Mailbox full").unwrap();
+
+    assert_eq!("452 Mailbox full\r\n", response.to_response());
+}
+
+#[test]
+fn custom_invalid_code() {
+    assert_eq!(None, Response::custom(99, "Too short"));
+    assert_eq!(None, Response::custom(1000, "Too long"));
+    assert_eq!(None, Response::custom(150, "Not an SMTP class"));
+    assert_eq!(None, Response::custom(460, "Not an SMTP category"));
+}
+
+#[test]
+fn custom_invalid_message() {
+    assert_eq!(None, Response::custom(250, "Ok\r\n250 Injected"));
+}
+
+#[test]
+fn queued() {
+    let response = Response::Queued("ABC123".into());
+
+    assert_eq!("250 2.0.0 Ok: queued as ABC123\r\n", response.to_response());
+}
+
+#[test]
+fn recipient_ok() {
+    let response = Response::RecipientOk(Mailbox {
+        local: "admin".into(),
+        domain: "nexium.app".into(),
+    });
+
+    assert_eq!(
+        "250 2.1.5 <admin@nexium.app>... Recipient ok\r\n",
+        response.to_response()
+    );
+}
+
+#[test]
+fn service_unavailable() {
+    assert_eq!(
+        "421 mx.nexium.app Service not available, closing transmission channel\r\n",
+        Response::ServiceUnavailable("mx.nexium.app".into()).to_response()
+    );
+}
+
+#[test]
+fn delivered() {
+    assert_eq!("250 2.6.0 Delivered\r\n", Response::Delivered.to_response());
+}
+
+#[test]
+fn recipient_rejections() {
+    assert_eq!(
+        "450 Mailbox unavailable\r\n",
+        Response::RecipientUnavailable.to_response()
+    );
+    assert_eq!(
+        "550 5.7.1 Relaying denied\r\n",
+        Response::RelayDenied.to_response()
+    );
+}
+
+#[test]
+fn content_rejections() {
+    assert_eq!(
+        "552 5.7.0 Message rejected, virus detected\r\n",
+        Response::VirusDetected.to_response()
+    );
+    assert_eq!(
+        "550 5.7.1 Message rejected by policy\r\n",
+        Response::PolicyRejected.to_response()
+    );
+    assert_eq!(
+        "451 4.7.0 Message could not be scanned, try again later\r\n",
+        Response::ScanFailed.to_response()
+    );
+}
+
+#[test]
+fn expanded() {
+    let members = vec![
+        crate::command::Mailbox {
+            local: "alice".into(),
+            domain: "nexium.app".into(),
+        },
+        crate::command::Mailbox {
+            local: "bob".into(),
+            domain: "nexium.app".into(),
+        },
+    ];
+
+    assert_eq!(
+        "250-<alice@nexium.app>\r\n250 <bob@nexium.app>\r\n",
+        Response::Expanded(members).to_response()
+    );
+}
+
+#[test]
+fn builder_capabilities() {
+    let reply = ReplyBuilder::new(250)
+        .line("mx.nexium.app ESMTP")
+        .lines(
+            ["SIZE 1024", "8BITMIME", "PIPELINING", "SMTPUTF8"]
+                .iter()
+                .map(|c| c.to_string()),
+        )
+        .build();
+
+    assert_eq!(
+        "250-mx.nexium.app ESMTP\r\n250-SIZE 1024\r\n250-8BITMIME\r\n250-PIPELINING\r\n250 SMTPUTF8\r\n",
+        reply
+    );
+}
+
+#[test]
+fn builder_enhanced_status() {
+    let reply = ReplyBuilder::new(250)
+        .enhanced("2.1.5")
+        .line("First")
+        .line("Second")
+        .build();
+
+    assert_eq!("250-2.1.5 First\r\n250 2.1.5 Second\r\n", reply);
+}
+
+#[test]
+fn builder_folds_long_text() {
+    let word = "a".repeat(100);
+    let text = [word.as_str(); 10].join(" ");
+    let reply = ReplyBuilder::new(550).enhanced("5.7.1").line(text).build();
+
+    let lines: Vec<&str> = reply.split_inclusive("\r\n").collect();
+    assert_eq!(3, lines.len());
+    assert!(lines.iter().all(|l| l.len() <= builder::MAX_LINE_LENGTH));
+    assert!(lines[0].starts_with("550-5.7.1 aaaa"));
+    assert!(lines[2].starts_with("550 5.7.1 aaaa"));
+    assert_eq!(
+        1000,
+        lines.iter().map(|l| l.matches('a').count()).sum::<usize>()
+    );
+}
+
+#[test]
+fn builder_folds_without_spaces() {
+    let reply = ReplyBuilder::new(250).line("é".repeat(300)).build();
+
+    let lines: Vec<&str> = reply.split_inclusive("\r\n").collect();
+    assert_eq!(2, lines.len());
+    assert!(lines.iter().all(|l| l.len() <= builder::MAX_LINE_LENGTH));
+}
+
+#[test]
+fn builder_without_text() {
+    assert_eq!("250\r\n", ReplyBuilder::new(250).build());
+}
+
+#[test]
+fn line_breaks_never_emitted() {
+    let injected = [
+        Response::Custom {
+            code: 250,
+            message: "Ok\r\n250 Injected".into(),
+        },
+        Response::Queued("ABC\r\n250 Injected".into()),
+        Response::Helo("mx\n250 Injected\0".into()),
+        Response::RecipientOk(Mailbox {
+            local: "\"admin\r\n250 Injected\"".into(),
+            domain: "nexium.app".into(),
+        }),
+    ];
+
+    for response in injected.iter() {
+        let reply = response.to_response();
+
+        assert_eq!(1, reply.matches("\r\n").count(), "{:?}", reply);
+        assert!(reply.ends_with("\r\n"));
+        assert!(!reply[..reply.len() - 2].contains(char::is_control));
+    }
+}
+
+#[test]
+fn reply_classes() {
+    assert_eq!(451, Response::TryLater.code());
+    assert!(Response::TryLater.is_transient());
+    assert!(!Response::TryLater.is_positive());
+    assert!(!Response::TryLater.is_permanent());
+
+    assert_eq!(550, Response::RecipientNotLocal.code());
+    assert!(Response::RecipientNotLocal.is_permanent());
+    assert!(!Response::RecipientNotLocal.is_transient());
+
+    assert!(Response::Ok.is_positive());
+    assert!(Response::StartData.is_positive());
+    assert!(!Response::StartData.is_permanent());
+
+    let custom = Response::custom(452, "Mailbox full").unwrap();
+    assert!(custom.is_transient());
+}
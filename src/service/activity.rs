@@ -0,0 +1,136 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+use tokio::{sync::Notify, time::Instant};
+
+/// Sessions of a service by their id, with the time they became idle, to evict the longest idle one
+/// or disconnect a specific one. A session is idle while it waits for the next command.
+#[derive(Debug, Default)]
+pub(crate) struct ActivityRegistry {
+    sessions: Mutex<HashMap<u64, Activity>>,
+}
+
+#[derive(Debug)]
+struct Activity {
+    idle_since: Option<Instant>,
+    evict: Arc<Notify>,
+    disconnect: Arc<Notify>,
+}
+
+/// Registration of a single session, which is removed from the registry when dropped.
+#[derive(Debug)]
+pub(crate) struct ActivityHandle {
+    registry: Arc<ActivityRegistry>,
+    key: u64,
+    evict: Arc<Notify>,
+    disconnect: Arc<Notify>,
+}
+
+impl ActivityRegistry {
+    /// Create an empty registry.
+    pub(crate) fn new() -> Self {
+        ActivityRegistry::default()
+    }
+
+    /// Add a new session with its id, which starts out active.
+    pub(crate) fn register(self: &Arc<Self>, id: u64) -> ActivityHandle {
+        let evict = Arc::new(Notify::new());
+        let disconnect = Arc::new(Notify::new());
+
+        self.sessions().insert(
+            id,
+            Activity {
+                idle_since: None,
+                evict: evict.clone(),
+                disconnect: disconnect.clone(),
+            },
+        );
+
+        ActivityHandle {
+            registry: self.clone(),
+            key: id,
+            evict,
+            disconnect,
+        }
+    }
+
+    /// Evict the longest idle session when `max_idle` sessions are idle, to make room for a new one.
+    /// Returns true when a session was evicted.
+    pub(crate) fn make_room(&self, max_idle: usize) -> bool {
+        let mut sessions = self.sessions();
+
+        let idle = sessions
+            .values()
+            .filter(|activity| activity.idle_since.is_some())
+            .count();
+        if idle < max_idle {
+            return false;
+        }
+
+        let oldest = sessions
+            .values_mut()
+            .filter(|activity| activity.idle_since.is_some())
+            .min_by_key(|activity| activity.idle_since);
+
+        match oldest {
+            Some(oldest) => {
+                // It is no longer counted, even before the session notices the eviction.
+                oldest.idle_since = None;
+                oldest.evict.notify_one();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Ask the session with the id to close, returning false when no such session is running.
+    pub(crate) fn disconnect(&self, id: u64) -> bool {
+        match self.sessions().get(&id) {
+            Some(activity) => {
+                activity.disconnect.notify_one();
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn sessions(&self) -> std::sync::MutexGuard<'_, HashMap<u64, Activity>> {
+        self.sessions.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn set_idle(&self, key: u64, idle: bool) {
+        if let Some(activity) = self.sessions().get_mut(&key) {
+            activity.idle_since = idle.then(Instant::now);
+        }
+    }
+}
+
+impl ActivityHandle {
+    /// Mark the session as waiting for the next command.
+    pub(crate) fn idle(&self) {
+        self.registry.set_idle(self.key, true);
+    }
+
+    /// Mark the session as busy, e.g. processing a command or receiving a message.
+    pub(crate) fn active(&self) {
+        self.registry.set_idle(self.key, false);
+    }
+
+    /// Wait until the session is evicted to make room for others.
+    pub(crate) async fn evicted(&self) {
+        self.evict.notified().await
+    }
+
+    /// Wait until the session is disconnected with `SmtpService::disconnect`.
+    /// A disconnect requested while the session was not waiting is returned right away.
+    pub(crate) async fn disconnected(&self) {
+        self.disconnect.notified().await
+    }
+}
+
+impl Drop for ActivityHandle {
+    fn drop(&mut self) {
+        self.registry.sessions().remove(&self.key);
+    }
+}
@@ -0,0 +1,432 @@
+use std::{
+    fmt,
+    net::SocketAddr,
+    sync::{atomic::AtomicBool, Arc},
+    time::Duration,
+};
+
+use super::{ActivityRegistry, SmtpService};
+use crate::{
+    command::Domain,
+    config::{Config, LineEnding, RedactionLevel},
+    metrics::Metrics,
+    trace::{Direction, Tracer},
+    Handler, IpNet, Resolver, Response,
+};
+
+/// Builder for a `SmtpService`, created with `SmtpService::builder()`.
+/// At least one address and the handler are required, all other settings have a default.
+pub struct SmtpServiceBuilder {
+    addresses: Vec<SocketAddr>,
+    handler: Option<Arc<dyn Handler>>,
+    metrics: Option<Arc<dyn Metrics>>,
+    trace: Option<Tracer>,
+    reply_texts: Vec<(Response, String)>,
+    config: Config,
+}
+
+/// Error returned by `SmtpServiceBuilder::build` when a required setting is missing or invalid.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum BuildError {
+    MissingAddress,
+    MissingHandler,
+    /// A reply text was set for a reply without fixed text, or contains a line break.
+    InvalidReplyText,
+    /// The EHLO hostname is not a valid domain, see `SmtpServiceBuilder::ehlo_hostname`.
+    InvalidHostname,
+    /// An extra EHLO capability is not a keyword followed by parameters, see `SmtpServiceBuilder::extra_capabilities`.
+    InvalidCapability,
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildError::MissingAddress => write!(f, "no listen address was set"),
+            BuildError::MissingHandler => write!(f, "no handler was set"),
+            BuildError::InvalidReplyText => write!(f, "a reply text can not be overridden"),
+            BuildError::InvalidHostname => write!(f, "the EHLO hostname is not a valid domain"),
+            BuildError::InvalidCapability => write!(f, "an EHLO capability is invalid"),
+        }
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+impl SmtpServiceBuilder {
+    /// Create a builder with the default settings.
+    pub fn new() -> Self {
+        SmtpServiceBuilder {
+            addresses: Vec::new(),
+            handler: None,
+            metrics: None,
+            trace: None,
+            reply_texts: Vec::new(),
+            config: Config::new("localhost".into()),
+        }
+    }
+
+    /// Add an address to listen on, can be called multiple times to listen on several addresses.
+    pub fn address(mut self, address: SocketAddr) -> Self {
+        self.addresses.push(address);
+        self
+    }
+
+    /// Add multiple addresses to listen on, for example both an IPv4 and IPv6 address.
+    pub fn addresses(mut self, addresses: impl IntoIterator<Item = SocketAddr>) -> Self {
+        self.addresses.extend(addresses);
+        self
+    }
+
+    /// Set the handler deciding what happens with recipients and messages.
+    pub fn handler(mut self, handler: Arc<dyn Handler>) -> Self {
+        self.handler = Some(handler);
+        self
+    }
+
+    /// Set the recorder notified of connections, messages and received bytes.
+    /// Without a recorder no metrics are kept.
+    pub fn metrics(mut self, metrics: Arc<dyn Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Set a callback receiving the raw bytes exchanged with every client, see `Tracer`.
+    /// Without a callback nothing is traced.
+    pub fn trace(mut self, trace: impl Fn(Direction, &[u8]) + Send + Sync + 'static) -> Self {
+        self.trace = Some(Arc::new(trace));
+        self
+    }
+
+    /// Set the name of the server used in the greeting, defaults to `localhost`.
+    pub fn server_name(mut self, name: impl Into<String>) -> Self {
+        self.config.server_name = name.into();
+        self
+    }
+
+    /// Set the fully qualified hostname of the server, used in the greeting and the HELO and EHLO replies.
+    /// The server name then only follows the greeting as banner, e.g. `220 mx.nexium.app ESMTP Postbus`.
+    /// Without a hostname, the server name is used in its place. Building fails unless it is a valid ASCII domain.
+    pub fn ehlo_hostname(mut self, hostname: impl Into<String>) -> Self {
+        self.config.ehlo_hostname = Some(hostname.into());
+        self
+    }
+
+    /// Set the maximum number of consecutive bad commands a client may send, defaults to 10.
+    /// Once exceeded, the client receives a 554 reply and the connection is closed.
+    pub fn max_bad_commands(mut self, max: usize) -> Self {
+        self.config.max_bad_commands = max;
+        self
+    }
+
+    /// Set the maximum message size in bytes, or `None` for no limit.
+    /// The limit is advertised with the SIZE extension.
+    pub fn max_size(mut self, max: Option<usize>) -> Self {
+        self.config.max_size = max;
+        self
+    }
+
+    /// Set the maximum number of lines of a message, or `None` for no limit, which is the default.
+    /// Messages with many tiny lines are costly to process even within the size limit, they are rejected with 552.
+    pub fn max_data_lines(mut self, max: Option<usize>) -> Self {
+        self.config.max_data_lines = max;
+        self
+    }
+
+    /// Set the number of recipients accepted per message, defaults to 100.
+    /// Additional recipients are rejected with a 452, keeping the accepted ones.
+    pub fn max_recipients(mut self, max: usize) -> Self {
+        self.config.max_recipients_soft = max;
+        self
+    }
+
+    /// Set the number of RCPT commands per message after which the connection is closed with a 421.
+    /// This includes rejected recipients, `None` disables the limit.
+    pub fn max_recipients_hard(mut self, max: Option<usize>) -> Self {
+        self.config.max_recipients_hard = max;
+        self
+    }
+
+    /// Set the number of messages a client may send over one connection, `None` disables the limit, which is the default.
+    /// Once reached, the next MAIL is answered with 421 and the connection is closed, so the client reconnects for more.
+    /// Only saved messages count, rejected ones don't.
+    pub fn max_transactions_per_connection(mut self, max: Option<usize>) -> Self {
+        self.config.max_transactions_per_connection = max;
+        self
+    }
+
+    /// Set how long to wait for input from the client, defaults to five minutes.
+    /// When it expires the client receives a 421 reply and the connection is closed, `None` waits forever.
+    pub fn command_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.config.command_timeout = timeout;
+        self
+    }
+
+    /// Set how long the handler may take to save a message, defaults to five minutes.
+    /// When it expires the client receives a 451 reply and the transaction is reset, `None` waits forever.
+    pub fn save_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.config.save_timeout = timeout;
+        self
+    }
+
+    /// Set how long the handler may take to check a recipient or allow data, defaults to one minute.
+    /// When it expires the client receives a 451 reply, `None` waits forever.
+    pub fn handler_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.config.handler_timeout = timeout;
+        self
+    }
+
+    /// Set the number of pipelined commands processed at once, defaults to 100.
+    /// Further commands from the same read wait until other sessions had a chance to run.
+    pub fn max_commands_per_batch(mut self, max: usize) -> Self {
+        self.config.max_commands_per_batch = max.max(1);
+        self
+    }
+
+    /// Set the number of commands a client may issue per second, unlimited when `None`, which is the default.
+    /// A client may burst up to this many commands after a pause, beyond that the replies to its commands are delayed.
+    /// This slows down clients flooding the server with valid commands, which the bad command limit does not catch.
+    pub fn max_commands_per_second(mut self, max: Option<u32>) -> Self {
+        self.config.max_commands_per_second = max;
+        self
+    }
+
+    /// Set the length of a command line in octets including the CRLF, defaults to the 512 of RFC 5321.
+    /// Longer lines are answered with 500 and count as a bad command, they are discarded while received.
+    /// This does not limit the lines of a message, see `max_data_lines` for those.
+    pub fn max_command_length(mut self, max: usize) -> Self {
+        self.config.max_command_length = max;
+        self
+    }
+
+    /// Set the length of the local part of senders and recipients in octets, unlimited when `None`, which is the default.
+    /// Longer addresses are answered with 501 before the handler is asked about them, sparing it the lookups.
+    /// RFC 5321 allows 64 octets, but asks servers to accept longer ones where they can.
+    pub fn max_local_part_len(mut self, max: Option<usize>) -> Self {
+        self.config.max_local_part_len = max;
+        self
+    }
+
+    /// Set the length of the domain of senders and recipients in octets, unlimited when `None`, which is the default.
+    /// Longer addresses are answered with 501 before the handler is asked about them, sparing it the lookups.
+    /// RFC 5321 allows 255 octets.
+    pub fn max_domain_len(mut self, max: Option<usize>) -> Self {
+        self.config.max_domain_len = max;
+        self
+    }
+
+    /// Set whether the EXPN command expands mailing lists through the handler, defaults to false.
+    /// This discloses list members to anyone connecting, so only enable it when that is intended.
+    pub fn allow_expn(mut self, allow: bool) -> Self {
+        self.config.allow_expn = allow;
+        self
+    }
+
+    /// Set whether Nagle's algorithm is disabled on accepted connections, defaults to true.
+    /// Replies are small and written once per batch of commands, so delaying them only adds latency.
+    pub fn tcp_nodelay(mut self, nodelay: bool) -> Self {
+        self.config.tcp_nodelay = nodelay;
+        self
+    }
+
+    /// Set the idle time after which TCP keepalive probes are sent on accepted connections, defaults to `None`.
+    /// This detects dead peers on connections without a command timeout, or with a long one.
+    pub fn tcp_keepalive(mut self, idle: Option<Duration>) -> Self {
+        self.config.tcp_keepalive = idle;
+        self
+    }
+
+    /// Set which line endings are accepted for commands, defaults to `LineEnding::Lenient`.
+    /// With `LineEnding::Strict` a command ending in a bare LF is answered with 500 and counts as a bad command.
+    /// Message data always accepts both.
+    pub fn line_ending(mut self, policy: LineEnding) -> Self {
+        self.config.line_ending = policy;
+        self
+    }
+
+    /// Set whether clients have to wait for the 354 reply to DATA before sending the message, defaults to false.
+    /// RFC 2920 requires DATA to be the last command of a pipelined group, but some clients send the message right away.
+    /// When strict, input following DATA in the same read is answered with 503 and the connection is closed,
+    /// which catches spam software cutting corners, at the risk of losing mail from such clients.
+    /// Otherwise the input is taken as the message once DATA is accepted, or as commands when it is not.
+    pub fn strict_pipelining(mut self, strict: bool) -> Self {
+        self.config.strict_pipelining = strict;
+        self
+    }
+
+    /// Set a pause before sending the greeting, disabled when `None`, which is the default.
+    /// Well-behaved clients wait for the greeting, so clients talking during the pause are
+    /// likely spambots and are closed with 554. Every connection is delayed, so keep it short.
+    pub fn greet_delay(mut self, delay: Option<Duration>) -> Self {
+        self.config.greet_delay = delay;
+        self
+    }
+
+    /// Replace the text of a fixed reply, e.g. the `Go ahead` of `Response::StartData`.
+    /// Only the text changes, the reply code and enhanced status code stay the same.
+    /// Building fails for replies containing the server name or transaction details, and for texts with line breaks.
+    pub fn reply_text(mut self, response: Response, text: impl Into<String>) -> Self {
+        self.reply_texts.push((response, text.into()));
+        self
+    }
+
+    /// Add EHLO lines advertising extensions the server does not implement itself, e.g. `X-MY-EXT` or `XFOO 1 2`.
+    /// They are listed after the built-in extensions, the handler is responsible for what they announce.
+    /// Building fails unless every line is a keyword of letters, digits and dashes, followed by space separated parameters.
+    pub fn extra_capabilities(
+        mut self,
+        capabilities: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Self {
+        self.config
+            .extra_capabilities
+            .extend(capabilities.into_iter().map(Into::into));
+        self
+    }
+
+    /// Set the maximum time a connection may stay open, unlimited when `None`, which is the default.
+    /// When it expires the client receives a 421 and is disconnected, even during a command or message.
+    /// A message which was not completely received by then is not saved.
+    pub fn max_session_duration(mut self, duration: Option<Duration>) -> Self {
+        self.config.max_session_duration = duration;
+        self
+    }
+
+    /// Set whether messages are saved with `Handler::save_stream` while their body is received, defaults to false.
+    /// The body is then never kept in `SmtpState::data`, which suits handlers piping it elsewhere.
+    /// Messages sent in BDAT chunks are still saved whole with `Handler::save_message`.
+    pub fn stream_data(mut self, stream: bool) -> Self {
+        self.config.stream_data = stream;
+        self
+    }
+
+    /// Set whether messages are also kept exactly as received in `SmtpState::raw_data`, defaults to false.
+    /// This includes the original line endings and dot-stuffing, for handlers needing the exact bytes like DKIM verifiers.
+    /// It doubles the memory used per message, also when streaming with `stream_data`.
+    pub fn preserve_raw_data(mut self, preserve: bool) -> Self {
+        self.config.preserve_raw_data = preserve;
+        self
+    }
+
+    /// Set whether an accepted recipient is echoed in the reply to RCPT, defaults to false.
+    /// When enabled the reply is e.g. `250 2.1.5 <user@nexium.app>... Recipient ok` instead of `250 Ok`,
+    /// which makes transcripts easier to follow.
+    pub fn echo_recipients(mut self, echo: bool) -> Self {
+        self.config.echo_recipients = echo;
+        self
+    }
+
+    /// Set how many clients may wait for their next command at once, unlimited when `None`, which is the default.
+    /// When a new client connects at the limit, the longest waiting client receives a 421 and is disconnected.
+    /// This keeps slow or stalled clients from occupying every connection, clients busy with a message are never evicted.
+    pub fn max_idle_sessions(mut self, max: Option<usize>) -> Self {
+        self.config.max_idle_sessions = max;
+        self
+    }
+
+    /// Set how many sessions may run at once, unlimited when `None`, which is the default.
+    /// Clients connecting at the limit receive a 421 and are disconnected right away, without starting a session.
+    /// This bounds the file descriptors and memory used under a connection flood, set it below the process limits.
+    pub fn max_concurrent_sessions(mut self, max: Option<usize>) -> Self {
+        self.config.max_concurrent_sessions = max;
+        self
+    }
+
+    /// Set the networks clients may connect from, any network when empty, which is the default.
+    /// Clients from other networks receive a 554 and are disconnected right away, without starting a session.
+    /// Behind a load balancer, see `trust_proxy`, this is the address of the load balancer, not the client.
+    pub fn allow_cidrs(mut self, networks: Vec<IpNet>) -> Self {
+        self.config.allow_cidrs = networks;
+        self
+    }
+
+    /// Set the networks clients are refused from, none by default.
+    /// These take precedence over `allow_cidrs`, e.g. to allow a network except for one of its addresses.
+    /// Clients from these networks receive a 554 and are disconnected right away, without starting a session.
+    pub fn block_cidrs(mut self, networks: Vec<IpNet>) -> Self {
+        self.config.block_cidrs = networks;
+        self
+    }
+
+    /// Set what is masked in log output, defaults to `RedactionLevel::None`.
+    /// Addresses appear in the debug logs of commands and replies, mask them when logs must not contain personal data.
+    pub fn log_redaction(mut self, level: RedactionLevel) -> Self {
+        self.config.log_redaction = level;
+        self
+    }
+
+    /// Set the resolver used to look up the forward-confirmed reverse DNS of every client.
+    /// The name is passed to `Handler::connection_allowed` in `SmtpState::reverse_dns`. Without a resolver there are no lookups.
+    pub fn resolver(mut self, resolver: Arc<dyn Resolver>) -> Self {
+        self.config.resolver = Some(resolver);
+        self
+    }
+
+    /// Set whether every connection starts with a PROXY protocol header, defaults to false.
+    /// Enable this when behind a load balancer, the client address is then taken from the header.
+    /// Connections without a valid header are closed, so never enable it for directly reachable ports.
+    pub fn trust_proxy(mut self, trust: bool) -> Self {
+        self.config.trust_proxy = trust;
+        self
+    }
+
+    /// Create the service, failing when no address was added or the handler is missing.
+    pub fn build(mut self) -> Result<SmtpService, BuildError> {
+        if self.addresses.is_empty() {
+            return Err(BuildError::MissingAddress);
+        }
+
+        for (response, text) in self.reply_texts {
+            if !response.has_fixed_text() || text.contains(['\r', '\n']) {
+                return Err(BuildError::InvalidReplyText);
+            }
+
+            self.config
+                .reply_texts
+                .insert(std::mem::discriminant(&response), text);
+        }
+
+        if let Some(hostname) = &self.config.ehlo_hostname {
+            if !hostname.is_ascii() || hostname.parse::<Domain>().is_err() {
+                return Err(BuildError::InvalidHostname);
+            }
+        }
+
+        if !self
+            .config
+            .extra_capabilities
+            .iter()
+            .all(|line| valid_capability(line))
+        {
+            return Err(BuildError::InvalidCapability);
+        }
+
+        Ok(SmtpService {
+            addresses: self.addresses,
+            handler: self.handler.ok_or(BuildError::MissingHandler)?,
+            metrics: self.metrics,
+            trace: self.trace,
+            config: self.config,
+            paused: Arc::new(AtomicBool::new(false)),
+            registry: Arc::new(ActivityRegistry::new()),
+        })
+    }
+}
+
+/// Check an EHLO line per RFC 5321, a keyword starting with a letter or digit followed by parameters of printable characters.
+fn valid_capability(line: &str) -> bool {
+    let mut parts = line.split(' ');
+
+    let keyword = parts.next().unwrap_or_default();
+    let keyword_valid = keyword.starts_with(|c: char| c.is_ascii_alphanumeric())
+        && keyword
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-');
+
+    keyword_valid
+        && parts.all(|param| !param.is_empty() && param.chars().all(|c| c.is_ascii_graphic()))
+}
+
+impl Default for SmtpServiceBuilder {
+    fn default() -> Self {
+        SmtpServiceBuilder::new()
+    }
+}
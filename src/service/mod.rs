@@ -0,0 +1,322 @@
+use socket2::{SockRef, TcpKeepalive};
+use std::{
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    sync::{OwnedSemaphorePermit, Semaphore},
+    task::JoinSet,
+};
+
+use crate::{
+    config::Config,
+    metrics::{Metrics, Rejection},
+    trace::Tracer,
+    Handler, Response, SmtpSession,
+};
+
+pub(crate) use activity::{ActivityHandle, ActivityRegistry};
+pub use builder::{BuildError, SmtpServiceBuilder};
+
+mod activity;
+mod builder;
+
+#[cfg(test)]
+mod tests;
+
+/// Time a health check waits for the greeting, on top of the greeting delay.
+const HEALTHCHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Limits shared by all listeners, so they apply to the service as a whole.
+#[derive(Debug, Clone)]
+struct Limits {
+    /// Running sessions, to evict the longest idle one or disconnect one by its id.
+    registry: Arc<ActivityRegistry>,
+    /// A permit for every running session.
+    sessions: Option<Arc<Semaphore>>,
+    /// Whether new clients are refused, see `SmtpService::set_paused`.
+    paused: Arc<AtomicBool>,
+}
+
+impl Limits {
+    /// Decide whether a new client may start a session, returning the reply refusing it otherwise.
+    /// The permit is held for as long as the session runs.
+    fn admit(
+        &self,
+        config: &Config,
+        peer: SocketAddr,
+    ) -> Result<Option<OwnedSemaphorePermit>, Response> {
+        if !config.address_allowed(peer.ip()) {
+            return Err(Response::AddressRefused);
+        }
+
+        if self.paused.load(Ordering::Relaxed) {
+            return Err(Response::ServiceUnavailable(config.hostname().to_string()));
+        }
+
+        match &self.sessions {
+            Some(sessions) => match sessions.clone().try_acquire_owned() {
+                Ok(permit) => Ok(Some(permit)),
+                Err(_) => Err(Response::TooManyConnections),
+            },
+            None => Ok(None),
+        }
+    }
+}
+
+/// Smtp service.
+pub struct SmtpService {
+    addresses: Vec<SocketAddr>,
+    pub(crate) config: Config,
+    pub(crate) handler: Arc<dyn Handler>,
+    pub(crate) metrics: Option<Arc<dyn Metrics>>,
+    pub(crate) trace: Option<Tracer>,
+    paused: Arc<AtomicBool>,
+    registry: Arc<ActivityRegistry>,
+}
+
+impl SmtpService {
+    /// Create a new service with the default settings.
+    /// This does not listen on the port, call `.listen()` for that.
+    pub fn create(
+        address: SocketAddr,
+        server_name: String,
+        handler: Arc<dyn Handler>,
+    ) -> SmtpService {
+        SmtpService {
+            addresses: vec![address],
+            config: Config::new(server_name),
+            handler,
+            metrics: None,
+            trace: None,
+            paused: Arc::new(AtomicBool::new(false)),
+            registry: Arc::new(ActivityRegistry::new()),
+        }
+    }
+
+    /// Start configuring a new service, see `SmtpServiceBuilder`.
+    pub fn builder() -> SmtpServiceBuilder {
+        SmtpServiceBuilder::new()
+    }
+
+    /// Stop or resume accepting new clients, e.g. to drain the server before a restart.
+    /// While paused, new clients receive a 421 and are disconnected, running sessions are not affected.
+    /// This can be called while the service is listening.
+    pub fn set_paused(&self, paused: bool) {
+        self.paused.store(paused, Ordering::Relaxed);
+    }
+
+    /// Whether new clients are currently refused, see `set_paused`.
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    /// Close the session with the given id with a 421, e.g. to kick an abusive client.
+    /// The id is `SmtpState::session_id`, as seen by the handler and in the log output.
+    /// The session closes once it waits for the client, so a command being processed is still answered,
+    /// but a message being received is aborted. Returns false when no session with the id is running.
+    pub fn disconnect(&self, session_id: u64) -> bool {
+        self.registry.disconnect(session_id)
+    }
+
+    /// Check whether the service accepts clients, e.g. from the `/healthz` endpoint of the application.
+    /// This connects to every address of the service over loopback and waits for the greeting, so it only
+    /// succeeds while the service listens. A paused or overloaded service refuses the connection, and is unhealthy.
+    /// Every check is a short session, seen by the handler and metrics like any other client.
+    pub async fn healthcheck(&self) -> bool {
+        let limit = HEALTHCHECK_TIMEOUT + self.config.greet_delay.unwrap_or_default();
+
+        for &address in &self.addresses {
+            let probe = probe(loopback(address), self.config.trust_proxy);
+
+            if !matches!(tokio::time::timeout(limit, probe).await, Ok(Ok(true))) {
+                debug!(address:% = address; "Health check failed.");
+                return false;
+            }
+        }
+
+        true
+    }
+
+    /// Listen the server on all of its addresses.
+    /// This is a normal Tokio server, and should be awaited.
+    /// Addresses which can not be bound are skipped, it only panics when none could be bound.
+    pub async fn listen(&self) -> ! {
+        let listeners = self.bind().await;
+        self.serve(listeners).await
+    }
+
+    /// Accept clients on the bound listeners, sharing the handler and settings between them.
+    async fn serve(&self, listeners: Vec<(SocketAddr, TcpListener)>) -> ! {
+        let config = Arc::new(self.config.clone());
+        let limits = Limits {
+            registry: self.registry.clone(),
+            sessions: config
+                .max_concurrent_sessions
+                .map(|max| Arc::new(Semaphore::new(max))),
+            paused: self.paused.clone(),
+        };
+
+        // Owned by this future, so the listeners are closed when it is dropped.
+        let mut accepting = JoinSet::new();
+
+        for (address, listener) in listeners {
+            accepting.spawn(accept(
+                listener,
+                address,
+                config.clone(),
+                self.handler.clone(),
+                self.metrics.clone(),
+                self.trace.clone(),
+                limits.clone(),
+            ));
+        }
+
+        while accepting.join_next().await.is_some() {}
+
+        std::future::pending::<()>().await;
+        unreachable!()
+    }
+
+    /// Bind a listener for every address, logging the ones which failed.
+    async fn bind(&self) -> Vec<(SocketAddr, TcpListener)> {
+        let mut listeners = Vec::with_capacity(self.addresses.len());
+
+        for &address in &self.addresses {
+            match TcpListener::bind(address).await {
+                Ok(listener) => {
+                    debug!(address:% = address; "Started listening.");
+                    listeners.push((address, listener));
+                }
+                Err(e) => {
+                    error!(address:% = address, error:% = e; "Could not listen on the SMTP address.");
+                }
+            }
+        }
+
+        if listeners.is_empty() {
+            panic!("Could not listen on any SMTP address.");
+        }
+
+        listeners
+    }
+}
+
+/// Accept clients on a listener, starting a session for each of them.
+async fn accept(
+    listener: TcpListener,
+    address: SocketAddr,
+    config: Arc<Config>,
+    handler: Arc<dyn Handler>,
+    metrics: Option<Arc<dyn Metrics>>,
+    trace: Option<Tracer>,
+    limits: Limits,
+) {
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(c) => c,
+            Err(e) => {
+                warn!(address:% = address, error:% = e; "Failed to accept SMTP socket.");
+                continue;
+            }
+        };
+
+        if let Some(metrics) = &metrics {
+            metrics.connection_accepted(addr);
+        }
+
+        if let Err(e) = configure_socket(&stream, &config) {
+            warn!(address:% = address, peer:% = addr, error:% = e; "Failed to set the socket options.");
+        }
+
+        // Taken before anything is allocated for the session, and released when it ends.
+        let permit = match limits.admit(&config, addr) {
+            Ok(permit) => permit,
+            Err(response) => {
+                debug!(address:% = address, peer:% = addr, reason:? = response; "Refused a client.");
+                let reply = response.to_response_with(&config.reply_texts);
+                tokio::spawn(refuse(stream, reply));
+
+                if let Some(metrics) = &metrics {
+                    if response == Response::TooManyConnections {
+                        metrics.rejected(Rejection::RateLimited);
+                    }
+                    metrics.connection_closed();
+                }
+                continue;
+            }
+        };
+
+        if config
+            .max_idle_sessions
+            .is_some_and(|max| limits.registry.make_room(max))
+        {
+            debug!(address:% = address, peer:% = addr; "Evicting the longest idle client for a new one.");
+        }
+
+        let session = SmtpSession::new(
+            stream,
+            addr,
+            config.clone(),
+            handler.clone(),
+            metrics.clone(),
+            trace.clone(),
+            Some(&limits.registry),
+        );
+
+        tokio::spawn(async move {
+            session.handle().await;
+            drop(permit);
+        });
+    }
+}
+
+/// Apply the socket options of the service to an accepted connection.
+fn configure_socket(stream: &TcpStream, config: &Config) -> std::io::Result<()> {
+    stream.set_nodelay(config.tcp_nodelay)?;
+
+    if let Some(idle) = config.tcp_keepalive {
+        let keepalive = TcpKeepalive::new().with_time(idle);
+        SockRef::from(stream).set_tcp_keepalive(&keepalive)?;
+    }
+
+    Ok(())
+}
+
+/// Connect to a listener of the service and read the greeting, quitting the session afterwards.
+/// Returns whether the client was greeted, instead of refused.
+async fn probe(address: SocketAddr, proxied: bool) -> std::io::Result<bool> {
+    let mut stream = BufReader::new(TcpStream::connect(address).await?);
+
+    if proxied {
+        stream.get_mut().write_all(b"PROXY UNKNOWN\r\n").await?;
+    }
+
+    let mut greeting = String::new();
+    stream.read_line(&mut greeting).await?;
+    stream.get_mut().write_all(b"QUIT\r\n").await?;
+
+    Ok(greeting.starts_with("220 "))
+}
+
+/// Address to reach a listener on from the same host, replacing a wildcard address with loopback.
+fn loopback(address: SocketAddr) -> SocketAddr {
+    let ip = match address {
+        SocketAddr::V4(v4) if v4.ip().is_unspecified() => Ipv4Addr::LOCALHOST.into(),
+        SocketAddr::V6(v6) if v6.ip().is_unspecified() => Ipv6Addr::LOCALHOST.into(),
+        _ => address.ip(),
+    };
+
+    SocketAddr::new(ip, address.port())
+}
+
+/// Send the reply refusing a client and close the connection, giving up when the client does not accept it quickly.
+async fn refuse(mut stream: TcpStream, reply: String) {
+    let _ = tokio::time::timeout(Duration::from_secs(5), stream.write_all(reply.as_bytes())).await;
+}
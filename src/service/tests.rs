@@ -0,0 +1,690 @@
+use async_trait::async_trait;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+use super::*;
+use crate::{
+    command::Mailbox, IpNet, LineEnding, MetricsCounters, RedactionLevel, Rejection, Response,
+    SmtpState,
+};
+
+struct AcceptingHandler {}
+
+#[async_trait]
+impl Handler for AcceptingHandler {
+    async fn recipient_local(&self, _recipient: &Mailbox) -> bool {
+        true
+    }
+
+    async fn save(&self, _state: &SmtpState) -> bool {
+        true
+    }
+}
+
+fn address() -> SocketAddr {
+    "127.0.0.1:2525".parse().unwrap()
+}
+
+fn net(cidr: &str) -> IpNet {
+    cidr.parse().unwrap()
+}
+
+#[test]
+fn builder_fully_configured() {
+    let service = SmtpService::builder()
+        .address(address())
+        .handler(Arc::new(AcceptingHandler {}))
+        .server_name("mx.nexium.app")
+        .max_bad_commands(3)
+        .max_size(Some(1024))
+        .max_recipients(5)
+        .max_recipients_hard(Some(10))
+        .command_timeout(Some(Duration::from_secs(30)))
+        .save_timeout(Some(Duration::from_secs(60)))
+        .handler_timeout(None)
+        .max_commands_per_batch(20)
+        .max_commands_per_second(Some(50))
+        .allow_expn(true)
+        .trust_proxy(true)
+        .greet_delay(Some(Duration::from_secs(2)))
+        .max_session_duration(Some(Duration::from_secs(600)))
+        .max_idle_sessions(Some(50))
+        .max_transactions_per_connection(Some(20))
+        .max_data_lines(Some(10_000))
+        .preserve_raw_data(true)
+        .echo_recipients(true)
+        .log_redaction(RedactionLevel::Addresses)
+        .line_ending(LineEnding::Strict)
+        .strict_pipelining(true)
+        .allow_cidrs(vec![net("192.0.2.0/24")])
+        .block_cidrs(vec![net("192.0.2.66/32")])
+        .max_command_length(1000)
+        .max_local_part_len(Some(32))
+        .max_domain_len(Some(64))
+        .tcp_nodelay(false)
+        .tcp_keepalive(Some(Duration::from_secs(300)))
+        .build()
+        .unwrap();
+
+    assert_eq!(service.addresses, vec![address()]);
+    assert_eq!(service.config.server_name, "mx.nexium.app");
+    assert_eq!(service.config.max_bad_commands, 3);
+    assert_eq!(service.config.max_size, Some(1024));
+    assert_eq!(service.config.max_recipients_soft, 5);
+    assert_eq!(service.config.max_recipients_hard, Some(10));
+    assert_eq!(
+        service.config.command_timeout,
+        Some(Duration::from_secs(30))
+    );
+    assert_eq!(service.config.save_timeout, Some(Duration::from_secs(60)));
+    assert_eq!(service.config.handler_timeout, None);
+    assert_eq!(service.config.max_commands_per_batch, 20);
+    assert_eq!(Some(50), service.config.max_commands_per_second);
+    assert!(service.config.allow_expn);
+    assert!(service.config.trust_proxy);
+    assert_eq!(Some(Duration::from_secs(2)), service.config.greet_delay);
+    assert_eq!(
+        Some(Duration::from_secs(600)),
+        service.config.max_session_duration
+    );
+    assert_eq!(Some(50), service.config.max_idle_sessions);
+    assert_eq!(Some(20), service.config.max_transactions_per_connection);
+    assert_eq!(Some(10_000), service.config.max_data_lines);
+    assert!(service.config.preserve_raw_data);
+    assert!(service.config.echo_recipients);
+    assert_eq!(RedactionLevel::Addresses, service.config.log_redaction);
+    assert_eq!(LineEnding::Strict, service.config.line_ending);
+    assert!(service.config.strict_pipelining);
+    assert_eq!(vec![net("192.0.2.0/24")], service.config.allow_cidrs);
+    assert_eq!(vec![net("192.0.2.66/32")], service.config.block_cidrs);
+    assert_eq!(1000, service.config.max_command_length);
+    assert_eq!(Some(32), service.config.max_local_part_len);
+    assert_eq!(Some(64), service.config.max_domain_len);
+    assert!(!service.config.tcp_nodelay);
+    assert_eq!(Some(Duration::from_secs(300)), service.config.tcp_keepalive);
+}
+
+#[test]
+fn builder_defaults_match_create() {
+    let built = SmtpService::builder()
+        .address(address())
+        .handler(Arc::new(AcceptingHandler {}))
+        .server_name("test")
+        .build()
+        .unwrap();
+    let created = SmtpService::create(address(), "test".into(), Arc::new(AcceptingHandler {}));
+
+    assert_eq!(
+        format!("{:?}", built.config),
+        format!("{:?}", created.config)
+    );
+}
+
+#[test]
+fn builder_missing_address() {
+    let res = SmtpService::builder()
+        .handler(Arc::new(AcceptingHandler {}))
+        .build();
+
+    assert_eq!(res.err(), Some(BuildError::MissingAddress));
+}
+
+#[test]
+fn builder_missing_handler() {
+    let res = SmtpService::builder().address(address()).build();
+
+    assert_eq!(res.err(), Some(BuildError::MissingHandler));
+}
+
+#[test]
+fn builder_reply_text() {
+    let service = SmtpService::builder()
+        .address(address())
+        .handler(Arc::new(AcceptingHandler {}))
+        .reply_text(Response::StartData, "Send the message")
+        .build()
+        .unwrap();
+
+    assert_eq!(
+        Some(&"Send the message".to_string()),
+        service
+            .config
+            .reply_texts
+            .get(&std::mem::discriminant(&Response::StartData))
+    );
+}
+
+#[test]
+fn builder_invalid_reply_text() {
+    let injected = SmtpService::builder()
+        .address(address())
+        .handler(Arc::new(AcceptingHandler {}))
+        .reply_text(Response::StartData, "Go ahead\r\n250 Injected")
+        .build();
+    let not_fixed = SmtpService::builder()
+        .address(address())
+        .handler(Arc::new(AcceptingHandler {}))
+        .reply_text(Response::Greeting(String::new()), "Welcome")
+        .build();
+
+    assert_eq!(injected.err(), Some(BuildError::InvalidReplyText));
+    assert_eq!(not_fixed.err(), Some(BuildError::InvalidReplyText));
+}
+
+#[test]
+fn builder_extra_capabilities() {
+    let service = SmtpService::builder()
+        .address(address())
+        .handler(Arc::new(AcceptingHandler {}))
+        .extra_capabilities(["X-MY-EXT", "XFOO 1 2"])
+        .build()
+        .unwrap();
+
+    assert_eq!(
+        vec!["X-MY-EXT".to_string(), "XFOO 1 2".to_string()],
+        service.config.extra_capabilities
+    );
+}
+
+#[test]
+fn builder_invalid_capability() {
+    let invalid = [
+        "",
+        "-EXT",
+        "X_EXT",
+        "XFOO  1",
+        "XFOO 1\r\n250 X",
+        "XFOO \u{e9}",
+    ];
+
+    for capability in invalid {
+        let res = SmtpService::builder()
+            .address(address())
+            .handler(Arc::new(AcceptingHandler {}))
+            .extra_capabilities([capability])
+            .build();
+
+        assert_eq!(
+            res.err(),
+            Some(BuildError::InvalidCapability),
+            "{:?}",
+            capability
+        );
+    }
+}
+
+/// Find a free local address by binding to an ephemeral port and releasing it.
+fn free_address() -> SocketAddr {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap()
+}
+
+/// Connect to the service and read the greeting.
+async fn greeting(address: SocketAddr) -> String {
+    let stream = TcpStream::connect(address).await.unwrap();
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line).await.unwrap();
+
+    line.trim_end().to_string()
+}
+
+#[test]
+fn builder_multiple_addresses() {
+    let other: SocketAddr = "[::1]:2525".parse().unwrap();
+    let service = SmtpService::builder()
+        .address(address())
+        .addresses(vec![other])
+        .handler(Arc::new(AcceptingHandler {}))
+        .build()
+        .unwrap();
+
+    assert_eq!(service.addresses, vec![address(), other]);
+}
+
+#[tokio::test]
+async fn listen_on_multiple_addresses() {
+    let (first, second) = (free_address(), free_address());
+    let service = SmtpService::builder()
+        .addresses(vec![first, second])
+        .handler(Arc::new(AcceptingHandler {}))
+        .server_name("test")
+        .build()
+        .unwrap();
+
+    let listeners = service.bind().await;
+    assert_eq!(listeners.len(), 2);
+
+    tokio::spawn(async move { service.serve(listeners).await });
+
+    assert_eq!(greeting(first).await, "220 test ESMTP");
+    assert_eq!(greeting(second).await, "220 test ESMTP");
+}
+
+#[tokio::test]
+async fn dropped_listen_stops_accepting() {
+    let address = free_address();
+    let service = SmtpService::builder()
+        .address(address)
+        .handler(Arc::new(AcceptingHandler {}))
+        .server_name("test")
+        .build()
+        .unwrap();
+
+    let listening = tokio::spawn(async move { service.listen().await });
+    let mut connected = TcpStream::connect(address).await;
+    while connected.is_err() {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        connected = TcpStream::connect(address).await;
+    }
+
+    listening.abort();
+    let _ = listening.await;
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    assert!(TcpStream::connect(address).await.is_err());
+}
+
+#[tokio::test]
+async fn listen_skips_unavailable_address() {
+    let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let free = free_address();
+    let service = SmtpService::builder()
+        .addresses(vec![taken.local_addr().unwrap(), free])
+        .handler(Arc::new(AcceptingHandler {}))
+        .server_name("test")
+        .build()
+        .unwrap();
+
+    let listeners = service.bind().await;
+    assert_eq!(listeners.len(), 1);
+    assert_eq!(listeners[0].0, free);
+}
+
+#[tokio::test]
+async fn metrics_count_connections() {
+    let metrics = Arc::new(MetricsCounters::new());
+    let address = free_address();
+    let service = SmtpService::builder()
+        .address(address)
+        .handler(Arc::new(AcceptingHandler {}))
+        .metrics(metrics.clone())
+        .server_name("test")
+        .build()
+        .unwrap();
+
+    let listeners = service.bind().await;
+    tokio::spawn(async move { service.serve(listeners).await });
+
+    greeting(address).await;
+    greeting(address).await;
+
+    assert_eq!(metrics.connections_accepted(), 2);
+}
+
+/// Connect to the service and read the greeting, keeping the connection open.
+async fn idle_client(address: SocketAddr) -> BufReader<TcpStream> {
+    let mut client = BufReader::new(TcpStream::connect(address).await.unwrap());
+    let mut line = String::new();
+    client.read_line(&mut line).await.unwrap();
+
+    // Give the session time to start waiting for a command.
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    client
+}
+
+#[tokio::test]
+async fn idle_clients_evicted_oldest_first() {
+    let address = free_address();
+    let service = SmtpService::builder()
+        .address(address)
+        .handler(Arc::new(AcceptingHandler {}))
+        .server_name("test")
+        .max_idle_sessions(Some(2))
+        .build()
+        .unwrap();
+
+    let listeners = service.bind().await;
+    tokio::spawn(async move { service.serve(listeners).await });
+
+    let mut oldest = idle_client(address).await;
+    let mut second = idle_client(address).await;
+    let mut newest = idle_client(address).await;
+
+    let mut closed = String::new();
+    oldest.read_to_string(&mut closed).await.unwrap();
+    assert_eq!(
+        "421 Too many idle connections, closing connection\r\n",
+        closed
+    );
+
+    for client in [&mut second, &mut newest] {
+        let mut line = String::new();
+        client.get_mut().write_all(b"NOOP\r\n").await.unwrap();
+        client.read_line(&mut line).await.unwrap();
+        assert_eq!("250 Ok\r\n", line);
+    }
+}
+
+/// Handler passing the id of every session which connects to the test.
+struct SessionIdHandler {
+    ids: tokio::sync::mpsc::UnboundedSender<u64>,
+}
+
+#[async_trait]
+impl Handler for SessionIdHandler {
+    async fn connection_allowed(&self, state: &SmtpState) -> bool {
+        self.ids.send(state.session_id).unwrap();
+        true
+    }
+
+    async fn recipient_local(&self, _recipient: &Mailbox) -> bool {
+        true
+    }
+
+    async fn save(&self, _state: &SmtpState) -> bool {
+        true
+    }
+}
+
+#[tokio::test]
+async fn session_disconnected_by_id() {
+    let address = free_address();
+    let (ids, mut connected) = tokio::sync::mpsc::unbounded_channel();
+    let service = Arc::new(
+        SmtpService::builder()
+            .address(address)
+            .handler(Arc::new(SessionIdHandler { ids }))
+            .server_name("test")
+            .build()
+            .unwrap(),
+    );
+
+    let listeners = service.bind().await;
+    let serving = service.clone();
+    tokio::spawn(async move { serving.serve(listeners).await });
+
+    let mut kicked = idle_client(address).await;
+    let kicked_id = connected.recv().await.unwrap();
+    let mut other = idle_client(address).await;
+    let other_id = connected.recv().await.unwrap();
+    assert_ne!(kicked_id, other_id);
+
+    let admin = service.clone();
+    let disconnected = tokio::spawn(async move { admin.disconnect(kicked_id) });
+    assert!(disconnected.await.unwrap());
+
+    let mut closed = String::new();
+    kicked.read_to_string(&mut closed).await.unwrap();
+    assert_eq!("421 Closing connection\r\n", closed);
+
+    let mut line = String::new();
+    other.get_mut().write_all(b"NOOP\r\n").await.unwrap();
+    other.read_line(&mut line).await.unwrap();
+    assert_eq!("250 Ok\r\n", line);
+
+    // Session ids start at one.
+    assert!(!service.disconnect(0));
+}
+
+#[test]
+fn addresses_allowed_and_blocked() {
+    let ip = |ip: &str| ip.parse().unwrap();
+    let mut config = Config::new("test".into());
+    assert!(config.address_allowed(ip("192.0.2.1")));
+    assert!(config.address_allowed(ip("2001:db8::1")));
+
+    config.block_cidrs = vec![net("192.0.2.0/25"), net("2001:db8:bad::/48")];
+    assert!(!config.address_allowed(ip("192.0.2.1")));
+    assert!(!config.address_allowed(ip("::ffff:192.0.2.1")));
+    assert!(!config.address_allowed(ip("2001:db8:bad::1")));
+    assert!(config.address_allowed(ip("192.0.2.129")));
+    assert!(config.address_allowed(ip("2001:db8::1")));
+
+    // Only allowed networks may connect, blocked ones not even when allowed.
+    config.allow_cidrs = vec![net("192.0.2.0/24"), net("2001:db8::/32")];
+    assert!(config.address_allowed(ip("192.0.2.129")));
+    assert!(config.address_allowed(ip("::ffff:192.0.2.129")));
+    assert!(config.address_allowed(ip("2001:db8::1")));
+    assert!(!config.address_allowed(ip("192.0.2.1")));
+    assert!(!config.address_allowed(ip("2001:db8:bad::1")));
+    assert!(!config.address_allowed(ip("198.51.100.1")));
+    assert!(!config.address_allowed(ip("2001:db9::1")));
+}
+
+#[tokio::test]
+async fn blocked_clients_refused() {
+    let allowed = free_address();
+    let blocked = free_address();
+    let not_allowed = free_address();
+
+    for (address, allow, block) in [
+        (allowed, vec![net("127.0.0.0/8")], vec![]),
+        (blocked, vec![net("127.0.0.0/8")], vec![net("127.0.0.1/32")]),
+        (not_allowed, vec![net("::1/128")], vec![]),
+    ] {
+        let service = SmtpService::builder()
+            .address(address)
+            .handler(Arc::new(AcceptingHandler {}))
+            .server_name("test")
+            .allow_cidrs(allow)
+            .block_cidrs(block)
+            .build()
+            .unwrap();
+
+        let listeners = service.bind().await;
+        tokio::spawn(async move { service.serve(listeners).await });
+    }
+
+    assert_eq!("220 test ESMTP", greeting(allowed).await);
+    for address in [blocked, not_allowed] {
+        assert_eq!(
+            "554 5.7.1 Connection refused from this address",
+            greeting(address).await
+        );
+    }
+}
+
+#[tokio::test]
+async fn concurrent_sessions_limited() {
+    let metrics = Arc::new(MetricsCounters::new());
+    let address = free_address();
+    let service = SmtpService::builder()
+        .address(address)
+        .handler(Arc::new(AcceptingHandler {}))
+        .server_name("test")
+        .max_concurrent_sessions(Some(1))
+        .metrics(metrics.clone())
+        .build()
+        .unwrap();
+
+    let listeners = service.bind().await;
+    tokio::spawn(async move { service.serve(listeners).await });
+
+    let mut first = idle_client(address).await;
+
+    let mut refused = TcpStream::connect(address).await.unwrap();
+    let mut closed = String::new();
+    refused.read_to_string(&mut closed).await.unwrap();
+    assert_eq!("421 Too many connections, try again later\r\n", closed);
+    assert_eq!(1, metrics.rejections(Rejection::RateLimited));
+
+    first.get_mut().write_all(b"QUIT\r\n").await.unwrap();
+    let mut rest = String::new();
+    first.read_to_string(&mut rest).await.unwrap();
+    assert_eq!("221 Goodbye!\r\n", rest);
+
+    // The permit of the first session is released once it ended.
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    assert_eq!("220 test ESMTP", greeting(address).await);
+}
+
+/// Accept a loopback connection with the socket options of the config applied.
+async fn accepted_stream(config: &Config) -> (TcpStream, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let client = TcpStream::connect(listener.local_addr().unwrap())
+        .await
+        .unwrap();
+    let (stream, _) = listener.accept().await.unwrap();
+
+    configure_socket(&stream, config).unwrap();
+    (client, stream)
+}
+
+#[tokio::test]
+async fn socket_options_applied() {
+    let mut config = Config::new("test".into());
+    config.tcp_keepalive = Some(Duration::from_secs(120));
+
+    let (_client, stream) = accepted_stream(&config).await;
+    let socket = SockRef::from(&stream);
+
+    assert!(stream.nodelay().unwrap());
+    assert!(socket.keepalive().unwrap());
+    assert_eq!(
+        Duration::from_secs(120),
+        socket.tcp_keepalive_time().unwrap()
+    );
+}
+
+#[tokio::test]
+async fn socket_options_disabled() {
+    let mut config = Config::new("test".into());
+    config.tcp_nodelay = false;
+
+    let (_client, stream) = accepted_stream(&config).await;
+
+    assert!(!stream.nodelay().unwrap());
+    assert!(!SockRef::from(&stream).keepalive().unwrap());
+}
+
+#[tokio::test]
+async fn healthcheck() {
+    let address = free_address();
+    let service = Arc::new(
+        SmtpService::builder()
+            .address(address)
+            .handler(Arc::new(AcceptingHandler {}))
+            .server_name("test")
+            .build()
+            .unwrap(),
+    );
+
+    assert!(!service.healthcheck().await);
+
+    let listeners = service.bind().await;
+    let serving = service.clone();
+    tokio::spawn(async move { serving.serve(listeners).await });
+
+    assert!(service.healthcheck().await);
+
+    service.set_paused(true);
+    assert!(!service.healthcheck().await);
+}
+
+#[tokio::test]
+async fn healthcheck_on_wildcard_address() {
+    let port = free_address().port();
+    let service = Arc::new(
+        SmtpService::builder()
+            .address(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), port))
+            .handler(Arc::new(AcceptingHandler {}))
+            .trust_proxy(true)
+            .build()
+            .unwrap(),
+    );
+
+    let listeners = service.bind().await;
+    let serving = service.clone();
+    tokio::spawn(async move { serving.serve(listeners).await });
+
+    assert!(service.healthcheck().await);
+}
+
+#[tokio::test]
+async fn paused_service_refuses_new_clients() {
+    let address = free_address();
+    let service = Arc::new(
+        SmtpService::builder()
+            .address(address)
+            .handler(Arc::new(AcceptingHandler {}))
+            .server_name("test")
+            .build()
+            .unwrap(),
+    );
+
+    let listeners = service.bind().await;
+    let serving = service.clone();
+    tokio::spawn(async move { serving.serve(listeners).await });
+
+    let mut running = idle_client(address).await;
+    running
+        .get_mut()
+        .write_all(
+            b"HELO nexium.app\r\nMAIL FROM:<info@nexium.app>\r\nRCPT TO:<admin@nexium.app>\r\n",
+        )
+        .await
+        .unwrap();
+    for _ in 0..3 {
+        let mut line = String::new();
+        running.read_line(&mut line).await.unwrap();
+    }
+
+    service.set_paused(true);
+    assert!(service.is_paused());
+
+    let mut refused = TcpStream::connect(address).await.unwrap();
+    let mut closed = String::new();
+    refused.read_to_string(&mut closed).await.unwrap();
+    assert_eq!(
+        "421 test Service not available, closing transmission channel\r\n",
+        closed
+    );
+
+    running
+        .get_mut()
+        .write_all(b"DATA\r\nHello\r\n.\r\nQUIT\r\n")
+        .await
+        .unwrap();
+    let mut rest = String::new();
+    running.read_to_string(&mut rest).await.unwrap();
+    assert_eq!("354 Go ahead\r\n250 Ok\r\n221 Goodbye!\r\n", rest);
+
+    service.set_paused(false);
+    assert_eq!("220 test ESMTP", greeting(address).await);
+}
+
+#[tokio::test]
+async fn greeting_uses_ehlo_hostname() {
+    let address = free_address();
+    let service = SmtpService::builder()
+        .address(address)
+        .handler(Arc::new(AcceptingHandler {}))
+        .server_name("Postbus")
+        .ehlo_hostname("mx.nexium.app")
+        .build()
+        .unwrap();
+
+    let listeners = service.bind().await;
+    tokio::spawn(async move { service.serve(listeners).await });
+
+    assert_eq!("220 mx.nexium.app ESMTP Postbus", greeting(address).await);
+}
+
+#[test]
+fn builder_invalid_hostname() {
+    for hostname in ["not a host!", "", "mx.nexium.app.", "παράδειγμα.ελ"] {
+        let res = SmtpService::builder()
+            .address(address())
+            .handler(Arc::new(AcceptingHandler {}))
+            .ehlo_hostname(hostname)
+            .build();
+
+        assert_eq!(
+            res.err(),
+            Some(BuildError::InvalidHostname),
+            "{:?}",
+            hostname
+        );
+    }
+}
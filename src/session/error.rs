@@ -0,0 +1,40 @@
+use std::{fmt, io};
+
+/// Reason a session ended before the client quit.
+#[derive(Debug)]
+pub(crate) enum SessionError {
+    /// Reading from or writing to the connection failed.
+    Io(io::Error),
+    /// The client was idle too long, or the session time limit was reached.
+    Timeout,
+    /// The client was refused, e.g. for talking before the greeting or sending too many recipients.
+    PolicyReject,
+    /// The client broke the protocol, e.g. with invalid UTF-8, a bad PROXY header or too many bad commands.
+    ProtocolViolation,
+}
+
+impl fmt::Display for SessionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SessionError::Io(e) => write!(f, "connection error: {}", e),
+            SessionError::Timeout => write!(f, "client timed out"),
+            SessionError::PolicyReject => write!(f, "client rejected by policy"),
+            SessionError::ProtocolViolation => write!(f, "client violated the protocol"),
+        }
+    }
+}
+
+impl std::error::Error for SessionError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SessionError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for SessionError {
+    fn from(e: io::Error) -> Self {
+        SessionError::Io(e)
+    }
+}
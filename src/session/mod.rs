@@ -0,0 +1,877 @@
+use std::{future::Future, net::SocketAddr, sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+    time::{timeout, Instant},
+};
+
+use crate::{
+    command::{Command, Mailbox},
+    config::{Config, LineEnding},
+    dns::forward_confirmed,
+    machine::{Action, SmtpMachine, SmtpState},
+    metrics::{Metrics, Rejection},
+    proxy::{parse_proxy_header, ProxyHeader},
+    redact,
+    service::{ActivityHandle, ActivityRegistry},
+    trace::{Direction, Tracer},
+    AbortReason, DataDecision, Handler, LoadLevel, RecipientDecision, Response, SaveOutcome,
+};
+
+mod error;
+#[cfg(test)]
+mod tests;
+mod throttle;
+mod upload;
+
+pub(crate) use error::SessionError;
+use throttle::CommandBucket;
+use upload::BodyUpload;
+
+/// Longest command timeout for clients connecting under high load.
+const HIGH_LOAD_COMMAND_TIMEOUT: Duration = Duration::from_secs(60);
+/// Shortest greeting delay for clients connecting under high load.
+const HIGH_LOAD_GREET_DELAY: Duration = Duration::from_secs(5);
+
+/// Struct holding data about the session.
+/// This drives a `SmtpMachine` over a TCP connection, or any other stream.
+pub struct SmtpSession<S = TcpStream> {
+    id: u64,
+    stream: S,
+    /// Input not processed yet, an unterminated line or commands deferred to the next batch.
+    remaining: Vec<u8>,
+    /// Whether the rest of an overlong command line is being discarded, until its end.
+    overlong: bool,
+    output: Vec<u8>,
+    addr: SocketAddr,
+    handler: Arc<dyn Handler>,
+    metrics: Option<Arc<dyn Metrics>>,
+    trace: Option<Tracer>,
+    config: Arc<Config>,
+    machine: SmtpMachine,
+    deadline: Option<Instant>,
+    upload: Option<BodyUpload>,
+    activity: Option<ActivityHandle>,
+    /// Commands the client may still issue, when their rate is limited.
+    bucket: Option<CommandBucket>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> SmtpSession<S> {
+    /// Create a new session.
+    pub(crate) fn new(
+        stream: S,
+        addr: SocketAddr,
+        config: Arc<Config>,
+        handler: Arc<dyn Handler>,
+        metrics: Option<Arc<dyn Metrics>>,
+        trace: Option<Tracer>,
+        registry: Option<&Arc<ActivityRegistry>>,
+    ) -> Self {
+        let machine = SmtpMachine::with_config(config.clone(), addr);
+        let activity = registry.map(|registry| registry.register(machine.state().session_id));
+        let bucket = config.max_commands_per_second.map(CommandBucket::new);
+
+        SmtpSession {
+            id: machine.state().session_id,
+            stream,
+            handler,
+            metrics,
+            trace,
+            config,
+            addr,
+            remaining: Vec::with_capacity(128),
+            overlong: false,
+            output: Vec::with_capacity(512),
+            machine,
+            deadline: None,
+            upload: None,
+            activity,
+            bucket,
+        }
+    }
+
+    /// Handle the session, reading and writing.
+    /// Should only be called once, returns when the connection should be dropped.
+    pub(crate) async fn handle(mut self) {
+        debug!(session = self.id, peer:% = self.addr; "Accepted new client.");
+
+        let result = self.run().await;
+
+        if let Some(metrics) = &self.metrics {
+            metrics.connection_closed();
+        }
+
+        if self.machine.state().from.is_some() {
+            let reason = match &result {
+                Ok(true) => AbortReason::Quit,
+                Ok(false) => AbortReason::Eof,
+                Err(SessionError::Timeout) => AbortReason::Timeout,
+                Err(SessionError::Io(_)) => AbortReason::Error,
+                Err(SessionError::PolicyReject | SessionError::ProtocolViolation) => {
+                    AbortReason::Closed
+                }
+            };
+
+            debug!(session = self.id, peer:% = self.addr, reason:? = reason; "Transaction aborted.");
+            let aborted = self
+                .handler
+                .transaction_aborted(self.machine.state(), reason);
+            if bounded(self.config.handler_timeout, aborted)
+                .await
+                .is_none()
+            {
+                warn!(session = self.id, peer:% = self.addr; "Handler timed out handling the aborted transaction.");
+            }
+        }
+
+        match result {
+            Ok(_) => debug!(session = self.id, peer:% = self.addr; "Closed client."),
+            Err(SessionError::Io(e)) => warn!(
+                session = self.id, peer:% = self.addr, error:% = e;
+                "Closed client after a connection error."
+            ),
+            Err(e) => debug!(session = self.id, peer:% = self.addr, reason:% = e; "Closed client."),
+        }
+    }
+
+    /// Run the session until the connection should be closed.
+    /// Returns `Ok` when the client quit or closed the connection, with true when it quit, otherwise the reason it was closed.
+    async fn run(&mut self) -> Result<bool, SessionError> {
+        let mut buff = vec![0; 1024];
+        self.deadline = self
+            .config
+            .max_session_duration
+            .map(|duration| Instant::now() + duration);
+
+        let early_input = if self.config.trust_proxy {
+            self.read_proxy_header(&mut buff).await?
+        } else {
+            Vec::new()
+        };
+
+        match self.load_hint().await {
+            LoadLevel::Normal => {}
+            LoadLevel::High => {
+                debug!(session = self.id, peer:% = self.addr; "Serving the client strictly under high load.");
+                self.tighten();
+            }
+            LoadLevel::Critical => {
+                debug!(session = self.id, peer:% = self.addr; "Refused the client under critical load.");
+                self.rejected(Rejection::RateLimited);
+                self.send(&Response::ServiceUnavailable(
+                    self.config.hostname().to_string(),
+                ));
+                self.flush().await?;
+                return Err(SessionError::PolicyReject);
+            }
+        }
+
+        if !self.connection_allowed().await {
+            debug!(session = self.id, peer:% = self.addr; "Handler refused the client.");
+            self.send(&Response::ConnectionRefused);
+            self.flush().await?;
+            return Err(SessionError::PolicyReject);
+        }
+
+        if let Some(delay) = self.config.greet_delay {
+            if !early_input.is_empty() || self.talks_early(&mut buff, delay).await {
+                debug!(session = self.id, peer:% = self.addr; "Client sent data before the greeting.");
+                self.send(&Response::EarlyTalker);
+                self.flush().await?;
+                return Err(SessionError::PolicyReject);
+            }
+        }
+
+        let greeting = self.machine.greeting();
+        self.send(&greeting);
+        self.flush().await?;
+
+        if !early_input.is_empty() && self.received(&early_input).await? {
+            return Ok(true);
+        }
+
+        loop {
+            let n = self.read(&mut buff).await?;
+            if n == 0 {
+                return Ok(false);
+            }
+
+            if self.received(&buff[..n]).await? {
+                return Ok(true);
+            }
+
+            // A client sending input without pause never hits the read limit.
+            if self.deadline.is_some_and(|d| Instant::now() >= d) {
+                debug!(session = self.id, peer:% = self.addr; "Session time limit exceeded.");
+                self.send(&Response::SessionExpired);
+                self.flush().await?;
+                return Err(SessionError::Timeout);
+            }
+        }
+    }
+
+    /// Wait for input from the client, closing the connection with a 421 when the command timeout
+    /// or the session time limit expires, or the session is evicted or disconnected.
+    /// Returns the number of bytes read, which is zero when the client closed the connection.
+    async fn read(&mut self, buff: &mut [u8]) -> Result<usize, SessionError> {
+        let (limit, expired) = self.read_limit();
+
+        // Waiting for more of a message, after DATA or within a BDAT chunk, is not idle.
+        // Only waiting for the next command is.
+        let idle = !self.receiving_message();
+        if let (Some(activity), true) = (&self.activity, idle) {
+            activity.idle();
+        }
+
+        let stream = &mut self.stream;
+        let read = async {
+            match limit {
+                Some(duration) => timeout(duration, stream.read(buff)).await,
+                None => Ok(stream.read(buff).await),
+            }
+        };
+        let activity = self.activity.as_ref();
+        let evicted = async {
+            match activity {
+                Some(activity) if idle => activity.evicted().await,
+                _ => std::future::pending().await,
+            }
+        };
+        let disconnected = async {
+            match activity {
+                Some(activity) => activity.disconnected().await,
+                None => std::future::pending().await,
+            }
+        };
+
+        let read = tokio::select! {
+            read = read => Ok(read),
+            _ = evicted => Err(Response::Evicted),
+            _ = disconnected => Err(Response::Disconnected),
+        };
+
+        let read = match read {
+            Ok(read) => read,
+            Err(response) => {
+                if response == Response::Evicted {
+                    debug!(session = self.id, peer:% = self.addr; "Evicted idle client.");
+                    self.rejected(Rejection::RateLimited);
+                } else {
+                    debug!(session = self.id, peer:% = self.addr; "Disconnected client on request.");
+                }
+
+                self.send(&response);
+                self.flush().await?;
+                return Err(SessionError::PolicyReject);
+            }
+        };
+
+        if let Some(activity) = &self.activity {
+            activity.active();
+        }
+
+        match read {
+            Ok(n) => {
+                let n = n?;
+                if let Some(metrics) = &self.metrics {
+                    metrics.bytes_received(n);
+                }
+
+                Ok(n)
+            }
+            Err(_) => {
+                debug!(session = self.id, peer:% = self.addr, response:? = expired; "Client timed out.");
+                self.send(&expired);
+                self.flush().await?;
+                Err(SessionError::Timeout)
+            }
+        }
+    }
+
+    /// Time to wait for input, with the reply to send when it expires.
+    /// This is the command timeout, unless the session time limit is reached earlier.
+    fn read_limit(&self) -> (Option<Duration>, Response) {
+        let remaining = self
+            .deadline
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()));
+
+        match (self.config.command_timeout, remaining) {
+            (Some(command), Some(remaining)) if command < remaining => {
+                (Some(command), Response::Timeout)
+            }
+            (_, Some(remaining)) => (Some(remaining), Response::SessionExpired),
+            (command, None) => (command, Response::Timeout),
+        }
+    }
+
+    /// Ask the handler for the current load, assuming normal load when it does not answer in time.
+    async fn load_hint(&self) -> LoadLevel {
+        match bounded(self.config.handler_timeout, self.handler.load_hint()).await {
+            Some(level) => level,
+            None => {
+                warn!(session = self.id, peer:% = self.addr; "Handler timed out reporting the load, assuming normal load.");
+                LoadLevel::Normal
+            }
+        }
+    }
+
+    /// Shorten the command timeout and delay the greeting, for a client connecting under high load.
+    fn tighten(&mut self) {
+        let mut config = (*self.config).clone();
+
+        config.command_timeout = Some(
+            config
+                .command_timeout
+                .map_or(HIGH_LOAD_COMMAND_TIMEOUT, |t| {
+                    t.min(HIGH_LOAD_COMMAND_TIMEOUT)
+                }),
+        );
+        config.greet_delay = Some(
+            config
+                .greet_delay
+                .map_or(HIGH_LOAD_GREET_DELAY, |d| d.max(HIGH_LOAD_GREET_DELAY)),
+        );
+
+        self.config = Arc::new(config);
+    }
+
+    /// Look up the reverse DNS of the client when the service has a resolver, and let the handler decide on the client.
+    async fn connection_allowed(&mut self) -> bool {
+        if let Some(resolver) = self.config.resolver.clone() {
+            let lookup = forward_confirmed(resolver.as_ref(), self.addr.ip());
+            let name = match bounded(self.config.handler_timeout, lookup).await {
+                Some(name) => name,
+                None => {
+                    warn!(session = self.id, peer:% = self.addr; "Reverse DNS lookup timed out.");
+                    None
+                }
+            };
+
+            debug!(session = self.id, peer:% = self.addr, name:? = name.as_deref().map(|n| redact::text(self.config.log_redaction, n)); "Looked up the reverse DNS.");
+            self.machine.set_reverse_dns(name);
+        }
+
+        let allowed = self.handler.connection_allowed(self.machine.state());
+        match bounded(self.config.handler_timeout, allowed).await {
+            Some(allowed) => allowed,
+            None => {
+                warn!(session = self.id, peer:% = self.addr; "Handler timed out allowing the client, allowing it.");
+                true
+            }
+        }
+    }
+
+    /// Wait for the greeting delay, returning true when the client sends or closes during it.
+    async fn talks_early(&mut self, buff: &mut [u8], delay: Duration) -> bool {
+        timeout(delay, self.stream.read(buff)).await.is_ok()
+    }
+
+    /// Read the PROXY protocol header, replacing the client address with the one from the header.
+    /// Returns the input following the header, failing when no valid header was received.
+    async fn read_proxy_header(&mut self, buff: &mut [u8]) -> Result<Vec<u8>, SessionError> {
+        let mut input = Vec::new();
+
+        loop {
+            let n = self.read(buff).await?;
+            if n == 0 {
+                debug!(session = self.id, peer:% = self.addr; "Client closed without PROXY header.");
+                return Err(SessionError::ProtocolViolation);
+            }
+            input.extend_from_slice(&buff[..n]);
+
+            match parse_proxy_header(&input) {
+                ProxyHeader::Incomplete => continue,
+                ProxyHeader::Invalid => {
+                    debug!(session = self.id, peer:% = self.addr; "Received an invalid PROXY header.");
+                    return Err(SessionError::ProtocolViolation);
+                }
+                ProxyHeader::Complete { source, length } => {
+                    if let Some(source) = source {
+                        debug!(session = self.id, peer:% = self.addr, client:% = source; "Client connected through a proxy.");
+                        self.addr = source;
+                        self.machine.set_peer(source);
+                    }
+
+                    return Ok(input.split_off(length));
+                }
+            }
+        }
+    }
+
+    /// Handle bytes received from the client.
+    /// Returns true when the client quit.
+    async fn received(&mut self, bytes: &[u8]) -> Result<bool, SessionError> {
+        // Replies to the whole read are written at once, instead of one write per command.
+        let quit = self.input(bytes).await?;
+        self.flush().await?;
+        if quit {
+            debug!(session = self.id, peer:% = self.addr; "Server indicated to quit.");
+        }
+
+        Ok(quit)
+    }
+
+    /// Handle new incoming input.
+    /// Commands are processed in batches, yielding to other sessions between them.
+    /// Every command is answered in order, so a QUIT closes the connection only after the replies before it.
+    /// Input after an accepted DATA is message data, including lines like QUIT, until the end of data.
+    /// Every command line takes a token from the rate limit, message data does not.
+    /// The DATA prompt is written before any data following it in the same read is processed.
+    /// Message data is passed on as octets, only command lines have to be valid UTF-8.
+    /// The chunk following BDAT is taken by its size instead of lines, the command is answered once it is complete.
+    async fn input(&mut self, input: &[u8]) -> Result<bool, SessionError> {
+        self.remaining.extend_from_slice(input);
+
+        loop {
+            let full_input = std::mem::take(&mut self.remaining);
+
+            let full_input = if self.machine.receiving_chunk() {
+                let (action, rem) = self.machine.chunk(&full_input);
+                self.traced(Direction::In, &full_input[..full_input.len() - rem.len()]);
+
+                match action {
+                    Some(action) => {
+                        if self.perform(action, Stage::Message).await? {
+                            return Ok(true);
+                        }
+
+                        rem
+                    }
+                    None => return Ok(false),
+                }
+            } else {
+                full_input
+            };
+
+            let full_input = if self.machine.receiving_data() {
+                let (action, rem) = self.machine.data(&full_input);
+                self.traced(Direction::In, &full_input[..full_input.len() - rem.len()]);
+
+                if let Some(upload) = self.upload.as_mut() {
+                    upload
+                        .send(self.machine.take_data(), self.config.save_timeout)
+                        .await;
+                }
+
+                if let Some(action) = action {
+                    if let Action::Reply(_) = action {
+                        // The message was rejected while it was received, so it is never saved.
+                        self.upload = None;
+
+                        if let Some(metrics) = &self.metrics {
+                            metrics.message_rejected();
+                        }
+                    }
+
+                    if self.perform(action, Stage::Message).await? {
+                        return Ok(true);
+                    }
+                }
+
+                // The rest is an unterminated line of data, which is completed by the next read.
+                if self.machine.receiving_data() {
+                    self.remaining = rem;
+                    return Ok(false);
+                }
+
+                rem
+            } else {
+                full_input
+            };
+
+            let full_input = match self.overlong {
+                true => match full_input.iter().position(|&b| b == b'\n') {
+                    Some(end) => {
+                        self.overlong = false;
+                        let action = self.machine.line_too_long();
+                        if self.perform(action, Stage::Command).await? {
+                            return Ok(true);
+                        }
+
+                        full_input[end + 1..].to_vec()
+                    }
+                    None => return Ok(false),
+                },
+                false => full_input,
+            };
+
+            let (batch, deferred) = split_batch(&full_input, self.config.max_commands_per_batch);
+            let mut rest = batch;
+
+            while let Some(end) = rest.iter().position(|&b| b == b'\n') {
+                let (line, tail) = rest.split_at(end + 1);
+                rest = tail;
+
+                if let Some(wait) = self.bucket.as_mut().and_then(CommandBucket::take) {
+                    self.throttle(wait).await?;
+                }
+                self.traced(Direction::In, line);
+
+                if line.len() > self.config.max_command_length {
+                    debug!(session = self.id, peer:% = self.addr, length = line.len(); "Command line too long.");
+                    let action = self.machine.line_too_long();
+
+                    if self.perform(action, Stage::Command).await? {
+                        return Ok(true);
+                    }
+                    continue;
+                }
+
+                let line = match std::str::from_utf8(line) {
+                    Ok(line) => line,
+                    Err(_) => {
+                        debug!(session = self.id, peer:% = self.addr; "Received a non-utf8 command.");
+                        // The commands before it were processed, so their replies are still sent.
+                        self.flush().await?;
+                        return Err(SessionError::ProtocolViolation);
+                    }
+                };
+
+                if self.config.line_ending == LineEnding::Strict && !line.ends_with("\r\n") {
+                    debug!(session = self.id, peer:% = self.addr; "Command ended in a bare LF.");
+                    let action = self.machine.invalid_command();
+
+                    if self.perform(action, Stage::Command).await? {
+                        return Ok(true);
+                    }
+                    continue;
+                }
+
+                let (cmds, _) = super::parser::parse(line);
+
+                for (text, command) in cmds {
+                    debug!(session = self.id, peer:% = self.addr, command:? = command.as_ref().map(|c| redact::command(self.config.log_redaction, c)); "Processing command.");
+
+                    // Nothing sent after DATA could have waited for its reply.
+                    if self.config.strict_pipelining
+                        && matches!(command, Some(Command::DATA))
+                        && !(rest.is_empty() && deferred.is_empty())
+                    {
+                        debug!(session = self.id, peer:% = self.addr; "Client sent input before the DATA reply.");
+                        self.rejected(Rejection::OutOfSequence);
+                        self.send(&Response::PipelinedData);
+                        self.flush().await?;
+                        return Err(SessionError::ProtocolViolation);
+                    }
+
+                    let stage = match &command {
+                        Some(Command::FROM(_, _)) => Stage::Sender,
+                        Some(Command::RCPT(_, _)) => Stage::Recipient,
+                        _ => Stage::Command,
+                    };
+                    let action = match command {
+                        Some(c) => self.machine.command(c),
+                        None => self.unknown_command(text).await,
+                    };
+
+                    if self.perform(action, stage).await? {
+                        return Ok(true);
+                    }
+                }
+
+                // Anything following an accepted DATA or a BDAT is message data, not commands.
+                if self.receiving_message() {
+                    break;
+                }
+            }
+
+            self.remaining = [rest, deferred].concat();
+
+            // An unterminated command line is only kept while it could still fit the limit.
+            if !self.receiving_message()
+                && deferred.is_empty()
+                && rest.len() > self.config.max_command_length
+            {
+                debug!(session = self.id, peer:% = self.addr; "Discarding an overlong command line.");
+                self.remaining.clear();
+                self.overlong = true;
+            }
+
+            if self.receiving_message() && !self.remaining.is_empty() {
+                continue;
+            }
+
+            if deferred.is_empty() {
+                return Ok(false);
+            }
+
+            debug!(session = self.id, peer:% = self.addr; "Deferring pipelined commands to the next batch.");
+            tokio::task::yield_now().await;
+        }
+    }
+
+    /// Check if the input is message data, after DATA or BDAT, instead of commands.
+    fn receiving_message(&self) -> bool {
+        self.machine.receiving_data() || self.machine.receiving_chunk()
+    }
+
+    /// Delay the next command of a client exceeding its command rate.
+    /// The replies so far are sent first, so the client receives them at the allowed rate.
+    async fn throttle(&mut self, wait: Duration) -> Result<(), SessionError> {
+        debug!(session = self.id, peer:% = self.addr, wait:? = wait; "Command rate exceeded, delaying the client.");
+        self.flush().await?;
+        tokio::time::sleep(wait).await;
+        Ok(())
+    }
+
+    /// Let the handler answer a command line which could not be parsed.
+    async fn unknown_command(&mut self, line: &str) -> Action {
+        let handled = self.handler.unknown_command(line, self.machine.state_mut());
+        let response = match bounded(self.config.handler_timeout, handled).await {
+            Some(response) => response,
+            None => {
+                warn!(session = self.id, peer:% = self.addr; "Handler timed out handling an unknown command.");
+                None
+            }
+        };
+
+        self.machine.unknown_checked(response)
+    }
+
+    /// Perform an action requested by the state machine, answering the given stage of the transaction.
+    /// Returns true when the client quit, or the reason when the server closes the connection.
+    async fn perform(&mut self, action: Action, stage: Stage) -> Result<bool, SessionError> {
+        let (response, close) = match action {
+            Action::Reply(response) => (response, false),
+            Action::Close(response) => (response, true),
+            Action::CheckRecipient(recipient, params) => {
+                let check = check_recipient(
+                    self.handler.as_ref(),
+                    self.machine.state(),
+                    recipient.clone(),
+                );
+                let (recipient, result) = match bounded(self.config.handler_timeout, check).await {
+                    Some(checked) => checked,
+                    None => {
+                        warn!(session = self.id, peer:% = self.addr; "Handler timed out checking the recipient.");
+                        (recipient, RecipientDecision::Custom(Response::TryLater))
+                    }
+                };
+
+                (
+                    self.machine.recipient_checked(recipient, params, result),
+                    false,
+                )
+            }
+            Action::CheckHelo => {
+                let state = self.machine.state();
+                let identity = state.helo_identity().unwrap_or_default();
+                let allowed = self.handler.helo_allowed(state, identity);
+                let allowed = match bounded(self.config.handler_timeout, allowed).await {
+                    Some(allowed) => allowed,
+                    None => {
+                        warn!(session = self.id, peer:% = self.addr; "Handler timed out checking the HELO identity, allowing it.");
+                        true
+                    }
+                };
+
+                (self.machine.helo_checked(allowed), false)
+            }
+            Action::CheckData => {
+                let decision = self.data_allowed().await;
+                (self.machine.data_checked(decision), false)
+            }
+            Action::ReadChunk(_) => return Ok(false),
+            Action::CheckChunk => {
+                let decision = self.data_allowed().await;
+                let action = self.machine.chunk_checked(decision);
+                return Box::pin(self.perform(action, stage)).await;
+            }
+            Action::Expand(list) => {
+                let expand = self.handler.expand(&list);
+                let members = match bounded(self.config.handler_timeout, expand).await {
+                    Some(members) => members,
+                    None => {
+                        warn!(session = self.id, peer:% = self.addr; "Handler timed out expanding the list.");
+                        None
+                    }
+                };
+
+                (self.machine.expanded(members), false)
+            }
+            Action::Save => {
+                let (saved, size) = match self.upload.take() {
+                    Some(upload) => {
+                        let size = upload.size;
+                        (upload.finish(self.config.save_timeout).await, size)
+                    }
+                    None => {
+                        let save = self.handler.save_message(self.machine.state());
+                        let saved = bounded(self.config.save_timeout, save).await;
+                        (saved, self.machine.state().data.len())
+                    }
+                };
+
+                let result = match saved {
+                    Some(result) => result,
+                    None => {
+                        warn!(session = self.id, peer:% = self.addr; "Handler failed to save the message in time.");
+                        SaveOutcome::Rejected(Response::TryLater)
+                    }
+                };
+
+                if let Some(metrics) = &self.metrics {
+                    match result.is_accepted() {
+                        true => metrics.message_accepted(size),
+                        false => metrics.message_rejected(),
+                    }
+                }
+
+                (self.machine.saved(result), false)
+            }
+        };
+
+        if let Some(reason) = rejection(&response, stage) {
+            self.rejected(reason);
+        }
+
+        // The client waits for the DATA prompt before sending the message, so it can't wait for the batch.
+        let flush = close || response == Response::StartData;
+        self.send(&response);
+
+        if response == Response::StartData && self.config.stream_data {
+            let state = self.machine.state().clone();
+            self.upload = Some(BodyUpload::start(self.handler.clone(), state));
+        }
+
+        if flush {
+            self.flush().await?;
+        }
+
+        match (close, response) {
+            (false, _) => Ok(false),
+            (true, Response::Goodbye) => Ok(true),
+            (true, Response::TooManyErrors) => Err(SessionError::ProtocolViolation),
+            (true, _) => Err(SessionError::PolicyReject),
+        }
+    }
+
+    /// Ask the handler if the transaction may transfer its data, deferring it when the handler does not answer in time.
+    async fn data_allowed(&self) -> DataDecision {
+        let allowed = self.handler.data_allowed(self.machine.state());
+        match bounded(self.config.handler_timeout, allowed).await {
+            Some(decision) => decision,
+            None => {
+                warn!(session = self.id, peer:% = self.addr; "Handler timed out allowing the data.");
+                DataDecision::TryLater
+            }
+        }
+    }
+
+    /// Report a refusal to the metrics, if there are any.
+    fn rejected(&self, reason: Rejection) {
+        if let Some(metrics) = &self.metrics {
+            metrics.rejected(reason);
+        }
+    }
+
+    /// Queue a response for the client, it is written on the next flush.
+    fn send(&mut self, res: &Response) {
+        debug!(session = self.id, peer:% = self.addr, response:% = redact::response(self.config.log_redaction, res); "Sending response.");
+
+        let reply = res.to_response_with(&self.config.reply_texts);
+        self.traced(Direction::Out, reply.as_bytes());
+        self.output.extend_from_slice(reply.as_bytes());
+    }
+
+    /// Pass protocol text to the tracer, if there is one.
+    fn traced(&self, direction: Direction, text: &[u8]) {
+        if let (Some(trace), false) = (&self.trace, text.is_empty()) {
+            trace(direction, text);
+        }
+    }
+
+    /// Write the queued responses to the client.
+    async fn flush(&mut self) -> Result<(), SessionError> {
+        if self.output.is_empty() {
+            return Ok(());
+        }
+
+        let written = self.stream.write_all(&self.output).await;
+        self.output.clear();
+
+        Ok(written?)
+    }
+}
+
+/// Let the handler decide on a recipient, and rewrite it when accepted.
+/// Returns the recipient to store with the decision.
+async fn check_recipient(
+    handler: &dyn Handler,
+    state: &SmtpState,
+    recipient: Mailbox,
+) -> (Mailbox, RecipientDecision) {
+    let decision = handler.recipient_allowed(state, &recipient).await;
+
+    if !matches!(
+        decision,
+        RecipientDecision::Accept | RecipientDecision::Relay
+    ) {
+        return (recipient, decision);
+    }
+
+    match handler.rewrite_recipient(recipient.clone()).await {
+        Some(rewritten) => (rewritten, decision),
+        None => (recipient, RecipientDecision::RejectPermanent),
+    }
+}
+
+/// Await a handler call, returning `None` when it did not complete within the limit.
+async fn bounded<T>(limit: Option<Duration>, call: impl Future<Output = T>) -> Option<T> {
+    match limit {
+        Some(duration) => timeout(duration, call).await.ok(),
+        None => Some(call.await),
+    }
+}
+
+/// Stage of the transaction a reply answers, telling apart the refusals reported to the metrics.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Stage {
+    Command,
+    Sender,
+    Recipient,
+    Message,
+}
+
+/// Reason to report for a reply refusing the client, `None` for a positive reply.
+/// Replies naming their own reason count towards it, any other refusal towards the stage it answers.
+fn rejection(response: &Response, stage: Stage) -> Option<Rejection> {
+    let reason = match response {
+        Response::SyntaxError
+        | Response::LineTooLong
+        | Response::InvalidParameters
+        | Response::NotImplemented
+        | Response::InvalidHelo
+        | Response::TooManyErrors => Rejection::Syntax,
+        Response::OutOfSequence | Response::NestedMail | Response::BdatRequired => {
+            Rejection::OutOfSequence
+        }
+        Response::SizeExceeded | Response::MessageTooBig => Rejection::Size,
+        Response::TooManyRecipients
+        | Response::TooManyRecipientsClosing
+        | Response::TooManyTransactions => Rejection::RateLimited,
+        _ if response.is_positive() => return None,
+        _ => match stage {
+            Stage::Command => return None,
+            Stage::Sender => Rejection::Sender,
+            Stage::Recipient => Rejection::Recipient,
+            Stage::Message => Rejection::Content,
+        },
+    };
+
+    Some(reason)
+}
+
+/// Split the input after `max` complete lines, returning the batch and the deferred rest.
+fn split_batch(input: &[u8], max: usize) -> (&[u8], &[u8]) {
+    let mut breaks = input
+        .iter()
+        .enumerate()
+        .filter(|(_, &b)| b == b'\n')
+        .map(|(i, _)| i);
+
+    match breaks.nth(max.max(1) - 1) {
+        Some(i) => input.split_at(i + 1),
+        None => (input, &[]),
+    }
+}
@@ -0,0 +1,1998 @@
+use async_trait::async_trait;
+use std::{
+    pin::Pin,
+    sync::atomic::{AtomicUsize, Ordering},
+    task::{Context, Poll},
+};
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, DuplexStream, ReadBuf,
+};
+use tokio::net::TcpListener;
+
+use super::*;
+use crate::{
+    command::Mailbox, BodyStream, DataDecision, MetricsCounters, RecipientDecision, Rejection,
+    SaveOutcome, SmtpState,
+};
+
+struct AcceptingHandler {}
+
+#[async_trait]
+impl Handler for AcceptingHandler {
+    async fn recipient_local(&self, _recipient: &Mailbox) -> bool {
+        true
+    }
+
+    async fn save(&self, _state: &SmtpState) -> bool {
+        true
+    }
+}
+
+/// Handler only allowing data for transactions with a single recipient.
+struct SingleRecipientHandler {}
+
+#[async_trait]
+impl Handler for SingleRecipientHandler {
+    async fn recipient_local(&self, _recipient: &Mailbox) -> bool {
+        true
+    }
+
+    async fn data_allowed(&self, state: &SmtpState) -> DataDecision {
+        match state.recipients.len() {
+            1 => DataDecision::Accept,
+            _ => DataDecision::Reject,
+        }
+    }
+
+    async fn save(&self, _state: &SmtpState) -> bool {
+        true
+    }
+}
+
+/// Handler only allowing data from the client behind the proxy in `PROXY_HEADER`.
+struct ProxiedClientHandler {}
+
+#[async_trait]
+impl Handler for ProxiedClientHandler {
+    async fn recipient_local(&self, _recipient: &Mailbox) -> bool {
+        true
+    }
+
+    async fn data_allowed(&self, state: &SmtpState) -> DataDecision {
+        match state.peer {
+            Some(peer) if peer == "192.0.2.1:56324".parse().unwrap() => DataDecision::Accept,
+            _ => DataDecision::Reject,
+        }
+    }
+
+    async fn save(&self, _state: &SmtpState) -> bool {
+        true
+    }
+}
+
+/// Handler rejecting external clients claiming to be the server itself.
+struct ForgedHeloHandler {}
+
+#[async_trait]
+impl Handler for ForgedHeloHandler {
+    async fn helo_allowed(&self, state: &SmtpState, identity: &str) -> bool {
+        let external = matches!(state.peer, Some(peer) if !peer.ip().is_loopback());
+
+        !(external && identity.eq_ignore_ascii_case("mx.nexium.app"))
+    }
+
+    async fn recipient_local(&self, _recipient: &Mailbox) -> bool {
+        true
+    }
+
+    async fn save(&self, _state: &SmtpState) -> bool {
+        true
+    }
+}
+
+/// Handler taking longer than the limits used in the timeout tests.
+struct SlowHandler {}
+
+#[async_trait]
+impl Handler for SlowHandler {
+    async fn recipient_local(&self, recipient: &Mailbox) -> bool {
+        if recipient.local == "slow" {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+        }
+
+        true
+    }
+
+    async fn save(&self, _state: &SmtpState) -> bool {
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        true
+    }
+}
+
+/// Handler saving every message with the queue id `ABC123`.
+struct QueueingHandler {}
+
+#[async_trait]
+impl Handler for QueueingHandler {
+    async fn recipient_local(&self, _recipient: &Mailbox) -> bool {
+        true
+    }
+
+    async fn save(&self, _state: &SmtpState) -> bool {
+        true
+    }
+
+    async fn save_message(&self, _state: &SmtpState) -> SaveOutcome {
+        SaveOutcome::Queued("ABC123".into())
+    }
+}
+
+/// Handler keeping the data of every saved message.
+#[derive(Default)]
+struct RecordingHandler {
+    messages: std::sync::Mutex<Vec<Vec<u8>>>,
+}
+
+#[async_trait]
+impl Handler for RecordingHandler {
+    async fn recipient_local(&self, _recipient: &Mailbox) -> bool {
+        true
+    }
+
+    async fn save(&self, state: &SmtpState) -> bool {
+        self.messages.lock().unwrap().push(state.data.clone());
+        true
+    }
+}
+
+/// Handler recording the saved messages, but only saving one once the test allows it.
+struct GatedHandler {
+    gate: tokio::sync::Semaphore,
+    messages: std::sync::Mutex<Vec<Vec<u8>>>,
+}
+
+#[async_trait]
+impl Handler for GatedHandler {
+    async fn recipient_local(&self, _recipient: &Mailbox) -> bool {
+        true
+    }
+
+    async fn save(&self, state: &SmtpState) -> bool {
+        self.gate.acquire().await.unwrap().forget();
+        self.messages.lock().unwrap().push(state.data.clone());
+        true
+    }
+}
+
+/// Handler implementing `XNOOP`, and `XTRUST` which marks the client as authenticated.
+/// Only authenticated clients may send data. Recipients outside of nexium.app are relayed.
+struct ExtensionHandler {}
+
+#[async_trait]
+impl Handler for ExtensionHandler {
+    async fn recipient_local(&self, _recipient: &Mailbox) -> bool {
+        true
+    }
+
+    async fn check_recipient(&self, recipient: &Mailbox) -> RecipientDecision {
+        match recipient.domain.normalized().as_str() {
+            "nexium.app" => RecipientDecision::Accept,
+            _ => RecipientDecision::Relay,
+        }
+    }
+
+    async fn data_allowed(&self, state: &SmtpState) -> DataDecision {
+        match state.authenticated {
+            true => DataDecision::Accept,
+            false => DataDecision::Reject,
+        }
+    }
+
+    async fn unknown_command(&self, line: &str, state: &mut SmtpState) -> Option<Response> {
+        match line.to_ascii_uppercase().as_str() {
+            "XNOOP" => Some(Response::Ok),
+            "XTRUST" => {
+                state.authenticated = true;
+                Response::custom(250, "Trusted")
+            }
+            _ => None,
+        }
+    }
+
+    async fn save(&self, _state: &SmtpState) -> bool {
+        true
+    }
+}
+
+/// Handler refusing the recipient `nobody` and every message.
+struct RefusingHandler {}
+
+#[async_trait]
+impl Handler for RefusingHandler {
+    async fn recipient_local(&self, recipient: &Mailbox) -> bool {
+        recipient.local != "nobody"
+    }
+
+    async fn save(&self, _state: &SmtpState) -> bool {
+        false
+    }
+}
+
+/// Handler accepting every recipient, counting how often it was asked.
+#[derive(Default)]
+struct SpyHandler {
+    lookups: AtomicUsize,
+}
+
+#[async_trait]
+impl Handler for SpyHandler {
+    async fn recipient_local(&self, _recipient: &Mailbox) -> bool {
+        self.lookups.fetch_add(1, Ordering::SeqCst);
+        true
+    }
+
+    async fn save(&self, _state: &SmtpState) -> bool {
+        true
+    }
+}
+
+/// Stream counting the writes made to it, to check how replies are batched.
+struct CountingStream {
+    inner: DuplexStream,
+    writes: Arc<AtomicUsize>,
+}
+
+impl AsyncRead for CountingStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for CountingStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        self.writes.fetch_add(1, Ordering::SeqCst);
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Handler streaming message bodies, reporting how many bytes it read or why reading failed.
+struct StreamingHandler {
+    read: tokio::sync::mpsc::UnboundedSender<std::io::Result<usize>>,
+}
+
+#[async_trait]
+impl Handler for StreamingHandler {
+    async fn recipient_local(&self, _recipient: &Mailbox) -> bool {
+        true
+    }
+
+    async fn save(&self, _state: &SmtpState) -> bool {
+        unreachable!("messages are streamed")
+    }
+
+    async fn save_stream(&self, _state: &SmtpState, mut body: BodyStream) -> SaveOutcome {
+        let mut buff = [0; 7];
+        let mut total = 0;
+
+        let read = loop {
+            match body.read(&mut buff).await {
+                Ok(0) => break Ok(total),
+                Ok(n) => total += n,
+                Err(e) => break Err(e),
+            }
+        };
+
+        let result = match read {
+            Ok(_) => SaveOutcome::Queued("STREAMED".into()),
+            Err(_) => SaveOutcome::Rejected(Response::TransactionFailed),
+        };
+        self.read.send(read).unwrap();
+        result
+    }
+}
+
+/// Start a session streaming bodies to a `StreamingHandler`, receiving what it read.
+async fn connect_streaming(
+    mut config: Config,
+) -> (
+    BufReader<TcpStream>,
+    tokio::sync::mpsc::UnboundedReceiver<std::io::Result<usize>>,
+) {
+    config.stream_data = true;
+    let (read, results) = tokio::sync::mpsc::unbounded_channel();
+
+    let mut client =
+        BufReader::new(connect_with(config, Arc::new(StreamingHandler { read })).await);
+    client
+        .write_all(
+            b"HELO nexium.app\r\nMAIL FROM:<a@nexium.app>\r\nRCPT TO:<b@nexium.app>\r\nDATA\r\n",
+        )
+        .await
+        .unwrap();
+    read_lines(&mut client, 5).await;
+
+    (client, results)
+}
+
+/// Handler recording the transaction id seen when allowing and when saving every message.
+#[derive(Default)]
+struct TransactionHandler {
+    seen: std::sync::Mutex<Vec<(&'static str, u64)>>,
+}
+
+#[async_trait]
+impl Handler for TransactionHandler {
+    async fn recipient_local(&self, _recipient: &Mailbox) -> bool {
+        true
+    }
+
+    async fn data_allowed(&self, state: &SmtpState) -> DataDecision {
+        self.seen
+            .lock()
+            .unwrap()
+            .push(("data", state.transaction_id));
+        DataDecision::Accept
+    }
+
+    async fn save(&self, state: &SmtpState) -> bool {
+        self.seen
+            .lock()
+            .unwrap()
+            .push(("save", state.transaction_id));
+        true
+    }
+}
+
+/// Resolver knowing only the loopback address, optionally with a PTR name.
+struct LoopbackResolver {
+    name: Option<&'static str>,
+}
+
+#[async_trait]
+impl crate::Resolver for LoopbackResolver {
+    async fn reverse(&self, ip: std::net::IpAddr) -> Vec<String> {
+        match (ip.is_loopback(), self.name) {
+            (true, Some(name)) => vec![name.to_string()],
+            _ => vec![],
+        }
+    }
+
+    async fn forward(&self, name: &str) -> Vec<std::net::IpAddr> {
+        match Some(name) == self.name {
+            true => vec!["127.0.0.1".parse().unwrap()],
+            false => vec![],
+        }
+    }
+}
+
+/// Handler refusing clients without forward-confirmed reverse DNS.
+struct ReverseDnsHandler {}
+
+#[async_trait]
+impl Handler for ReverseDnsHandler {
+    async fn connection_allowed(&self, state: &SmtpState) -> bool {
+        state.reverse_dns.is_some()
+    }
+
+    async fn recipient_local(&self, _recipient: &Mailbox) -> bool {
+        true
+    }
+
+    async fn save(&self, _state: &SmtpState) -> bool {
+        true
+    }
+}
+
+/// Connect with a resolver giving the client the PTR name, if any.
+async fn connect_resolved(name: Option<&'static str>) -> TcpStream {
+    let mut config = Config::new("test".into());
+    config.resolver = Some(Arc::new(LoopbackResolver { name }));
+
+    connect_with(config, Arc::new(ReverseDnsHandler {})).await
+}
+
+/// Stream which never receives input and fails every write, like a connection reset by the client.
+struct BrokenStream;
+
+impl AsyncRead for BrokenStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        _buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Poll::Pending
+    }
+}
+
+impl AsyncWrite for BrokenStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        _buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Poll::Ready(Err(std::io::ErrorKind::ConnectionReset.into()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Create a session on the given stream with the accepting handler.
+fn session_on<S: AsyncRead + AsyncWrite + Unpin + Send>(
+    stream: S,
+    config: Config,
+) -> SmtpSession<S> {
+    SmtpSession::new(
+        stream,
+        "127.0.0.1:2525".parse().unwrap(),
+        Arc::new(config),
+        Arc::new(AcceptingHandler {}),
+        None,
+        None,
+        None,
+    )
+}
+
+/// Handler stripping the `+tag` from recipients, keeping the recipients of every saved message.
+#[derive(Default)]
+struct CanonicalHandler {
+    recipients: std::sync::Mutex<Vec<Mailbox>>,
+}
+
+#[async_trait]
+impl Handler for CanonicalHandler {
+    async fn recipient_local(&self, _recipient: &Mailbox) -> bool {
+        true
+    }
+
+    async fn rewrite_recipient(&self, mut recipient: Mailbox) -> Option<Mailbox> {
+        if recipient.local == "nobody" {
+            return None;
+        }
+
+        if let Some(end) = recipient.local.find('+') {
+            recipient.local.truncate(end);
+        }
+
+        Some(recipient)
+    }
+
+    async fn save(&self, state: &SmtpState) -> bool {
+        self.recipients
+            .lock()
+            .unwrap()
+            .extend(state.recipient_mailboxes().cloned());
+        true
+    }
+}
+
+const PROXY_HEADER: &[u8] = b"PROXY TCP4 192.0.2.1 192.0.2.2 56324 25\r\n";
+
+/// Start a session on a local socket, returning the client side of the connection.
+async fn connect(config: Config) -> TcpStream {
+    connect_with(config, Arc::new(AcceptingHandler {})).await
+}
+
+/// Start a session with a specific handler, returning the client side of the connection.
+async fn connect_with(config: Config, handler: Arc<dyn Handler>) -> TcpStream {
+    connect_metered(config, handler, None).await
+}
+
+/// Start a session recording its metrics, returning the client side of the connection.
+async fn connect_metered(
+    config: Config,
+    handler: Arc<dyn Handler>,
+    metrics: Option<Arc<dyn Metrics>>,
+) -> TcpStream {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let client = TcpStream::connect(listener.local_addr().unwrap())
+        .await
+        .unwrap();
+    let (stream, addr) = listener.accept().await.unwrap();
+
+    let session = SmtpSession::new(stream, addr, Arc::new(config), handler, metrics, None, None);
+    tokio::spawn(session.handle());
+
+    client
+}
+
+/// Read the given number of reply lines from the server.
+async fn read_lines<R: AsyncBufRead + Unpin>(client: &mut R, count: usize) -> Vec<String> {
+    let mut lines = Vec::new();
+
+    for _ in 0..count {
+        let mut line = String::new();
+        client.read_line(&mut line).await.unwrap();
+        lines.push(line.trim_end().to_string());
+    }
+
+    lines
+}
+
+/// Read everything the server sends until it closes the connection.
+async fn read_until_closed(client: &mut TcpStream) -> Vec<String> {
+    let mut output = String::new();
+    client.read_to_string(&mut output).await.unwrap();
+
+    output.lines().map(|l| l.to_string()).collect()
+}
+
+#[tokio::test]
+async fn bad_commands_close_connection() {
+    let mut config = Config::new("test".into());
+    config.max_bad_commands = 3;
+
+    let mut client = connect(config).await;
+    client
+        .write_all(b"NOPE\r\nNOPE\r\nNOPE\r\nNOPE\r\n")
+        .await
+        .unwrap();
+
+    let lines = read_until_closed(&mut client).await;
+
+    assert_eq!(
+        vec![
+            "220 test ESMTP",
+            "500 Syntax error",
+            "500 Syntax error",
+            "500 Syntax error",
+            "554 Too many errors, closing connection",
+        ],
+        lines
+    );
+}
+
+#[tokio::test]
+async fn blank_line_answered_once() {
+    let mut client = connect(Config::new("test".into())).await;
+    client
+        .write_all(b"HELO nexium.app\r\n\r\nMAIL FROM:<info@nexium.app>\r\nQUIT\r\n")
+        .await
+        .unwrap();
+
+    assert_eq!(
+        vec![
+            "220 test ESMTP",
+            "250 test ESMTP",
+            "500 Syntax error",
+            "250 Ok",
+            "221 Goodbye!",
+        ],
+        read_until_closed(&mut client).await
+    );
+}
+
+#[tokio::test]
+async fn command_line_too_long() {
+    let mut client = connect(Config::new("test".into())).await;
+    let line = format!("MAIL FROM:<{}@nexium.app>\r\n", "a".repeat(600 - 25));
+    assert_eq!(600, line.len());
+
+    client
+        .write_all(format!("HELO nexium.app\r\n{}NOOP\r\nQUIT\r\n", line).as_bytes())
+        .await
+        .unwrap();
+
+    assert_eq!(
+        vec![
+            "220 test ESMTP",
+            "250 test ESMTP",
+            "500 Line too long",
+            "250 Ok",
+            "221 Goodbye!",
+        ],
+        read_until_closed(&mut client).await
+    );
+}
+
+#[tokio::test]
+async fn overlong_line_discarded_across_reads() {
+    let mut client = BufReader::new(connect(Config::new("test".into())).await);
+    client.write_all(b"HELO nexium.app\r\n").await.unwrap();
+    read_lines(&mut client, 2).await;
+
+    for _ in 0..4 {
+        client.write_all(&[b'A'; 1000]).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    client.write_all(b"\r\nNOOP\r\n").await.unwrap();
+
+    assert_eq!(
+        vec!["500 Line too long", "250 Ok"],
+        read_lines(&mut client, 2).await
+    );
+}
+
+#[tokio::test]
+async fn bare_lf_accepted_when_lenient() {
+    let mut client = connect(Config::new("test".into())).await;
+    client
+        .write_all(b"HELO nexium.app\nMAIL FROM:<info@nexium.app>\nQUIT\r\n")
+        .await
+        .unwrap();
+
+    assert_eq!(
+        vec!["220 test ESMTP", "250 test ESMTP", "250 Ok", "221 Goodbye!"],
+        read_until_closed(&mut client).await
+    );
+}
+
+#[tokio::test]
+async fn bare_lf_rejected_when_strict() {
+    let mut config = Config::new("test".into());
+    config.line_ending = crate::LineEnding::Strict;
+    let mut client = connect(config).await;
+    client
+        .write_all(b"HELO nexium.app\r\nMAIL FROM:<info@nexium.app>\nNOOP\r\nQUIT\r\n")
+        .await
+        .unwrap();
+
+    assert_eq!(
+        vec![
+            "220 test ESMTP",
+            "250 test ESMTP",
+            "500 Syntax error",
+            "250 Ok",
+            "221 Goodbye!",
+        ],
+        read_until_closed(&mut client).await
+    );
+}
+
+/// Transaction with the message sent right after DATA, without waiting for its reply.
+const PIPELINED_BODY: &[u8] =
+    b"HELO nexium.app\r\nMAIL FROM:<info@nexium.app>\r\nRCPT TO:<admin@nexium.app>\r\nDATA\r\nHello\r\n.\r\nQUIT\r\n";
+
+#[tokio::test]
+async fn pipelined_body_tolerated() {
+    let mut client = connect(Config::new("test".into())).await;
+    client.write_all(PIPELINED_BODY).await.unwrap();
+
+    assert_eq!(
+        vec![
+            "220 test ESMTP",
+            "250 test ESMTP",
+            "250 Ok",
+            "250 Ok",
+            "354 Go ahead",
+            "250 Ok",
+            "221 Goodbye!",
+        ],
+        read_until_closed(&mut client).await
+    );
+}
+
+#[tokio::test]
+async fn pipelined_body_rejected_when_strict() {
+    let mut config = Config::new("test".into());
+    config.strict_pipelining = true;
+    let mut client = connect(config).await;
+    client.write_all(PIPELINED_BODY).await.unwrap();
+
+    assert_eq!(
+        vec![
+            "220 test ESMTP",
+            "250 test ESMTP",
+            "250 Ok",
+            "250 Ok",
+            "503 5.5.0 Data sent before the DATA reply, closing connection",
+        ],
+        read_until_closed(&mut client).await
+    );
+}
+
+#[tokio::test]
+async fn data_prompt_awaited_when_strict() {
+    let mut config = Config::new("test".into());
+    config.strict_pipelining = true;
+    let mut client = BufReader::new(connect(config).await);
+    client
+        .write_all(b"HELO nexium.app\r\nMAIL FROM:<info@nexium.app>\r\nRCPT TO:<admin@nexium.app>\r\nDATA\r\n")
+        .await
+        .unwrap();
+    assert_eq!("354 Go ahead", read_lines(&mut client, 5).await[4]);
+
+    client.write_all(b"Hello\r\n.\r\nQUIT\r\n").await.unwrap();
+    assert_eq!(
+        vec!["250 Ok", "221 Goodbye!"],
+        read_until_closed(client.get_mut()).await
+    );
+}
+
+#[tokio::test]
+async fn long_address_rejected_before_handler() {
+    let mut config = Config::new("test".into());
+    config.max_local_part_len = Some(64);
+    let handler = Arc::new(SpyHandler::default());
+    let mut client = connect_with(config, handler.clone()).await;
+
+    let recipient = format!("RCPT TO:<{}@nexium.app>\r\n", "a".repeat(65));
+    client
+        .write_all(format!("HELO nexium.app\r\nMAIL FROM:<info@nexium.app>\r\n{}RCPT TO:<admin@nexium.app>\r\nQUIT\r\n", recipient).as_bytes())
+        .await
+        .unwrap();
+
+    assert_eq!(
+        vec![
+            "220 test ESMTP",
+            "250 test ESMTP",
+            "250 Ok",
+            "501 Address too long",
+            "250 Ok",
+            "221 Goodbye!",
+        ],
+        read_until_closed(&mut client).await
+    );
+    assert_eq!(1, handler.lookups.load(Ordering::SeqCst));
+}
+
+#[tokio::test]
+async fn bad_commands_reset_on_valid_command() {
+    let mut config = Config::new("test".into());
+    config.max_bad_commands = 1;
+
+    let mut client = connect(config).await;
+    client
+        .write_all(b"NOPE\r\nEHLO nexium.app\r\nNOPE\r\nQUIT\r\n")
+        .await
+        .unwrap();
+
+    let lines = read_until_closed(&mut client).await;
+
+    assert_eq!(
+        vec![
+            "220 test ESMTP",
+            "500 Syntax error",
+            "250-test ESMTP",
+            "250-SIZE 0",
+            "250-8BITMIME",
+            "250-BINARYMIME",
+            "250-CHUNKING",
+            "250 SMTPUTF8",
+            "500 Syntax error",
+            "221 Goodbye!",
+        ],
+        lines
+    );
+}
+
+#[tokio::test]
+async fn data_rejected_by_handler() {
+    let mut client = connect_with(
+        Config::new("test".into()),
+        Arc::new(SingleRecipientHandler {}),
+    )
+    .await;
+    client
+        .write_all(
+            b"EHLO nexium.app\r\nMAIL FROM:<info@nexium.app>\r\nRCPT TO:<a@nexium.app>\r\nRCPT TO:<b@nexium.app>\r\nDATA\r\nQUIT\r\n",
+        )
+        .await
+        .unwrap();
+
+    let lines = read_until_closed(&mut client).await;
+
+    assert_eq!(
+        vec![
+            "220 test ESMTP",
+            "250-test ESMTP",
+            "250-SIZE 0",
+            "250-8BITMIME",
+            "250-BINARYMIME",
+            "250-CHUNKING",
+            "250 SMTPUTF8",
+            "250 Ok",
+            "250 Ok",
+            "250 Ok",
+            "550 Transaction rejected",
+            "221 Goodbye!",
+        ],
+        lines
+    );
+}
+
+#[tokio::test]
+async fn command_after_end_of_data() {
+    let mut client = BufReader::new(connect(Config::new("test".into())).await);
+    client
+        .get_mut()
+        .write_all(
+            b"EHLO nexium.app\r\nMAIL FROM:<info@nexium.app>\r\nRCPT TO:<a@nexium.app>\r\nDATA\r\n",
+        )
+        .await
+        .unwrap();
+
+    let lines = read_lines(&mut client, 10).await;
+    assert_eq!("354 Go ahead", lines[9]);
+
+    client
+        .get_mut()
+        .write_all(b"Hello\r\n.\r\nRSET\r\n")
+        .await
+        .unwrap();
+
+    let lines = read_lines(&mut client, 2).await;
+    assert_eq!(vec!["250 Ok", "250 Ok"], lines);
+}
+
+#[tokio::test]
+async fn idle_client_times_out() {
+    let mut config = Config::new("test".into());
+    config.command_timeout = Some(Duration::from_millis(50));
+
+    let mut client = connect(config).await;
+    client.write_all(b"EHLO nexium.app\r\n").await.unwrap();
+
+    let lines = read_until_closed(&mut client).await;
+
+    assert_eq!(
+        vec![
+            "220 test ESMTP",
+            "250-test ESMTP",
+            "250-SIZE 0",
+            "250-8BITMIME",
+            "250-BINARYMIME",
+            "250-CHUNKING",
+            "250 SMTPUTF8",
+            "421 Timeout, closing connection",
+        ],
+        lines
+    );
+}
+
+#[tokio::test]
+async fn proxied_client_address() {
+    let mut config = Config::new("test".into());
+    config.trust_proxy = true;
+
+    let client = connect_with(config, Arc::new(ProxiedClientHandler {})).await;
+    let mut client = BufReader::new(client);
+    client.write_all(PROXY_HEADER).await.unwrap();
+    client
+        .write_all(b"EHLO nexium.app\r\nMAIL FROM:<info@nexium.app>\r\nRCPT TO:<admin@nexium.app>\r\nDATA\r\n")
+        .await
+        .unwrap();
+
+    let lines = read_lines(&mut client, 10).await;
+
+    assert_eq!("220 test ESMTP", lines[0]);
+    assert_eq!("354 Go ahead", lines[9]);
+}
+
+#[tokio::test]
+async fn unproxied_client_address() {
+    let client = connect_with(
+        Config::new("test".into()),
+        Arc::new(ProxiedClientHandler {}),
+    )
+    .await;
+    let mut client = BufReader::new(client);
+    client
+        .write_all(b"EHLO nexium.app\r\nMAIL FROM:<info@nexium.app>\r\nRCPT TO:<admin@nexium.app>\r\nDATA\r\n")
+        .await
+        .unwrap();
+
+    let lines = read_lines(&mut client, 10).await;
+
+    assert_eq!("550 Transaction rejected", lines[9]);
+}
+
+#[tokio::test]
+async fn missing_proxy_header_closes_connection() {
+    let mut config = Config::new("test".into());
+    config.trust_proxy = true;
+
+    let mut client = connect(config).await;
+    client.write_all(b"EHLO nexium.app\r\n").await.unwrap();
+
+    let lines = read_until_closed(&mut client).await;
+
+    assert!(lines.is_empty());
+}
+
+#[tokio::test]
+async fn metrics_after_transaction() {
+    let metrics = Arc::new(MetricsCounters::new());
+    let client = connect_metered(
+        Config::new("test".into()),
+        Arc::new(AcceptingHandler {}),
+        Some(metrics.clone()),
+    )
+    .await;
+    let mut client = BufReader::new(client);
+
+    let commands =
+        b"EHLO nexium.app\r\nMAIL FROM:<info@nexium.app>\r\nRCPT TO:<admin@nexium.app>\r\nDATA\r\n";
+    client.write_all(commands).await.unwrap();
+    read_lines(&mut client, 10).await;
+
+    let body = b"Hello\r\n.\r\nQUIT\r\n";
+    client.write_all(body).await.unwrap();
+    let mut output = String::new();
+    client.read_to_string(&mut output).await.unwrap();
+
+    assert_eq!("250 Ok\r\n221 Goodbye!\r\n", output);
+    assert_eq!(metrics.messages_accepted(), 1);
+    assert_eq!(metrics.messages_rejected(), 0);
+    assert_eq!(metrics.connections_closed(), 1);
+    assert_eq!(
+        metrics.bytes_received(),
+        (commands.len() + body.len()) as u64
+    );
+}
+
+#[tokio::test]
+async fn metrics_message_too_big() {
+    let metrics = Arc::new(MetricsCounters::new());
+    let mut config = Config::new("test".into());
+    config.max_size = Some(4);
+
+    let client =
+        connect_metered(config, Arc::new(AcceptingHandler {}), Some(metrics.clone())).await;
+    let mut client = BufReader::new(client);
+    client
+        .write_all(b"EHLO nexium.app\r\nMAIL FROM:<info@nexium.app>\r\nRCPT TO:<admin@nexium.app>\r\nDATA\r\n")
+        .await
+        .unwrap();
+    read_lines(&mut client, 10).await;
+
+    client.write_all(b"Hello\r\n.\r\nQUIT\r\n").await.unwrap();
+    let mut output = String::new();
+    client.read_to_string(&mut output).await.unwrap();
+
+    assert_eq!(metrics.messages_accepted(), 0);
+    assert_eq!(metrics.messages_rejected(), 1);
+}
+
+#[tokio::test]
+async fn metrics_rejections_by_reason() {
+    let metrics = Arc::new(MetricsCounters::new());
+
+    // Senders with an overlong address are refused.
+    let mut config = Config::new("test".into());
+    config.max_local_part_len = Some(1);
+    let mut client =
+        connect_metered(config, Arc::new(RefusingHandler {}), Some(metrics.clone())).await;
+    client
+        .write_all(b"EHLO nexium.app\r\nMAIL FROM:<ab@nexium.app>\r\nQUIT\r\n")
+        .await
+        .unwrap();
+    read_until_closed(&mut client).await;
+
+    let mut config = Config::new("test".into());
+    config.max_size = Some(100);
+    config.max_recipients_soft = 1;
+    let mut client =
+        connect_metered(config, Arc::new(RefusingHandler {}), Some(metrics.clone())).await;
+    client
+        .write_all(
+            b"EHLO nexium.app\r\nXFOO\r\nRCPT TO:<a@nexium.app>\r\nMAIL FROM:<a@nexium.app> SIZE=1000\r\n\
+              MAIL FROM:<a@nexium.app>\r\nRCPT TO:<nobody@nexium.app>\r\nRCPT TO:<b@nexium.app>\r\n\
+              RCPT TO:<c@nexium.app>\r\nDATA\r\nHello\r\n.\r\nQUIT\r\n",
+        )
+        .await
+        .unwrap();
+    read_until_closed(&mut client).await;
+
+    for reason in [
+        Rejection::Syntax,
+        Rejection::OutOfSequence,
+        Rejection::Sender,
+        Rejection::Size,
+        Rejection::Recipient,
+        Rejection::RateLimited,
+        Rejection::Content,
+    ] {
+        assert_eq!(1, metrics.rejections(reason), "{:?}", reason);
+    }
+}
+
+#[tokio::test]
+async fn save_timeout() {
+    let mut config = Config::new("test".into());
+    config.save_timeout = Some(Duration::from_millis(50));
+
+    let client = connect_with(config, Arc::new(SlowHandler {})).await;
+    let mut client = BufReader::new(client);
+    client
+        .write_all(b"EHLO nexium.app\r\nMAIL FROM:<info@nexium.app>\r\nRCPT TO:<admin@nexium.app>\r\nDATA\r\n")
+        .await
+        .unwrap();
+    read_lines(&mut client, 10).await;
+
+    client
+        .write_all(b"Hello\r\n.\r\nDATA\r\nQUIT\r\n")
+        .await
+        .unwrap();
+    let mut output = String::new();
+    client.read_to_string(&mut output).await.unwrap();
+
+    assert_eq!(
+        "451 Try again later\r\n503 Command out of sequence\r\n221 Goodbye!\r\n",
+        output
+    );
+}
+
+#[tokio::test]
+async fn recipient_timeout() {
+    let mut config = Config::new("test".into());
+    config.handler_timeout = Some(Duration::from_millis(50));
+
+    let mut client = connect_with(config, Arc::new(SlowHandler {})).await;
+    client
+        .write_all(b"EHLO nexium.app\r\nMAIL FROM:<info@nexium.app>\r\nRCPT TO:<slow@nexium.app>\r\nRCPT TO:<admin@nexium.app>\r\nQUIT\r\n")
+        .await
+        .unwrap();
+
+    let lines = read_until_closed(&mut client).await;
+
+    assert_eq!(
+        vec![
+            "220 test ESMTP",
+            "250-test ESMTP",
+            "250-SIZE 0",
+            "250-8BITMIME",
+            "250-BINARYMIME",
+            "250-CHUNKING",
+            "250 SMTPUTF8",
+            "250 Ok",
+            "451 Try again later",
+            "250 Ok",
+            "221 Goodbye!",
+        ],
+        lines
+    );
+}
+
+#[tokio::test]
+async fn queue_id_in_reply() {
+    let client = connect_with(Config::new("test".into()), Arc::new(QueueingHandler {})).await;
+    let mut client = BufReader::new(client);
+    client
+        .write_all(b"EHLO nexium.app\r\nMAIL FROM:<info@nexium.app>\r\nRCPT TO:<admin@nexium.app>\r\nDATA\r\n")
+        .await
+        .unwrap();
+    read_lines(&mut client, 10).await;
+
+    client.write_all(b"Hello\r\n.\r\n").await.unwrap();
+
+    assert_eq!(
+        vec!["250 2.0.0 Ok: queued as ABC123"],
+        read_lines(&mut client, 1).await
+    );
+}
+
+#[test]
+fn split_batch_limits_lines() {
+    assert_eq!(
+        (&b"NOOP\r\n"[..], &b"NOOP\r\nQU"[..]),
+        split_batch(&b"NOOP\r\nNOOP\r\nQU"[..], 1)
+    );
+    assert_eq!(
+        (&b"NOOP\r\nNOOP\r\n"[..], &b"QUIT\r\n"[..]),
+        split_batch(&b"NOOP\r\nNOOP\r\nQUIT\r\n"[..], 2)
+    );
+    assert_eq!(
+        (&b"NOOP\r\nQU"[..], &b""[..]),
+        split_batch(&b"NOOP\r\nQU"[..], 2)
+    );
+    assert_eq!(
+        (&b"NOOP\r\n"[..], &b"NOOP\r\n"[..]),
+        split_batch(&b"NOOP\r\nNOOP\r\n"[..], 0)
+    );
+}
+
+#[tokio::test]
+async fn pipelined_commands_in_batches() {
+    let mut config = Config::new("test".into());
+    config.max_commands_per_batch = 64;
+
+    let client = connect(config).await;
+    let (reader, mut writer) = client.into_split();
+
+    tokio::spawn(async move {
+        let commands = format!("{}QUIT\r\n", "NOOP\r\n".repeat(10_000));
+        writer.write_all(commands.as_bytes()).await.unwrap();
+    });
+
+    let mut output = String::new();
+    BufReader::new(reader)
+        .read_to_string(&mut output)
+        .await
+        .unwrap();
+    let lines: Vec<&str> = output.lines().collect();
+
+    assert_eq!(10_002, lines.len());
+    assert!(lines[1..10_001].iter().all(|l| *l == "250 Ok"));
+    assert_eq!("221 Goodbye!", lines[10_001]);
+}
+
+#[tokio::test]
+async fn command_rate_limited() {
+    let mut config = Config::new("test".into());
+    config.max_commands_per_second = Some(20);
+
+    let mut client = connect(config).await;
+    let started = std::time::Instant::now();
+    let commands = format!("{}QUIT\r\n", "NOOP\r\n".repeat(29));
+    client.write_all(commands.as_bytes()).await.unwrap();
+    let lines = read_until_closed(&mut client).await;
+
+    // The first 20 commands are a burst, the other 10 have to wait for the rate.
+    assert!(started.elapsed() >= Duration::from_millis(450));
+    assert_eq!(31, lines.len());
+    assert!(lines[1..30].iter().all(|l| *l == "250 Ok"));
+    assert_eq!("221 Goodbye!", lines[30]);
+}
+
+#[tokio::test]
+async fn batches_leave_other_sessions_responsive() {
+    let mut config = Config::new("test".into());
+    config.max_commands_per_batch = 1;
+
+    let busy = connect(config.clone()).await;
+    let (_busy_reader, mut busy_writer) = busy.into_split();
+    let commands = "NOOP\r\n".repeat(10_000);
+    busy_writer.write_all(commands.as_bytes()).await.unwrap();
+
+    let mut idle = connect(config).await;
+    idle.write_all(b"QUIT\r\n").await.unwrap();
+
+    assert_eq!(
+        vec!["220 test ESMTP", "221 Goodbye!"],
+        read_until_closed(&mut idle).await
+    );
+}
+
+#[tokio::test]
+async fn size_exceeded_mid_transfer() {
+    let mut config = Config::new("test".into());
+    config.max_size = Some(16);
+
+    let client = connect(config).await;
+    let mut client = BufReader::new(client);
+    client
+        .write_all(b"EHLO nexium.app\r\nMAIL FROM:<info@nexium.app>\r\nRCPT TO:<admin@nexium.app>\r\nDATA\r\n")
+        .await
+        .unwrap();
+    read_lines(&mut client, 10).await;
+
+    for _ in 0..3 {
+        client
+            .write_all(b"This body keeps growing past the limit.\r\n")
+            .await
+            .unwrap();
+    }
+    client
+        .write_all(b".\r\nMAIL FROM:<info@nexium.app>\r\n")
+        .await
+        .unwrap();
+
+    assert_eq!(
+        vec!["552 5.3.4 Message too big", "250 Ok"],
+        read_lines(&mut client, 2).await
+    );
+}
+
+#[tokio::test]
+async fn quit_during_data_is_message_text() {
+    let handler = Arc::new(RecordingHandler::default());
+
+    let mut client = connect_with(Config::new("test".into()), handler.clone()).await;
+    client
+        .write_all(b"EHLO nexium.app\r\nMAIL FROM:<info@nexium.app>\r\nRCPT TO:<admin@nexium.app>\r\nDATA\r\nHello\r\nQUIT\r\n.\r\nQUIT\r\n")
+        .await
+        .unwrap();
+
+    let lines = read_until_closed(&mut client).await;
+
+    assert_eq!(
+        vec![
+            "220 test ESMTP",
+            "250-test ESMTP",
+            "250-SIZE 0",
+            "250-8BITMIME",
+            "250-BINARYMIME",
+            "250-CHUNKING",
+            "250 SMTPUTF8",
+            "250 Ok",
+            "250 Ok",
+            "354 Go ahead",
+            "250 Ok",
+            "221 Goodbye!",
+        ],
+        lines
+    );
+    assert_eq!(
+        vec![b"Hello\r\nQUIT".to_vec()],
+        *handler.messages.lock().unwrap()
+    );
+}
+
+#[tokio::test]
+async fn rset_during_data_is_message_text() {
+    let handler = Arc::new(RecordingHandler::default());
+    let mut client = connect_with(Config::new("test".into()), handler.clone()).await;
+    client
+        .write_all(b"HELO nexium.app\r\nMAIL FROM:<a@nexium.app>\r\nRCPT TO:<b@nexium.app>\r\nDATA\r\nHello\r\nRSET\r\n.\r\nRSET\r\nRCPT TO:<b@nexium.app>\r\nQUIT\r\n")
+        .await
+        .unwrap();
+
+    assert_eq!(
+        vec![
+            "220 test ESMTP",
+            "250 test ESMTP",
+            "250 Ok",
+            "250 Ok",
+            "354 Go ahead",
+            "250 Ok",
+            "250 Ok",
+            "503 Command out of sequence",
+            "221 Goodbye!",
+        ],
+        read_until_closed(&mut client).await
+    );
+    assert_eq!(
+        vec![b"Hello\r\nRSET".to_vec()],
+        *handler.messages.lock().unwrap()
+    );
+}
+
+#[tokio::test]
+async fn data_refused_when_every_recipient_rejected() {
+    let mut client = connect_with(Config::new("test".into()), Arc::new(RefusingHandler {})).await;
+    client
+        .write_all(b"HELO nexium.app\r\nMAIL FROM:<a@nexium.app>\r\nRCPT TO:<nobody@nexium.app>\r\nDATA\r\nQUIT\r\n")
+        .await
+        .unwrap();
+
+    assert_eq!(
+        vec![
+            "220 test ESMTP",
+            "250 test ESMTP",
+            "250 Ok",
+            "550 User not local",
+            "554 No valid recipient",
+            "221 Goodbye!",
+        ],
+        read_until_closed(&mut client).await
+    );
+}
+
+#[tokio::test]
+async fn quit_after_recipient_answers_everything_first() {
+    let mut client = connect(Config::new("test".into())).await;
+    client
+        .write_all(b"EHLO nexium.app\r\nMAIL FROM:<info@nexium.app>\r\nRCPT TO:<admin@nexium.app>\r\nQUIT\r\nNOOP\r\n")
+        .await
+        .unwrap();
+
+    let lines = read_until_closed(&mut client).await;
+
+    assert_eq!(
+        vec![
+            "220 test ESMTP",
+            "250-test ESMTP",
+            "250-SIZE 0",
+            "250-8BITMIME",
+            "250-BINARYMIME",
+            "250-CHUNKING",
+            "250 SMTPUTF8",
+            "250 Ok",
+            "250 Ok",
+            "221 Goodbye!",
+        ],
+        lines
+    );
+}
+
+#[tokio::test]
+async fn pipelined_replies_written_once() {
+    let (client, server) = tokio::io::duplex(4096);
+    let writes = Arc::new(AtomicUsize::new(0));
+    let stream = CountingStream {
+        inner: server,
+        writes: writes.clone(),
+    };
+
+    let session = SmtpSession::new(
+        stream,
+        "127.0.0.1:2525".parse().unwrap(),
+        Arc::new(Config::new("test".into())),
+        Arc::new(AcceptingHandler {}),
+        None,
+        None,
+        None,
+    );
+    tokio::spawn(session.handle());
+
+    let mut client = BufReader::new(client);
+    client.write_all(b"HELO nexium.app\r\n").await.unwrap();
+    read_lines(&mut client, 2).await;
+    let before = writes.load(Ordering::SeqCst);
+
+    client
+        .write_all(
+            b"MAIL FROM:<a@nexium.app>\r\nRCPT TO:<b@nexium.app>\r\nRCPT TO:<c@nexium.app>\r\n",
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(
+        vec!["250 Ok", "250 Ok", "250 Ok"],
+        read_lines(&mut client, 3).await
+    );
+    assert_eq!(1, writes.load(Ordering::SeqCst) - before);
+}
+
+#[tokio::test]
+async fn data_prompt_sent_before_pipelined_body() {
+    let handler = Arc::new(GatedHandler {
+        gate: tokio::sync::Semaphore::new(0),
+        messages: Default::default(),
+    });
+    let mut client =
+        BufReader::new(connect_with(Config::new("test".into()), handler.clone()).await);
+
+    client
+        .write_all(b"HELO nexium.app\r\nMAIL FROM:<a@nexium.app>\r\nRCPT TO:<b@nexium.app>\r\nDATA\r\nbody\r\n.\r\n")
+        .await
+        .unwrap();
+
+    // The message is being saved, so the prompt can't have waited for the rest of the read.
+    assert_eq!(
+        vec![
+            "220 test ESMTP",
+            "250 test ESMTP",
+            "250 Ok",
+            "250 Ok",
+            "354 Go ahead"
+        ],
+        read_lines(&mut client, 5).await
+    );
+
+    handler.gate.add_permits(1);
+
+    assert_eq!(vec!["250 Ok"], read_lines(&mut client, 1).await);
+    assert_eq!(vec![b"body".to_vec()], *handler.messages.lock().unwrap());
+}
+
+#[tokio::test]
+async fn extension_commands_answered_by_handler() {
+    let mut client = connect_with(Config::new("test".into()), Arc::new(ExtensionHandler {})).await;
+    client
+        .write_all(b"HELO nexium.app\r\nXNOOP\r\nXFOO\r\nMAIL FROM:<a@nexium.app>\r\nRCPT TO:<b@nexium.app>\r\nDATA\r\nxtrust\r\nDATA\r\nHello\r\n.\r\nQUIT\r\n")
+        .await
+        .unwrap();
+
+    assert_eq!(
+        vec![
+            "220 test ESMTP",
+            "250 test ESMTP",
+            "250 Ok",
+            "500 Syntax error",
+            "250 Ok",
+            "250 Ok",
+            "550 Transaction rejected",
+            "250 Trusted",
+            "354 Go ahead",
+            "250 Ok",
+            "221 Goodbye!",
+        ],
+        read_until_closed(&mut client).await
+    );
+}
+
+#[tokio::test]
+async fn relay_after_handler_authentication() {
+    let mut client = connect_with(Config::new("test".into()), Arc::new(ExtensionHandler {})).await;
+    client
+        .write_all(b"HELO nexium.app\r\nMAIL FROM:<a@nexium.app>\r\nRCPT TO:<b@example.org>\r\nXTRUST\r\nRCPT TO:<b@example.org>\r\nQUIT\r\n")
+        .await
+        .unwrap();
+
+    assert_eq!(
+        vec![
+            "220 test ESMTP",
+            "250 test ESMTP",
+            "250 Ok",
+            "550 5.7.1 Relaying denied",
+            "250 Trusted",
+            "250 Ok",
+            "221 Goodbye!",
+        ],
+        read_until_closed(&mut client).await
+    );
+}
+
+#[tokio::test]
+async fn character_split_across_reads() {
+    let handler = Arc::new(RecordingHandler::default());
+    let mut client =
+        BufReader::new(connect_with(Config::new("test".into()), handler.clone()).await);
+
+    client
+        .write_all(
+            b"HELO nexium.app\r\nMAIL FROM:<a@nexium.app>\r\nRCPT TO:<b@nexium.app>\r\nDATA\r\n",
+        )
+        .await
+        .unwrap();
+    read_lines(&mut client, 5).await;
+
+    client.write_all(b"Caf").await.unwrap();
+    for byte in "\u{20AC}".as_bytes() {
+        client.write_all(&[*byte]).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    client.write_all(b"\r\n.\r\n").await.unwrap();
+
+    assert_eq!(vec!["250 Ok"], read_lines(&mut client, 1).await);
+    assert_eq!(
+        vec!["Caf\u{20AC}".as_bytes().to_vec()],
+        *handler.messages.lock().unwrap()
+    );
+}
+
+#[tokio::test]
+async fn eight_bit_body_saved_unchanged() {
+    let handler = Arc::new(RecordingHandler::default());
+    let mut client =
+        BufReader::new(connect_with(Config::new("test".into()), handler.clone()).await);
+
+    client
+        .write_all(b"EHLO nexium.app\r\nMAIL FROM:<a@nexium.app> BODY=8BITMIME\r\nRCPT TO:<b@nexium.app>\r\nDATA\r\n")
+        .await
+        .unwrap();
+    read_lines(&mut client, 10).await;
+
+    let body: Vec<u8> = (0x80..=0xFF).collect();
+    client
+        .write_all(b"Subject: Gr\xfc\xdfe\r\n\r\n")
+        .await
+        .unwrap();
+    client.write_all(&body).await.unwrap();
+    client.write_all(b"\r\n.\r\n").await.unwrap();
+
+    assert_eq!(vec!["250 Ok"], read_lines(&mut client, 1).await);
+    assert_eq!(
+        vec![[&b"Subject: Gr\xfc\xdfe\r\n\r\n"[..], &body].concat()],
+        *handler.messages.lock().unwrap()
+    );
+}
+
+#[tokio::test]
+async fn binary_chunks_saved_verbatim() {
+    let handler = Arc::new(RecordingHandler::default());
+    let mut client =
+        BufReader::new(connect_with(Config::new("test".into()), handler.clone()).await);
+
+    client
+        .write_all(b"EHLO nexium.app\r\nMAIL FROM:<a@nexium.app> BODY=BINARYMIME\r\nRCPT TO:<b@nexium.app>\r\nDATA\r\n")
+        .await
+        .unwrap();
+    let lines = read_lines(&mut client, 10).await;
+    assert_eq!("250-BINARYMIME", lines[4]);
+    assert_eq!("250-CHUNKING", lines[5]);
+    assert_eq!("503 5.5.1 BINARYMIME requires BDAT", lines[9]);
+
+    // Line breaks and the end of data sequence are content here, as is a command after them.
+    let first = b"\x00\xff\r\n.\r\nQUIT\r\n";
+    let second = b"\n.\n\r\r\n..\r";
+    client.write_all(b"BDAT 13\r\n").await.unwrap();
+    client.write_all(&first[..5]).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    client.write_all(&first[5..]).await.unwrap();
+    client.write_all(b"BDAT 9 LAST\r\n").await.unwrap();
+    client.write_all(second).await.unwrap();
+    client.write_all(b"NOOP\r\n").await.unwrap();
+
+    assert_eq!(
+        vec!["250 2.0.0 13 octets received", "250 Ok", "250 Ok"],
+        read_lines(&mut client, 3).await
+    );
+    assert_eq!(
+        vec![[&first[..], &second[..]].concat()],
+        *handler.messages.lock().unwrap()
+    );
+}
+
+#[tokio::test]
+async fn session_within_chunk_not_evicted() {
+    let registry = Arc::new(ActivityRegistry::new());
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let client = TcpStream::connect(listener.local_addr().unwrap())
+        .await
+        .unwrap();
+    let (stream, addr) = listener.accept().await.unwrap();
+    let session = SmtpSession::new(
+        stream,
+        addr,
+        Arc::new(Config::new("test".into())),
+        Arc::new(AcceptingHandler {}),
+        None,
+        None,
+        Some(&registry),
+    );
+    tokio::spawn(session.handle());
+
+    let mut client = BufReader::new(client);
+    client
+        .write_all(b"EHLO nexium.app\r\nMAIL FROM:<a@nexium.app>\r\nRCPT TO:<b@nexium.app>\r\n")
+        .await
+        .unwrap();
+    read_lines(&mut client, 9).await;
+
+    // The session waits for the rest of the chunk while the idle cap is reached.
+    client.write_all(b"BDAT 10 LAST\r\nHello").await.unwrap();
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    assert!(!registry.make_room(1));
+
+    client.write_all(b"World").await.unwrap();
+    assert_eq!(vec!["250 Ok"], read_lines(&mut client, 1).await);
+}
+
+#[tokio::test]
+async fn non_utf8_command_closes_connection() {
+    let mut client = connect(Config::new("test".into())).await;
+
+    client
+        .write_all(b"HELO nexium.app\r\nHELO caf\xe9\r\n")
+        .await
+        .unwrap();
+
+    assert_eq!(
+        vec!["220 test ESMTP", "250 test ESMTP"],
+        read_until_closed(&mut client).await
+    );
+}
+
+#[tokio::test]
+async fn rewritten_recipients_saved() {
+    let handler = Arc::new(CanonicalHandler::default());
+    let mut client =
+        BufReader::new(connect_with(Config::new("test".into()), handler.clone()).await);
+
+    client
+        .write_all(b"HELO nexium.app\r\nMAIL FROM:<a@nexium.app>\r\nRCPT TO:<user+tag@example.com>\r\nRCPT TO:<nobody@example.com>\r\nDATA\r\nHello\r\n.\r\n")
+        .await
+        .unwrap();
+
+    assert_eq!(
+        vec![
+            "220 test ESMTP",
+            "250 test ESMTP",
+            "250 Ok",
+            "250 Ok",
+            "550 User not local",
+            "354 Go ahead",
+            "250 Ok",
+        ],
+        read_lines(&mut client, 7).await
+    );
+    assert_eq!(
+        vec![Mailbox {
+            local: "user".into(),
+            domain: "example.com".into(),
+        }],
+        *handler.recipients.lock().unwrap()
+    );
+}
+
+#[tokio::test]
+async fn early_talker_rejected() {
+    let mut config = Config::new("test".into());
+    config.greet_delay = Some(Duration::from_millis(200));
+
+    let mut client = connect(config).await;
+    client.write_all(b"EHLO nexium.app\r\n").await.unwrap();
+
+    assert_eq!(
+        vec!["554 Sent data before the greeting, closing connection"],
+        read_until_closed(&mut client).await
+    );
+}
+
+#[tokio::test]
+async fn patient_client_greeted_after_delay() {
+    let mut config = Config::new("test".into());
+    config.greet_delay = Some(Duration::from_millis(50));
+
+    let mut client = BufReader::new(connect(config).await);
+    assert_eq!(vec!["220 test ESMTP"], read_lines(&mut client, 1).await);
+
+    client.write_all(b"HELO nexium.app\r\n").await.unwrap();
+    assert_eq!(vec!["250 test ESMTP"], read_lines(&mut client, 1).await);
+}
+
+#[tokio::test]
+async fn overridden_data_prompt() {
+    let mut config = Config::new("test".into());
+    config.reply_texts.insert(
+        std::mem::discriminant(&Response::StartData),
+        "Send the message".into(),
+    );
+
+    let mut client = connect(config).await;
+    client
+        .write_all(b"HELO nexium.app\r\nMAIL FROM:<a@nexium.app>\r\nRCPT TO:<b@nexium.app>\r\nDATA\r\n.\r\nQUIT\r\n")
+        .await
+        .unwrap();
+
+    assert_eq!(
+        vec![
+            "220 test ESMTP",
+            "250 test ESMTP",
+            "250 Ok",
+            "250 Ok",
+            "354 Send the message",
+            "250 Ok",
+            "221 Goodbye!",
+        ],
+        read_until_closed(&mut client).await
+    );
+}
+
+#[tokio::test]
+async fn forged_helo_rejected() {
+    let mut config = Config::new("mx.nexium.app".into());
+    config.trust_proxy = true;
+
+    let mut client = connect_with(config, Arc::new(ForgedHeloHandler {})).await;
+    client.write_all(PROXY_HEADER).await.unwrap();
+    client
+        .write_all(b"EHLO MX.nexium.app\r\nMAIL FROM:<info@nexium.app>\r\nHELO client.example.com\r\nQUIT\r\n")
+        .await
+        .unwrap();
+
+    assert_eq!(
+        vec![
+            "220 mx.nexium.app ESMTP",
+            "550 Invalid HELO",
+            "503 Command out of sequence",
+            "250 mx.nexium.app ESMTP",
+            "221 Goodbye!",
+        ],
+        read_until_closed(&mut client).await
+    );
+}
+
+#[tokio::test]
+async fn own_helo_from_loopback_allowed() {
+    let mut client = BufReader::new(
+        connect_with(
+            Config::new("mx.nexium.app".into()),
+            Arc::new(ForgedHeloHandler {}),
+        )
+        .await,
+    );
+    client.write_all(b"HELO mx.nexium.app\r\n").await.unwrap();
+
+    assert_eq!(
+        vec!["220 mx.nexium.app ESMTP", "250 mx.nexium.app ESMTP"],
+        read_lines(&mut client, 2).await
+    );
+}
+
+#[tokio::test]
+async fn session_time_limit() {
+    let mut config = Config::new("test".into());
+    config.max_session_duration = Some(Duration::from_millis(200));
+
+    let mut client = BufReader::new(connect(config).await);
+    read_lines(&mut client, 1).await;
+
+    let mut replies = 0;
+    let last = loop {
+        client.write_all(b"NOOP\r\n").await.unwrap();
+        let line = read_lines(&mut client, 1).await.remove(0);
+
+        if line != "250 Ok" {
+            break line;
+        }
+
+        replies += 1;
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    };
+
+    assert!(replies > 2);
+    assert_eq!("421 Session time limit exceeded", last);
+}
+
+#[tokio::test]
+async fn write_failure_is_io_error() {
+    let mut session = session_on(BrokenStream, Config::new("test".into()));
+
+    let result = session.run().await;
+
+    assert!(
+        matches!(result, Err(SessionError::Io(e)) if e.kind() == std::io::ErrorKind::ConnectionReset)
+    );
+}
+
+#[tokio::test]
+async fn idle_client_is_timeout_error() {
+    let mut config = Config::new("test".into());
+    config.command_timeout = Some(Duration::from_millis(50));
+    let (client, server) = tokio::io::duplex(4096);
+    let mut session = session_on(server, config);
+
+    let result = session.run().await;
+
+    assert!(matches!(result, Err(SessionError::Timeout)));
+    drop(client);
+}
+
+/// Handler reporting a fixed load.
+struct LoadedHandler {
+    level: LoadLevel,
+}
+
+#[async_trait]
+impl Handler for LoadedHandler {
+    async fn load_hint(&self) -> LoadLevel {
+        self.level
+    }
+
+    async fn recipient_local(&self, _recipient: &Mailbox) -> bool {
+        true
+    }
+
+    async fn save(&self, _state: &SmtpState) -> bool {
+        true
+    }
+}
+
+#[tokio::test]
+async fn critical_load_refuses_clients() {
+    let handler = Arc::new(LoadedHandler {
+        level: LoadLevel::Critical,
+    });
+    let mut client = connect_with(Config::new("test".into()), handler).await;
+
+    assert_eq!(
+        vec!["421 test Service not available, closing transmission channel"],
+        read_until_closed(&mut client).await
+    );
+}
+
+#[tokio::test]
+async fn high_load_delays_greeting() {
+    let handler = Arc::new(LoadedHandler {
+        level: LoadLevel::High,
+    });
+    let mut client = connect_with(Config::new("test".into()), handler).await;
+    client.write_all(b"HELO nexium.app\r\n").await.unwrap();
+
+    assert_eq!(
+        vec!["554 Sent data before the greeting, closing connection"],
+        read_until_closed(&mut client).await
+    );
+}
+
+/// Handler reporting aborted transactions with the data received so far.
+struct AbortHandler {
+    aborted: tokio::sync::mpsc::UnboundedSender<(AbortReason, Vec<u8>)>,
+}
+
+#[async_trait]
+impl Handler for AbortHandler {
+    async fn recipient_local(&self, _recipient: &Mailbox) -> bool {
+        true
+    }
+
+    async fn save(&self, _state: &SmtpState) -> bool {
+        true
+    }
+
+    async fn transaction_aborted(&self, state: &SmtpState, reason: AbortReason) {
+        self.aborted.send((reason, state.data.clone())).unwrap();
+    }
+}
+
+/// Start a session with an `AbortHandler`, returning the receiver of its reports.
+async fn connect_aborting(
+    config: Config,
+) -> (
+    TcpStream,
+    tokio::sync::mpsc::UnboundedReceiver<(AbortReason, Vec<u8>)>,
+) {
+    let (aborted, reports) = tokio::sync::mpsc::unbounded_channel();
+    let client = connect_with(config, Arc::new(AbortHandler { aborted })).await;
+
+    (client, reports)
+}
+
+#[tokio::test]
+async fn eof_during_data_aborts_transaction() {
+    let (client, mut reports) = connect_aborting(Config::new("test".into())).await;
+    let mut client = BufReader::new(client);
+    client
+        .write_all(b"HELO nexium.app\r\nMAIL FROM:<info@nexium.app>\r\nRCPT TO:<admin@nexium.app>\r\nDATA\r\nPartial\r\n")
+        .await
+        .unwrap();
+    read_lines(&mut client, 5).await;
+    drop(client);
+
+    assert_eq!(
+        Some((AbortReason::Eof, b"Partial".to_vec())),
+        reports.recv().await
+    );
+}
+
+#[tokio::test]
+async fn timeout_during_transaction_aborts_it() {
+    let mut config = Config::new("test".into());
+    config.command_timeout = Some(Duration::from_millis(50));
+    let (client, mut reports) = connect_aborting(config).await;
+    let mut client = BufReader::new(client);
+    client
+        .write_all(b"HELO nexium.app\r\nMAIL FROM:<info@nexium.app>\r\n")
+        .await
+        .unwrap();
+
+    assert_eq!(
+        Some((AbortReason::Timeout, Vec::new())),
+        reports.recv().await
+    );
+    drop(client);
+}
+
+/// Handler which never finishes handling an aborted transaction.
+struct StuckAbortHandler {}
+
+#[async_trait]
+impl Handler for StuckAbortHandler {
+    async fn recipient_local(&self, _recipient: &Mailbox) -> bool {
+        true
+    }
+
+    async fn save(&self, _state: &SmtpState) -> bool {
+        true
+    }
+
+    async fn transaction_aborted(&self, _state: &SmtpState, _reason: AbortReason) {
+        std::future::pending::<()>().await
+    }
+}
+
+#[tokio::test]
+async fn aborted_transaction_timeout() {
+    let mut config = Config::new("test".into());
+    config.handler_timeout = Some(Duration::from_millis(50));
+    let mut client = connect_with(config, Arc::new(StuckAbortHandler {})).await;
+    client
+        .write_all(b"HELO nexium.app\r\nMAIL FROM:<info@nexium.app>\r\nQUIT\r\n")
+        .await
+        .unwrap();
+
+    // The session still ends, closing the connection.
+    let lines = tokio::time::timeout(Duration::from_secs(5), read_until_closed(&mut client))
+        .await
+        .unwrap();
+    assert_eq!(Some("221 Goodbye!"), lines.last().map(String::as_str));
+}
+
+#[tokio::test]
+async fn completed_transaction_not_aborted() {
+    let (mut client, mut reports) = connect_aborting(Config::new("test".into())).await;
+    client
+        .write_all(b"HELO nexium.app\r\nMAIL FROM:<info@nexium.app>\r\nRCPT TO:<admin@nexium.app>\r\nDATA\r\nHello\r\n.\r\nQUIT\r\n")
+        .await
+        .unwrap();
+    read_until_closed(&mut client).await;
+
+    // The handler is dropped with the session, closing the channel without a report.
+    assert_eq!(None, reports.recv().await);
+}
+
+#[tokio::test]
+async fn body_streamed_to_handler() {
+    let (mut client, mut results) = connect_streaming(Config::new("test".into())).await;
+    let body = "Subject: Streamed\r\n\r\n.Dotted line\r\nEnd";
+
+    client
+        .write_all(b"Subject: Streamed\r\n\r\n")
+        .await
+        .unwrap();
+    client
+        .write_all(b"..Dotted line\r\nEnd\r\n.\r\n")
+        .await
+        .unwrap();
+
+    assert_eq!(
+        vec!["250 2.0.0 Ok: queued as STREAMED"],
+        read_lines(&mut client, 1).await
+    );
+    assert_eq!(body.len(), results.recv().await.unwrap().unwrap());
+}
+
+#[tokio::test]
+async fn oversized_stream_aborted() {
+    let mut config = Config::new("test".into());
+    config.max_size = Some(10);
+    let (mut client, mut results) = connect_streaming(config).await;
+
+    client
+        .write_all(b"Short\r\nThis line is too large\r\n.\r\n")
+        .await
+        .unwrap();
+
+    assert_eq!(
+        vec!["552 5.3.4 Message too big"],
+        read_lines(&mut client, 1).await
+    );
+    assert_eq!(
+        std::io::ErrorKind::UnexpectedEof,
+        results.recv().await.unwrap().unwrap_err().kind()
+    );
+}
+
+#[tokio::test]
+async fn interleaved_sessions_have_distinct_transactions() {
+    let handler = Arc::new(TransactionHandler::default());
+    let mut first = BufReader::new(connect_with(Config::new("test".into()), handler.clone()).await);
+    let mut second =
+        BufReader::new(connect_with(Config::new("test".into()), handler.clone()).await);
+
+    for command in [
+        "HELO nexium.app\r\n",
+        "MAIL FROM:<info@nexium.app>\r\n",
+        "RCPT TO:<admin@nexium.app>\r\n",
+        "DATA\r\n",
+        "Hello\r\n.\r\n",
+    ] {
+        for client in [&mut first, &mut second] {
+            client.write_all(command.as_bytes()).await.unwrap();
+            read_lines(client, if command.starts_with("HELO") { 2 } else { 1 }).await;
+        }
+    }
+
+    let seen = handler.seen.lock().unwrap().clone();
+    assert_eq!(
+        vec!["data", "data", "save", "save"],
+        seen.iter().map(|(call, _)| *call).collect::<Vec<_>>()
+    );
+
+    let (first_id, second_id) = (seen[0].1, seen[1].1);
+    assert_ne!(first_id, second_id);
+    assert_eq!(first_id, seen[2].1);
+    assert_eq!(second_id, seen[3].1);
+}
+
+#[tokio::test]
+async fn client_with_reverse_dns_greeted() {
+    let mut client = BufReader::new(connect_resolved(Some("localhost.")).await);
+
+    assert_eq!(vec!["220 test ESMTP"], read_lines(&mut client, 1).await);
+}
+
+#[tokio::test]
+async fn client_without_reverse_dns_refused() {
+    let mut client = connect_resolved(None).await;
+
+    assert_eq!(
+        vec!["554 5.7.1 Connection refused"],
+        read_until_closed(&mut client).await
+    );
+}
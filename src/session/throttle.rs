@@ -0,0 +1,38 @@
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Token bucket limiting the commands of a session to a rate per second.
+/// It holds one second of commands, so a client may burst up to the rate after a pause.
+pub(crate) struct CommandBucket {
+    rate: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl CommandBucket {
+    /// Create a full bucket allowing the given number of commands per second.
+    pub(crate) fn new(per_second: u32) -> Self {
+        let rate = f64::from(per_second.max(1));
+
+        CommandBucket {
+            rate,
+            tokens: rate,
+            updated: Instant::now(),
+        }
+    }
+
+    /// Take a token for a command.
+    /// Returns how long the command has to wait for it, `None` when it may run right away.
+    pub(crate) fn take(&mut self) -> Option<Duration> {
+        let now = Instant::now();
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.updated = now;
+
+        self.tokens -= 1.0;
+        match self.tokens < 0.0 {
+            true => Some(Duration::from_secs_f64(-self.tokens / self.rate)),
+            false => None,
+        }
+    }
+}
@@ -0,0 +1,79 @@
+use std::{sync::Arc, time::Duration};
+use tokio::{sync::mpsc, task::JoinHandle};
+
+use super::bounded;
+use crate::{
+    body::{body_channel, BodyChunk},
+    Handler, SaveOutcome, SmtpState,
+};
+
+/// Chunks of the body buffered for the handler, before reading from the client pauses.
+const BUFFERED_CHUNKS: usize = 16;
+
+/// Message saved by `Handler::save_stream` on its own task, while the body is received.
+/// Dropping it before `finish` aborts the body.
+pub(crate) struct BodyUpload {
+    sender: Option<mpsc::Sender<BodyChunk>>,
+    save: JoinHandle<SaveOutcome>,
+    /// Bytes of the body passed to the handler.
+    pub(crate) size: usize,
+}
+
+impl BodyUpload {
+    /// Start saving the message of the transaction in the state.
+    pub(crate) fn start(handler: Arc<dyn Handler>, state: SmtpState) -> Self {
+        let (sender, body) = body_channel(BUFFERED_CHUNKS);
+        let save = tokio::spawn(async move { handler.save_stream(&state, body).await });
+
+        BodyUpload {
+            sender: Some(sender),
+            save,
+            size: 0,
+        }
+    }
+
+    /// Pass the next part of the body to the handler, waiting while it is behind.
+    /// When the handler stops reading, or does not catch up within the limit, the body is aborted.
+    pub(crate) async fn send(&mut self, data: Vec<u8>, limit: Option<Duration>) {
+        let sender = match &self.sender {
+            Some(sender) if !data.is_empty() => sender,
+            _ => return,
+        };
+
+        self.size += data.len();
+
+        if !matches!(bounded(limit, sender.send(Some(data))).await, Some(Ok(()))) {
+            self.sender = None;
+        }
+    }
+
+    /// End the body and wait for the handler to save the message.
+    /// Returns `None` when the body was aborted, or the handler did not finish within the limit.
+    pub(crate) async fn finish(mut self, limit: Option<Duration>) -> Option<SaveOutcome> {
+        let sender = match self.sender.take() {
+            Some(sender) => sender,
+            None => {
+                self.save.abort();
+                return None;
+            }
+        };
+
+        let save = &mut self.save;
+        let saved = bounded(limit, async move {
+            // The handler may return before reading the end, which closes the body.
+            let _ = sender.send(None).await;
+            save.await
+        })
+        .await;
+
+        match saved {
+            Some(Ok(result)) => Some(result),
+            // The handler panicked.
+            Some(Err(_)) => None,
+            None => {
+                self.save.abort();
+                None
+            }
+        }
+    }
+}
@@ -0,0 +1,273 @@
+//! Helpers to test a `Handler` against the real protocol flow, without binding ports.
+//! Enable the `test-util` feature to use them.
+
+use std::{net::SocketAddr, sync::Arc};
+use tokio::io::{duplex, AsyncBufReadExt, AsyncWriteExt, BufReader, DuplexStream};
+
+use crate::{command::Mailbox, config::Config, Handler, Response, SmtpService, SmtpSession};
+
+#[cfg(test)]
+mod tests;
+
+/// Size of the in-memory buffer between the harness and the session.
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// Replies without arguments, recognized by their exact text.
+const FIXED_REPLIES: &[Response] = &[
+    Response::Goodbye,
+    Response::Ok,
+    Response::Delivered,
+    Response::StartData,
+    Response::TryLater,
+    Response::RecipientUnavailable,
+    Response::TooManyRecipientsClosing,
+    Response::TooManyTransactions,
+    Response::Timeout,
+    Response::SessionExpired,
+    Response::TooManyRecipients,
+    Response::SyntaxError,
+    Response::LineTooLong,
+    Response::AddressTooLong,
+    Response::InvalidParameters,
+    Response::OutOfSequence,
+    Response::NestedMail,
+    Response::BdatRequired,
+    Response::PipelinedData,
+    Response::NotImplemented,
+    Response::RecipientNotLocal,
+    Response::InvalidHelo,
+    Response::RelayDenied,
+    Response::TransactionRejected,
+    Response::SizeExceeded,
+    Response::MessageTooBig,
+    Response::NonAsciiAddress,
+    Response::InvalidRecipient,
+    Response::TransactionFailed,
+    Response::VirusDetected,
+    Response::PolicyRejected,
+    Response::ScanFailed,
+    Response::TooManyErrors,
+    Response::EarlyTalker,
+    Response::ConnectionRefused,
+    Response::AddressRefused,
+    Response::Evicted,
+    Response::Disconnected,
+    Response::TooManyConnections,
+];
+
+/// Client connected to a session over an in-memory stream.
+/// Every call sends input to the session and returns its parsed reply.
+pub struct TestHarness {
+    client: BufReader<DuplexStream>,
+    greeting: Response,
+}
+
+impl TestHarness {
+    /// Start a session with the default settings, using the given handler.
+    pub async fn new(handler: Arc<dyn Handler>) -> Self {
+        TestHarness::start(Config::new("localhost".into()), handler, None, None).await
+    }
+
+    /// Start a session with the settings, handler and metrics of a service.
+    pub async fn with_service(service: &SmtpService) -> Self {
+        TestHarness::start(
+            service.config.clone(),
+            service.handler.clone(),
+            service.metrics.clone(),
+            service.trace.clone(),
+        )
+        .await
+    }
+
+    async fn start(
+        config: Config,
+        handler: Arc<dyn Handler>,
+        metrics: Option<Arc<dyn crate::Metrics>>,
+        trace: Option<crate::Tracer>,
+    ) -> Self {
+        let (client, server) = duplex(BUFFER_SIZE);
+        let peer = SocketAddr::from(([127, 0, 0, 1], 0));
+
+        let session = SmtpSession::new(
+            server,
+            peer,
+            Arc::new(config),
+            handler,
+            metrics,
+            trace,
+            None,
+        );
+        tokio::spawn(session.handle());
+
+        let mut client = BufReader::new(client);
+        let greeting = read_reply(&mut client)
+            .await
+            .expect("Session closed before the greeting.");
+
+        TestHarness { client, greeting }
+    }
+
+    /// The greeting sent when the session started.
+    pub fn greeting(&self) -> &Response {
+        &self.greeting
+    }
+
+    /// Send a command line, without line ending, and return the reply.
+    /// Panics when the session closed the connection.
+    pub async fn send(&mut self, command: &str) -> Response {
+        self.write(format!("{}\r\n", command).as_bytes()).await;
+
+        read_reply(&mut self.client)
+            .await
+            .expect("Session closed the connection.")
+    }
+
+    /// Send a message body after a 354 reply, terminating it, and return the reply.
+    /// Lines starting with a dot are dot-stuffed.
+    /// Panics when the session closed the connection.
+    pub async fn send_data(&mut self, body: &str) -> Response {
+        let mut data = String::with_capacity(body.len() + 8);
+
+        for line in body.lines() {
+            if line.starts_with('.') {
+                data.push('.');
+            }
+
+            data.push_str(line);
+            data.push_str("\r\n");
+        }
+
+        data.push_str(".\r\n");
+        self.write(data.as_bytes()).await;
+
+        read_reply(&mut self.client)
+            .await
+            .expect("Session closed the connection.")
+    }
+
+    /// Send a BDAT command with the chunk following it, and return the reply.
+    /// The chunk is sent as is, `last` marks it as the end of the message.
+    /// Panics when the session closed the connection.
+    pub async fn send_chunk(&mut self, chunk: &[u8], last: bool) -> Response {
+        let command = match last {
+            true => format!("BDAT {} LAST\r\n", chunk.len()),
+            false => format!("BDAT {}\r\n", chunk.len()),
+        };
+        self.write(&[command.as_bytes(), chunk].concat()).await;
+
+        read_reply(&mut self.client)
+            .await
+            .expect("Session closed the connection.")
+    }
+
+    /// Check if the session closed the connection, without sending anything.
+    pub async fn is_closed(&mut self) -> bool {
+        read_reply(&mut self.client).await.is_none()
+    }
+
+    async fn write(&mut self, input: &[u8]) {
+        self.client
+            .get_mut()
+            .write_all(input)
+            .await
+            .expect("Could not write to the session.");
+    }
+}
+
+/// Read a full, possibly multiline, reply. Returns `None` when the connection was closed.
+async fn read_reply(client: &mut BufReader<DuplexStream>) -> Option<Response> {
+    let mut reply = String::new();
+
+    loop {
+        let start = reply.len();
+        if client.read_line(&mut reply).await.ok()? == 0 {
+            return None;
+        }
+
+        if reply.as_bytes().get(start + 3) != Some(&b'-') {
+            return Some(parse_reply(&reply));
+        }
+    }
+}
+
+/// Turn the text of a reply back into the `Response` which rendered it.
+fn parse_reply(reply: &str) -> Response {
+    if let Some(fixed) = FIXED_REPLIES.iter().find(|r| r.to_response() == reply) {
+        return fixed.clone();
+    }
+
+    let lines: Vec<&str> = reply.lines().collect();
+    let code = lines[0].get(..3).unwrap_or_default();
+    let texts: Vec<&str> = lines
+        .iter()
+        .map(|l| l.get(4..).unwrap_or_default())
+        .collect();
+
+    if let (1, Some(id)) = (lines.len(), reply.strip_prefix("250 2.0.0 Ok: queued as ")) {
+        return Response::Queued(id.trim_end().to_string());
+    }
+
+    let chunk = reply
+        .strip_prefix("250 2.0.0 ")
+        .and_then(|r| r.strip_suffix(" octets received\r\n"))
+        .and_then(|r| r.parse().ok());
+    if let (1, Some(size)) = (lines.len(), chunk) {
+        return Response::ChunkReceived(size);
+    }
+
+    let recipient = reply
+        .strip_prefix("250 2.1.5 <")
+        .and_then(|r| r.strip_suffix(">... Recipient ok\r\n"))
+        .map(|r| {
+            parse_mailbox(r).unwrap_or_else(|| Mailbox {
+                local: r.to_string(),
+                domain: "".into(),
+            })
+        });
+    if let (1, Some(recipient)) = (lines.len(), recipient) {
+        return Response::RecipientOk(recipient);
+    }
+
+    let members: Option<Vec<Mailbox>> = texts
+        .iter()
+        .map(|t| t.strip_prefix('<')?.strip_suffix('>'))
+        .map(|m| m.and_then(parse_mailbox))
+        .collect();
+
+    if let ("250", Some(members)) = (code, members) {
+        return Response::Expanded(members);
+    }
+
+    let unavailable = texts[0].strip_suffix(" Service not available, closing transmission channel");
+    if let ("421", 1, Some(name)) = (code, lines.len(), unavailable) {
+        return Response::ServiceUnavailable(name.to_string());
+    }
+
+    let banner = texts[0].split_once(" ESMTP ");
+    if let ("220", 1, Some((hostname, banner))) = (code, lines.len(), banner) {
+        return Response::BannerGreeting(hostname.to_string(), banner.to_string());
+    }
+
+    match (code, texts[0].strip_suffix(" ESMTP")) {
+        ("220", Some(name)) if lines.len() == 1 => Response::Greeting(name.to_string()),
+        ("250", Some(name)) if lines.len() == 1 => Response::Helo(name.to_string()),
+        ("250", Some(name)) => Response::Ehlo(
+            name.to_string(),
+            texts[1..].iter().map(|t| t.to_string()).collect(),
+        ),
+        _ => Response::Custom {
+            code: code.parse().unwrap_or_default(),
+            message: texts.join("\n"),
+        },
+    }
+}
+
+/// Split an address from an EXPN reply into its local part and domain.
+fn parse_mailbox(address: &str) -> Option<Mailbox> {
+    let (local, domain) = address.rsplit_once('@')?;
+
+    Some(Mailbox {
+        local: local.to_string(),
+        domain: domain.into(),
+    })
+}
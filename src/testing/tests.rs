@@ -0,0 +1,498 @@
+use async_trait::async_trait;
+
+use super::*;
+use crate::{command::Mailbox, Direction, RecipientDecision, SaveOutcome, SmtpState};
+
+/// Handler accepting mail for nexium.app, and only messages with a subject.
+struct ExampleHandler {}
+
+#[async_trait]
+impl Handler for ExampleHandler {
+    async fn recipient_local(&self, recipient: &Mailbox) -> bool {
+        recipient.domain == "nexium.app".into()
+    }
+
+    async fn save(&self, state: &SmtpState) -> bool {
+        state.data.starts_with(b"Subject:")
+    }
+}
+
+async fn harness() -> TestHarness {
+    TestHarness::new(Arc::new(ExampleHandler {})).await
+}
+
+#[tokio::test]
+async fn harness_chunks() {
+    let mut harness = harness().await;
+
+    harness.send("EHLO example.com").await;
+    harness
+        .send("MAIL FROM:<info@example.com> BODY=BINARYMIME")
+        .await;
+    harness.send("RCPT TO:<admin@nexium.app>").await;
+
+    assert_eq!(
+        Response::ChunkReceived(10),
+        harness.send_chunk(b"Subject: \0", false).await
+    );
+    assert_eq!(Response::Ok, harness.send_chunk(b"\r\n.\r\n", true).await);
+}
+
+#[tokio::test]
+async fn harness_transaction() {
+    let mut harness = harness().await;
+
+    assert_eq!(&Response::Greeting("localhost".into()), harness.greeting());
+    assert_eq!(
+        Response::Ehlo(
+            "localhost".into(),
+            vec![
+                "SIZE 0".into(),
+                "8BITMIME".into(),
+                "BINARYMIME".into(),
+                "CHUNKING".into(),
+                "SMTPUTF8".into()
+            ]
+        ),
+        harness.send("EHLO example.com").await
+    );
+    assert_eq!(
+        Response::Ok,
+        harness.send("MAIL FROM:<info@example.com>").await
+    );
+    assert_eq!(
+        Response::RecipientNotLocal,
+        harness.send("RCPT TO:<info@example.com>").await
+    );
+    assert_eq!(
+        Response::Ok,
+        harness.send("RCPT TO:<admin@nexium.app>").await
+    );
+    assert_eq!(Response::StartData, harness.send("DATA").await);
+    assert_eq!(
+        Response::Ok,
+        harness
+            .send_data("Subject: Hello\r\n\r\n.Dotted line\r\n")
+            .await
+    );
+    assert_eq!(Response::Goodbye, harness.send("QUIT").await);
+    assert!(harness.is_closed().await);
+}
+
+#[tokio::test]
+async fn harness_message_rejected() {
+    let mut harness = harness().await;
+
+    harness.send("HELO example.com").await;
+    harness.send("MAIL FROM:<info@example.com>").await;
+    harness.send("RCPT TO:<admin@nexium.app>").await;
+    harness.send("DATA").await;
+
+    assert_eq!(
+        Response::TransactionFailed,
+        harness.send_data("No subject").await
+    );
+}
+
+/// Handler rejecting messages on their content, with the reply named in the subject.
+struct ScanningHandler {}
+
+#[async_trait]
+impl Handler for ScanningHandler {
+    async fn recipient_local(&self, _recipient: &Mailbox) -> bool {
+        true
+    }
+
+    async fn save(&self, _state: &SmtpState) -> bool {
+        true
+    }
+
+    async fn save_message(&self, state: &SmtpState) -> SaveOutcome {
+        match &state.data[..] {
+            b"Subject: virus" => SaveOutcome::Rejected(Response::VirusDetected),
+            b"Subject: spam" => SaveOutcome::Rejected(Response::PolicyRejected),
+            b"Subject: unscannable" => SaveOutcome::Rejected(Response::ScanFailed),
+            b"Subject: local" => SaveOutcome::Delivered,
+            _ => SaveOutcome::Accepted,
+        }
+    }
+}
+
+#[tokio::test]
+async fn harness_content_rejected() {
+    let mut harness = TestHarness::new(Arc::new(ScanningHandler {})).await;
+    harness.send("HELO example.com").await;
+
+    for (subject, reply) in [
+        ("virus", Response::VirusDetected),
+        ("spam", Response::PolicyRejected),
+        ("unscannable", Response::ScanFailed),
+        ("local", Response::Delivered),
+        ("clean", Response::Ok),
+    ] {
+        harness.send("MAIL FROM:<info@example.com>").await;
+        harness.send("RCPT TO:<admin@nexium.app>").await;
+        harness.send("DATA").await;
+
+        assert_eq!(
+            reply,
+            harness
+                .send_data(&format!("Subject: {}\r\n", subject))
+                .await
+        );
+    }
+}
+
+#[tokio::test]
+async fn harness_with_service() {
+    let service = SmtpService::builder()
+        .address("127.0.0.1:25".parse().unwrap())
+        .handler(Arc::new(ExampleHandler {}))
+        .server_name("mx.nexium.app")
+        .max_bad_commands(0)
+        .build()
+        .unwrap();
+    let mut harness = TestHarness::with_service(&service).await;
+
+    assert_eq!(
+        &Response::Greeting("mx.nexium.app".into()),
+        harness.greeting()
+    );
+    assert_eq!(
+        Response::Helo("mx.nexium.app".into()),
+        harness.send("HELO example.com").await
+    );
+    assert_eq!(Response::TooManyErrors, harness.send("NOPE").await);
+    assert!(harness.is_closed().await);
+}
+
+#[tokio::test]
+async fn harness_trace() {
+    let trace = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let recorded = trace.clone();
+    let service = SmtpService::builder()
+        .address("127.0.0.1:25".parse().unwrap())
+        .handler(Arc::new(ExampleHandler {}))
+        .server_name("mx.nexium.app")
+        .trace(move |direction, bytes| {
+            let line = String::from_utf8(bytes.to_vec()).unwrap();
+            recorded.lock().unwrap().push((direction, line));
+        })
+        .build()
+        .unwrap();
+    let mut harness = TestHarness::with_service(&service).await;
+
+    harness.send("HELO example.com").await;
+    harness.send("MAIL FROM:<info@example.com>").await;
+    harness.send("RCPT TO:<admin@nexium.app>").await;
+    harness.send("DATA").await;
+    harness.send_data("Subject: Hi").await;
+    harness.send("QUIT").await;
+
+    let expected = vec![
+        (Direction::Out, "220 mx.nexium.app ESMTP\r\n"),
+        (Direction::In, "HELO example.com\r\n"),
+        (Direction::Out, "250 mx.nexium.app ESMTP\r\n"),
+        (Direction::In, "MAIL FROM:<info@example.com>\r\n"),
+        (Direction::Out, "250 Ok\r\n"),
+        (Direction::In, "RCPT TO:<admin@nexium.app>\r\n"),
+        (Direction::Out, "250 Ok\r\n"),
+        (Direction::In, "DATA\r\n"),
+        (Direction::Out, "354 Go ahead\r\n"),
+        (Direction::In, "Subject: Hi\r\n.\r\n"),
+        (Direction::Out, "250 Ok\r\n"),
+        (Direction::In, "QUIT\r\n"),
+        (Direction::Out, "221 Goodbye!\r\n"),
+    ];
+    let expected: Vec<(Direction, String)> = expected
+        .into_iter()
+        .map(|(direction, line)| (direction, line.to_string()))
+        .collect();
+    assert_eq!(expected, *trace.lock().unwrap());
+}
+
+/// Handler rejecting recipients based on their local part.
+struct DecidingHandler {}
+
+#[async_trait]
+impl Handler for DecidingHandler {
+    async fn recipient_local(&self, _recipient: &Mailbox) -> bool {
+        true
+    }
+
+    async fn check_recipient(&self, recipient: &Mailbox) -> RecipientDecision {
+        match recipient.local.as_str() {
+            "gone" => RecipientDecision::RejectPermanent,
+            "busy" => RecipientDecision::RejectTemporary,
+            "remote" => RecipientDecision::Relay,
+            _ => RecipientDecision::Accept,
+        }
+    }
+
+    async fn save(&self, _state: &SmtpState) -> bool {
+        true
+    }
+}
+
+#[tokio::test]
+async fn harness_recipient_decisions() {
+    let mut harness = TestHarness::new(Arc::new(DecidingHandler {})).await;
+
+    harness.send("EHLO example.com").await;
+    harness.send("MAIL FROM:<info@example.com>").await;
+
+    assert_eq!(
+        Response::RecipientNotLocal,
+        harness.send("RCPT TO:<gone@nexium.app>").await
+    );
+    assert_eq!(
+        Response::RecipientUnavailable,
+        harness.send("RCPT TO:<busy@nexium.app>").await
+    );
+    assert_eq!(
+        Response::RelayDenied,
+        harness.send("RCPT TO:<remote@example.org>").await
+    );
+    assert_eq!(
+        Response::Ok,
+        harness.send("RCPT TO:<admin@nexium.app>").await
+    );
+}
+
+#[tokio::test]
+async fn harness_greylisted_recipient() {
+    let delay = std::time::Duration::from_millis(100);
+    let handler = crate::handlers::GreylistHandler::new(ExampleHandler {}, delay);
+    let mut harness = TestHarness::new(Arc::new(handler)).await;
+
+    harness.send("EHLO example.com").await;
+    harness.send("MAIL FROM:<info@example.com>").await;
+
+    assert_eq!(
+        Response::RecipientUnavailable,
+        harness.send("RCPT TO:<admin@nexium.app>").await
+    );
+    assert_eq!(
+        Response::RecipientNotLocal,
+        harness.send("RCPT TO:<admin@example.org>").await
+    );
+
+    tokio::time::sleep(delay).await;
+
+    assert_eq!(
+        Response::Ok,
+        harness.send("RCPT TO:<admin@nexium.app>").await
+    );
+}
+
+/// Handler looking up recipients in a user directory which can be down.
+struct DirectoryHandler {
+    down: std::sync::atomic::AtomicBool,
+}
+
+impl DirectoryHandler {
+    /// Look up whether the recipient exists, failing while the directory is down.
+    fn lookup(&self, recipient: &Mailbox) -> Result<bool, ()> {
+        match self.down.load(std::sync::atomic::Ordering::SeqCst) {
+            true => Err(()),
+            false => Ok(recipient.local == "admin"),
+        }
+    }
+}
+
+#[async_trait]
+impl Handler for DirectoryHandler {
+    async fn recipient_local(&self, recipient: &Mailbox) -> bool {
+        self.lookup(recipient).unwrap_or(false)
+    }
+
+    async fn check_recipient(&self, recipient: &Mailbox) -> RecipientDecision {
+        match self.lookup(recipient) {
+            Ok(true) => RecipientDecision::Accept,
+            Ok(false) => RecipientDecision::RejectPermanent,
+            Err(()) => RecipientDecision::RejectTemporary,
+        }
+    }
+
+    async fn save(&self, _state: &SmtpState) -> bool {
+        true
+    }
+}
+
+#[tokio::test]
+async fn harness_directory_outage_is_temporary() {
+    let handler = Arc::new(DirectoryHandler {
+        down: std::sync::atomic::AtomicBool::new(true),
+    });
+    let mut harness = TestHarness::new(handler.clone()).await;
+
+    harness.send("EHLO example.com").await;
+    harness.send("MAIL FROM:<info@example.com>").await;
+
+    // The sender retries later, instead of bouncing the message.
+    let reply = harness.send("RCPT TO:<admin@nexium.app>").await;
+    assert_eq!(Response::RecipientUnavailable, reply);
+    assert_eq!(450, reply.code());
+
+    handler
+        .down
+        .store(false, std::sync::atomic::Ordering::SeqCst);
+    assert_eq!(
+        Response::Ok,
+        harness.send("RCPT TO:<admin@nexium.app>").await
+    );
+    assert_eq!(
+        Response::RecipientNotLocal,
+        harness.send("RCPT TO:<nobody@nexium.app>").await
+    );
+}
+
+/// Handler knowing a single mailing list, `staff`.
+struct ListHandler {}
+
+#[async_trait]
+impl Handler for ListHandler {
+    async fn recipient_local(&self, _recipient: &Mailbox) -> bool {
+        true
+    }
+
+    async fn expand(&self, list: &str) -> Option<Vec<Mailbox>> {
+        match list {
+            "staff" => Some(vec![
+                Mailbox {
+                    local: "alice".into(),
+                    domain: "nexium.app".into(),
+                },
+                Mailbox {
+                    local: "bob".into(),
+                    domain: "nexium.app".into(),
+                },
+            ]),
+            _ => None,
+        }
+    }
+
+    async fn save(&self, _state: &SmtpState) -> bool {
+        true
+    }
+}
+
+fn list_service(allow_expn: bool) -> SmtpService {
+    SmtpService::builder()
+        .address("127.0.0.1:25".parse().unwrap())
+        .handler(Arc::new(ListHandler {}))
+        .allow_expn(allow_expn)
+        .build()
+        .unwrap()
+}
+
+#[tokio::test]
+async fn harness_expn() {
+    let mut harness = TestHarness::with_service(&list_service(true)).await;
+
+    match harness.send("EXPN staff").await {
+        Response::Expanded(members) => {
+            let addresses: Vec<String> = members.iter().map(|m| m.to_string()).collect();
+            assert_eq!(vec!["alice@nexium.app", "bob@nexium.app"], addresses);
+        }
+        other => panic!("Unexpected reply {:?}", other),
+    }
+    assert_eq!(Response::NotImplemented, harness.send("EXPN unknown").await);
+}
+
+#[tokio::test]
+async fn harness_expn_disabled() {
+    let mut harness = TestHarness::with_service(&list_service(false)).await;
+
+    assert_eq!(Response::NotImplemented, harness.send("EXPN staff").await);
+}
+
+#[tokio::test]
+async fn harness_unimplemented_verbs() {
+    let mut harness = harness().await;
+
+    assert_eq!(
+        Response::NotImplemented,
+        harness.send("ETRN example.com").await
+    );
+    assert_eq!(Response::NotImplemented, harness.send("TURN").await);
+    assert_eq!(
+        Response::NotImplemented,
+        harness.send("ATRN example.com").await
+    );
+    assert_eq!(Response::SyntaxError, harness.send("GIBBERISH").await);
+}
+
+#[test]
+fn parse_queued_reply() {
+    assert_eq!(
+        Response::Queued("ABC123".into()),
+        parse_reply("250 2.0.0 Ok: queued as ABC123\r\n")
+    );
+}
+
+#[test]
+fn parse_recipient_ok_reply() {
+    assert_eq!(
+        Response::RecipientOk(Mailbox {
+            local: "admin".into(),
+            domain: "nexium.app".into(),
+        }),
+        parse_reply("250 2.1.5 <admin@nexium.app>... Recipient ok\r\n")
+    );
+    assert_eq!(
+        Response::RecipientOk(Mailbox {
+            local: "postmaster".into(),
+            domain: "".into(),
+        }),
+        parse_reply("250 2.1.5 <postmaster>... Recipient ok\r\n")
+    );
+}
+
+#[test]
+fn parse_service_unavailable_reply() {
+    assert_eq!(
+        Response::ServiceUnavailable("mx.nexium.app".into()),
+        parse_reply("421 mx.nexium.app Service not available, closing transmission channel\r\n")
+    );
+}
+
+#[test]
+fn parse_custom_reply() {
+    assert_eq!(
+        Response::custom(250, "2.0.0 Ok: queued").unwrap(),
+        parse_reply("250 2.0.0 Ok: queued\r\n")
+    );
+}
+
+/// Handler accepting only the postmaster as recipient.
+struct PostmasterHandler {}
+
+#[async_trait]
+impl Handler for PostmasterHandler {
+    async fn recipient_local(&self, recipient: &Mailbox) -> bool {
+        recipient.is_postmaster()
+    }
+
+    async fn save(&self, _state: &SmtpState) -> bool {
+        true
+    }
+}
+
+#[tokio::test]
+async fn harness_postmaster_recipient() {
+    let mut harness = TestHarness::new(Arc::new(PostmasterHandler {})).await;
+    harness.send("HELO example.com").await;
+    harness.send("MAIL FROM:<info@example.com>").await;
+
+    assert_eq!(Response::Ok, harness.send("RCPT TO:<postmaster>").await);
+    assert_eq!(
+        Response::Ok,
+        harness.send("RCPT TO:<Postmaster@example.com>").await
+    );
+    assert_eq!(
+        Response::RecipientNotLocal,
+        harness.send("RCPT TO:<admin@example.com>").await
+    );
+    assert_eq!(Response::SyntaxError, harness.send("RCPT TO:<admin>").await);
+}
@@ -0,0 +1,16 @@
+use std::sync::Arc;
+
+/// Direction of traced protocol bytes, as seen from the server.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Direction {
+    /// Received from the client.
+    In,
+    /// Sent to the client.
+    Out,
+}
+
+/// Callback receiving the raw protocol exchanged with clients, install one with `SmtpServiceBuilder::trace`.
+/// It is called with every command line and chunk of message data received, and every reply sent,
+/// in the order they happen. This is meant for capturing sessions to debug interoperability,
+/// it is called on the connection's task, so it should return quickly.
+pub type Tracer = Arc<dyn Fn(Direction, &[u8]) + Send + Sync>;